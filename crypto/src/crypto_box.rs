@@ -4,6 +4,7 @@
 use failure::Fail;
 use hex::{FromHex, FromHexError};
 use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, GroupElement, Scalar};
 
 use super::nonce::Nonce;
 use std::ops::Deref;
@@ -120,6 +121,23 @@ pub fn precompute(pk_as_hex_string: &str, sk_as_hex_string: &str) -> Result<Prec
     Ok(PrecomputedKey(box_::precompute(&*PublicKey::from_hex(pk_as_hex_string)?, &*SecretKey::from_hex(sk_as_hex_string)?)))
 }
 
+/// Generate new random key pair
+///
+/// Returns hex strings representing `(public_key, secret_key)`
+pub fn random_keypair() -> (String, String) {
+    let (pk, sk) = box_::gen_keypair();
+    (hex::encode(&pk.0), hex::encode(&sk.0))
+}
+
+/// Derive public key from the secret key
+///
+/// Returns hex string representing public key
+pub fn public_key_from_secret_key(sk_as_hex_string: &str) -> Result<String, FromHexError> {
+    let secret_key = SecretKey::from_hex(sk_as_hex_string)?;
+    let GroupElement(public_key) = scalarmult_base(&Scalar((secret_key.0).0));
+    Ok(hex::encode(&public_key))
+}
+
 /// Encrypt binary message
 ///
 /// # Arguments
@@ -184,6 +202,25 @@ mod tests {
         Ok(assert_eq!(expected_precomputed, precomputed))
     }
 
+    #[test]
+    fn generate_random_keypair() -> Result<(), Error> {
+        let (pk_local, sk_local) = random_keypair();
+        let (pk_remote, sk_remote) = random_keypair();
+        assert_ne!(pk_local, pk_remote);
+
+        // both sides should compute the same key
+        let precomputed_local = precompute(&pk_remote, &sk_local)?;
+        let precomputed_remote = precompute(&pk_local, &sk_remote)?;
+        Ok(assert!(precomputed_local == precomputed_remote))
+    }
+
+    #[test]
+    fn derive_public_key_from_secret_key() -> Result<(), Error> {
+        let (pk, sk) = random_keypair();
+        assert_eq!(pk, public_key_from_secret_key(&sk)?);
+        Ok(())
+    }
+
     #[test]
     fn encrypt_message() -> Result<(), Error> {
         let nonce = Nonce::new(&hex::decode("8dde158c55cff52f4be9352787d333e616a67853640d72c5")?);
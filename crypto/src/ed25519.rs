@@ -0,0 +1,113 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Ed25519 keys and signatures in the tezos format (base58check `edsk` seeds, `edsig` signatures).
+//!
+//! Tezos signs blake2b digest of the watermarked data, not the data itself.
+
+use std::fmt;
+
+use failure::Fail;
+use sodiumoxide::crypto::sign::ed25519;
+
+use crate::base58::FromBase58Check;
+use crate::blake2b;
+use crate::hash::{ChainId, HashType};
+
+/// Watermark of the signed block header
+const BLOCK_HEADER_WATERMARK: u8 = 0x01;
+
+#[derive(Debug, Fail)]
+pub enum SigningKeyError {
+    #[fail(display = "Invalid base58check encoding of the secret key")]
+    InvalidEncoding,
+    #[fail(display = "Only unencrypted ed25519 seed (edsk, 54 characters) is supported")]
+    UnsupportedKey,
+}
+
+/// Ed25519 key pair used to sign blocks
+#[derive(Clone)]
+pub struct SigningKey {
+    public_key: ed25519::PublicKey,
+    secret_key: ed25519::SecretKey,
+}
+
+impl SigningKey {
+    /// Create key pair from the unencrypted `edsk` seed
+    pub fn from_base58_seed(seed: &str) -> Result<Self, SigningKeyError> {
+        let decoded = seed.from_base58check().map_err(|_| SigningKeyError::InvalidEncoding)?;
+        let prefix = HashType::SeedEd25519.prefix();
+        if decoded.len() != prefix.len() + HashType::SeedEd25519.size() || !decoded.starts_with(prefix) {
+            return Err(SigningKeyError::UnsupportedKey);
+        }
+
+        let seed = ed25519::Seed::from_slice(&decoded[prefix.len()..]).ok_or(SigningKeyError::UnsupportedKey)?;
+        let (public_key, secret_key) = ed25519::keypair_from_seed(&seed);
+        Ok(SigningKey { public_key, secret_key })
+    }
+
+    /// Public key as `edpk` string
+    pub fn public_key(&self) -> String {
+        HashType::PublicKeyEd25519.bytes_to_string(self.public_key.as_ref())
+    }
+
+    /// Public key hash as `tz1` string
+    pub fn public_key_hash(&self) -> String {
+        HashType::ContractTz1Hash.bytes_to_string(&blake2b::digest_160(self.public_key.as_ref()))
+    }
+
+    /// Sign `data` prefixed by `watermark`, returns 64 bytes of the signature
+    pub fn sign(&self, watermark: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut watermarked = Vec::with_capacity(watermark.len() + data.len());
+        watermarked.extend_from_slice(watermark);
+        watermarked.extend_from_slice(data);
+        let digest = blake2b::digest_256(&watermarked);
+        ed25519::sign_detached(&digest, &self.secret_key).as_ref().to_vec()
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // secret key is never printed
+        f.debug_struct("SigningKey").field("public_key_hash", &self.public_key_hash()).finish()
+    }
+}
+
+/// Watermark of the block header signed for the chain `chain_id`
+pub fn block_header_watermark(chain_id: &ChainId) -> Vec<u8> {
+    let mut watermark = Vec::with_capacity(1 + chain_id.len());
+    watermark.push(BLOCK_HEADER_WATERMARK);
+    watermark.extend_from_slice(chain_id);
+    watermark
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signing_key_from_seed() -> Result<(), failure::Error> {
+        // sandbox bootstrap1 account
+        let key = SigningKey::from_base58_seed("edsk3gUfUPyBSfrS9CCgmCiQsTCHGkviBDusMxDJstFtojtc1zcpsh")?;
+        assert_eq!("edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav", key.public_key());
+        assert_eq!("tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx", key.public_key_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn signing_key_rejects_public_key() {
+        assert!(SigningKey::from_base58_seed("edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav").is_err());
+    }
+
+    #[test]
+    fn sign_watermarked_data() -> Result<(), failure::Error> {
+        let key = SigningKey::from_base58_seed("edsk3gUfUPyBSfrS9CCgmCiQsTCHGkviBDusMxDJstFtojtc1zcpsh")?;
+        let watermark = block_header_watermark(&hex::decode("8eceda2f")?);
+        let signature = key.sign(&watermark, b"block header");
+        assert_eq!(64, signature.len());
+
+        let signature = ed25519::Signature::from_slice(&signature).unwrap();
+        assert!(ed25519::verify_detached(&signature, &blake2b::digest_256(b"\x01\x8e\xce\xda\x2fblock header"), &key.public_key));
+        Ok(())
+    }
+}
@@ -18,6 +18,9 @@ mod prefix_bytes {
     pub const PUBLIC_KEY_ED25519: [u8; 4] = [13, 15, 37, 217];
     pub const PUBLIC_KEY_SECP256K1: [u8; 4] = [3, 254, 226, 86];
     pub const PUBLIC_KEY_P256: [u8; 4] = [3, 178, 139, 127];
+    pub const SEED_ED25519: [u8; 4] = [13, 15, 58, 7];
+    pub const SIGNATURE_ED25519: [u8; 5] = [9, 245, 205, 134, 18];
+    pub const NONCE_HASH: [u8; 3] = [69, 220, 169];
 }
 
 pub type Hash = Vec<u8>;
@@ -34,6 +37,9 @@ pub type CryptoboxPublicKeyHash = Hash;
 pub type PublicKeyEd25519 = Hash;
 pub type PublicKeySecp256k1 = Hash;
 pub type PublicKeyP256 = Hash;
+pub type SeedEd25519 = Hash;
+pub type SignatureEd25519 = Hash;
+pub type NonceHash = Hash;
 
 #[derive(Debug, Copy, Clone)]
 pub enum HashType {
@@ -65,6 +71,12 @@ pub enum HashType {
     // "\003\254\226\086" (* sppk(55) *)
     PublicKeyP256,
     // "\003\178\139\127" (* p2pk(55) *)
+    SeedEd25519,
+    // "\013\015\058\007" (* edsk(54) *)
+    SignatureEd25519,
+    // "\009\245\205\134\018" (* edsig(99) *)
+    NonceHash,
+    // "\069\220\169" (* nce(53) *)
 }
 
 impl HashType {
@@ -87,6 +99,9 @@ impl HashType {
             HashType::PublicKeyEd25519 => &PUBLIC_KEY_ED25519,
             HashType::PublicKeySecp256k1 => &PUBLIC_KEY_SECP256K1,
             HashType::PublicKeyP256 => &PUBLIC_KEY_P256,
+            HashType::SeedEd25519 => &SEED_ED25519,
+            HashType::SignatureEd25519 => &SIGNATURE_ED25519,
+            HashType::NonceHash => &NONCE_HASH,
         }
     }
 
@@ -99,7 +114,9 @@ impl HashType {
             | HashType::ProtocolHash
             | HashType::OperationHash
             | HashType::OperationListListHash
-            | HashType::PublicKeyEd25519 => 32,
+            | HashType::PublicKeyEd25519
+            | HashType::SeedEd25519
+            | HashType::NonceHash => 32,
             HashType::CryptoboxPublicKeyHash => 16,
             HashType::ContractKt1Hash
             | HashType::ContractTz1Hash
@@ -107,6 +124,7 @@ impl HashType {
             | HashType::ContractTz3Hash => 20,
             HashType::PublicKeySecp256k1
             | HashType::PublicKeyP256 => 33,
+            HashType::SignatureEd25519 => 64,
         }
     }
 
@@ -124,7 +142,10 @@ impl HashType {
             | HashType::ContractTz3Hash
             | HashType::PublicKeyEd25519
             | HashType::PublicKeySecp256k1
-            | HashType::PublicKeyP256 => &copy_bytes,
+            | HashType::PublicKeyP256
+            | HashType::SeedEd25519
+            | HashType::SignatureEd25519
+            | HashType::NonceHash => &copy_bytes,
             HashType::CryptoboxPublicKeyHash => &crate::blake2b::digest_128
         }
     }
@@ -7,5 +7,7 @@ pub mod blake2b;
 pub mod base58;
 pub mod nonce;
 pub mod crypto_box;
+pub mod ed25519;
+pub mod proof_of_work;
 #[macro_use]
 pub mod hash;
\ No newline at end of file
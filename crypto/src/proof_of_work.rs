@@ -0,0 +1,224 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Proof of work of the peer identity (see crypto_box.ml).
+//!
+//! Proof of work stamp is valid, if blake2b digest of the public key and the stamp (read as little-endian number)
+//! is not greater than the target computed from the expected difficulty.
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use failure::Fail;
+use num_bigint::BigUint;
+use num_traits::One;
+
+use crate::blake2b;
+
+/// Size of the proof of work stamp in bytes
+pub const POW_STAMP_SIZE: usize = 24;
+/// How often is the progress of the proof of work search reported
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Worker threads publish the count of attempts in batches
+const ATTEMPTS_BATCH: u64 = 10_000;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum PowError {
+    #[fail(display = "Invalid proof of work difficulty: {}, expected value between 0 and 256", difficulty)]
+    InvalidDifficulty {
+        difficulty: f64,
+    },
+    #[fail(display = "Invalid proof of work stamp size: {}, expected: 24", size)]
+    InvalidStampSize {
+        size: usize,
+    },
+    #[fail(display = "Proof of work stamp does not satisfy the expected difficulty: {}", difficulty)]
+    InsufficientPow {
+        difficulty: f64,
+    },
+    #[fail(display = "Proof of work search failed, all worker threads are gone")]
+    SearchFailed,
+}
+
+/// Target of the proof of work computed from the expected difficulty
+#[derive(Clone, Debug)]
+pub struct PowTarget {
+    difficulty: f64,
+    target: BigUint,
+}
+
+impl PowTarget {
+    /// Difficulty is the expected count of leading zero bits of the digest, fractional part is supported too
+    pub fn new(difficulty: f64) -> Result<Self, PowError> {
+        if !(0.0..=256.0).contains(&difficulty) {
+            return Err(PowError::InvalidDifficulty { difficulty });
+        }
+
+        let shift = difficulty.trunc() as usize;
+        let frac = difficulty.fract();
+        let mantissa = if frac == 0.0 {
+            (BigUint::one() << 54) - BigUint::one()
+        } else {
+            BigUint::from(2f64.powf(54.0 - frac) as u64)
+        };
+        let target = if shift < 202 {
+            (mantissa << (202 - shift)) | ((BigUint::one() << (202 - shift)) - BigUint::one())
+        } else {
+            mantissa >> (shift - 202)
+        };
+
+        Ok(PowTarget { difficulty, target })
+    }
+
+    pub fn difficulty(&self) -> f64 {
+        self.difficulty
+    }
+
+    /// Check that the `proof_of_work_stamp` of the `public_key` satisfies this target
+    pub fn check(&self, public_key: &[u8], proof_of_work_stamp: &[u8]) -> Result<(), PowError> {
+        if proof_of_work_stamp.len() != POW_STAMP_SIZE {
+            return Err(PowError::InvalidStampSize { size: proof_of_work_stamp.len() });
+        }
+
+        let mut data = Vec::with_capacity(public_key.len() + proof_of_work_stamp.len());
+        data.extend_from_slice(public_key);
+        data.extend_from_slice(proof_of_work_stamp);
+
+        if self.is_satisfied_by(&data) {
+            Ok(())
+        } else {
+            Err(PowError::InsufficientPow { difficulty: self.difficulty })
+        }
+    }
+
+    /// `data` are the public key followed by the proof of work stamp
+    fn is_satisfied_by(&self, data: &[u8]) -> bool {
+        BigUint::from_bytes_le(&blake2b::digest_256(data)) <= self.target
+    }
+}
+
+/// Search for the proof of work stamp of the `public_key`, which satisfies the expected `difficulty`.
+///
+/// Search runs in `threads` worker threads, every thread starts from the random stamp.
+/// `progress` is periodically called with the count of stamps tried so far.
+pub fn generate_proof_of_work<F: FnMut(u64)>(public_key: &[u8], difficulty: f64, threads: usize, mut progress: F) -> Result<Vec<u8>, PowError> {
+    let target = Arc::new(PowTarget::new(difficulty)?);
+    let found = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicU64::new(0));
+    let (result_tx, result_rx) = mpsc::channel();
+
+    let workers = (0..cmp::max(threads, 1))
+        .map(|_| {
+            let target = target.clone();
+            let found = found.clone();
+            let attempts = attempts.clone();
+            let result_tx = result_tx.clone();
+            let mut data = public_key.to_vec();
+            data.extend_from_slice(&rand::random::<[u8; POW_STAMP_SIZE]>());
+
+            thread::spawn(move || {
+                let stamp_offset = data.len() - POW_STAMP_SIZE;
+                let mut batch = 0;
+                while !found.load(Ordering::Relaxed) {
+                    if target.is_satisfied_by(&data) {
+                        found.store(true, Ordering::Relaxed);
+                        let _ = result_tx.send(data[stamp_offset..].to_vec());
+                        break;
+                    }
+                    increment_stamp(&mut data[stamp_offset..]);
+
+                    batch += 1;
+                    if batch == ATTEMPTS_BATCH {
+                        attempts.fetch_add(batch, Ordering::Relaxed);
+                        batch = 0;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    // only workers can send the result
+    drop(result_tx);
+
+    let result = loop {
+        match result_rx.recv_timeout(PROGRESS_INTERVAL) {
+            Ok(stamp) => break Ok(stamp),
+            Err(RecvTimeoutError::Timeout) => progress(attempts.load(Ordering::Relaxed)),
+            Err(RecvTimeoutError::Disconnected) => break Err(PowError::SearchFailed),
+        }
+    };
+
+    found.store(true, Ordering::Relaxed);
+    for worker in workers {
+        let _ = worker.join();
+    }
+    result
+}
+
+/// Stamp is incremented as a little-endian number
+fn increment_stamp(stamp: &mut [u8]) {
+    for byte in stamp.iter_mut() {
+        let (incremented, overflow) = byte.overflowing_add(1);
+        *byte = incremented;
+        if !overflow {
+            break;
+        }
+    }
+}
+
+/// Check that the `proof_of_work_stamp` of the `public_key` satisfies the expected `difficulty`
+pub fn check_proof_of_work(public_key: &[u8], proof_of_work_stamp: &[u8], difficulty: f64) -> Result<(), PowError> {
+    PowTarget::new(difficulty)?.check(public_key, proof_of_work_stamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // identity generated by the tezos node with the expected difficulty 26
+    const PUBLIC_KEY: &str = "5fd7ba1d15650abc9a510c37a8bee566b708fe6577f0d832b903e8f13d71c94a";
+    const POW_STAMP: &str = "4b1354dcfc087e52c8fb510317b9464c297b8a55b79bfc95";
+
+    #[test]
+    fn test_check_proof_of_work() -> Result<(), failure::Error> {
+        let public_key = hex::decode(PUBLIC_KEY)?;
+        let pow_stamp = hex::decode(POW_STAMP)?;
+
+        assert_eq!(Ok(()), check_proof_of_work(&public_key, &pow_stamp, 0.0));
+        assert_eq!(Ok(()), check_proof_of_work(&public_key, &pow_stamp, 26.0));
+        assert_eq!(Ok(()), check_proof_of_work(&public_key, &pow_stamp, 25.5));
+        assert_eq!(Err(PowError::InsufficientPow { difficulty: 26.5 }), check_proof_of_work(&public_key, &pow_stamp, 26.5));
+        assert_eq!(Err(PowError::InsufficientPow { difficulty: 27.0 }), check_proof_of_work(&public_key, &pow_stamp, 27.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_proof_of_work() -> Result<(), failure::Error> {
+        let public_key = hex::decode(PUBLIC_KEY)?;
+
+        let stamp = generate_proof_of_work(&public_key, 12.0, 2, |_| ())?;
+        assert_eq!(POW_STAMP_SIZE, stamp.len());
+        assert_eq!(Ok(()), check_proof_of_work(&public_key, &stamp, 12.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_increment_stamp() {
+        let mut stamp = [0xff, 0xff, 0x01];
+        increment_stamp(&mut stamp);
+        assert_eq!([0x00, 0x00, 0x02], stamp);
+    }
+
+    #[test]
+    fn test_check_proof_of_work_invalid_input() -> Result<(), failure::Error> {
+        let public_key = hex::decode(PUBLIC_KEY)?;
+
+        assert_eq!(Err(PowError::InvalidStampSize { size: 3 }), check_proof_of_work(&public_key, &[0, 0, 0], 0.0));
+        assert_eq!(Err(PowError::InvalidDifficulty { difficulty: 257.0 }), check_proof_of_work(&public_key, &[0; POW_STAMP_SIZE], 257.0));
+        assert_eq!(Err(PowError::InvalidDifficulty { difficulty: -1.0 }), check_proof_of_work(&public_key, &[0; POW_STAMP_SIZE], -1.0));
+        Ok(())
+    }
+}
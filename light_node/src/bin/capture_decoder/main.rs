@@ -0,0 +1,91 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Decoder of the p2p capture (see `--capture-p2p`), prints every captured message as a JSON line.
+//! Messages, which can not be decoded, are printed as hex together with the decoding error.
+
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use clap::{App, Arg};
+use serde_json::json;
+
+use networking::p2p::capture::{CapturedMessage, CaptureFilter, CaptureReader, Direction};
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::prelude::PeerMessageResponse;
+
+fn to_json(captured: &CapturedMessage) -> serde_json::Value {
+    let timestamp = captured.timestamp.duration_since(UNIX_EPOCH)
+        .map(|timestamp| timestamp.as_millis() as u64)
+        .unwrap_or(0);
+    let direction = match captured.direction {
+        Direction::Incoming => "incoming",
+        Direction::Outgoing => "outgoing",
+    };
+    let message = match PeerMessageResponse::from_bytes_for_version(captured.bytes.clone(), &captured.network_version()) {
+        Ok(message) => json!(message),
+        Err(e) => json!({
+            "error": format!("{:?}", e),
+            "bytes": hex::encode(&captured.bytes),
+        }),
+    };
+
+    json!({
+        "timestamp": timestamp,
+        "direction": direction,
+        "peer_id": &captured.peer_id,
+        "peer_address": captured.peer_address.to_string(),
+        "distributed_db_version": captured.network_version.0,
+        "p2p_version": captured.network_version.1,
+        "message": message,
+    })
+}
+
+fn main() {
+    let matches = App::new("Capture Decoder")
+        .version("0.1.0")
+        .author("SimpleStaking and the project contributors")
+        .about("Prints p2p messages captured by the light node (--capture-p2p) as JSON lines")
+        .arg(Arg::with_name("capture-file")
+            .required(true)
+            .value_name("PATH")
+            .help("Path to the capture file"))
+        .arg(Arg::with_name("peers")
+            .long("peers")
+            .takes_value(true)
+            .value_name("PEER_ID|IP")
+            .help("Print only messages of these peers, identified by the peer id or IP address. Peers are delimited by a comma. Format: ID1,IP2. Default: all peers"))
+        .get_matches();
+
+    let filter = matches.value_of("peers")
+        .map(|peers| CaptureFilter::new(&peers.split(',').map(|peer| peer.trim().to_string()).collect::<Vec<_>>()))
+        .unwrap_or_default();
+
+    let path = matches.value_of("capture-file").unwrap();
+    let reader = match File::open(path).map_err(failure::Error::from).and_then(|file| CaptureReader::new(BufReader::new(file)).map_err(failure::Error::from)) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Failed to open capture file '{}': {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for captured in reader {
+        match captured {
+            Ok(captured) => if filter.is_selected(&captured.peer_id, &captured.peer_address) {
+                if writeln!(out, "{}", to_json(&captured)).is_err() {
+                    // stdout was closed (e.g. piped to head)
+                    return;
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to read captured message: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
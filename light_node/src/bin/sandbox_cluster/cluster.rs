@@ -0,0 +1,363 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+use failure::{bail, format_err};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+
+use crate::link::Link;
+
+/// Settings shared by all nodes of the cluster
+pub struct ClusterConfiguration {
+    pub nodes: usize,
+    pub light_node_path: PathBuf,
+    pub config_file: PathBuf,
+    pub base_dir: PathBuf,
+    pub identity_expected_pow: f64,
+    pub p2p_port: u16,
+    pub rpc_port: u16,
+    pub websocket_port: u16,
+    pub monitor_port: u16,
+    pub link_port: u16,
+    /// Arguments appended to the arguments of every node
+    pub node_args: Vec<String>,
+}
+
+impl ClusterConfiguration {
+    /// Ports of the `index`-th node, fails if any port does not fit into the valid port range
+    pub fn node_ports(&self, index: usize) -> Result<NodePorts, failure::Error> {
+        Ok(NodePorts {
+            p2p: nth_port("p2p", self.p2p_port, index)?,
+            rpc: nth_port("rpc", self.rpc_port, index)?,
+            websocket: nth_port("websocket", self.websocket_port, index)?,
+            monitor: nth_port("monitor", self.monitor_port, index)?,
+        })
+    }
+
+    /// Port of the link from node `from` to node `to`
+    pub fn link_port(&self, from: usize, to: usize) -> Result<u16, failure::Error> {
+        let index = from.checked_mul(self.nodes)
+            .and_then(|index| index.checked_add(to))
+            .ok_or_else(|| format_err!("Link port of the link {} -> {} is out of range", from, to))?;
+        nth_port("link", self.link_port, index)
+    }
+}
+
+/// Ports allocated for a single node of the cluster
+#[derive(Debug, PartialEq)]
+pub struct NodePorts {
+    pub p2p: u16,
+    pub rpc: u16,
+    pub websocket: u16,
+    pub monitor: u16,
+}
+
+/// Port incremented by the `index` from the `first` port
+fn nth_port(name: &str, first: u16, index: usize) -> Result<u16, failure::Error> {
+    u16::try_from(index).ok()
+        .and_then(|index| first.checked_add(index))
+        .ok_or_else(|| format_err!("Invalid configuration, {} port {} incremented by {} is out of the valid port range", name, first, index))
+}
+
+/// Single light node process of the cluster
+struct SandboxNode {
+    index: usize,
+    data_dir: PathBuf,
+    p2p_port: u16,
+    rpc_port: u16,
+    args: Vec<String>,
+    process: Option<Child>,
+    /// Nodes can communicate only with the nodes of the same group
+    group: usize,
+}
+
+impl SandboxNode {
+    fn is_running(&mut self) -> bool {
+        match &mut self.process {
+            Some(process) => matches!(process.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    fn start(&mut self, light_node_path: &PathBuf) -> Result<(), failure::Error> {
+        if self.is_running() {
+            bail!("Node {} is already running", self.index);
+        }
+
+        fs::create_dir_all(&self.data_dir)?;
+        let log_file = File::create(self.data_dir.join("light-node.log"))?;
+        let process = Command::new(light_node_path)
+            .args(&self.args)
+            .stdout(Stdio::from(log_file.try_clone()?))
+            .stderr(Stdio::from(log_file))
+            .spawn()
+            .map_err(|e| format_err!("Failed to start node {} ({:?}), reason: {}", self.index, light_node_path, e))?;
+        self.process = Some(process);
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<(), failure::Error> {
+        match self.process.take() {
+            Some(mut process) => {
+                // kill is expected to fail, if process already exited
+                let _ = process.kill();
+                process.wait()?;
+                Ok(())
+            }
+            None => bail!("Node {} is not running", self.index),
+        }
+    }
+
+    fn status(&mut self) -> NodeStatus {
+        NodeStatus {
+            index: self.index,
+            running: self.is_running(),
+            pid: self.process.as_ref().map(Child::id),
+            p2p_port: self.p2p_port,
+            rpc_port: self.rpc_port,
+            data_dir: self.data_dir.to_string_lossy().to_string(),
+            group: self.group,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct NodeStatus {
+    index: usize,
+    running: bool,
+    pid: Option<u32>,
+    p2p_port: u16,
+    rpc_port: u16,
+    data_dir: String,
+    group: usize,
+}
+
+/// Cluster of sandbox nodes, which are connected only to each other (through the links)
+pub struct Cluster {
+    light_node_path: PathBuf,
+    nodes: Mutex<Vec<SandboxNode>>,
+    links: Vec<Link>,
+    log: Logger,
+}
+
+impl Cluster {
+    /// Prepare nodes and start the links between them, nodes are not started
+    pub async fn create(cfg: ClusterConfiguration, log: Logger) -> Result<Self, failure::Error> {
+        if cfg.nodes == 0 {
+            bail!("Cluster must contain at least one node");
+        }
+        let localhost = |port: u16| -> SocketAddr { ([127, 0, 0, 1], port).into() };
+
+        // all ports are checked before any link is started
+        let ports = (0..cfg.nodes)
+            .map(|index| cfg.node_ports(index))
+            .collect::<Result<Vec<_>, _>>()?;
+        cfg.link_port(cfg.nodes - 1, cfg.nodes - 1)?;
+
+        let mut links = Vec::new();
+        for from in 0..cfg.nodes {
+            for to in (0..cfg.nodes).filter(|to| *to != from) {
+                let target = localhost(ports[to].p2p);
+                links.push(Link::spawn(from, to, localhost(cfg.link_port(from, to)?), target, log.clone()).await?);
+            }
+        }
+
+        let nodes = ports.into_iter()
+            .enumerate()
+            .map(|(index, ports)| {
+                let data_dir = cfg.base_dir.join(format!("node-{}", index));
+                let p2p_port = ports.p2p;
+                let rpc_port = ports.rpc;
+                let peers = links.iter()
+                    .filter(|link| link.from == index)
+                    .map(|link| link.address.to_string())
+                    .collect::<Vec<_>>();
+
+                let mut args = vec![
+                    format!("--config-file={}", cfg.config_file.display()),
+                    "--network=sandbox".to_string(),
+                    format!("--tezos-data-dir={}", data_dir.join("tezos-node").display()),
+                    format!("--bootstrap-db-path={}", data_dir.join("light-node").display()),
+                    format!("--identity-file={}", data_dir.join("identity.json").display()),
+                    format!("--identity-expected-pow={}", cfg.identity_expected_pow),
+                    format!("--p2p-port={}", p2p_port),
+                    format!("--rpc-port={}", rpc_port),
+                    format!("--websocket-address={}", localhost(ports.websocket)),
+                    format!("--monitor-port={}", ports.monitor),
+                ];
+                // nodes do not look for any other peers, than the other nodes of the cluster
+                if !peers.is_empty() {
+                    args.push(format!("--peers={}", peers.join(",")));
+                    args.push("--private-node=true".to_string());
+                }
+                args.extend(cfg.node_args.iter().cloned());
+
+                SandboxNode { index, data_dir, p2p_port, rpc_port, args, process: None, group: 0 }
+            })
+            .collect();
+
+        Ok(Cluster {
+            light_node_path: cfg.light_node_path,
+            nodes: Mutex::new(nodes),
+            links,
+            log,
+        })
+    }
+
+    pub fn start_all(&self) -> Result<(), failure::Error> {
+        let mut nodes = self.nodes.lock().unwrap();
+        for node in nodes.iter_mut() {
+            node.start(&self.light_node_path)?;
+            info!(self.log, "Node started"; "node" => node.index, "p2p_port" => node.p2p_port, "rpc_port" => node.rpc_port, "data_dir" => node.data_dir.to_string_lossy().to_string());
+        }
+        Ok(())
+    }
+
+    pub fn kill_all(&self) {
+        let mut nodes = self.nodes.lock().unwrap();
+        for node in nodes.iter_mut().filter(|node| node.process.is_some()) {
+            if let Err(e) = node.kill() {
+                warn!(self.log, "Failed to kill node"; "node" => node.index, "reason" => format!("{}", e));
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<NodeStatus> {
+        self.nodes.lock().unwrap().iter_mut().map(SandboxNode::status).collect()
+    }
+
+    /// Kill the node process, data of the node are kept
+    pub fn kill(&self, index: usize) -> Result<NodeStatus, failure::Error> {
+        self.with_node(index, |node| {
+            node.kill()?;
+            Ok(node.status())
+        })
+    }
+
+    /// Start the node again (node is killed first, if it is running)
+    pub fn restart(&self, index: usize) -> Result<NodeStatus, failure::Error> {
+        let light_node_path = &self.light_node_path;
+        self.with_node(index, |node| {
+            if node.process.is_some() {
+                node.kill()?;
+            }
+            node.start(light_node_path)?;
+            Ok(node.status())
+        })
+    }
+
+    /// Split nodes into the groups, which can not communicate with each other.
+    /// Nodes not listed in any group are isolated from all other nodes.
+    pub fn partition(&self, groups: &[Vec<usize>]) -> Result<Vec<NodeStatus>, failure::Error> {
+        let mut nodes = self.nodes.lock().unwrap();
+
+        let mut assigned_groups = vec![None; nodes.len()];
+        for (group, indexes) in groups.iter().enumerate() {
+            for index in indexes {
+                match assigned_groups.get_mut(*index) {
+                    Some(assigned) if assigned.is_none() => *assigned = Some(group),
+                    Some(_) => bail!("Node {} is listed in more than one group", index),
+                    None => bail!("Node {} does not exist", index),
+                }
+            }
+        }
+        let mut isolated_group = groups.len();
+        for (node, group) in nodes.iter_mut().zip(assigned_groups) {
+            node.group = group.unwrap_or_else(|| {
+                isolated_group += 1;
+                isolated_group
+            });
+        }
+
+        self.apply_groups(&nodes);
+        info!(self.log, "Nodes partitioned"; "groups" => format!("{:?}", groups));
+        Ok(nodes.iter_mut().map(SandboxNode::status).collect())
+    }
+
+    /// Remove all partitions
+    pub fn heal(&self) -> Vec<NodeStatus> {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.iter_mut().for_each(|node| node.group = 0);
+
+        self.apply_groups(&nodes);
+        info!(self.log, "Partitions healed");
+        nodes.iter_mut().map(SandboxNode::status).collect()
+    }
+
+    fn apply_groups(&self, nodes: &[SandboxNode]) {
+        for link in &self.links {
+            link.set_enabled(nodes[link.from].group == nodes[link.to].group);
+        }
+    }
+
+    fn with_node<T, F>(&self, index: usize, f: F) -> Result<T, failure::Error>
+        where F: FnOnce(&mut SandboxNode) -> Result<T, failure::Error> {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get_mut(index) {
+            Some(node) => f(node),
+            None => bail!("Node {} does not exist", index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(nodes: usize, first_port: u16) -> ClusterConfiguration {
+        ClusterConfiguration {
+            nodes,
+            light_node_path: PathBuf::from("light-node"),
+            config_file: PathBuf::from("tezedge_sandbox.config"),
+            base_dir: PathBuf::from("/tmp/tezedge_sandbox_cluster"),
+            identity_expected_pow: 0.0,
+            p2p_port: first_port,
+            rpc_port: 18732,
+            websocket_port: 14927,
+            monitor_port: 13030,
+            link_port: 29732,
+            node_args: vec![],
+        }
+    }
+
+    #[test]
+    fn test_node_ports() -> Result<(), failure::Error> {
+        let cfg = configuration(3, 19732);
+        assert_eq!(NodePorts { p2p: 19732, rpc: 18732, websocket: 14927, monitor: 13030 }, cfg.node_ports(0)?);
+        assert_eq!(NodePorts { p2p: 19734, rpc: 18734, websocket: 14929, monitor: 13032 }, cfg.node_ports(2)?);
+        assert_eq!(29732, cfg.link_port(0, 0)?);
+        assert_eq!(29732 + 5, cfg.link_port(1, 2)?);
+        assert_eq!(29732 + 8, cfg.link_port(2, 2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_node_ports_overflow() -> Result<(), failure::Error> {
+        let cfg = configuration(3, std::u16::MAX - 1);
+        assert_eq!(std::u16::MAX, cfg.node_ports(1)?.p2p);
+        assert!(cfg.node_ports(2).is_err());
+        assert!(cfg.node_ports(std::u16::MAX as usize + 1).is_err());
+
+        let mut cfg = configuration(200, 19732);
+        assert!(cfg.link_port(199, 199).is_err());
+        cfg.link_port = 0;
+        assert_eq!(200 * 200 - 1, cfg.link_port(199, 199)? as usize);
+        assert!(cfg.link_port(std::usize::MAX, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_fails_on_port_overflow() {
+        let log = Logger::root(slog::Discard, slog::o!());
+        let mut runtime = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
+        let result = runtime.block_on(Cluster::create(configuration(3, std::u16::MAX - 1), log));
+        assert!(result.is_err());
+    }
+}
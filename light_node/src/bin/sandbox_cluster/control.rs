@@ -0,0 +1,88 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Http api for controlling the cluster:
+//!
+//! * `GET /nodes` - status of all nodes
+//! * `POST /nodes/:index/kill` - kill the node process
+//! * `POST /nodes/:index/restart` - (re)start the node process, data of the node are kept
+//! * `POST /partition` with body `{"groups": [[0, 1], [2]]}` - nodes of the different groups can not communicate, not listed nodes are isolated
+//! * `POST /heal` - remove all partitions
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Cluster;
+
+#[derive(Deserialize)]
+struct PartitionRequest {
+    groups: Vec<Vec<usize>>,
+}
+
+/// Serve control api at `address` until the future is dropped
+pub async fn serve(address: SocketAddr, cluster: Arc<Cluster>) -> Result<(), hyper::Error> {
+    hyper::Server::bind(&address)
+        .serve(make_service_fn(move |_| {
+            let cluster = cluster.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| handle(req, cluster.clone())))
+            }
+        }))
+        .await
+}
+
+async fn handle(req: Request<Body>, cluster: Arc<Cluster>) -> Result<Response<Body>, hyper::Error> {
+    let method = req.method().clone();
+    let path = req.uri().path().trim_matches('/').split('/').map(str::to_string).collect::<Vec<_>>();
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let response = match (&method, path.as_slice()) {
+        (&Method::GET, ["nodes"]) => json_response(&cluster.status()),
+        (&Method::POST, ["nodes", index, "kill"]) => match index.parse() {
+            Ok(index) => result_response(cluster.kill(index)),
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Invalid node index"),
+        },
+        (&Method::POST, ["nodes", index, "restart"]) => match index.parse() {
+            Ok(index) => result_response(cluster.restart(index)),
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Invalid node index"),
+        },
+        (&Method::POST, ["partition"]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            match serde_json::from_slice::<PartitionRequest>(&body) {
+                Ok(request) => result_response(cluster.partition(&request.groups)),
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &format!("Invalid partition request: {}", e)),
+            }
+        }
+        (&Method::POST, ["heal"]) => json_response(&cluster.heal()),
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+fn result_response<T: Serialize>(result: Result<T, failure::Error>) -> Response<Body> {
+    match result {
+        Ok(value) => json_response(&value),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &format!("{}", e)),
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", e)),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,85 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Every p2p connection between two nodes of the cluster goes through the link (tcp proxy) owned by the launcher,
+//! so the connection can be cut (and refused) when the nodes are partitioned.
+
+use std::net::SocketAddr;
+
+use futures::future;
+use slog::{debug, warn, Logger};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// Directed link from the node `from` to the node `to`
+pub struct Link {
+    pub from: usize,
+    pub to: usize,
+    /// Node `from` connects to this address instead of the p2p address of the node `to`
+    pub address: SocketAddr,
+    enabled: watch::Sender<bool>,
+}
+
+impl Link {
+    /// Start listening at `address` and forward accepted connections to `target`
+    pub async fn spawn(from: usize, to: usize, address: SocketAddr, target: SocketAddr, log: Logger) -> Result<Self, failure::Error> {
+        let mut listener = TcpListener::bind(address).await?;
+        let (enabled, enabled_rx) = watch::channel(true);
+
+        tokio::spawn(async move {
+            loop {
+                let incoming = match listener.accept().await {
+                    Ok((incoming, _)) => incoming,
+                    Err(e) => {
+                        warn!(log, "Failed to accept connection"; "from" => from, "to" => to, "reason" => format!("{}", e));
+                        continue;
+                    }
+                };
+                if !*enabled_rx.borrow() {
+                    // nodes are partitioned, connection is refused
+                    debug!(log, "Connection refused, link is cut"; "from" => from, "to" => to);
+                    continue;
+                }
+
+                let enabled_rx = enabled_rx.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    match TcpStream::connect(target).await {
+                        Ok(outgoing) => forward(incoming, outgoing, enabled_rx).await,
+                        Err(e) => debug!(log, "Failed to connect to the node"; "from" => from, "to" => to, "reason" => format!("{}", e)),
+                    }
+                });
+            }
+        });
+
+        Ok(Link { from, to, address, enabled })
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        // receivers live as long as the listener task, which is never finished
+        let _ = self.enabled.broadcast(enabled);
+    }
+}
+
+/// Forward data in both directions until one side closes the connection or the link is cut
+async fn forward(mut incoming: TcpStream, mut outgoing: TcpStream, mut enabled: watch::Receiver<bool>) {
+    let (mut incoming_rx, mut incoming_tx) = incoming.split();
+    let (mut outgoing_rx, mut outgoing_tx) = outgoing.split();
+
+    let transfer = future::try_join(
+        io::copy(&mut incoming_rx, &mut outgoing_tx),
+        io::copy(&mut outgoing_rx, &mut incoming_tx),
+    );
+    let cut = async {
+        while let Some(enabled) = enabled.recv().await {
+            if !enabled {
+                break;
+            }
+        }
+    };
+
+    futures::pin_mut!(transfer, cut);
+    // result does not matter, both streams are closed when dropped
+    let _ = future::select(transfer, cut).await;
+}
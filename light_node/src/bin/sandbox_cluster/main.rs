@@ -0,0 +1,245 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Launcher of the local cluster of sandboxed light nodes, which are connected only to each other.
+//! Nodes can be killed, restarted and partitioned through the http control api (see `control` module).
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches};
+use futures::future::{self, Either};
+use slog::{crit, info, Drain, Level, Logger};
+
+use crate::cluster::{Cluster, ClusterConfiguration};
+
+mod cluster;
+mod control;
+mod link;
+
+macro_rules! parse_validator_fn {
+    ($t:ident, $err:expr) => {|v| if v.parse::<$t>().is_ok() { Ok(()) } else { Err($err.to_string()) } }
+}
+
+fn create_logger(log_level: Level) -> Logger {
+    let drain = slog_async::Async::new(
+        slog_term::FullFormat::new(
+            slog_term::TermDecorator::new().build()
+        ).build().fuse()
+    ).build().filter_level(log_level).fuse();
+
+    Logger::root(drain, slog::o!())
+}
+
+/// By default, light node binary is expected next to the launcher binary
+fn default_light_node_path() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("light-node")))
+        .unwrap_or_else(|| PathBuf::from("./target/release/light-node"))
+}
+
+fn cluster_app() -> App<'static, 'static> {
+    App::new("Sandbox Cluster")
+        .version("0.1.0")
+        .author("SimpleStaking and the project contributors")
+        .about("Launches cluster of sandboxed light nodes on localhost")
+        .arg(Arg::with_name("nodes")
+            .long("nodes")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Count of nodes in the cluster. Default: 3")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("light-node")
+            .long("light-node")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Path to the light-node binary. Default: light-node next to this binary"))
+        .arg(Arg::with_name("config-file")
+            .long("config-file")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Configuration file shared by all nodes, ports, data dirs, identities and peers are overridden for every node. Default: ./light_node/etc/tezedge_sandbox/tezedge_sandbox.config")
+            .validator(|v| if Path::new(&v).exists() { Ok(()) } else { Err(format!("Configuration file not found at '{}'", v)) }))
+        .arg(Arg::with_name("base-dir")
+            .long("base-dir")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Directory, where data dir of every node (node-<index>) is created. Default: /tmp/tezedge_sandbox_cluster"))
+        .arg(Arg::with_name("clean")
+            .long("clean")
+            .help("Remove data of all nodes before the start"))
+        .arg(Arg::with_name("identity-expected-pow")
+            .long("identity-expected-pow")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Expected power of work of the generated node identities. Default: 0.0")
+            .validator(parse_validator_fn!(f64, "Value must be a valid number")))
+        .arg(Arg::with_name("p2p-port")
+            .long("p2p-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("P2p port of the first node, port of the next node is incremented by one. Default: 19732")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("rpc-port")
+            .long("rpc-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Rpc port of the first node, port of the next node is incremented by one. Default: 18732")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("websocket-port")
+            .long("websocket-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Websocket port of the first node, port of the next node is incremented by one. Default: 14927")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("monitor-port")
+            .long("monitor-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Monitor port of the first node, port of the next node is incremented by one. Default: 13030")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("link-port")
+            .long("link-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("First port of the links between the nodes, nodes * nodes ports are used. Default: 29732")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("control-port")
+            .long("control-port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Port of the http control api. Default: 18700")
+            .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .takes_value(true)
+            .value_name("LEVEL")
+            .possible_values(&["critical", "error", "warn", "info", "debug", "trace"])
+            .help("Set log level of the launcher"))
+        .arg(Arg::with_name("node-args")
+            .multiple(true)
+            .last(true)
+            .value_name("NODE_ARGS")
+            .help("Arguments passed to every node (after --)"))
+}
+
+macro_rules! value_or {
+    ($matches:expr, $name:expr, $t:ty, $default:expr) => {
+        $matches.value_of($name).map(|v| v.parse::<$t>().expect("Value was already validated")).unwrap_or($default)
+    }
+}
+
+/// Cluster configuration from the already validated command line arguments
+fn cluster_configuration(matches: &ArgMatches) -> ClusterConfiguration {
+    ClusterConfiguration {
+        nodes: value_or!(matches, "nodes", usize, 3),
+        light_node_path: matches.value_of("light-node").map(PathBuf::from).unwrap_or_else(default_light_node_path),
+        config_file: matches.value_of("config-file").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("./light_node/etc/tezedge_sandbox/tezedge_sandbox.config")),
+        base_dir: matches.value_of("base-dir").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/tmp/tezedge_sandbox_cluster")),
+        identity_expected_pow: value_or!(matches, "identity-expected-pow", f64, 0.0),
+        p2p_port: value_or!(matches, "p2p-port", u16, 19732),
+        rpc_port: value_or!(matches, "rpc-port", u16, 18732),
+        websocket_port: value_or!(matches, "websocket-port", u16, 14927),
+        monitor_port: value_or!(matches, "monitor-port", u16, 13030),
+        link_port: value_or!(matches, "link-port", u16, 29732),
+        node_args: matches.values_of("node-args").map(|args| args.map(str::to_string).collect()).unwrap_or_default(),
+    }
+}
+
+fn main() {
+    let matches = cluster_app().get_matches();
+
+    let log_level = matches.value_of("log-level")
+        .unwrap_or("info")
+        .parse::<slog::Level>()
+        .expect("Was expecting one value from slog::Level");
+    let log = create_logger(log_level);
+
+    let cfg = cluster_configuration(&matches);
+    if matches.is_present("clean") && cfg.base_dir.exists() {
+        fs::remove_dir_all(&cfg.base_dir).expect("Failed to remove data of the nodes");
+    }
+    let control_address: SocketAddr = ([127, 0, 0, 1], value_or!(matches, "control-port", u16, 18700)).into();
+
+    let mut tokio_runtime = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
+    tokio_runtime.block_on(async move {
+        let cluster = match Cluster::create(cfg, log.clone()).await {
+            Ok(cluster) => Arc::new(cluster),
+            Err(e) => {
+                crit!(log, "Failed to create cluster"; "reason" => format!("{}", e));
+                return;
+            }
+        };
+        if let Err(e) = cluster.start_all() {
+            crit!(log, "Failed to start cluster"; "reason" => format!("{}", e));
+            cluster.kill_all();
+            return;
+        }
+        info!(log, "Cluster started, control api is available"; "address" => format!("{}", control_address));
+
+        let server = Box::pin(control::serve(control_address, cluster.clone()));
+        let ctrl_c = Box::pin(tokio::signal::ctrl_c());
+        match future::select(server, ctrl_c).await {
+            Either::Left((Err(e), _)) => crit!(log, "Control api failed"; "reason" => format!("{}", e)),
+            _ => info!(log, "Shutting down cluster"),
+        }
+        cluster.kill_all();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_configuration_defaults() {
+        let matches = cluster_app().get_matches_from(vec!["sandbox-cluster"]);
+        let cfg = cluster_configuration(&matches);
+        assert_eq!(3, cfg.nodes);
+        assert_eq!(PathBuf::from("/tmp/tezedge_sandbox_cluster"), cfg.base_dir);
+        assert_eq!(19732, cfg.p2p_port);
+        assert_eq!(18732, cfg.rpc_port);
+        assert_eq!(14927, cfg.websocket_port);
+        assert_eq!(13030, cfg.monitor_port);
+        assert_eq!(29732, cfg.link_port);
+        assert!(cfg.node_args.is_empty());
+    }
+
+    #[test]
+    fn test_cluster_configuration() {
+        let matches = cluster_app().get_matches_from(vec![
+            "sandbox-cluster",
+            "--nodes=5",
+            "--base-dir=/tmp/cluster",
+            "--identity-expected-pow=1.5",
+            "--p2p-port=20000",
+            "--rpc-port=21000",
+            "--websocket-port=22000",
+            "--monitor-port=23000",
+            "--link-port=24000",
+            "--",
+            "--log-level=debug",
+            "--ocaml-log-enabled=false",
+        ]);
+        let cfg = cluster_configuration(&matches);
+        assert_eq!(5, cfg.nodes);
+        assert_eq!(PathBuf::from("/tmp/cluster"), cfg.base_dir);
+        assert_eq!(1.5, cfg.identity_expected_pow);
+        assert_eq!(20000, cfg.p2p_port);
+        assert_eq!(21000, cfg.rpc_port);
+        assert_eq!(22000, cfg.websocket_port);
+        assert_eq!(23000, cfg.monitor_port);
+        assert_eq!(24000, cfg.link_port);
+        assert_eq!(vec!["--log-level=debug".to_string(), "--ocaml-log-enabled=false".to_string()], cfg.node_args);
+    }
+
+    #[test]
+    fn test_cluster_configuration_invalid_port() {
+        assert!(cluster_app().get_matches_from_safe(vec!["sandbox-cluster", "--p2p-port=65536"]).is_err());
+        assert!(cluster_app().get_matches_from_safe(vec!["sandbox-cluster", "--nodes=-1"]).is_err());
+    }
+}
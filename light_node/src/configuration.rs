@@ -11,7 +11,18 @@ use std::time::Duration;
 
 use clap::{App, Arg};
 
+use crypto::ed25519::SigningKey;
+
+use networking::p2p::bandwidth::BandwidthLimits;
+use networking::p2p::capture::CaptureFilter;
+use networking::p2p::socks5::{ProxyScope, Socks5Proxy};
+use shell::chain_manager::SynchronisationThreshold;
+use shell::mempool_prevalidator::MempoolLimits;
+use shell::peer_filter::{IpNetwork, PeerFilter};
 use shell::peer_manager::Threshold;
+use shell::supervision::SupervisionPolicy;
+
+use crate::shutdown::ShutdownTimeouts;
 use tezos_api::environment;
 use tezos_api::environment::TezosEnvironment;
 use tezos_api::ffi::PatchContext;
@@ -23,8 +34,18 @@ pub struct P2p {
     pub bootstrap_lookup_addresses: Vec<String>,
     pub initial_peers: Vec<SocketAddr>,
     pub peer_threshold: Threshold,
+    pub synchronisation_threshold: SynchronisationThreshold,
     pub disable_mempool: bool,
+    pub mempool_limits: MempoolLimits,
+    /// Upload/download rate limits of the p2p connections
+    pub bandwidth_limits: BandwidthLimits,
     pub private_node: bool,
+    /// Allow/deny lists and trusted peers
+    pub peer_filter: PeerFilter,
+    /// Outgoing connections go through this SOCKS5 proxy
+    pub proxy: Option<Socks5Proxy>,
+    /// Minimal proof of work of the identity required from the remote peers
+    pub peer_expected_pow: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -92,6 +113,16 @@ pub struct Environment {
     pub tezos_network: TezosEnvironment,
     pub enable_testchain: bool,
     pub tokio_threads: usize,
+    pub supervision: SupervisionPolicy,
+    pub shutdown: ShutdownTimeouts,
+    /// If set, network and shell events are recorded to this file
+    pub record_events: Option<PathBuf>,
+    /// If set, decrypted p2p messages of the selected peers are captured to this file
+    pub capture_p2p: Option<PathBuf>,
+    /// Peers selected for the p2p capture, all peers if empty
+    pub capture_peers: CaptureFilter,
+    /// Key used to sign blocks produced by the sandbox rpc (only for sandbox network)
+    pub sandbox_baker_key: Option<SigningKey>,
 }
 
 macro_rules! parse_validator_fn {
@@ -150,8 +181,14 @@ pub fn tezos_app() -> App<'static, 'static> {
             .long("identity-expected-pow")
             .takes_value(true)
             .value_name("NUM")
-            .help("Expected power of identity for node. It is used to generate new identity. Default: 26.0")
+            .help("Expected power of identity for node. It is used to generate new identity and the loaded identity must satisfy it. Default: 26.0")
             .validator(parse_validator_fn!(f64, "Value must be a valid f64 number for expected_pow")))
+        .arg(Arg::with_name("peer-expected-pow")
+            .long("peer-expected-pow")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Minimal proof of work of the identity required from the remote peers (number in range 0.0..=256.0), peers with insufficient proof of work are rejected during the handshake. Default: used according to --network parameter see TezosEnvironment")
+            .validator(|v| validate_expected_pow(&v)))
         .arg(Arg::with_name("bootstrap-db-path")
             .long("bootstrap-db-path")
             .takes_value(true)
@@ -191,13 +228,55 @@ pub fn tezos_app() -> App<'static, 'static> {
             .takes_value(true)
             .value_name("BOOL")
             .help("Enable or disable mempool"))
+        .arg(Arg::with_name("mempool-max-operations")
+            .long("mempool-max-operations")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max count of operations in the mempool, operations with the lowest priority are evicted. Default: 10000")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("mempool-max-bytes")
+            .long("mempool-max-bytes")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max size of operations in the mempool in bytes, operations with the lowest priority are evicted. Default: 33554432")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("mempool-max-operations-per-peer")
+            .long("mempool-max-operations-per-peer")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max count of mempool operations accepted from one peer for one block. Default: 1000")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("max-upload-speed")
+            .long("max-upload-speed")
+            .takes_value(true)
+            .value_name("KIB_PER_SEC")
+            .help("Max upload speed of all p2p connections together in KiB/s. Default: unlimited")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("max-download-speed")
+            .long("max-download-speed")
+            .takes_value(true)
+            .value_name("KIB_PER_SEC")
+            .help("Max download speed of all p2p connections together in KiB/s. Default: unlimited")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("peer-max-upload-speed")
+            .long("peer-max-upload-speed")
+            .takes_value(true)
+            .value_name("KIB_PER_SEC")
+            .help("Max upload speed of a single p2p connection in KiB/s. Default: unlimited")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("peer-max-download-speed")
+            .long("peer-max-download-speed")
+            .takes_value(true)
+            .value_name("KIB_PER_SEC")
+            .help("Max download speed of a single p2p connection in KiB/s. Default: unlimited")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
         .arg(Arg::with_name("private-node")
             .long("private-node")
             .takes_value(true)
             .value_name("BOOL")
             .requires("peers")
             .conflicts_with("bootstrap-lookup-address")
-            .help("Enable or disable private node. Use peers to set IP addresses of the peers you want to connect to"))
+            .help("Enable or disable private node. Private node connects to and accepts connections only from the trusted peers (--peers, --trusted-peers, --trusted-peer-ids) and is not advertised by them"))
         .arg(Arg::with_name("network")
             .long("network")
             .takes_value(true)
@@ -248,6 +327,64 @@ pub fn tezos_app() -> App<'static, 'static> {
                     Err(format!("Value '{}' is not valid. Expected format is: IP1:PORT1,IP2:PORT2,IP3:PORT3", v))
                 }
             }))
+        .arg(Arg::with_name("trusted-peers")
+            .long("trusted-peers")
+            .takes_value(true)
+            .value_name("IP:PORT")
+            .help("Trusted peers, which are always reconnected and never blacklisted. Incoming connections are trusted by the peer id learned from the outgoing connection, not by IP. Peers are delimited by a colon. Format: IP1:PORT1,IP2:PORT2,IP3:PORT3")
+            .validator(|v| {
+                let err_count = v.split(',')
+                    .map(|ip_port| ip_port.parse::<SocketAddr>())
+                    .filter(|v| v.is_err())
+                    .count();
+                if err_count == 0 {
+                    Ok(())
+                } else {
+                    Err(format!("Value '{}' is not valid. Expected format is: IP1:PORT1,IP2:PORT2,IP3:PORT3", v))
+                }
+            }))
+        .arg(Arg::with_name("trusted-peer-ids")
+            .long("trusted-peer-ids")
+            .takes_value(true)
+            .value_name("PEER_ID")
+            .help("Peer ids of the trusted peers, which are never blacklisted. Peer ids are delimited by a colon. Format: ID1,ID2,ID3"))
+        .arg(Arg::with_name("allow-cidr")
+            .long("allow-cidr")
+            .takes_value(true)
+            .value_name("CIDR")
+            .help("If set, only peers from these networks are connected and accepted (trusted peers excluded). Networks are delimited by a colon. Format: 10.0.0.0/8,fd00::/8")
+            .validator(|v| validate_cidr_list(&v)))
+        .arg(Arg::with_name("deny-cidr")
+            .long("deny-cidr")
+            .takes_value(true)
+            .value_name("CIDR")
+            .help("Peers from these networks are never connected nor accepted (trusted peers excluded). Networks are delimited by a colon. Format: 10.0.0.0/8,fd00::/8")
+            .validator(|v| validate_cidr_list(&v)))
+        .arg(Arg::with_name("socks5-proxy")
+            .long("socks5-proxy")
+            .takes_value(true)
+            .value_name("IP:PORT")
+            .help("SOCKS5 proxy for the outgoing p2p connections (see --socks5-proxy-scope). Peers are configured and dialed by IP address, so peers cannot be set by domain name (e.g. Tor .onion address)")
+            .validator(parse_validator_fn!(SocketAddr, "Value must be a valid IP:PORT")))
+        .arg(Arg::with_name("socks5-proxy-scope")
+            .long("socks5-proxy-scope")
+            .takes_value(true)
+            .value_name("SCOPE")
+            .possible_values(&["all", "public", "ipv4", "ipv6"])
+            .requires("socks5-proxy")
+            .help("Outgoing connections, which go through the SOCKS5 proxy, the others are dialed directly. Public means all except loopback, private and link-local addresses. Default: all"))
+        .arg(Arg::with_name("socks5-proxy-username")
+            .long("socks5-proxy-username")
+            .takes_value(true)
+            .value_name("STRING")
+            .requires_all(&["socks5-proxy", "socks5-proxy-password"])
+            .help("Username for the SOCKS5 proxy authentication"))
+        .arg(Arg::with_name("socks5-proxy-password")
+            .long("socks5-proxy-password")
+            .takes_value(true)
+            .value_name("STRING")
+            .requires_all(&["socks5-proxy", "socks5-proxy-username"])
+            .help("Password for the SOCKS5 proxy authentication"))
         .arg(Arg::with_name("peer-thresh-low")
             .long("peer-thresh-low")
             .takes_value(true)
@@ -260,6 +397,18 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("NUM")
             .help("Maximal number of peers to connect to")
             .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("synchronisation-threshold")
+            .long("synchronisation-threshold")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Number of peers with recent current head needed to consider node as synchronised (bootstrapped). Zero means node is always synchronised. Default: 4")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("sync-latency")
+            .long("sync-latency")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max age of the current head in seconds, to be considered as recent by synchronisation heuristic. Default: 150")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
         .arg(Arg::with_name("protocol-runner")
             .long("protocol-runner")
             .takes_value(true)
@@ -302,6 +451,55 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("NUM")
             .help("Number of threads spawned by a tokio thread pool. If value is zero, then number of threads equal to CPU cores is spawned.")
             .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("worker-restart-max-backoff")
+            .long("worker-restart-max-backoff")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max delay in seconds before a failed worker thread is restarted. Default: 60")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("worker-max-failures")
+            .long("worker-max-failures")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Count of consecutive failures of a worker thread, after which the node is shut down. Zero means that worker is restarted forever. Default: 0")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("shutdown-block-application-timeout")
+            .long("shutdown-block-application-timeout")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max time in seconds to wait on shutdown for the in-flight block application. Default: 30")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("shutdown-context-events-timeout")
+            .long("shutdown-context-events-timeout")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max time in seconds to wait on shutdown for draining of the context action events. Default: 10")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("shutdown-actors-timeout")
+            .long("shutdown-actors-timeout")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Max time in seconds to wait on shutdown for the actors to stop. Default: 5")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("record-events")
+            .long("record-events")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Path to the file, where network and shell events are recorded for later replay. Existing file is overwritten.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("capture-p2p")
+            .long("capture-p2p")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Path to the file, where decrypted p2p messages of the selected peers (see --capture-peers) are captured. Existing file is overwritten.
+                       Capture can be printed as JSON by the capture-decoder binary.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("capture-peers")
+            .long("capture-peers")
+            .takes_value(true)
+            .value_name("PEER_ID|IP")
+            .requires("capture-p2p")
+            .help("Peers, whose p2p messages are captured, identified by the peer id or IP address. Peers are delimited by a comma. Format: ID1,IP2. Default: all peers"))
         .arg(Arg::with_name("store-context-actions")
             .long("store-context-actions")
             .takes_value(true)
@@ -313,7 +511,14 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("PATH")
             .required(false)
             .help("Path to the json file with key-values, which will be added to empty context on startup and commit genesis.")
-            .validator(|v| if Path::new(&v).exists() { Ok(()) } else { Err(format!("Sandbox patch-context json file not found at '{}'", v)) }));
+            .validator(|v| if Path::new(&v).exists() { Ok(()) } else { Err(format!("Sandbox patch-context json file not found at '{}'", v)) }))
+        .arg(Arg::with_name("sandbox-baker-secret-key")
+            .long("sandbox-baker-secret-key")
+            .takes_value(true)
+            .value_name("EDSK")
+            .required(false)
+            .help("Unencrypted ed25519 secret key (edsk...), which signs blocks baked by the sandbox rpc (/dev/sandbox/*). Used only for sandbox network.")
+            .validator(|v| SigningKey::from_base58_seed(&v).map(|_| ()).map_err(|e| format!("Invalid sandbox baker secret key: {}", e))));
     app
 }
 
@@ -340,7 +545,23 @@ pub fn validate_required_args(args: &clap::ArgMatches) {
     validate_required_arg(args, "identity-file");
     validate_required_arg(args, "identity-expected-pow");
 
-    // "bootstrap-lookup-address", "log-file" and "peers" are not required
+    // "bootstrap-lookup-address", "log-file", "record-events", "capture-p2p", "capture-peers", "sandbox-baker-secret-key", "peer-expected-pow", "peers", trusted peers, allow/deny lists, SOCKS5 proxy and bandwidth limits are not required
+}
+
+// Validates list of CIDR networks delimited by a colon
+fn validate_cidr_list(value: &str) -> Result<(), String> {
+    match value.split(',').find(|network| network.parse::<IpNetwork>().is_err()) {
+        None => Ok(()),
+        Some(network) => Err(format!("Value '{}' is not valid CIDR network. Expected format is: 10.0.0.0/8,fd00::/8", network)),
+    }
+}
+
+// Validates proof of work, which must be a number in range 0.0..=256.0
+fn validate_expected_pow(value: &str) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(pow) if (0.0..=256.0).contains(&pow) => Ok(()),
+        _ => Err(format!("Value '{}' must be a valid number in range 0.0..=256.0 for expected_pow", value)),
+    }
 }
 
 // Validates single required arg. If missing, exit whole process
@@ -486,14 +707,89 @@ impl Environment {
                         .parse::<usize>()
                         .expect("Provided value cannot be converted to number"),
                 ),
+                synchronisation_threshold: SynchronisationThreshold::new(
+                    args.value_of("synchronisation-threshold")
+                        .unwrap_or("4")
+                        .parse::<usize>()
+                        .expect("Provided value cannot be converted to number"),
+                    args.value_of("sync-latency")
+                        .unwrap_or("150")
+                        .parse::<u64>()
+                        .map(Duration::from_secs)
+                        .expect("Provided value cannot be converted to number"),
+                ),
                 private_node: args.value_of("private-node")
                     .unwrap_or("false")
                     .parse::<bool>()
                     .expect("Provided value cannot be converted to bool"),
+                peer_filter: {
+                    let cidr_list = |name: &str| args.value_of(name)
+                        .map(|networks| networks
+                            .split(',')
+                            .map(|network| network.parse::<IpNetwork>().expect("Was expecting CIDR network"))
+                            .collect()
+                        ).unwrap_or_default();
+                    PeerFilter::new(
+                        cidr_list("allow-cidr"),
+                        cidr_list("deny-cidr"),
+                        args.value_of("trusted-peers")
+                            .map(|peers_str| peers_str
+                                .split(',')
+                                .map(|ip_port| ip_port.parse().expect("Was expecting IP:PORT"))
+                                .collect()
+                            ).unwrap_or_default(),
+                        args.value_of("trusted-peer-ids")
+                            .map(|peer_ids| peer_ids
+                                .split(',')
+                                .map(|peer_id| peer_id.trim().to_string())
+                                .collect()
+                            ).unwrap_or_default(),
+                    )
+                },
+                proxy: args.value_of("socks5-proxy")
+                    .map(|address| Socks5Proxy::new(
+                        address.parse().expect("Was expecting IP:PORT"),
+                        args.value_of("socks5-proxy-username")
+                            .and_then(|username| args.value_of("socks5-proxy-password").map(|password| (username.to_string(), password.to_string()))),
+                        args.value_of("socks5-proxy-scope")
+                            .unwrap_or("all")
+                            .parse::<ProxyScope>()
+                            .expect("Provided value cannot be converted to proxy scope"),
+                    )),
+                peer_expected_pow: args.value_of("peer-expected-pow")
+                    .map(|value| value.parse::<f64>().expect("Provided value cannot be converted to number"))
+                    .unwrap_or_else(|| match environment::TEZOS_ENV.get(&tezos_network) {
+                        None => panic!("No tezos environment configured for: {:?}", tezos_network),
+                        Some(cfg) => cfg.expected_pow,
+                    }),
                 disable_mempool: args.value_of("disable-mempool")
                     .unwrap_or("false")
                     .parse::<bool>()
                     .expect("Provided value cannot be converted to bool"),
+                mempool_limits: {
+                    let default_limits = MempoolLimits::default();
+                    MempoolLimits::new(
+                        args.value_of("mempool-max-operations")
+                            .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                            .unwrap_or(default_limits.max_operations),
+                        args.value_of("mempool-max-bytes")
+                            .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                            .unwrap_or(default_limits.max_bytes),
+                        args.value_of("mempool-max-operations-per-peer")
+                            .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                            .unwrap_or(default_limits.max_operations_per_peer),
+                    )
+                },
+                bandwidth_limits: {
+                    let kib_per_sec = |name: &str| args.value_of(name)
+                        .map(|value| value.parse::<u64>().expect("Provided value cannot be converted to number") * 1024);
+                    BandwidthLimits {
+                        global_upload: kib_per_sec("max-upload-speed"),
+                        global_download: kib_per_sec("max-download-speed"),
+                        peer_upload: kib_per_sec("peer-max-upload-speed"),
+                        peer_download: kib_per_sec("peer-max-download-speed"),
+                    }
+                },
             },
             rpc: crate::configuration::Rpc {
                 listener_port: args
@@ -617,6 +913,40 @@ impl Environment {
                 .unwrap_or("false")
                 .parse::<bool>()
                 .expect("Provided value cannot be converted to bool"),
+            supervision: {
+                let default_policy = SupervisionPolicy::default();
+                SupervisionPolicy::new(
+                    default_policy.initial_backoff,
+                    args.value_of("worker-restart-max-backoff")
+                        .map(|value| value.parse::<u64>().map(Duration::from_secs).expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_policy.max_backoff),
+                    args.value_of("worker-max-failures")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .filter(|max_failures| *max_failures > 0),
+                )
+            },
+            shutdown: {
+                let default_timeouts = ShutdownTimeouts::default();
+                let timeout = |name: &str, default: Duration| args.value_of(name)
+                    .map(|value| value.parse::<u64>().map(Duration::from_secs).expect("Provided value cannot be converted to number"))
+                    .unwrap_or(default);
+                ShutdownTimeouts {
+                    block_application: timeout("shutdown-block-application-timeout", default_timeouts.block_application),
+                    context_events: timeout("shutdown-context-events-timeout", default_timeouts.context_events),
+                    actors: timeout("shutdown-actors-timeout", default_timeouts.actors),
+                }
+            },
+            record_events: args.value_of("record-events")
+                .map(|v| v.parse::<PathBuf>().expect("Provided value cannot be converted to path"))
+                .map(|path| get_final_path(&data_dir, path)),
+            capture_p2p: args.value_of("capture-p2p")
+                .map(|v| v.parse::<PathBuf>().expect("Provided value cannot be converted to path"))
+                .map(|path| get_final_path(&data_dir, path)),
+            capture_peers: CaptureFilter::new(&args.value_of("capture-peers")
+                .map(|peers| peers.split(',').map(|peer| peer.trim().to_string()).collect::<Vec<_>>())
+                .unwrap_or_default()),
+            sandbox_baker_key: args.value_of("sandbox-baker-secret-key")
+                .map(|v| SigningKey::from_base58_seed(v).expect("Provided value is not a valid secret key")),
         }
     }
 }
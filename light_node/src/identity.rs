@@ -8,8 +8,7 @@ use std::path::{Path, PathBuf};
 use failure::Fail;
 use slog::{info, Logger};
 
-use tezos_api::identity::Identity;
-use tezos_client::client;
+use tezos_api::identity::{Identity, IdentityError as InvalidIdentity};
 
 #[derive(Fail, Debug)]
 pub enum IdentityError {
@@ -17,10 +16,13 @@ pub enum IdentityError {
     IoError {
         reason: io::Error
     },
-    #[fail(display = "Service error: {}, e: {}", message, error)]
-    ServiceError {
-        error: failure::Error,
-        message: &'static str,
+    #[fail(display = "Failed to generate identity: {}", reason)]
+    GenerateError {
+        reason: InvalidIdentity
+    },
+    #[fail(display = "Invalid identity: {}", reason)]
+    ValidationError {
+        reason: InvalidIdentity
     },
     #[fail(display = "Identity serialization error: {}", reason)]
     SerializationError {
@@ -60,33 +62,24 @@ pub fn store_identity(path: &PathBuf, identity: &Identity) -> Result<(), Identit
     Ok(())
 }
 
-/// Ensures (load or create) identity exists according to the configuration
+/// Ensures (load or create) identity exists according to the configuration.
+/// Loaded identity must satisfy the configured expected proof of work.
 pub fn ensure_identity(identity_cfg: &crate::configuration::Identity, log: Logger) -> Result<Identity, IdentityError> {
     if identity_cfg.identity_json_file_path.exists() {
-        load_identity(&identity_cfg.identity_json_file_path)
+        let identity = load_identity(&identity_cfg.identity_json_file_path)?;
+        identity.validate(identity_cfg.expected_pow).map_err(|reason| IdentityError::ValidationError { reason })?;
+        Ok(identity)
     } else {
-        info!(log, "Generating new tezos identity. This will take a while"; "expected_pow" => identity_cfg.expected_pow);
+        let threads = num_cpus::get();
+        info!(log, "Generating new tezos identity. This will take a while"; "expected_pow" => identity_cfg.expected_pow, "threads" => threads);
+
+        let identity = Identity::generate(identity_cfg.expected_pow, threads, |attempts| {
+            info!(log, "Generating identity, proof of work search in progress"; "attempts" => attempts);
+        }).map_err(|reason| IdentityError::GenerateError { reason })?;
+        info!(log, "Identity successfully generated"; "peer_id" => &identity.peer_id);
 
-        // TODO: TE-74 will be replace with rust version without protocol_runner
-        match client::generate_identity(identity_cfg.expected_pow) {
-            Ok(identity) => {
-                client::shutdown_runtime();
-                info!(log, "Identity successfully generated");
-                match store_identity(&identity_cfg.identity_json_file_path, &identity) {
-                    Ok(()) => {
-                        info!(log, "Generated identity stored to file"; "file" => identity_cfg.identity_json_file_path.clone().into_os_string().into_string().unwrap());
-                        Ok(identity)
-                    }
-                    Err(e) => Err(e)
-                }
-            }
-            Err(e) => {
-                client::shutdown_runtime();
-                return Err(IdentityError::ServiceError {
-                    error: e.into(),
-                    message: "Failed to generate identity",
-                });
-            }
-        }
+        store_identity(&identity_cfg.identity_json_file_path, &identity)?;
+        info!(log, "Generated identity stored to file"; "file" => identity_cfg.identity_json_file_path.clone().into_os_string().into_string().unwrap());
+        Ok(identity)
     }
-}
\ No newline at end of file
+}
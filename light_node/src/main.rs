@@ -2,25 +2,28 @@
 // SPDX-License-Identifier: MIT
 
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use std::thread;
-use std::time::Duration;
 
+use futures::future::Either;
 use riker::actors::*;
 use slog::{crit, debug, Drain, error, info, Logger};
 
 use logging::detailed_json;
 use logging::file::FileAppenderBuilder;
 use monitoring::{Monitor, WebsocketHandler};
+use networking::p2p::bandwidth::Bandwidth;
+use networking::p2p::capture::TrafficCapture;
 use networking::p2p::network_channel::NetworkChannel;
 use rpc::rpc_actor::RpcServer;
+use rpc::SandboxConfiguration;
 use shell::chain_feeder::ChainFeeder;
 use shell::chain_manager::ChainManager;
 use shell::context_listener::ContextListener;
 use shell::mempool_prevalidator::MempoolPrevalidator;
 use shell::peer_manager::PeerManager;
-use shell::shell_channel::{ShellChannel, ShellChannelTopic, ShuttingDown};
-use storage::{block_storage, BlockMetaStorage, BlockStorage, check_database_compatibility, context_action_storage, ContextActionStorage, MempoolStorage, OperationsMetaStorage, OperationsStorage, resolve_storage_init_chain_data, StorageInitInfo, SystemStorage};
+use shell::recorder::{create_recording_file, EventRecorder};
+use shell::shell_channel::ShellChannel;
+use shell::supervision::Supervisor;
+use storage::{block_storage, BlockMetaStorage, BlockStorage, check_database_compatibility, context_action_storage, ContextActionStorage, MempoolStorage, OperationsMetaStorage, OperationsStorage, PointStorage, resolve_storage_init_chain_data, StorageInitInfo, SystemStorage};
 use storage::persistent::{CommitLogSchema, KeyValueSchema, open_cl, open_kv, PersistentStorage};
 use storage::persistent::sequence::Sequences;
 use storage::skip_list::{DatabaseBackedSkipList, Lane, ListValue};
@@ -36,9 +39,11 @@ use crate::configuration::LogFormat;
 
 mod configuration;
 mod identity;
+mod shutdown;
 
 const DATABASE_VERSION: i64 = 14;
-const SUPPORTED_DISTRIBUTED_DB_VERSION: u16 = 0;
+/// Supported distributed_db versions, the best common version is negotiated with every peer
+const SUPPORTED_DISTRIBUTED_DB_VERSIONS: &[u16] = &[0];
 const SUPPORTED_P2P_VERSION: u16 = 1;
 
 macro_rules! shutdown_and_exit {
@@ -105,12 +110,14 @@ fn block_on_actors(
 
     // if feeding is started, than run chain manager
     let is_sandbox = env.tezos_network == environment::TezosEnvironment::Sandbox;
-    // version
-    let network_version = NetworkVersion::new(
-        tezos_env.version.clone(),
-        SUPPORTED_DISTRIBUTED_DB_VERSION,
-        SUPPORTED_P2P_VERSION,
-    );
+    // versions, the newest one is reported by rpc
+    let network_versions = SUPPORTED_DISTRIBUTED_DB_VERSIONS.iter()
+        .map(|distributed_db_version| NetworkVersion::new(tezos_env.version.clone(), *distributed_db_version, SUPPORTED_P2P_VERSION))
+        .collect::<Vec<_>>();
+    let network_version = network_versions.iter()
+        .max_by_key(|version| version.distributed_db_version())
+        .cloned()
+        .expect("At least one distributed_db version has to be supported");
 
     // create pool for ffi protocol runner connections (used just for readonly context)
     let tezos_readonly_api = Arc::new(
@@ -172,12 +179,38 @@ fn block_on_actors(
     let shell_channel = ShellChannel::actor(&actor_system)
         .expect("Failed to create shell channel");
 
+    // recorder is started first, so it does not miss any event
+    if let Some(record_events) = &env.record_events {
+        match create_recording_file(record_events) {
+            Ok(writer) => {
+                let _ = EventRecorder::actor(&actor_system, network_channel.clone(), shell_channel.clone(), writer)
+                    .expect("Failed to create event recorder");
+                info!(log, "Network and shell events are recorded"; "file" => record_events.to_string_lossy().to_string());
+            }
+            Err(e) => shutdown_and_exit!(error!(log, "Failed to create recording file"; "file" => record_events.to_string_lossy().to_string(), "reason" => format!("{}", e)), actor_system),
+        }
+    }
+
+    let capture = match &env.capture_p2p {
+        Some(capture_p2p) => match TrafficCapture::create(capture_p2p, env.capture_peers.clone(), log.clone()) {
+            Ok(capture) => {
+                info!(log, "P2P messages are captured"; "file" => capture_p2p.to_string_lossy().to_string());
+                Some(Arc::new(capture))
+            }
+            Err(e) => shutdown_and_exit!(error!(log, "Failed to create p2p capture file"; "file" => capture_p2p.to_string_lossy().to_string(), "reason" => format!("{}", e)), actor_system),
+        },
+        None => None,
+    };
+
+    // worker threads of shell actors are restarted on failure, after too many failures node is shut down
+    let (supervisor, shutdown_request) = Supervisor::new(env.supervision.clone(), shell_channel.clone());
+
     // it's important to start ContextListener before ChainFeeder, because chain_feeder can trigger init_genesis which sends ContextAction, and we need to process this action first
-    let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_block_protocol_events.expect("Context listener needs event server"), log.clone(), env.storage.store_context_actions)
+    let _ = ContextListener::actor(&actor_system, shell_channel.clone(), &persistent_storage, apply_block_protocol_events.expect("Context listener needs event server"), &supervisor, log.clone(), env.storage.store_context_actions)
         .expect("Failed to create context event listener");
-    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_block_protocol_commands, log.clone())
+    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_block_protocol_commands, &supervisor, log.clone())
         .expect("Failed to create chain feeder");
-    let _ = ChainManager::actor(&actor_system, network_channel.clone(), shell_channel.clone(), &persistent_storage, &init_storage_data.chain_id, is_sandbox)
+    let _ = ChainManager::actor(&actor_system, network_channel.clone(), shell_channel.clone(), &persistent_storage, &init_storage_data.chain_id, is_sandbox, env.p2p.synchronisation_threshold.clone(), env.p2p.mempool_limits.clone())
         .expect("Failed to create chain manager");
 
     let _ = MempoolPrevalidator::actor(
@@ -186,27 +219,36 @@ fn block_on_actors(
         &persistent_storage,
         &init_storage_data,
         tezos_readonly_api.clone(),
+        env.p2p.mempool_limits.clone(),
+        &supervisor,
         log.clone(),
-    ).expect("Failed to create chain feeder");
+    ).expect("Failed to create mempool prevalidator");
 
     // and than open p2p and others
+    let bandwidth = Arc::new(Bandwidth::new(env.p2p.bandwidth_limits.clone()));
     let _ = PeerManager::actor(
         &actor_system,
         network_channel.clone(),
         shell_channel.clone(),
+        &persistent_storage,
         tokio_runtime.handle().clone(),
         &env.p2p.bootstrap_lookup_addresses,
         &env.p2p.initial_peers,
         env.p2p.peer_threshold,
         env.p2p.listener_port,
         identity,
-        network_version.clone(),
+        env.p2p.peer_expected_pow,
+        network_versions,
+        bandwidth.clone(),
+        capture,
+        env.p2p.proxy.clone(),
+        env.p2p.peer_filter.clone(),
         env.p2p.disable_mempool,
         env.p2p.private_node,
     ).expect("Failed to create peer manager");
     let websocket_handler = WebsocketHandler::actor(&actor_system, env.rpc.websocket_address, log.clone())
         .expect("Failed to start websocket actor");
-    let _ = Monitor::actor(&actor_system, network_channel.clone(), websocket_handler, shell_channel.clone(), &persistent_storage)
+    let _ = Monitor::actor(&actor_system, network_channel.clone(), websocket_handler, shell_channel.clone(), &persistent_storage, bandwidth)
         .expect("Failed to create monitor actor");
     let _ = RpcServer::actor(
         &actor_system,
@@ -218,32 +260,38 @@ fn block_on_actors(
         tezos_env.clone(),
         network_version,
         &init_storage_data,
+        if is_sandbox { Some(SandboxConfiguration { signing_key: env.sandbox_baker_key.clone() }) } else { None },
     ).expect("Failed to create RPC server");
 
     tokio_runtime.block_on(async move {
         use tokio::signal;
 
-        signal::ctrl_c().await.expect("Failed to listen for ctrl-c event");
-        info!(log, "ctrl-c received!");
-
-        // disable/stop protocol runner for applying blocks feature
-        apply_blocks_protocol_runner_endpoint_run_feature.store(false, Ordering::Release);
-
-        info!(log, "Sending shutdown notification to actors");
-        shell_channel.tell(
-            Publish {
-                msg: ShuttingDown.into(),
-                topic: ShellChannelTopic::ShellCommands.into(),
-            }, None,
-        );
-
-        // give actors some time to shut down
-        thread::sleep(Duration::from_secs(1));
-
+        // wait for ctrl-c or for shutdown requested by supervisor
+        let ctrl_c = Box::pin(signal::ctrl_c());
+        match futures::future::select(ctrl_c, shutdown_request).await {
+            Either::Left((ctrl_c, _)) => {
+                ctrl_c.expect("Failed to listen for ctrl-c event");
+                info!(log, "ctrl-c received!");
+            }
+            Either::Right((Ok(worker_failed), _)) => {
+                crit!(log, "Shutting down, because worker failed too many times"; "worker" => worker_failed.worker, "failures" => worker_failed.failures, "reason" => worker_failed.reason);
+            }
+            Either::Right((Err(_), ctrl_c)) => {
+                // supervisor was dropped, so we just wait for ctrl-c
+                ctrl_c.await.expect("Failed to listen for ctrl-c event");
+                info!(log, "ctrl-c received!");
+            }
+        }
 
-        info!(log, "Shutting down actors");
-        let _ = actor_system.shutdown().await;
-        info!(log, "Shutdown complete");
+        shutdown::shutdown(
+            &env.shutdown,
+            shell_channel,
+            supervisor,
+            apply_blocks_protocol_runner_endpoint_run_feature,
+            actor_system,
+            persistent_storage,
+            &log,
+        ).await;
     });
 }
 
@@ -285,6 +333,7 @@ fn main() {
         ListValue::descriptor(),
         Sequences::descriptor(),
         MempoolStorage::descriptor(),
+        PointStorage::descriptor(),
     ];
     let rocks_db = match open_kv(&env.storage.bootstrap_db_path, schemas) {
         Ok(db) => Arc::new(db),
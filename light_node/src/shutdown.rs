@@ -0,0 +1,96 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Ordered shutdown of the node.
+//!
+//! Node is shut down in stages, each stage is limited by a timeout:
+//! 1. peers are no longer accepted and chain manager stops processing of new blocks,
+//! 2. in-flight block application is finished (or aborted by stopping of the protocol runner after timeout),
+//! 3. protocol runner is stopped and remaining context actions are drained from the event stream,
+//! 4. actors are stopped,
+//! 5. storage is flushed.
+//!
+//! Everything, what was not finished in time, is logged.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use riker::actors::*;
+use slog::{info, Logger, warn};
+
+use shell::shell_channel::{ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use shell::supervision::Supervisor;
+use storage::persistent::PersistentStorage;
+
+/// Name of the supervised block applier worker of the chain feeder
+const CHAIN_FEEDER_WORKER: &str = "chain-feeder";
+/// Name of the supervised worker of the context listener
+const CONTEXT_LISTENER_WORKER: &str = "context-listener";
+
+/// Max durations of the shutdown stages, configurable by `--shutdown-*-timeout` arguments
+#[derive(Clone, Debug)]
+pub struct ShutdownTimeouts {
+    /// How long to wait for the in-flight block application
+    pub block_application: Duration,
+    /// How long to wait for draining of the context action events
+    pub context_events: Duration,
+    /// How long to wait for the actors to stop
+    pub actors: Duration,
+}
+
+impl Default for ShutdownTimeouts {
+    fn default() -> Self {
+        ShutdownTimeouts {
+            block_application: Duration::from_secs(30),
+            context_events: Duration::from_secs(10),
+            actors: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Shuts down the node in stages, see module documentation.
+pub async fn shutdown(
+    timeouts: &ShutdownTimeouts,
+    shell_channel: ShellChannelRef,
+    supervisor: Supervisor,
+    protocol_runner_run_feature: Arc<AtomicBool>,
+    actor_system: ActorSystem,
+    persistent_storage: PersistentStorage,
+    log: &Logger) {
+
+    // stop accepting peers and finish in-flight block application
+    info!(log, "Sending shutdown notification to actors");
+    shell_channel.tell(
+        Publish {
+            msg: ShuttingDown.into(),
+            topic: ShellChannelTopic::ShellCommands.into(),
+        }, None,
+    );
+    info!(log, "Waiting for block application to finish"; "timeout_ms" => timeouts.block_application.as_millis() as u64);
+    let unfinished = supervisor.wait_for_workers(&[CHAIN_FEEDER_WORKER], timeouts.block_application).await;
+    if !unfinished.is_empty() {
+        warn!(log, "Block application was not finished in time, it will be aborted"; "unfinished" => format!("{:?}", unfinished));
+    }
+
+    // disable/stop protocol runner for applying blocks feature, this closes the context event stream
+    protocol_runner_run_feature.store(false, Ordering::Release);
+
+    info!(log, "Waiting for context events to be drained"; "timeout_ms" => timeouts.context_events.as_millis() as u64);
+    let unfinished = supervisor.wait_for_workers(&[CHAIN_FEEDER_WORKER, CONTEXT_LISTENER_WORKER], timeouts.context_events).await;
+    if !unfinished.is_empty() {
+        warn!(log, "Context events were not drained in time, remaining context actions are lost"; "unfinished" => format!("{:?}", unfinished));
+    }
+
+    info!(log, "Shutting down actors"; "timeout_ms" => timeouts.actors.as_millis() as u64);
+    if tokio::time::timeout(timeouts.actors, actor_system.shutdown()).await.is_err() {
+        warn!(log, "Actors were not stopped in time");
+    }
+
+    info!(log, "Flushing storage");
+    if let Err(e) = persistent_storage.flush() {
+        warn!(log, "Failed to flush storage"; "reason" => format!("{:?}", e));
+    }
+
+    info!(log, "Shutdown complete");
+}
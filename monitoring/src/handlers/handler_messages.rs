@@ -6,6 +6,9 @@ use std::iter::FromIterator;
 use serde::Serialize;
 use slog_derive::SerdeValue;
 
+use networking::p2p::bandwidth::BandwidthLimits;
+use shell::stats::peer_stats::{PeerStatsSnapshot, RequestStats};
+
 use crate::monitors::PeerMonitor;
 use crate::monitors::ChainMonitor;
 
@@ -93,6 +96,109 @@ impl PeerMetrics {
     }
 }
 
+// -------------------------- BANDWIDTH STATS MESSAGE -------------------------- //
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BandwidthMetrics {
+    /// Current rates of all p2p connections in bytes per second
+    upload_speed: f32,
+    download_speed: f32,
+    /// Configured limits in bytes per second, `None` means unlimited
+    upload_limit: Option<u64>,
+    download_limit: Option<u64>,
+    peer_upload_limit: Option<u64>,
+    peer_download_limit: Option<u64>,
+    peers: Vec<PeerBandwidthMetrics>,
+}
+
+impl BandwidthMetrics {
+    pub fn new(upload_speed: f32, download_speed: f32, limits: &BandwidthLimits, peers: Vec<PeerBandwidthMetrics>) -> Self {
+        Self {
+            upload_speed,
+            download_speed,
+            upload_limit: limits.global_upload,
+            download_limit: limits.global_download,
+            peer_upload_limit: limits.peer_upload,
+            peer_download_limit: limits.peer_download,
+            peers,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerBandwidthMetrics {
+    ip_address: String,
+    upload_speed: f32,
+    download_speed: f32,
+}
+
+impl PeerBandwidthMetrics {
+    pub fn new(ip_address: String, upload_speed: f32, download_speed: f32) -> Self {
+        Self {
+            ip_address,
+            upload_speed,
+            download_speed,
+        }
+    }
+}
+
+// -------------------------- PEER LATENCY STATS MESSAGE -------------------------- //
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerLatencyMetrics {
+    id: String,
+    block_headers: RequestMetrics,
+    operations_for_blocks: RequestMetrics,
+    current_head: RequestMetrics,
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    score: f64,
+}
+
+impl From<PeerStatsSnapshot> for PeerLatencyMetrics {
+    fn from(stats: PeerStatsSnapshot) -> Self {
+        Self {
+            id: stats.peer_id,
+            block_headers: stats.block_headers.into(),
+            operations_for_blocks: stats.operations_for_blocks.into(),
+            current_head: stats.current_head.into(),
+            messages_received: stats.messages_received,
+            messages_sent: stats.messages_sent,
+            bytes_received: stats.bytes_received,
+            bytes_sent: stats.bytes_sent,
+            score: stats.score,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestMetrics {
+    sent: u64,
+    succeeded: u64,
+    failed: u64,
+    success_ratio: f64,
+    /// Round-trip latencies in milliseconds
+    average_latency: Option<f64>,
+    last_latency: Option<f64>,
+}
+
+impl From<RequestStats> for RequestMetrics {
+    fn from(stats: RequestStats) -> Self {
+        Self {
+            sent: stats.sent,
+            succeeded: stats.succeeded,
+            failed: stats.failed,
+            success_ratio: stats.success_ratio(),
+            average_latency: stats.average_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            last_latency: stats.last_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
 // -------------------------- PEER CONNECTING/DISCONNECTING MESSAGE -------------------------- //
 #[derive(Clone, Serialize, Debug)]
 #[serde(rename_all = "camelCase", tag = "status", content = "id")]
@@ -133,6 +239,12 @@ pub enum HandlerMessage {
     ChainStatus {
         payload:  ChainMonitor,
     },
+    BandwidthStatus {
+        payload: BandwidthMetrics,
+    },
+    PeerLatencyStatus {
+        payload: Vec<PeerLatencyMetrics>,
+    },
     NotImplemented(String),
 }
 
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: MIT
 
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 use riker::{
@@ -13,6 +14,7 @@ use slog::{Logger, warn};
 use networking::p2p::{
     network_channel::{NetworkChannelMsg, NetworkChannelTopic, PeerMessageReceived, NetworkChannelRef},
 };
+use networking::p2p::bandwidth::Bandwidth;
 use networking::p2p::network_channel::PeerBootstrapped;
 use shell::shell_channel::{ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
 use storage::{BlockMetaStorage, BlockMetaStorageReader, IteratorMode};
@@ -24,7 +26,7 @@ use crate::{
     handlers::WebsocketHandlerMsg,
     monitors::*,
 };
-use crate::handlers::handler_messages::HandlerMessage;
+use crate::handlers::handler_messages::{HandlerMessage, PeerLatencyMetrics};
 
 #[derive(Clone, Debug)]
 pub enum BroadcastSignal {
@@ -46,6 +48,7 @@ pub struct Monitor {
     blocks_monitor: BlocksMonitor,
     block_application_monitor: ApplicationMonitor,
     chain_monitor: ChainMonitor,
+    bandwidth_monitor: BandwidthMonitor,
 }
 
 impl Monitor {
@@ -53,10 +56,10 @@ impl Monitor {
         "monitor-manager"
     }
 
-    pub fn actor(sys: &impl ActorRefFactory, event_channel: NetworkChannelRef, msg_channel: ActorRef<WebsocketHandlerMsg>, shell_channel: ShellChannelRef, persistent_storage: &PersistentStorage) -> Result<MonitorRef, CreateError> {
+    pub fn actor(sys: &impl ActorRefFactory, event_channel: NetworkChannelRef, msg_channel: ActorRef<WebsocketHandlerMsg>, shell_channel: ShellChannelRef, persistent_storage: &PersistentStorage, bandwidth: Arc<Bandwidth>) -> Result<MonitorRef, CreateError> {
         sys.actor_of_props::<Monitor>(
             Self::name(),
-            Props::new_args((event_channel, msg_channel, shell_channel, persistent_storage.clone())),
+            Props::new_args((event_channel, msg_channel, shell_channel, persistent_storage.clone(), bandwidth)),
         )
     }
 
@@ -77,7 +80,11 @@ impl Monitor {
 
         if let Some(monitor) = self.peer_monitors.get_mut(msg.peer.uri()) {
             if monitor.public_key.is_some() {
-                let size = if let Ok(msg) = msg.message.as_bytes() {
+                let bytes = match &monitor.network_version {
+                    Some(network_version) => msg.message.as_bytes_for_version(network_version),
+                    None => msg.message.as_bytes(),
+                };
+                let size = if let Ok(msg) = bytes {
                     msg.len()
                 } else {
                     size_of_val(&msg.message)
@@ -90,8 +97,8 @@ impl Monitor {
     }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellChannelRef, PersistentStorage)> for Monitor {
-    fn create_args((event_channel, msg_channel, shell_channel, persistent_storage): (NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellChannelRef, PersistentStorage)) -> Self {
+impl ActorFactoryArgs<(NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellChannelRef, PersistentStorage, Arc<Bandwidth>)> for Monitor {
+    fn create_args((event_channel, msg_channel, shell_channel, persistent_storage, bandwidth): (NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellChannelRef, PersistentStorage, Arc<Bandwidth>)) -> Self {
         let blocks_meta = BlockMetaStorage::new(&persistent_storage);
         // TODO: TE-184 - monitor - count all downloaded blocks - is this necessery?
         // get downloaded count
@@ -117,6 +124,7 @@ impl ActorFactoryArgs<(NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellCh
             blocks_monitor: BlocksMonitor::new(4096, downloaded),
             block_application_monitor: ApplicationMonitor::new(),
             chain_monitor: ChainMonitor::new(),
+            bandwidth_monitor: BandwidthMonitor::new(bandwidth),
         }
     }
 }
@@ -187,6 +195,9 @@ impl Receive<BroadcastSignal> for Monitor {
             BroadcastSignal::PublishPeerStatistics => {
                 let peer_stats: HandlerMessage = self.peer_monitors.values_mut().collect();
                 self.msg_channel.tell(peer_stats, ctx.myself().into());
+
+                let payload = self.bandwidth_monitor.snapshot();
+                self.msg_channel.tell(HandlerMessage::BandwidthStatus { payload }, ctx.myself().into());
             }
             BroadcastSignal::PublishBlocksStatistics => {
                 let bootstrap_stats: HandlerMessage = self.bootstrap_monitor.snapshot().into();
@@ -228,13 +239,15 @@ impl Receive<NetworkChannelMsg> for Monitor {
             }
             NetworkChannelMsg::PeerBootstrapped(msg) => {
                 match msg {
-                    PeerBootstrapped::Success { peer, peer_id, .. } => if let Some(monitor) = self.peer_monitors.get_mut(peer.uri()) {
+                    PeerBootstrapped::Success { peer, peer_id, network_version, .. } => if let Some(monitor) = self.peer_monitors.get_mut(peer.uri()) {
                         monitor.public_key = Some(peer_id);
+                        monitor.network_version = Some(network_version);
                     }
                     PeerBootstrapped::Failure { .. } => ()
                 }
             }
             NetworkChannelMsg::PeerMessageReceived(msg) => self.process_peer_message(msg, &ctx.system.log()),
+            _ => (),
         }
     }
 }
@@ -242,7 +255,7 @@ impl Receive<NetworkChannelMsg> for Monitor {
 impl Receive<ShellChannelMsg> for Monitor {
     type Msg = MonitorMsg;
 
-    fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
         match msg {
             ShellChannelMsg::BlockReceived(msg) => {
                 // Update current max block count
@@ -273,7 +286,14 @@ impl Receive<ShellChannelMsg> for Monitor {
             // TODO: TE-173: mempool stats
             ShellChannelMsg::MempoolOperationReceived(_) => (),
             ShellChannelMsg::MempoolStateChanged(_) => (),
+            ShellChannelMsg::MempoolOperationBan(_) => (),
             ShellChannelMsg::InjectBlock(_) => (),
+            ShellChannelMsg::SyncStateChanged(_) => (),
+            ShellChannelMsg::WorkerFailed(_) => (),
+            ShellChannelMsg::PeerStatistics(msg) => {
+                let payload = msg.peers.into_iter().map(PeerLatencyMetrics::from).collect();
+                self.msg_channel.tell(HandlerMessage::PeerLatencyStatus { payload }, ctx.myself().into());
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,55 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use networking::p2p::bandwidth::Bandwidth;
+
+use crate::handlers::handler_messages::{BandwidthMetrics, PeerBandwidthMetrics};
+
+/// Current upload/download rates of the p2p connections, computed from the byte counters of the stream layer.
+pub(crate) struct BandwidthMonitor {
+    bandwidth: Arc<Bandwidth>,
+    /// Counters (uploaded, downloaded) at the last snapshot
+    last_total: (u64, u64),
+    last_peers: HashMap<SocketAddr, (u64, u64)>,
+    last_update: Instant,
+}
+
+impl BandwidthMonitor {
+    pub fn new(bandwidth: Arc<Bandwidth>) -> Self {
+        let last_total = (bandwidth.total().uploaded(), bandwidth.total().downloaded());
+        Self {
+            bandwidth,
+            last_total,
+            last_peers: HashMap::new(),
+            last_update: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&mut self) -> BandwidthMetrics {
+        let elapsed = self.last_update.elapsed().as_secs_f32().max(f32::EPSILON);
+        let speed = |current: u64, last: u64| current.saturating_sub(last) as f32 / elapsed;
+
+        let total = (self.bandwidth.total().uploaded(), self.bandwidth.total().downloaded());
+        let (upload_speed, download_speed) = (speed(total.0, self.last_total.0), speed(total.1, self.last_total.1));
+
+        let mut last_peers = HashMap::new();
+        let mut peers = Vec::new();
+        for (address, stats) in self.bandwidth.peers() {
+            let current = (stats.uploaded(), stats.downloaded());
+            let last = self.last_peers.get(&address).cloned().unwrap_or((0, 0));
+            peers.push(PeerBandwidthMetrics::new(address.to_string(), speed(current.0, last.0), speed(current.1, last.1)));
+            last_peers.insert(address, current);
+        }
+
+        self.last_total = total;
+        self.last_peers = last_peers;
+        self.last_update = Instant::now();
+
+        BandwidthMetrics::new(upload_speed, download_speed, self.bandwidth.limits(), peers)
+    }
+}
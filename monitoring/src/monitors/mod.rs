@@ -6,9 +6,11 @@ mod bootstrap_monitor;
 mod blocks_monitor;
 mod block_application_monitor;
 mod chain_monitor;
+mod bandwidth_monitor;
 
 pub(crate) use peer_monitor::PeerMonitor;
 pub(crate) use bootstrap_monitor::BootstrapMonitor;
 pub(crate) use blocks_monitor::BlocksMonitor;
 pub(crate) use block_application_monitor::ApplicationMonitor;
-pub(crate) use chain_monitor::ChainMonitor;
\ No newline at end of file
+pub(crate) use chain_monitor::ChainMonitor;
+pub(crate) use bandwidth_monitor::BandwidthMonitor;
\ No newline at end of file
@@ -5,6 +5,8 @@ use std::{net::SocketAddr, time::Instant};
 
 use riker::actor::ActorUri;
 
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+
 use crate::handlers::handler_messages::PeerMetrics;
 
 /// Peer specific details about transfer *FROM* peer.
@@ -13,6 +15,8 @@ pub(crate) struct PeerMonitor {
     pub total_transferred: usize,
     pub addr: Option<SocketAddr>,
     pub public_key: Option<String>,
+    /// Network version negotiated with the peer, received messages are encoded for this version
+    pub network_version: Option<NetworkVersion>,
     current_transferred: usize,
     last_update: Instant,
     first_update: Instant,
@@ -26,6 +30,7 @@ impl PeerMonitor {
             total_transferred: 0,
             addr: None,
             public_key: None,
+            network_version: None,
             current_transferred: 0,
             last_update: now,
             first_update: now,
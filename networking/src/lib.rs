@@ -3,4 +3,5 @@
 
 //! This crate handles low level p2p communication.
 
-pub mod p2p;
\ No newline at end of file
+pub mod p2p;
+pub mod records;
@@ -0,0 +1,218 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Upload and download rate limits of the p2p connections, enforced by the [stream](super::stream) layer.
+//!
+//! Limits are token buckets refilled with the configured rate (bytes per second), bucket can hold at most one second of transfer.
+//! Every connection is limited by its own limits and by the global limits shared by all connections.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Rate limits in bytes per second, `None` means unlimited
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthLimits {
+    pub global_upload: Option<u64>,
+    pub global_download: Option<u64>,
+    pub peer_upload: Option<u64>,
+    pub peer_download: Option<u64>,
+}
+
+/// Token bucket
+#[derive(Debug)]
+struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    /// Available bytes (negative value means debt) and the time of the last refill
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        RateLimiter { rate, state: Mutex::new((rate as f64, Instant::now())) }
+    }
+
+    /// Take `bytes` from the bucket, returns how long the caller has to wait to stay within the rate
+    fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate.max(1) as f64;
+        let mut state = self.state.lock().unwrap();
+        let (available, last_refill) = &mut *state;
+
+        let now = Instant::now();
+        *available = (*available + now.duration_since(*last_refill).as_secs_f64() * rate).min(rate);
+        *last_refill = now;
+        *available -= bytes as f64;
+
+        if *available >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*available / rate)
+        }
+    }
+}
+
+/// Count of bytes transferred since the start
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+}
+
+/// Global limits and statistics of all p2p connections
+#[derive(Debug)]
+pub struct Bandwidth {
+    limits: BandwidthLimits,
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+    total: TransferStats,
+    /// Statistics of the open connections by the remote address
+    peers: Mutex<HashMap<SocketAddr, Arc<TransferStats>>>,
+}
+
+impl Bandwidth {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Bandwidth {
+            upload: limits.global_upload.map(RateLimiter::new),
+            download: limits.global_download.map(RateLimiter::new),
+            limits,
+            total: TransferStats::default(),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> &BandwidthLimits {
+        &self.limits
+    }
+
+    /// Bytes transferred by all connections
+    pub fn total(&self) -> &TransferStats {
+        &self.total
+    }
+
+    /// Statistics of all open connections
+    pub fn peers(&self) -> Vec<(SocketAddr, Arc<TransferStats>)> {
+        self.peers.lock().unwrap()
+            .iter()
+            .map(|(address, stats)| (*address, stats.clone()))
+            .collect()
+    }
+
+    /// Create limits of the new connection to the `address`
+    pub fn peer(self: &Arc<Self>, address: SocketAddr) -> PeerBandwidth {
+        let stats = Arc::new(TransferStats::default());
+        self.peers.lock().unwrap().insert(address, stats.clone());
+
+        PeerBandwidth {
+            inner: Arc::new(PeerBandwidthInner {
+                bandwidth: self.clone(),
+                address,
+                upload: self.limits.peer_upload.map(RateLimiter::new),
+                download: self.limits.peer_download.map(RateLimiter::new),
+                stats,
+            })
+        }
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth::new(BandwidthLimits::default())
+    }
+}
+
+#[derive(Debug)]
+struct PeerBandwidthInner {
+    bandwidth: Arc<Bandwidth>,
+    address: SocketAddr,
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+    stats: Arc<TransferStats>,
+}
+
+impl Drop for PeerBandwidthInner {
+    fn drop(&mut self) {
+        let mut peers = self.bandwidth.peers.lock().unwrap();
+        if peers.get(&self.address).filter(|stats| Arc::ptr_eq(stats, &self.stats)).is_some() {
+            peers.remove(&self.address);
+        }
+    }
+}
+
+/// Limits of the single connection, shared by its reader and writer
+#[derive(Clone, Debug)]
+pub struct PeerBandwidth {
+    inner: Arc<PeerBandwidthInner>,
+}
+
+impl PeerBandwidth {
+    /// Record uploaded bytes and wait, until the upload rate is within the limits
+    pub async fn uploaded(&self, bytes: usize) {
+        let inner = &self.inner;
+        inner.stats.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        inner.bandwidth.total.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        throttle(bytes, &inner.upload, &inner.bandwidth.upload).await
+    }
+
+    /// Record downloaded bytes and wait, until the download rate is within the limits
+    pub async fn downloaded(&self, bytes: usize) {
+        let inner = &self.inner;
+        inner.stats.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        inner.bandwidth.total.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        throttle(bytes, &inner.download, &inner.bandwidth.download).await
+    }
+}
+
+async fn throttle(bytes: usize, peer_limiter: &Option<RateLimiter>, global_limiter: &Option<RateLimiter>) {
+    let wait = [peer_limiter, global_limiter].iter()
+        .filter_map(|limiter| limiter.as_ref())
+        .map(|limiter| limiter.take(bytes))
+        .max()
+        .unwrap_or_default();
+
+    if wait > Duration::from_secs(0) {
+        tokio::time::delay_for(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+        // bucket holds one second of transfer
+        assert_eq!(Duration::from_secs(0), limiter.take(1000));
+
+        let wait = limiter.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+
+    #[test]
+    fn test_peer_stats_are_released() {
+        let bandwidth = Arc::new(Bandwidth::default());
+        let address: SocketAddr = "127.0.0.1:9732".parse().unwrap();
+
+        let peer = bandwidth.peer(address);
+        let writer = peer.clone();
+        assert_eq!(1, bandwidth.peers().len());
+
+        drop(peer);
+        assert_eq!(1, bandwidth.peers().len());
+        drop(writer);
+        assert!(bandwidth.peers().is_empty());
+    }
+}
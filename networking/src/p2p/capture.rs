@@ -0,0 +1,315 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Capture of the decrypted p2p messages of the selected peers, so the sessions can be inspected after the fact without the keys.
+//!
+//! Capture file is a [`records`](crate::records) file of bincode encoded [`CapturedMessage`]s. Messages are captured after the successful handshake,
+//! so every captured message is a binary encoded `PeerMessageResponse` (or the raw bytes of the message, which could not be decoded)
+//! for the network version negotiated with the peer.
+//!
+//! Messages are written by a dedicated thread, so network tasks never wait for the disk.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use slog::{warn, Logger};
+
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+
+use crate::records::{RecordError, RecordReader, RecordWriter};
+
+use super::peer::PeerId;
+
+/// Version of the capture format (version 2: negotiated network version of the peer)
+const CAPTURE_VERSION: u8 = 2;
+/// Max count of the messages waiting for the writer thread, messages are dropped when the writer can not keep up
+const CAPTURE_QUEUE_SIZE: usize = 10_000;
+
+/// Possible errors of writing or reading of captures
+pub type CaptureError = RecordError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Message received from the peer
+    Incoming,
+    /// Message sent to the peer
+    Outgoing,
+}
+
+/// One captured message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapturedMessage {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub peer_id: PeerId,
+    pub peer_address: SocketAddr,
+    /// Network version negotiated with the peer (`distributed_db_version`, `p2p_version`), message is encoded for this version
+    pub network_version: (u16, u16),
+    /// Decrypted binary encoded message
+    pub bytes: Vec<u8>,
+}
+
+impl CapturedMessage {
+    /// Network version to decode the message with, chain name is not captured, because it does not affect the encoding
+    pub fn network_version(&self) -> NetworkVersion {
+        let (distributed_db_version, p2p_version) = self.network_version;
+        NetworkVersion::new(String::new(), distributed_db_version, p2p_version)
+    }
+}
+
+/// Writes captured messages
+pub struct CaptureWriter<W: Write> {
+    writer: RecordWriter<W>,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(writer: W) -> Result<Self, CaptureError> {
+        Ok(CaptureWriter { writer: RecordWriter::new(writer, CAPTURE_VERSION)? })
+    }
+
+    pub fn write(&mut self, message: &CapturedMessage) -> Result<(), CaptureError> {
+        self.writer.write(message)
+    }
+
+    pub fn flush(&mut self) -> Result<(), CaptureError> {
+        self.writer.flush()
+    }
+
+    /// Finish capture and return underlying writer
+    pub fn into_inner(self) -> Result<W, CaptureError> {
+        self.writer.into_inner()
+    }
+}
+
+/// Reads captured messages in the captured order
+pub struct CaptureReader<R: Read> {
+    reader: RecordReader<R>,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(reader: R) -> Result<Self, CaptureError> {
+        Ok(CaptureReader { reader: RecordReader::new(reader, CAPTURE_VERSION)? })
+    }
+
+    /// Reads next message, returns `None` at the end of the capture
+    pub fn read(&mut self) -> Result<Option<CapturedMessage>, CaptureError> {
+        self.reader.read()
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedMessage, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+/// Peers selected for the capture by peer id or IP address, no selected peers means all peers
+#[derive(Clone, Debug, Default)]
+pub struct CaptureFilter {
+    peer_ids: HashSet<PeerId>,
+    ip_addresses: HashSet<IpAddr>,
+}
+
+impl CaptureFilter {
+    /// Parse list of peer ids and IP addresses
+    pub fn new(peers: &[String]) -> Self {
+        let mut filter = CaptureFilter::default();
+        for peer in peers {
+            match peer.parse::<IpAddr>() {
+                Ok(ip_address) => filter.ip_addresses.insert(ip_address),
+                Err(_) => filter.peer_ids.insert(peer.clone()),
+            };
+        }
+        filter
+    }
+
+    pub fn is_selected(&self, peer_id: &str, peer_address: &SocketAddr) -> bool {
+        (self.peer_ids.is_empty() && self.ip_addresses.is_empty())
+            || self.peer_ids.contains(peer_id)
+            || self.ip_addresses.contains(&peer_address.ip())
+    }
+}
+
+/// Capture file shared by all peers, capture is stopped when writing fails
+pub struct TrafficCapture {
+    /// Messages for the writer thread
+    sender: Mutex<SyncSender<CapturedMessage>>,
+    /// Count of the messages dropped since the last write, because the queue was full
+    dropped: Arc<AtomicU64>,
+    filter: CaptureFilter,
+}
+
+impl TrafficCapture {
+    /// Create new capture file (existing file is overwritten) and start the writer thread
+    pub fn create(path: &Path, filter: CaptureFilter, log: Logger) -> Result<Self, CaptureError> {
+        let writer = CaptureWriter::new(BufWriter::new(File::create(path)?))?;
+        let (sender, receiver) = sync_channel(CAPTURE_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        {
+            let dropped = dropped.clone();
+            thread::Builder::new()
+                .name("p2p-capture".to_string())
+                .spawn(move || write_captured_messages(writer, receiver, dropped, log))?;
+        }
+
+        Ok(TrafficCapture {
+            sender: Mutex::new(sender),
+            dropped,
+            filter,
+        })
+    }
+
+    /// Returns capture of the peer's messages, if the peer is selected
+    pub fn peer(&self, peer_id: &str, peer_address: SocketAddr, network_version: &NetworkVersion) -> Option<PeerCapture> {
+        if self.filter.is_selected(peer_id, &peer_address) {
+            Some(PeerCapture {
+                sender: self.sender.lock().unwrap().clone(),
+                dropped: self.dropped.clone(),
+                peer_id: peer_id.to_string(),
+                peer_address,
+                network_version: (network_version.distributed_db_version(), network_version.p2p_version()),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Write messages until all senders are dropped or writing fails.
+/// Writer is flushed whenever there is no message waiting, so nothing is lost when the node crashes.
+fn write_captured_messages(mut writer: CaptureWriter<BufWriter<File>>, receiver: Receiver<CapturedMessage>, dropped: Arc<AtomicU64>, log: Logger) {
+    while let Ok(message) = receiver.recv() {
+        let written = writer.write(&message)
+            .and_then(|_| receiver.try_iter().try_for_each(|message| writer.write(&message)))
+            .and_then(|_| writer.flush());
+        if let Err(e) = written {
+            warn!(log, "Failed to write p2p capture, capture is stopped"; "reason" => format!("{}", e));
+            return;
+        }
+
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(log, "P2P capture can not keep up, messages were dropped"; "dropped" => dropped);
+        }
+    }
+}
+
+/// Captures messages of the single peer
+#[derive(Clone)]
+pub struct PeerCapture {
+    sender: SyncSender<CapturedMessage>,
+    dropped: Arc<AtomicU64>,
+    peer_id: PeerId,
+    peer_address: SocketAddr,
+    network_version: (u16, u16),
+}
+
+impl PeerCapture {
+    pub fn capture(&self, direction: Direction, bytes: &[u8]) {
+        let message = CapturedMessage {
+            timestamp: SystemTime::now(),
+            direction,
+            peer_id: self.peer_id.clone(),
+            peer_address: self.peer_address,
+            network_version: self.network_version,
+            bytes: bytes.to_vec(),
+        };
+        match self.sender.try_send(message) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // capture was stopped
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_capture() -> Result<(), failure::Error> {
+        let mut writer = CaptureWriter::new(Vec::new())?;
+        writer.write(&CapturedMessage {
+            timestamp: SystemTime::now(),
+            direction: Direction::Outgoing,
+            peer_id: "idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string(),
+            peer_address: "127.0.0.1:9732".parse()?,
+            network_version: (1, 1),
+            bytes: vec![0, 0, 0, 2, 0, 2],
+        })?;
+        let mut bytes = writer.into_inner()?;
+
+        let messages = CaptureReader::new(Cursor::new(bytes.clone()))?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(1, messages.len());
+        assert_eq!(Direction::Outgoing, messages[0].direction);
+        assert_eq!("idtqxHUjbjbCfaDn4jczoPGsnhacKX", messages[0].peer_id);
+        assert_eq!(vec![0, 0, 0, 2, 0, 2], messages[0].bytes);
+        assert_eq!(1, messages[0].network_version().distributed_db_version());
+
+        // truncated record
+        bytes.pop();
+        assert!(CaptureReader::new(Cursor::new(bytes))?.read().is_err());
+        // unsupported version
+        assert!(CaptureReader::new(Cursor::new(vec![CAPTURE_VERSION + 1])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_filter() -> Result<(), failure::Error> {
+        let address: SocketAddr = "127.0.0.1:9732".parse()?;
+        assert!(CaptureFilter::default().is_selected("idtqxHUjbjbCfaDn4jczoPGsnhacKX", &address));
+
+        let filter = CaptureFilter::new(&["idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string(), "10.0.0.1".to_string()]);
+        assert!(filter.is_selected("idtqxHUjbjbCfaDn4jczoPGsnhacKX", &address));
+        assert!(filter.is_selected("idrRoknJh9zwEePNswF3MPGFzmKaVp", &"10.0.0.1:9732".parse()?));
+        assert!(!filter.is_selected("idrRoknJh9zwEePNswF3MPGFzmKaVp", &address));
+        Ok(())
+    }
+
+    #[test]
+    fn test_traffic_capture_writer_thread() -> Result<(), failure::Error> {
+        let path = std::env::temp_dir().join(format!("__test_traffic_capture_{}.capture", std::process::id()));
+        let capture = TrafficCapture::create(&path, CaptureFilter::new(&["10.0.0.1".to_string()]), Logger::root(slog::Discard, slog::o!()))?;
+        let network_version = NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 0, 1);
+        assert!(capture.peer("idtqxHUjbjbCfaDn4jczoPGsnhacKX", "127.0.0.1:9732".parse()?, &network_version).is_none());
+
+        let peer = capture.peer("idtqxHUjbjbCfaDn4jczoPGsnhacKX", "10.0.0.1:9732".parse()?, &network_version).expect("Peer is selected");
+        peer.capture(Direction::Outgoing, &[0, 0, 0, 2, 0, 2]);
+        peer.capture(Direction::Incoming, &[0, 0, 0, 2, 0, 3]);
+        // writer thread finishes, when all senders are dropped
+        drop(peer);
+        drop(capture);
+
+        let started = std::time::Instant::now();
+        let messages = loop {
+            let messages = CaptureReader::new(File::open(&path)?)?.collect::<Result<Vec<_>, _>>().unwrap_or_default();
+            if messages.len() == 2 || started.elapsed() > std::time::Duration::from_secs(5) {
+                break messages;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(2, messages.len());
+        assert_eq!(Direction::Outgoing, messages[0].direction);
+        assert_eq!(vec![0, 0, 0, 2, 0, 3], messages[1].bytes);
+        assert_eq!((0, 1), messages[1].network_version);
+        Ok(())
+    }
+}
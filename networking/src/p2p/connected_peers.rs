@@ -0,0 +1,163 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Registry of the connections by peer id, only one connection to the same peer id is allowed.
+//!
+//! Connection is registered as soon as the peer id of the remote peer is known (during the handshake),
+//! and it is established only after the ACK of the remote peer is received.
+//! When two connections to the same peer id are bootstrapping at the same time (e.g. simultaneous inbound and outbound dial),
+//! the connection initiated by the node with the lower public key wins. Both nodes evaluate the rule the same way,
+//! so both of them keep the same connection. Already established connection always wins.
+//!
+//! Registration happens one round trip before the ACK is exchanged, so both nodes see both simultaneous connections
+//! unacknowledged and apply the tie-break.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::peer::PeerId;
+
+static CONNECTION_ID_GENERATOR: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct Connection {
+    id: u64,
+    incoming: bool,
+    established: bool,
+}
+
+/// Peer ids of the peers connected (or being connected) to the node
+#[derive(Clone, Debug, Default)]
+pub struct ConnectedPeers {
+    connections: Arc<Mutex<HashMap<PeerId, Connection>>>,
+}
+
+impl ConnectedPeers {
+    /// Try to register new connection to the `peer_id`.
+    ///
+    /// `wins_tie_break` - new connection was initiated by the node with the lower public key.
+    ///
+    /// Returns `None` if the connection is a duplicate and has to be refused. Registration is released when the returned guard is dropped.
+    pub fn register(&self, peer_id: &str, incoming: bool, wins_tie_break: bool) -> Option<ConnectionGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        let replace = match connections.get(peer_id) {
+            None => true,
+            Some(existing) if existing.established => false,
+            // same direction can not be resolved by the tie-break, first connection wins
+            Some(existing) if existing.incoming == incoming => false,
+            Some(_) => wins_tie_break,
+        };
+
+        if replace {
+            let id = CONNECTION_ID_GENERATOR.fetch_add(1, Ordering::SeqCst);
+            connections.insert(peer_id.to_string(), Connection { id, incoming, established: false });
+            Some(ConnectionGuard { connected_peers: self.clone(), peer_id: peer_id.to_string(), id })
+        } else {
+            None
+        }
+    }
+
+    /// Count of the registered connections
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Registered connection, registration is released on drop
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    connected_peers: ConnectedPeers,
+    peer_id: PeerId,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    /// Returns `false` if connection was replaced by the connection which won the tie-break, this connection has to be refused.
+    pub fn is_registered(&self) -> bool {
+        let connections = self.connected_peers.connections.lock().unwrap();
+        connections.get(&self.peer_id).filter(|connection| connection.id == self.id).is_some()
+    }
+
+    /// Mark connection as established, after the ACK of the remote peer was received.
+    ///
+    /// Returns `false` if connection was replaced by the connection which won the tie-break, this connection has to be closed.
+    pub fn establish(&self) -> bool {
+        let mut connections = self.connected_peers.connections.lock().unwrap();
+        match connections.get_mut(&self.peer_id) {
+            Some(connection) if connection.id == self.id => {
+                connection.established = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connected_peers.connections.lock().unwrap();
+        if connections.get(&self.peer_id).filter(|connection| connection.id == self.id).is_some() {
+            connections.remove(&self.peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_ID: &str = "idrRoknJh9zwEePNswF3MPGFzmKaVp";
+
+    #[test]
+    fn test_established_connection_wins() {
+        let connected_peers = ConnectedPeers::default();
+        let outgoing = connected_peers.register(PEER_ID, false, false).unwrap();
+        assert!(outgoing.establish());
+
+        assert!(connected_peers.register(PEER_ID, true, true).is_none());
+        assert!(connected_peers.register(PEER_ID, false, true).is_none());
+
+        drop(outgoing);
+        assert!(connected_peers.is_empty());
+        assert!(connected_peers.register(PEER_ID, true, false).is_some());
+    }
+
+    #[test]
+    fn test_simultaneous_connections_tie_break() {
+        let connected_peers = ConnectedPeers::default();
+        let outgoing = connected_peers.register(PEER_ID, false, false).unwrap();
+
+        // incoming connection loses the tie-break
+        assert!(connected_peers.register(PEER_ID, true, false).is_none());
+
+        // incoming connection wins the tie-break, outgoing connection is replaced
+        let incoming = connected_peers.register(PEER_ID, true, true).unwrap();
+        assert!(!outgoing.is_registered());
+        assert!(!outgoing.establish());
+        drop(outgoing);
+        assert_eq!(1, connected_peers.len());
+        assert!(incoming.is_registered());
+        assert!(incoming.establish());
+
+        drop(incoming);
+        assert!(connected_peers.is_empty());
+    }
+
+    #[test]
+    fn test_unacknowledged_connection_is_replaced() {
+        let connected_peers = ConnectedPeers::default();
+        // ACK was sent, but not received yet
+        let incoming = connected_peers.register(PEER_ID, true, false).unwrap();
+        assert!(incoming.is_registered());
+
+        let outgoing = connected_peers.register(PEER_ID, false, true).unwrap();
+        assert!(!incoming.establish());
+        assert!(outgoing.establish());
+        assert!(connected_peers.register(PEER_ID, true, true).is_none());
+    }
+}
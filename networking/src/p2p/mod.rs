@@ -3,6 +3,11 @@
 
 //! This module handles low level p2p communication.
 
-mod stream;
+pub mod stream;
 pub mod peer;
 pub mod network_channel;
+pub mod connected_peers;
+pub mod bandwidth;
+pub mod capture;
+pub mod socks5;
+pub mod send_queue;
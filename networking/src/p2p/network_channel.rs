@@ -10,6 +10,7 @@ use riker::actors::*;
 
 use tezos_messages::p2p::encoding::metadata::MetadataMessage;
 use tezos_messages::p2p::encoding::peer::PeerMessageResponse;
+use tezos_messages::p2p::encoding::version::NetworkVersion;
 
 use super::peer::PeerRef;
 
@@ -31,11 +32,15 @@ pub enum PeerBootstrapped {
         peer: PeerRef,
         peer_id: String,
         peer_metadata: MetadataMessage,
+        /// Network version negotiated with the peer
+        network_version: NetworkVersion,
     },
     Failure {
         address: SocketAddr,
         /// List of potential peers to connect to. Is extracted from `Nack`.
         potential_peers_to_connect: Option<Vec<String>>,
+        /// Why the bootstrap failed (e.g. insufficient proof of work of the peer)
+        reason: String,
     },
 }
 
@@ -47,12 +52,28 @@ pub struct PeerMessageReceived {
     pub peer_address: SocketAddr,
 }
 
+/// Message was sent to the peer, `bytes` is the size of the encoded message
+#[derive(Clone, Debug)]
+pub struct PeerMessageSent {
+    pub peer: PeerRef,
+    pub bytes: usize,
+}
+
+/// Peer misbehaved (e.g. sent us invalid data), so it should be disconnected and its IP blacklisted
+#[derive(Clone, Debug)]
+pub struct BlacklistPeer {
+    pub peer: PeerRef,
+    pub reason: String,
+}
+
 /// Network channel event message.
 #[derive(Clone, Debug)]
 pub enum NetworkChannelMsg {
     PeerCreated(PeerCreated),
     PeerBootstrapped(PeerBootstrapped),
     PeerMessageReceived(PeerMessageReceived),
+    PeerMessageSent(PeerMessageSent),
+    BlacklistPeer(BlacklistPeer),
 }
 
 impl From<PeerCreated> for NetworkChannelMsg {
@@ -73,6 +94,18 @@ impl From<PeerMessageReceived> for NetworkChannelMsg {
     }
 }
 
+impl From<PeerMessageSent> for NetworkChannelMsg {
+    fn from(msg: PeerMessageSent) -> Self {
+        NetworkChannelMsg::PeerMessageSent(msg)
+    }
+}
+
+impl From<BlacklistPeer> for NetworkChannelMsg {
+    fn from(msg: BlacklistPeer) -> Self {
+        NetworkChannelMsg::BlacklistPeer(msg)
+    }
+}
+
 /// Represents various topics
 pub enum NetworkChannelTopic {
     /// Events generated from networking layer
@@ -1,10 +1,11 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::collections::HashSet;
 use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::{Error, Fail};
 use futures::lock::Mutex;
@@ -12,17 +13,24 @@ use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 use tokio::net::TcpStream;
 use tokio::runtime::Handle;
+use tokio::sync::Notify;
 use tokio::time::timeout;
 
 use crypto::crypto_box::precompute;
 use crypto::hash::HashType;
 use crypto::nonce::{self, Nonce, NoncePair};
+use crypto::proof_of_work::{check_proof_of_work, PowError};
 use tezos_encoding::binary_reader::BinaryReaderError;
 use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryChunkError, BinaryMessage};
 use tezos_messages::p2p::encoding::ack::{NackInfo, NackMotive};
 use tezos_messages::p2p::encoding::prelude::*;
 
-use super::network_channel::{NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived};
+use super::bandwidth::Bandwidth;
+use super::capture::TrafficCapture;
+use super::connected_peers::{ConnectedPeers, ConnectionGuard};
+use super::network_channel::{NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived, PeerMessageSent};
+use super::send_queue::{Priority, Pushed, SendQueue};
+use super::socks5::Socks5Proxy;
 use super::stream::{EncryptedMessageReader, EncryptedMessageWriter, MessageStream, StreamError};
 
 const IO_TIMEOUT: Duration = Duration::from_secs(6);
@@ -35,9 +43,9 @@ pub type PublicKey = Vec<u8>;
 
 #[derive(Debug, Fail)]
 enum PeerError {
-    #[fail(display = "Unsupported protocol - supported_version: {} vs. {}", supported_version, incompatible_versions)]
+    #[fail(display = "Unsupported protocol - supported_versions: {} vs. {}", supported_versions, incompatible_versions)]
     UnsupportedProtocol {
-        supported_version: String,
+        supported_versions: String,
         incompatible_versions: String,
     },
     #[fail(display = "Received NACK from remote peer")]
@@ -46,8 +54,18 @@ enum PeerError {
     NackWithMotiveReceived {
         nack_info: NackInfo
     },
+    #[fail(display = "Connection refused, NACK sent to remote peer with info: {:?}", nack_info)]
+    NackSent {
+        nack_info: NackInfo
+    },
+    #[fail(display = "Connection was replaced by the simultaneous connection to the same peer")]
+    ConnectionReplaced,
     #[fail(display = "Failed to create precomputed key")]
     FailedToPrecomputeKey,
+    #[fail(display = "Invalid proof of work of the remote peer: {}", error)]
+    InvalidProofOfWork {
+        error: PowError,
+    },
     #[fail(display = "Network error: {}", message)]
     NetworkError {
         error: Error,
@@ -116,15 +134,34 @@ pub struct Bootstrap {
     incoming: bool,
     disable_mempool: bool,
     private_node: bool,
+    /// Connection to the already connected peer id is refused
+    connected_peers: ConnectedPeers,
+    /// Sent to the remote peer in the NACK message, when connection is refused
+    potential_peers: Vec<String>,
+    /// Connection is refused with this motive after the handshake
+    refuse_motive: Option<NackMotive>,
+    /// If set, connection is refused with the motive after the handshake unless the remote peer id is one of these
+    trusted_peer_ids: Option<(Arc<HashSet<PeerId>>, NackMotive)>,
 }
 
 impl Bootstrap {
-    pub fn incoming(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream, address, incoming: true, disable_mempool, private_node }
+    pub fn incoming(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool, connected_peers: ConnectedPeers, potential_peers: Vec<String>) -> Self {
+        Bootstrap { stream, address, incoming: true, disable_mempool, private_node, connected_peers, potential_peers, refuse_motive: None, trusted_peer_ids: None }
+    }
+
+    pub fn outgoing(stream: TcpStream, address: SocketAddr, disable_mempool: bool, private_node: bool, connected_peers: ConnectedPeers, potential_peers: Vec<String>) -> Self {
+        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node, connected_peers, potential_peers, refuse_motive: None, trusted_peer_ids: None }
+    }
+
+    /// Incoming connection, which is refused with NACK (see [refuse_connection])
+    pub fn refused(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool, motive: NackMotive, potential_peers: Vec<String>) -> Self {
+        Bootstrap { stream, address, incoming: true, disable_mempool, private_node, connected_peers: ConnectedPeers::default(), potential_peers, refuse_motive: Some(motive), trusted_peer_ids: None }
     }
 
-    pub fn outgoing(stream: TcpStream, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node }
+    /// Accept only the remote peers with the trusted peer ids, the others are refused with NACK `motive` after the handshake
+    pub fn trusted_only(mut self, trusted_peer_ids: Arc<HashSet<PeerId>>, motive: NackMotive) -> Self {
+        self.trusted_peer_ids = Some((trusted_peer_ids, motive));
+        self
     }
 }
 
@@ -139,6 +176,10 @@ impl SendMessage {
     pub fn new(msg: PeerMessageResponse) -> Self {
         SendMessage { message: Arc::new(msg) }
     }
+
+    pub fn message(&self) -> &Arc<PeerMessageResponse> {
+        &self.message
+    }
 }
 
 #[derive(Clone)]
@@ -148,8 +189,14 @@ struct Network {
     rx_run: Arc<AtomicBool>,
     /// Message sender
     tx: Arc<Mutex<Option<EncryptedMessageWriter>>>,
+    /// Messages waiting to be sent to the peer
+    queue: Arc<std::sync::Mutex<SendQueue<Arc<PeerMessageResponse>>>>,
+    /// Wakes up the writer, when a message is queued or the peer is stopped
+    queue_ready: Arc<Notify>,
     /// Socket address of the peer
     socket_address: SocketAddr,
+    /// Registration of the connection to the peer id, released when the peer is stopped
+    connection: Arc<std::sync::Mutex<Option<ConnectionGuard>>>,
 }
 
 /// Local node info
@@ -162,8 +209,48 @@ pub struct Local {
     secret_key: String,
     /// proof of work
     proof_of_work_stamp: String,
-    /// version of network protocol
-    version: NetworkVersion,
+    /// minimal proof of work required from the remote peer
+    expected_pow: f64,
+    /// supported versions of network protocol, the best common version is negotiated with the remote peer
+    versions: Vec<NetworkVersion>,
+    /// upload/download rate limits of the connections
+    bandwidth: Arc<Bandwidth>,
+    /// capture of the messages of the selected peers (if enabled)
+    capture: Option<Arc<TrafficCapture>>,
+    /// outgoing connections go through this proxy (if configured)
+    proxy: Option<Socks5Proxy>,
+}
+
+impl Local {
+    pub fn new(listener_port: u16,
+               public_key: &str,
+               secret_key: &str,
+               proof_of_work_stamp: &str,
+               expected_pow: f64,
+               versions: Vec<NetworkVersion>,
+               bandwidth: Arc<Bandwidth>,
+               capture: Option<Arc<TrafficCapture>>,
+               proxy: Option<Socks5Proxy>) -> Self {
+        Local {
+            listener_port,
+            proof_of_work_stamp: proof_of_work_stamp.into(),
+            expected_pow,
+            public_key: public_key.into(),
+            secret_key: secret_key.into(),
+            versions,
+            bandwidth,
+            capture,
+            proxy,
+        }
+    }
+
+    /// Open outgoing connection to the remote peer, through the SOCKS5 proxy if it is configured for the address
+    pub async fn connect(&self, address: &SocketAddr) -> Result<TcpStream, Error> {
+        match self.proxy.as_ref().filter(|proxy| proxy.is_proxied(address)) {
+            Some(proxy) => Ok(proxy.connect(address).await?),
+            None => Ok(TcpStream::connect(address).await?),
+        }
+    }
 }
 
 pub type PeerRef = ActorRef<PeerMsg>;
@@ -187,22 +274,11 @@ impl Peer {
     /// Create instance of a peer actor.
     pub fn actor(sys: &impl ActorRefFactory,
                  network_channel: NetworkChannelRef,
-                 listener_port: u16,
-                 public_key: &str,
-                 secret_key: &str,
-                 proof_of_work_stamp: &str,
-                 version: NetworkVersion,
+                 local: Arc<Local>,
                  tokio_executor: Handle,
                  socket_address: &SocketAddr) -> Result<PeerRef, CreateError>
     {
-        let info = Local {
-            listener_port,
-            proof_of_work_stamp: proof_of_work_stamp.into(),
-            public_key: public_key.into(),
-            secret_key: secret_key.into(),
-            version,
-        };
-        let props = Props::new_args::<Peer, _>((network_channel, Arc::new(info), tokio_executor, *socket_address));
+        let props = Props::new_args::<Peer, _>((network_channel, local, tokio_executor, *socket_address));
         let actor_id = ACTOR_ID_GENERATOR.fetch_add(1, Ordering::SeqCst);
         sys.actor_of_props(&format!("peer-{}", actor_id), props)
     }
@@ -216,7 +292,10 @@ impl ActorFactoryArgs<(NetworkChannelRef, Arc<Local>, Handle, SocketAddr)> for P
             net: Network {
                 rx_run: Arc::new(AtomicBool::new(false)),
                 tx: Arc::new(Mutex::new(None)),
+                queue: Arc::new(std::sync::Mutex::new(SendQueue::default())),
+                queue_ready: Arc::new(Notify::new()),
                 socket_address,
+                connection: Arc::new(std::sync::Mutex::new(None)),
             },
             tokio_executor,
             remote_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
@@ -229,6 +308,10 @@ impl Actor for Peer {
 
     fn post_stop(&mut self) {
         self.net.rx_run.store(false, Ordering::Release);
+        // wake up the writer, so it can finish
+        self.net.queue_ready.notify();
+        // release peer id, so the peer can connect again
+        self.net.connection.lock().unwrap().take();
     }
 
     fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
@@ -246,20 +329,22 @@ impl Receive<Bootstrap> for Peer {
         let system = ctx.system.clone();
         let net = self.net.clone();
         let network_channel = self.network_channel.clone();
+        let tokio_executor = self.tokio_executor.clone();
         self.remote_addr = msg.address;
 
         self.tokio_executor.spawn(async move {
-            async fn setup_net(net: &Network, tx: EncryptedMessageWriter) {
+            async fn setup_net(net: &Network, tx: EncryptedMessageWriter, connection: ConnectionGuard) {
                 net.rx_run.store(true, Ordering::Release);
                 *net.tx.lock().await = Some(tx);
+                *net.connection.lock().unwrap() = Some(connection);
             }
 
             let peer_address = msg.address;
             debug!(system.log(), "Bootstrapping"; "ip" => &peer_address, "peer" => myself.name());
             match bootstrap(msg, info, &system.log()).await {
-                Ok(BootstrapOutput(rx, tx, public_key, metadata)) => {
-                    debug!(system.log(), "Bootstrap successful"; "ip" => &peer_address, "peer" => myself.name(), "metadata" => format!("{:?}", &metadata));
-                    setup_net(&net, tx).await;
+                Ok(BootstrapOutput(rx, tx, public_key, metadata, network_version, connection)) => {
+                    debug!(system.log(), "Bootstrap successful"; "ip" => &peer_address, "peer" => myself.name(), "metadata" => format!("{:?}", &metadata), "network_version" => format!("{:?}", &network_version));
+                    setup_net(&net, tx, connection).await;
 
                     let peer_id = HashType::CryptoboxPublicKeyHash.bytes_to_string(&public_key);
                     // notify that peer was bootstrapped successfully
@@ -268,12 +353,14 @@ impl Receive<Bootstrap> for Peer {
                             peer: myself.clone(),
                             peer_id: peer_id.clone(),
                             peer_metadata: metadata,
+                            network_version,
                         }.into(),
                         topic: NetworkChannelTopic::NetworkEvents.into(),
                     }, Some(myself.clone().into()));
 
-                    // begin to process incoming messages in a loop
+                    // begin to send queued messages and to process incoming messages in a loop
                     let log = system.log().new(slog::o!("peer" => peer_id));
+                    tokio_executor.spawn(begin_process_outgoing(net.clone(), myself.clone(), network_channel.clone(), system.clone(), log.clone()));
                     begin_process_incoming(rx, net, myself.clone(), network_channel, log, peer_address.clone()).await;
                     // connection to peer was closed, stop this actor
                     system.stop(myself);
@@ -281,8 +368,10 @@ impl Receive<Bootstrap> for Peer {
                 Err(err) => {
                     info!(system.log(), "Connection to peer failed"; "reason" => &err, "ip" => &peer_address, "peer" => myself.name());
 
-                    let potential_peers = match err {
+                    let potential_peers = match &err {
                         PeerError::NackWithMotiveReceived { nack_info } => Some(nack_info.potential_peers_to_connect().clone()),
+                        // connection was refused by us, remote peer did not misbehave
+                        PeerError::NackSent { .. } | PeerError::ConnectionReplaced => Some(vec![]),
                         _ => None
                     };
 
@@ -291,6 +380,7 @@ impl Receive<Bootstrap> for Peer {
                         msg: PeerBootstrapped::Failure {
                             address: peer_address,
                             potential_peers_to_connect: potential_peers,
+                            reason: format!("{}", err),
                         }.into(),
                         topic: NetworkChannelTopic::NetworkEvents.into(),
                     }, Some(myself.clone().into()));
@@ -306,35 +396,38 @@ impl Receive<SendMessage> for Peer {
     type Msg = PeerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: SendMessage, _sender: Sender) {
-        let system = ctx.system.clone();
-        let myself = ctx.myself();
-        let tx = self.net.tx.clone();
-        self.tokio_executor.spawn(async move {
-            let mut tx_lock = tx.lock().await;
-            if let Some(tx) = tx_lock.as_mut() {
-                let write_result = timeout(IO_TIMEOUT, tx.write_message(&*msg.message)).await;
-                // release mutex as soon as possible
-                drop(tx_lock);
-
-                match write_result {
-                    Ok(write_result) => {
-                        if let Err(e) = write_result {
-                            warn!(system.log(), "Failed to send message"; "reason" => e);
-                            system.stop(myself);
-                        }
-                    }
-                    Err(_) => {
-                        warn!(system.log(), "Failed to send message"; "reason" => "timeout");
-                        system.stop(myself);
-                    }
-                }
+        let priority = Priority::of(&msg.message);
+        let coalescable = Priority::is_coalescable(&msg.message);
+        let pushed = self.net.queue.lock().unwrap().push(msg.message, priority, coalescable, Instant::now());
+
+        match pushed {
+            Pushed::Queued => self.net.queue_ready.notify(),
+            Pushed::Replaced => {
+                trace!(ctx.system.log(), "Older mempool message was dropped from the send queue"; "ip" => &self.remote_addr, "peer" => ctx.myself().name());
+                self.net.queue_ready.notify();
             }
-        });
+            Pushed::Rejected => warn!(ctx.system.log(), "Send queue is full, message was dropped"; "ip" => &self.remote_addr, "priority" => format!("{:?}", priority), "peer" => ctx.myself().name()),
+            Pushed::Stalled => {
+                warn!(ctx.system.log(), "Send queue stays under pressure, disconnecting peer"; "ip" => &self.remote_addr, "peer" => ctx.myself().name());
+                ctx.system.stop(ctx.myself());
+            }
+        }
+    }
+}
+
+/// Finish the handshake of the refused incoming connection and send NACK instead of ACK, so the remote peer
+/// knows the reason and gets potential peers to connect to. No peer actor is created for the connection.
+pub async fn refuse_connection(msg: Bootstrap, info: Arc<Local>, log: Logger) {
+    let peer_address = msg.address;
+    match bootstrap(msg, info, &log).await {
+        Err(PeerError::NackSent { nack_info }) => debug!(log, "Connection refused"; "ip" => &peer_address, "nack_info" => format!("{:?}", nack_info)),
+        Err(err) => debug!(log, "Failed to refuse connection"; "ip" => &peer_address, "reason" => err),
+        Ok(_) => warn!(log, "Refused connection was bootstrapped"; "ip" => &peer_address),
     }
 }
 
 /// Output values of the successful bootstrap process
-struct BootstrapOutput(EncryptedMessageReader, EncryptedMessageWriter, PublicKey, MetadataMessage);
+struct BootstrapOutput(EncryptedMessageReader, EncryptedMessageWriter, PublicKey, MetadataMessage, NetworkVersion, ConnectionGuard);
 
 async fn bootstrap(
     msg: Bootstrap,
@@ -343,19 +436,17 @@ async fn bootstrap(
 ) -> Result<BootstrapOutput, PeerError> {
     let (mut msg_rx, mut msg_tx) = {
         let stream = msg.stream.lock().await.take().expect("Someone took ownership of the socket before the Peer");
-        let msg_reader: MessageStream = stream.into();
+        let msg_reader = MessageStream::new(stream, Some(info.bandwidth.peer(msg.address)));
         msg_reader.split()
     };
 
-    let supported_protocol_version = info.version.clone();
-
     // send connection message
     let connection_message = ConnectionMessage::new(
         info.listener_port,
         &info.public_key,
         &info.proof_of_work_stamp,
         &Nonce::random().get_bytes(),
-        vec![supported_protocol_version.clone()]);
+        info.versions.clone());
     let connection_message_sent = {
         let connection_message_bytes = BinaryChunk::from_content(&connection_message.as_bytes()?)?;
         match timeout(IO_TIMEOUT, msg_tx.write_message(&connection_message_bytes)).await? {
@@ -378,8 +469,14 @@ async fn bootstrap(
     // convert received bytes from remote peer into `ConnectionMessage`
     let peer_public_key = connection_message.public_key();
     let peer_id = HashType::CryptoboxPublicKeyHash.bytes_to_string(&peer_public_key);
+    let remote_peer_id = peer_id.clone();
     debug!(log, "Received peer public key"; "public_key" => &peer_id);
 
+    // peers without sufficient proof of work are rejected before any encrypted message is exchanged
+    if let Err(error) = check_proof_of_work(peer_public_key, &connection_message.proof_of_work_stamp, info.expected_pow) {
+        return Err(PeerError::InvalidProofOfWork { error });
+    }
+
     // pre-compute encryption key
     let precomputed_key = match precompute(&hex::encode(peer_public_key), &info.secret_key) {
         Ok(key) => key,
@@ -390,18 +487,33 @@ async fn bootstrap(
     let mut msg_tx = EncryptedMessageWriter::new(msg_tx, precomputed_key.clone(), nonce_local, peer_id.clone(), log.clone());
     let mut msg_rx = EncryptedMessageReader::new(msg_rx, precomputed_key, nonce_remote, peer_id, log.clone());
 
-    let protocol_not_supported = !connection_message.versions().iter().any(|version| supported_protocol_version.supports(version));
-    if protocol_not_supported {
-        // send nack
-        timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::NackV0)).await??;
-
-        return Err(
-            PeerError::UnsupportedProtocol {
-                supported_version: format!("{:?}", &supported_protocol_version),
-                incompatible_versions: format!("{:?}", &connection_message.versions()),
-            }
-        );
-    }
+    let network_version = match NetworkVersion::negotiate(&info.versions, connection_message.versions()) {
+        Some(network_version) => network_version,
+        None => {
+            // send nack, NACK with motive is understood since p2p version 1
+            let nack = if connection_message.versions().iter().any(|version| version.p2p_version() >= 1) {
+                let motive = if connection_message.versions().iter().any(|version| info.versions.iter().any(|supported| version.chain_name() == supported.chain_name())) {
+                    NackMotive::DeprecatedDistributedDbVersion
+                } else {
+                    NackMotive::UnknownChainName
+                };
+                AckMessage::Nack(NackInfo::new(motive, &msg.potential_peers))
+            } else {
+                AckMessage::NackV0
+            };
+            timeout(IO_TIMEOUT, msg_tx.write_message(&nack)).await??;
+
+            return Err(
+                PeerError::UnsupportedProtocol {
+                    supported_versions: format!("{:?}", &info.versions),
+                    incompatible_versions: format!("{:?}", &connection_message.versions()),
+                }
+            );
+        }
+    };
+    debug!(log, "Negotiated network version"; "network_version" => format!("{:?}", &network_version));
+    msg_tx.set_network_version(network_version.clone());
+    msg_rx.set_network_version(network_version.clone());
 
     let connecting_to_self = hex::encode(connection_message.public_key()) == info.public_key;
     if connecting_to_self {
@@ -410,6 +522,29 @@ async fn bootstrap(
         return Err(PeerError::NackWithMotiveReceived { nack_info: NackInfo::new(NackMotive::AlreadyConnected, &vec![]) });
     }
 
+    // only one connection to the peer id is allowed, simultaneous connections are resolved by the tie-break:
+    // connection initiated by the node with the lower public key wins
+    let remote_public_key = hex::encode(peer_public_key);
+    let wins_tie_break = if msg.incoming {
+        remote_public_key < info.public_key
+    } else {
+        info.public_key < remote_public_key
+    };
+    let untrusted_motive = msg.trusted_peer_ids
+        .filter(|(trusted_peer_ids, _)| !trusted_peer_ids.contains(&remote_peer_id))
+        .map(|(_, motive)| motive);
+    let (mut refuse_motive, connection) = match (msg.refuse_motive, untrusted_motive) {
+        (Some(motive), _) => (Some(motive), None),
+        (None, Some(motive)) => {
+            debug!(log, "Remote peer is not trusted");
+            (Some(motive), None)
+        }
+        (None, None) => match msg.connected_peers.register(&remote_peer_id, msg.incoming, wins_tie_break) {
+            Some(connection) => (None, Some(connection)),
+            None => (Some(NackMotive::AlreadyConnected), None),
+        }
+    };
+
     // send metadata
     let metadata = MetadataMessage::new(msg.disable_mempool, msg.private_node);
     timeout(IO_TIMEOUT, msg_tx.write_message(&metadata)).await??;
@@ -418,6 +553,30 @@ async fn bootstrap(
     let metadata_received = timeout(IO_TIMEOUT, msg_rx.read_message::<MetadataMessage>()).await??;
     debug!(log, "Received remote peer metadata"; "disable_mempool" => metadata_received.disable_mempool(), "private_node" => metadata_received.private_node());
 
+    // connection could have been replaced in the meantime by the connection which won the tie-break
+    if let Some(connection) = &connection {
+        if !connection.is_registered() {
+            debug!(log, "Connection to the peer was replaced by the simultaneous connection");
+            refuse_motive = Some(NackMotive::AlreadyConnected);
+        }
+    }
+
+    // refused connection is closed with nack instead of ack
+    let connection = match (refuse_motive, connection) {
+        (None, Some(connection)) => connection,
+        (refuse_motive, _) => {
+            let nack_info = NackInfo::new(refuse_motive.unwrap_or(NackMotive::NoMotive), &msg.potential_peers);
+            // NACK with motive is understood since p2p version 1
+            let nack = if network_version.p2p_version() >= 1 {
+                AckMessage::Nack(nack_info.clone())
+            } else {
+                AckMessage::NackV0
+            };
+            timeout(IO_TIMEOUT, msg_tx.write_message(&nack)).await??;
+            return Err(PeerError::NackSent { nack_info });
+        }
+    };
+
     // send ack
     timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::Ack)).await??;
 
@@ -427,7 +586,17 @@ async fn bootstrap(
     match ack_received {
         AckMessage::Ack => {
             debug!(log, "Received ACK");
-            Ok(BootstrapOutput(msg_rx, msg_tx, peer_public_key.clone(), metadata_received))
+            // connection is established only after both sides acknowledged it, until then it can be replaced by the simultaneous connection
+            if !connection.establish() {
+                debug!(log, "Connection to the peer was replaced by the simultaneous connection");
+                return Err(PeerError::ConnectionReplaced);
+            }
+            if let Some(capture) = &info.capture {
+                // handshake is finished, only peer messages are captured
+                msg_tx.set_capture(capture.peer(&remote_peer_id, msg.address, &network_version));
+                msg_rx.set_capture(capture.peer(&remote_peer_id, msg.address, &network_version));
+            }
+            Ok(BootstrapOutput(msg_rx, msg_tx, peer_public_key.clone(), metadata_received, network_version, connection))
         }
         AckMessage::NackV0 => {
             debug!(log, "Received NACK");
@@ -449,6 +618,62 @@ fn generate_nonces(sent_msg: &BinaryChunk, recv_msg: &BinaryChunk, incoming: boo
     nonce::generate_nonces(sent_msg.raw(), recv_msg.raw(), incoming)
 }
 
+/// Send queued messages one by one, until the peer is stopped or the connection is closed.
+///
+/// Size of every sent message is published as `PeerMessageSent`, so the message is encoded only once.
+async fn begin_process_outgoing(net: Network, myself: PeerRef, event_channel: NetworkChannelRef, system: ActorSystem, log: Logger) {
+    loop {
+        let (message, stalled) = {
+            let now = Instant::now();
+            let mut queue = net.queue.lock().unwrap();
+            (queue.pop(now), queue.is_stalled(now))
+        };
+        if stalled {
+            warn!(log, "Send queue stays under pressure, disconnecting peer");
+            system.stop(myself);
+            break;
+        }
+        let message = match message {
+            Some(message) => message,
+            None if net.rx_run.load(Ordering::Acquire) => {
+                net.queue_ready.notified().await;
+                continue;
+            }
+            None => break,
+        };
+
+        let mut tx_lock = net.tx.lock().await;
+        let tx = match tx_lock.as_mut() {
+            Some(tx) => tx,
+            // connection was already closed
+            None => break,
+        };
+        match timeout(IO_TIMEOUT, tx.write_message(&*message)).await {
+            Ok(Ok(bytes)) => {
+                event_channel.tell(
+                    Publish {
+                        msg: PeerMessageSent {
+                            peer: myself.clone(),
+                            bytes,
+                        }.into(),
+                        topic: NetworkChannelTopic::NetworkEvents.into(),
+                    }, Some(myself.clone().into()));
+            }
+            Ok(Err(e)) => {
+                warn!(log, "Failed to send message"; "reason" => e);
+                system.stop(myself);
+                break;
+            }
+            Err(_) => {
+                warn!(log, "Failed to send message"; "reason" => "timeout");
+                system.stop(myself);
+                break;
+            }
+        }
+    }
+    debug!(log, "Stopped to send messages");
+}
+
 /// Start to process incoming data
 async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, myself: PeerRef, event_channel: NetworkChannelRef, log: Logger, peer_address: SocketAddr) {
     info!(log, "Starting to accept messages"; "ip" => format!("{:?}", &peer_address));
@@ -499,3 +724,148 @@ async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, my
 
     info!(log, "Stopped to accept messages"; "ip" => format!("{:?}", &peer_address));
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use riker::system::SystemBuilder;
+    use tokio::net::TcpListener;
+
+    use crypto::crypto_box::random_keypair;
+    use crypto::proof_of_work::generate_proof_of_work;
+
+    use crate::p2p::network_channel::{NetworkChannel, NetworkChannelMsg};
+
+    use super::*;
+
+    /// Names of the peer actors, which were bootstrapped successfully
+    type Bootstrapped = Arc<StdMutex<Vec<Result<String, SocketAddr>>>>;
+
+    struct BootstrapCollector {
+        network_channel: NetworkChannelRef,
+        bootstrapped: Bootstrapped,
+    }
+
+    impl ActorFactoryArgs<(NetworkChannelRef, Bootstrapped)> for BootstrapCollector {
+        fn create_args((network_channel, bootstrapped): (NetworkChannelRef, Bootstrapped)) -> Self {
+            BootstrapCollector { network_channel, bootstrapped }
+        }
+    }
+
+    impl Actor for BootstrapCollector {
+        type Msg = NetworkChannelMsg;
+
+        fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+            self.network_channel.tell(Subscribe {
+                actor: Box::new(ctx.myself()),
+                topic: NetworkChannelTopic::NetworkEvents.into(),
+            }, None);
+        }
+
+        fn recv(&mut self, _ctx: &Context<Self::Msg>, msg: Self::Msg, _sender: Sender) {
+            match msg {
+                NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, .. }) => self.bootstrapped.lock().unwrap().push(Ok(peer.name().to_string())),
+                NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, .. }) => self.bootstrapped.lock().unwrap().push(Err(address)),
+                _ => (),
+            }
+        }
+    }
+
+    struct TestNode {
+        system: ActorSystem,
+        network_channel: NetworkChannelRef,
+        local: Arc<Local>,
+        public_key: String,
+        connected_peers: ConnectedPeers,
+        bootstrapped: Bootstrapped,
+    }
+
+    impl TestNode {
+        fn new(name: &str, listener_port: u16) -> Self {
+            let system = SystemBuilder::new().name(name).log(Logger::root(slog::Discard, slog::o!())).create().expect("Failed to create actor system");
+            let network_channel = NetworkChannel::actor(&system).expect("Failed to create network channel");
+            let bootstrapped = Bootstrapped::default();
+            system.actor_of_props("bootstrap-collector", Props::new_args::<BootstrapCollector, _>((network_channel.clone(), bootstrapped.clone())))
+                .expect("Failed to create bootstrap collector");
+
+            let (public_key, secret_key) = random_keypair();
+            let proof_of_work_stamp = generate_proof_of_work(&hex::decode(&public_key).unwrap(), 0.0, 1, |_| ())
+                .expect("Failed to generate proof of work");
+            let local = Local::new(
+                listener_port,
+                &public_key,
+                &secret_key,
+                &hex::encode(proof_of_work_stamp),
+                0.0,
+                vec![NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 0, 1)],
+                Arc::new(Bandwidth::default()),
+                None,
+                None,
+            );
+
+            TestNode { system, network_channel, local: Arc::new(local), public_key, connected_peers: ConnectedPeers::default(), bootstrapped }
+        }
+
+        fn bootstrap(&self, msg: Bootstrap, handle: &Handle) -> String {
+            let peer = Peer::actor(&self.system, self.network_channel.clone(), self.local.clone(), handle.clone(), &msg.address)
+                .expect("Failed to create peer");
+            peer.tell(msg, None);
+            peer.name().to_string()
+        }
+
+        fn bootstrapped(&self) -> Vec<Result<String, SocketAddr>> {
+            self.bootstrapped.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn test_simultaneous_connections_keep_one_connection() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("Failed to create tokio runtime");
+        let handle = runtime.handle().clone();
+
+        runtime.block_on(async move {
+            let mut listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address_a = listener_a.local_addr().unwrap();
+            let address_b = listener_b.local_addr().unwrap();
+            let node_a = TestNode::new("node_a", address_a.port());
+            let node_b = TestNode::new("node_b", address_b.port());
+
+            // both nodes dial each other at the same time
+            let outgoing_a = TcpStream::connect(address_b).await.unwrap();
+            let (incoming_b, incoming_b_address) = listener_b.accept().await.unwrap();
+            let outgoing_b = TcpStream::connect(address_a).await.unwrap();
+            let (incoming_a, incoming_a_address) = listener_a.accept().await.unwrap();
+
+            let outgoing_peer_a = node_a.bootstrap(Bootstrap::outgoing(outgoing_a, address_b, false, false, node_a.connected_peers.clone(), vec![]), &handle);
+            let incoming_peer_b = node_b.bootstrap(Bootstrap::incoming(Arc::new(Mutex::new(Some(incoming_b))), incoming_b_address, false, false, node_b.connected_peers.clone(), vec![]), &handle);
+            let outgoing_peer_b = node_b.bootstrap(Bootstrap::outgoing(outgoing_b, address_a, false, false, node_b.connected_peers.clone(), vec![]), &handle);
+            let incoming_peer_a = node_a.bootstrap(Bootstrap::incoming(Arc::new(Mutex::new(Some(incoming_a))), incoming_a_address, false, false, node_a.connected_peers.clone(), vec![]), &handle);
+
+            // both handshakes are finished on both nodes
+            let started = Instant::now();
+            while (node_a.bootstrapped().len() < 2 || node_b.bootstrapped().len() < 2) && started.elapsed() < Duration::from_secs(20) {
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+            }
+
+            // both nodes keep the connection initiated by the node with the lower public key
+            let (expected_a, expected_b) = if node_a.public_key < node_b.public_key {
+                (outgoing_peer_a, incoming_peer_b)
+            } else {
+                (incoming_peer_a, outgoing_peer_b)
+            };
+            let successful = |bootstrapped: Vec<Result<String, SocketAddr>>| bootstrapped.into_iter().filter_map(Result::ok).collect::<Vec<_>>();
+            assert_eq!(vec![expected_a], successful(node_a.bootstrapped()));
+            assert_eq!(vec![expected_b], successful(node_b.bootstrapped()));
+            assert_eq!(2, node_a.bootstrapped().len());
+            assert_eq!(2, node_b.bootstrapped().len());
+            assert_eq!(1, node_a.connected_peers.len());
+            assert_eq!(1, node_b.connected_peers.len());
+        });
+    }
+}
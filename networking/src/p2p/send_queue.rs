@@ -0,0 +1,278 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Bounded queue of the messages waiting to be sent to the single peer.
+//!
+//! Messages are sent by priority classes. When the mempool class is full, the oldest mempool message is dropped,
+//! and the newer `CurrentHead` replaces the queued one. Control and chain data messages are never dropped silently,
+//! when their class is full the message is rejected.
+//!
+//! Queue is under pressure, when the control or chain data class is filled over the high watermark, and it stays under
+//! pressure until all these classes are drained under the low watermark, so a single sent message does not reset it.
+//! Peer, whose queue stays under pressure for longer than the timeout, should be disconnected. This is checked both
+//! on push and on pop, so the slow peer is disconnected even when no new messages are queued.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tezos_messages::p2p::encoding::prelude::*;
+
+/// Max number of queued control messages
+const CONTROL_CAPACITY: usize = 64;
+/// Max number of queued chain data messages (block headers, operations, protocols)
+const CHAIN_DATA_CAPACITY: usize = 256;
+/// Max number of queued mempool messages
+const MEMPOOL_CAPACITY: usize = 64;
+/// Peer is disconnected, if its queue is under pressure for longer than this
+const QUEUE_FULL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Queue is under pressure, when some class is filled at least to the 3/4 of its capacity
+fn high_watermark(capacity: usize) -> usize {
+    capacity - capacity / 4
+}
+
+/// Pressure is relieved, when all classes are drained to the half of their capacity
+fn low_watermark(capacity: usize) -> usize {
+    capacity / 2
+}
+
+/// Priority class of the outgoing message, messages of the higher class are always sent first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Handshake/control messages (bootstrap, advertise, swap, branches)
+    Control,
+    /// Requests and responses used for the chain synchronisation
+    ChainData,
+    /// Mempool messages, can be dropped or coalesced
+    Mempool,
+}
+
+impl Priority {
+    /// Priority of the message response is the highest priority of the contained messages
+    pub fn of(message: &PeerMessageResponse) -> Priority {
+        message.messages().iter()
+            .map(|message| match message {
+                PeerMessage::Disconnect
+                | PeerMessage::Advertise(_)
+                | PeerMessage::SwapRequest(_)
+                | PeerMessage::SwapAck(_)
+                | PeerMessage::Bootstrap
+                | PeerMessage::GetCurrentBranch(_)
+                | PeerMessage::CurrentBranch(_)
+                | PeerMessage::Deactivate(_)
+                | PeerMessage::GetCurrentHead(_) => Priority::Control,
+                PeerMessage::GetBlockHeaders(_)
+                | PeerMessage::BlockHeader(_)
+                | PeerMessage::GetProtocols(_)
+                | PeerMessage::Protocol(_)
+                | PeerMessage::GetOperationHashesForBlocks(_)
+                | PeerMessage::OperationHashesForBlock(_)
+                | PeerMessage::GetOperationsForBlocks(_)
+                | PeerMessage::OperationsForBlocks(_) => Priority::ChainData,
+                PeerMessage::CurrentHead(_)
+                | PeerMessage::GetOperations(_)
+                | PeerMessage::Operation(_) => Priority::Mempool,
+            })
+            .min_by_key(|priority| *priority as u8)
+            .unwrap_or(Priority::Control)
+    }
+
+    /// Only the latest queued current head is worth sending, the older ones are replaced by it
+    pub fn is_coalescable(message: &PeerMessageResponse) -> bool {
+        matches!(message.messages().as_slice(), [PeerMessage::CurrentHead(_)])
+    }
+}
+
+/// Result of the [SendQueue::push]
+#[derive(Debug, PartialEq)]
+pub enum Pushed {
+    /// Message was queued
+    Queued,
+    /// Message was queued, older mempool message was dropped or replaced to make the room
+    Replaced,
+    /// Queue of the message class is full, message was dropped
+    Rejected,
+    /// Queue is under pressure for longer than the timeout, peer should be disconnected
+    Stalled,
+}
+
+/// Outgoing messages of the single peer
+pub struct SendQueue<T> {
+    control: VecDeque<T>,
+    chain_data: VecDeque<T>,
+    /// Mempool messages with the flag whether the message can be replaced by the newer coalescable one
+    mempool: VecDeque<(T, bool)>,
+    capacity: [usize; 3],
+    full_timeout: Duration,
+    /// Since when the control or chain data class is over the high watermark
+    pressure_since: Option<Instant>,
+}
+
+impl<T> Default for SendQueue<T> {
+    fn default() -> Self {
+        SendQueue::new(CONTROL_CAPACITY, CHAIN_DATA_CAPACITY, MEMPOOL_CAPACITY, QUEUE_FULL_TIMEOUT)
+    }
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(control_capacity: usize, chain_data_capacity: usize, mempool_capacity: usize, full_timeout: Duration) -> Self {
+        SendQueue {
+            control: VecDeque::new(),
+            chain_data: VecDeque::new(),
+            mempool: VecDeque::new(),
+            capacity: [control_capacity, chain_data_capacity, mempool_capacity],
+            full_timeout,
+            pressure_since: None,
+        }
+    }
+
+    pub fn push(&mut self, message: T, priority: Priority, coalescable: bool, now: Instant) -> Pushed {
+        match priority {
+            Priority::Control | Priority::ChainData => {
+                let capacity = self.capacity[priority as usize];
+                let queue = match priority {
+                    Priority::Control => &mut self.control,
+                    _ => &mut self.chain_data,
+                };
+                let queued = if queue.len() < capacity {
+                    queue.push_back(message);
+                    true
+                } else {
+                    false
+                };
+                self.update_pressure(now);
+                if self.is_stalled(now) {
+                    Pushed::Stalled
+                } else if queued {
+                    Pushed::Queued
+                } else {
+                    Pushed::Rejected
+                }
+            }
+            Priority::Mempool => {
+                let capacity = self.capacity[priority as usize];
+                if capacity == 0 {
+                    return Pushed::Rejected;
+                }
+                let mut replaced = false;
+                if coalescable {
+                    let len = self.mempool.len();
+                    self.mempool.retain(|(_, queued_coalescable)| !queued_coalescable);
+                    replaced = len != self.mempool.len();
+                }
+                if self.mempool.len() >= capacity {
+                    replaced = self.mempool.pop_front().is_some();
+                }
+                self.mempool.push_back((message, coalescable));
+                if replaced { Pushed::Replaced } else { Pushed::Queued }
+            }
+        }
+    }
+
+    /// Take the next message to be sent, by priority
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        let message = self.control.pop_front()
+            .or_else(|| self.chain_data.pop_front())
+            .or_else(|| self.mempool.pop_front().map(|(message, _)| message));
+        self.update_pressure(now);
+        message
+    }
+
+    /// Check if the queue is under pressure for longer than the timeout
+    pub fn is_stalled(&self, now: Instant) -> bool {
+        match self.pressure_since {
+            Some(pressure_since) => now.duration_since(pressure_since) >= self.full_timeout,
+            None => false,
+        }
+    }
+
+    fn update_pressure(&mut self, now: Instant) {
+        let classes = [
+            (self.control.len(), self.capacity[Priority::Control as usize]),
+            (self.chain_data.len(), self.capacity[Priority::ChainData as usize]),
+        ];
+        if classes.iter().any(|(len, capacity)| *capacity > 0 && *len >= high_watermark(*capacity)) {
+            self.pressure_since.get_or_insert(now);
+        } else if classes.iter().all(|(len, capacity)| *len <= low_watermark(*capacity)) {
+            self.pressure_since = None;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.chain_data.len() + self.mempool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_queue_priority_and_coalescing() {
+        let mut queue = SendQueue::new(2, 2, 2, QUEUE_FULL_TIMEOUT);
+        let now = Instant::now();
+
+        assert_eq!(Pushed::Queued, queue.push("operation", Priority::Mempool, false, now));
+        assert_eq!(Pushed::Queued, queue.push("head_1", Priority::Mempool, true, now));
+        assert_eq!(Pushed::Replaced, queue.push("head_2", Priority::Mempool, true, now));
+        assert_eq!(Pushed::Queued, queue.push("block_header", Priority::ChainData, false, now));
+        assert_eq!(Pushed::Queued, queue.push("advertise", Priority::Control, false, now));
+        // full mempool class drops the oldest message
+        assert_eq!(Pushed::Replaced, queue.push("get_operations", Priority::Mempool, false, now));
+        assert_eq!(4, queue.len());
+
+        assert_eq!(Some("advertise"), queue.pop(now));
+        assert_eq!(Some("block_header"), queue.pop(now));
+        assert_eq!(Some("head_2"), queue.pop(now));
+        assert_eq!(Some("get_operations"), queue.pop(now));
+        assert_eq!(None, queue.pop(now));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_send_queue_stalled() {
+        let timeout = Duration::from_secs(10);
+        let mut queue = SendQueue::new(4, 4, 1, timeout);
+        let now = Instant::now();
+
+        for i in 0..4 {
+            assert_eq!(Pushed::Queued, queue.push(i, Priority::ChainData, false, now));
+        }
+        assert_eq!(Pushed::Rejected, queue.push(4, Priority::ChainData, false, now));
+        assert!(!queue.is_stalled(now + timeout / 2));
+
+        // single sent message does not relieve the pressure
+        assert_eq!(Some(0), queue.pop(now + timeout / 2));
+        assert_eq!(Pushed::Queued, queue.push(5, Priority::ChainData, false, now + timeout / 2));
+        assert_eq!(Pushed::Rejected, queue.push(6, Priority::ChainData, false, now + timeout / 2));
+
+        // stall is detected on pop too, without any new message
+        assert_eq!(Some(1), queue.pop(now + timeout));
+        assert!(queue.is_stalled(now + timeout));
+        assert_eq!(Pushed::Stalled, queue.push(7, Priority::ChainData, false, now + timeout));
+    }
+
+    #[test]
+    fn test_send_queue_pressure_relieved() {
+        let timeout = Duration::from_secs(10);
+        let mut queue = SendQueue::new(4, 4, 1, timeout);
+        let now = Instant::now();
+
+        for i in 0..4 {
+            assert_eq!(Pushed::Queued, queue.push(i, Priority::Control, false, now));
+        }
+        // still over the low watermark
+        assert_eq!(Some(0), queue.pop(now));
+        assert!(queue.is_stalled(now + timeout));
+
+        // drained to the low watermark
+        assert_eq!(Some(1), queue.pop(now));
+        assert!(!queue.is_stalled(now + timeout));
+        assert_eq!(Pushed::Queued, queue.push(4, Priority::Control, false, now + timeout));
+        assert!(!queue.is_stalled(now + timeout));
+    }
+}
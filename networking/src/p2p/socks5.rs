@@ -0,0 +1,470 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Outgoing connections through the SOCKS5 proxy (RFC 1928), optionally with the username/password authentication (RFC 1929).
+//!
+//! Proxy only opens the TCP connection, the p2p handshake runs over the returned stream as over the direct connection.
+//!
+//! Targets are IPv4/IPv6 addresses or domain names (address type 3, e.g. Tor `.onion` addresses), domain names are resolved by the proxy.
+//! Peers are identified by `SocketAddr` in the whole node, so the node itself dials peers by address, domain names are available
+//! through `Socks5Proxy::connect_target`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use failure::Fail;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_TYPE_IPV4: u8 = 1;
+const ADDRESS_TYPE_DOMAIN: u8 = 3;
+const ADDRESS_TYPE_IPV6: u8 = 4;
+
+#[derive(Debug, Fail)]
+pub enum Socks5Error {
+    #[fail(display = "I/O error: {}", error)]
+    IoError {
+        error: io::Error
+    },
+    #[fail(display = "Unsupported SOCKS version: {}", version)]
+    UnsupportedVersion {
+        version: u8
+    },
+    #[fail(display = "Proxy does not accept any of the offered authentication methods")]
+    NoAcceptableAuthMethod,
+    #[fail(display = "Proxy authentication failed")]
+    AuthenticationFailed,
+    #[fail(display = "Proxy failed to connect, reply: {} ({})", reply, reason)]
+    ConnectFailed {
+        reply: u8,
+        reason: &'static str,
+    },
+    #[fail(display = "Invalid target domain name: {}", domain)]
+    InvalidDomain {
+        domain: String
+    },
+    #[fail(display = "Invalid proxy reply: {}", reason)]
+    InvalidReply {
+        reason: &'static str
+    },
+}
+
+impl From<io::Error> for Socks5Error {
+    fn from(error: io::Error) -> Self {
+        Socks5Error::IoError { error }
+    }
+}
+
+/// Target of the connection opened by the proxy
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyTarget {
+    Address(SocketAddr),
+    /// Domain name resolved by the proxy
+    Domain {
+        host: String,
+        port: u16,
+    },
+}
+
+impl From<SocketAddr> for ProxyTarget {
+    fn from(address: SocketAddr) -> Self {
+        ProxyTarget::Address(address)
+    }
+}
+
+/// Which outgoing connections go through the proxy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyScope {
+    /// All connections
+    All,
+    /// Connections to the public addresses, loopback, private and link-local addresses are dialed directly
+    Public,
+    /// Connections to the IPv4 addresses
+    Ipv4,
+    /// Connections to the IPv6 addresses
+    Ipv6,
+}
+
+impl FromStr for ProxyScope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(ProxyScope::All),
+            "public" => Ok(ProxyScope::Public),
+            "ipv4" => Ok(ProxyScope::Ipv4),
+            "ipv6" => Ok(ProxyScope::Ipv6),
+            _ => Err(format!("Invalid proxy scope: {}, expected one of: all, public, ipv4, ipv6", value)),
+        }
+    }
+}
+
+impl ProxyScope {
+    pub fn contains(&self, address: &SocketAddr) -> bool {
+        match self {
+            ProxyScope::All => true,
+            ProxyScope::Public => is_public(&address.ip()),
+            ProxyScope::Ipv4 => address.is_ipv4(),
+            ProxyScope::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()),
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            let unique_local = first_segment & 0xfe00 == 0xfc00;
+            let link_local = first_segment & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+/// SOCKS5 proxy configuration
+#[derive(Clone, Debug)]
+pub struct Socks5Proxy {
+    address: SocketAddr,
+    /// Username and password, if the proxy requires authentication
+    credentials: Option<(String, String)>,
+    scope: ProxyScope,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: SocketAddr, credentials: Option<(String, String)>, scope: ProxyScope) -> Self {
+        Socks5Proxy { address, credentials, scope }
+    }
+
+    pub fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    /// Check if connection to the `target` goes through the proxy
+    pub fn is_proxied(&self, target: &SocketAddr) -> bool {
+        self.scope.contains(target)
+    }
+
+    /// Open connection to the `target` address through the proxy
+    pub async fn connect(&self, target: &SocketAddr) -> Result<TcpStream, Socks5Error> {
+        self.connect_target(&ProxyTarget::Address(*target)).await
+    }
+
+    /// Open connection to the `target` address or domain name through the proxy
+    pub async fn connect_target(&self, target: &ProxyTarget) -> Result<TcpStream, Socks5Error> {
+        // invalid target is rejected before the proxy is contacted
+        let request = connect_request(target)?;
+
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let _ = stream.set_nodelay(true);
+
+        self.authenticate(&mut stream).await?;
+
+        // connect request
+        stream.write_all(&request).await?;
+
+        // connect reply
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        check_version(reply[0])?;
+        if reply[1] != 0 {
+            return Err(Socks5Error::ConnectFailed { reply: reply[1], reason: reply_reason(reply[1]) });
+        }
+        // bound address is not used, but it has to be consumed
+        let bound_address_len = match reply[3] {
+            ADDRESS_TYPE_IPV4 => 4,
+            ADDRESS_TYPE_IPV6 => 16,
+            ADDRESS_TYPE_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(Socks5Error::InvalidReply { reason: "unknown address type" }),
+        };
+        let mut bound_address = vec![0u8; bound_address_len + 2];
+        stream.read_exact(&mut bound_address).await?;
+
+        Ok(stream)
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), Socks5Error> {
+        let greeting = match self.credentials {
+            Some(_) => vec![SOCKS_VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD],
+            None => vec![SOCKS_VERSION, 1, AUTH_NONE],
+        };
+        stream.write_all(&greeting).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        check_version(reply[0])?;
+
+        match (reply[1], &self.credentials) {
+            (AUTH_NONE, _) => Ok(()),
+            (AUTH_USERNAME_PASSWORD, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Socks5Error::AuthenticationFailed);
+                }
+                let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+
+                let mut reply = [0u8; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] == 0 {
+                    Ok(())
+                } else {
+                    Err(Socks5Error::AuthenticationFailed)
+                }
+            }
+            (AUTH_NO_ACCEPTABLE_METHOD, _) => Err(Socks5Error::NoAcceptableAuthMethod),
+            _ => Err(Socks5Error::InvalidReply { reason: "authentication method was not offered" }),
+        }
+    }
+}
+
+/// Encode connect request for the `target`, domain name has to be non-empty and at most 255 bytes long
+fn connect_request(target: &ProxyTarget) -> Result<Vec<u8>, Socks5Error> {
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+    let port = match target {
+        ProxyTarget::Address(address) => {
+            match address.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(ADDRESS_TYPE_IPV4);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(ADDRESS_TYPE_IPV6);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            address.port()
+        }
+        ProxyTarget::Domain { host, port } => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(Socks5Error::InvalidDomain { domain: host.clone() });
+            }
+            request.push(ADDRESS_TYPE_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+fn check_version(version: u8) -> Result<(), Socks5Error> {
+    if version == SOCKS_VERSION {
+        Ok(())
+    } else {
+        Err(Socks5Error::UnsupportedVersion { version })
+    }
+}
+
+fn reply_reason(reply: u8) -> &'static str {
+    match reply {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::runtime::{Builder, Runtime};
+
+    use super::*;
+
+    fn runtime() -> Runtime {
+        Builder::new().basic_scheduler().enable_all().build().unwrap()
+    }
+
+    /// Minimal SOCKS5 proxy stand-in, it accepts single connection, checks the credentials (if any) and relays data to the target
+    async fn run_proxy(mut listener: TcpListener, credentials: Option<(&'static str, &'static str)>) {
+        let (mut client, _) = listener.accept().await.unwrap();
+
+        let mut greeting = [0u8; 2];
+        client.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        client.read_exact(&mut methods).await.unwrap();
+
+        match credentials {
+            Some((username, password)) => {
+                client.write_all(&[SOCKS_VERSION, AUTH_USERNAME_PASSWORD]).await.unwrap();
+                let mut version_and_len = [0u8; 2];
+                client.read_exact(&mut version_and_len).await.unwrap();
+                let mut received_username = vec![0u8; version_and_len[1] as usize];
+                client.read_exact(&mut received_username).await.unwrap();
+                let mut len = [0u8; 1];
+                client.read_exact(&mut len).await.unwrap();
+                let mut received_password = vec![0u8; len[0] as usize];
+                client.read_exact(&mut received_password).await.unwrap();
+                if received_username != username.as_bytes() || received_password != password.as_bytes() {
+                    client.write_all(&[USERNAME_PASSWORD_VERSION, 1]).await.unwrap();
+                    return;
+                }
+                client.write_all(&[USERNAME_PASSWORD_VERSION, 0]).await.unwrap();
+            }
+            None => client.write_all(&[SOCKS_VERSION, AUTH_NONE]).await.unwrap(),
+        }
+
+        let mut request = [0u8; 4];
+        client.read_exact(&mut request).await.unwrap();
+        let ip = match request[3] {
+            ADDRESS_TYPE_IPV4 => {
+                let mut ip = [0u8; 4];
+                client.read_exact(&mut ip).await.unwrap();
+                IpAddr::from(ip)
+            }
+            ADDRESS_TYPE_DOMAIN => {
+                let mut len = [0u8; 1];
+                client.read_exact(&mut len).await.unwrap();
+                let mut host = vec![0u8; len[0] as usize];
+                client.read_exact(&mut host).await.unwrap();
+                assert_eq!(b"localhost", host.as_slice());
+                IpAddr::from([127, 0, 0, 1])
+            }
+            address_type => panic!("Unexpected address type: {}", address_type),
+        };
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).await.unwrap();
+        let target = SocketAddr::new(ip, u16::from_be_bytes(port));
+
+        match TcpStream::connect(target).await {
+            Ok(mut upstream) => {
+                client.write_all(&[SOCKS_VERSION, 0, 0, ADDRESS_TYPE_IPV4, 127, 0, 0, 1, 0, 0]).await.unwrap();
+                let (mut client_rx, mut client_tx) = client.split();
+                let (mut upstream_rx, mut upstream_tx) = upstream.split();
+                let _ = futures::future::join(
+                    tokio::io::copy(&mut client_rx, &mut upstream_tx),
+                    tokio::io::copy(&mut upstream_rx, &mut client_tx),
+                ).await;
+            }
+            Err(_) => client.write_all(&[SOCKS_VERSION, 5, 0, ADDRESS_TYPE_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap(),
+        }
+    }
+
+    async fn echo_once(mut listener: TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    }
+
+    #[test]
+    fn test_connect_through_proxy() {
+        runtime().block_on(async {
+            let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = target_listener.local_addr().unwrap();
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = Socks5Proxy::new(proxy_listener.local_addr().unwrap(), Some(("tezedge".to_string(), "secret".to_string())), ProxyScope::All);
+
+            tokio::spawn(echo_once(target_listener));
+            tokio::spawn(run_proxy(proxy_listener, Some(("tezedge", "secret"))));
+
+            let mut stream = proxy.connect(&target).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"ping", &buf);
+        });
+    }
+
+    #[test]
+    fn test_connect_to_domain_through_proxy() {
+        runtime().block_on(async {
+            let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = ProxyTarget::Domain { host: "localhost".to_string(), port: target_listener.local_addr().unwrap().port() };
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = Socks5Proxy::new(proxy_listener.local_addr().unwrap(), None, ProxyScope::All);
+
+            tokio::spawn(echo_once(target_listener));
+            tokio::spawn(run_proxy(proxy_listener, None));
+
+            let mut stream = proxy.connect_target(&target).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"ping", &buf);
+        });
+    }
+
+    #[test]
+    fn test_connect_request() {
+        let ipv4: SocketAddr = "10.0.0.1:9732".parse().unwrap();
+        assert_eq!(
+            vec![SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_IPV4, 10, 0, 0, 1, 0x26, 0x04],
+            connect_request(&ipv4.into()).unwrap()
+        );
+
+        let ipv6: SocketAddr = "[2001:db8::1]:9732".parse().unwrap();
+        let mut expected = vec![SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_IPV6, 0x20, 0x01, 0x0d, 0xb8];
+        expected.extend_from_slice(&[0; 11]);
+        expected.extend_from_slice(&[1, 0x26, 0x04]);
+        assert_eq!(expected, connect_request(&ipv6.into()).unwrap());
+
+        let onion = ProxyTarget::Domain { host: "expyuzz4wqqyqhjn.onion".to_string(), port: 9732 };
+        let mut expected = vec![SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_DOMAIN, 22];
+        expected.extend_from_slice(b"expyuzz4wqqyqhjn.onion");
+        expected.extend_from_slice(&[0x26, 0x04]);
+        assert_eq!(expected, connect_request(&onion).unwrap());
+
+        // domain name length has to fit into single byte
+        let too_long = ProxyTarget::Domain { host: "a".repeat(256), port: 9732 };
+        assert!(matches!(connect_request(&too_long), Err(Socks5Error::InvalidDomain { .. })));
+        let empty = ProxyTarget::Domain { host: String::new(), port: 9732 };
+        assert!(matches!(connect_request(&empty), Err(Socks5Error::InvalidDomain { .. })));
+    }
+
+    #[test]
+    fn test_proxy_errors() {
+        runtime().block_on(async {
+            // target is not listening
+            let target = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = Socks5Proxy::new(proxy_listener.local_addr().unwrap(), None, ProxyScope::All);
+            tokio::spawn(run_proxy(proxy_listener, None));
+            assert!(matches!(proxy.connect(&target).await, Err(Socks5Error::ConnectFailed { reply: 5, .. })));
+
+            // wrong credentials
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = Socks5Proxy::new(proxy_listener.local_addr().unwrap(), Some(("tezedge".to_string(), "wrong".to_string())), ProxyScope::All);
+            tokio::spawn(run_proxy(proxy_listener, Some(("tezedge", "secret"))));
+            assert!(matches!(proxy.connect(&target).await, Err(Socks5Error::AuthenticationFailed)));
+        });
+    }
+
+    #[test]
+    fn test_proxy_scope() {
+        let public: SocketAddr = "8.8.8.8:9732".parse().unwrap();
+        let private: SocketAddr = "192.168.1.1:9732".parse().unwrap();
+        let public_v6: SocketAddr = "[2001:db8::1]:9732".parse().unwrap();
+        let unique_local_v6: SocketAddr = "[fd00::1]:9732".parse().unwrap();
+
+        assert!(ProxyScope::Public.contains(&public));
+        assert!(!ProxyScope::Public.contains(&private));
+        assert!(ProxyScope::Public.contains(&public_v6));
+        assert!(!ProxyScope::Public.contains(&unique_local_v6));
+        assert!(ProxyScope::Ipv4.contains(&private));
+        assert!(!ProxyScope::Ipv6.contains(&private));
+        assert!(ProxyScope::All.contains(&private));
+        assert_eq!(Ok(ProxyScope::Public), "public".parse());
+        assert!("onion".parse::<ProxyScope>().is_err());
+    }
+}
@@ -20,7 +20,10 @@ use crypto::crypto_box::{CryptoError, decrypt, encrypt, PrecomputedKey};
 use crypto::nonce::Nonce;
 use tezos_encoding::binary_reader::BinaryReaderError;
 use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryChunkError, BinaryMessage, CONTENT_LENGTH_FIELD_BYTES};
+use tezos_messages::p2p::encoding::version::NetworkVersion;
 
+use crate::p2p::bandwidth::PeerBandwidth;
+use crate::p2p::capture::{Direction, PeerCapture};
 use crate::p2p::peer::PeerId;
 
 /// Max allowed content length in bytes when taking into account extra data added by encryption
@@ -90,14 +93,15 @@ pub struct MessageStream {
 }
 
 impl MessageStream {
-    fn new(stream: TcpStream) -> MessageStream {
+    /// Create message stream, transfer rates are limited by the `bandwidth` limits (if any)
+    pub fn new(stream: TcpStream, bandwidth: Option<PeerBandwidth>) -> MessageStream {
         let _ = stream.set_linger(Some(Duration::from_secs(2)));
         let _ = stream.set_nodelay(true);
 
         let (rx, tx) = tokio::io::split(stream);
         MessageStream {
-            reader: MessageReader { stream: rx },
-            writer: MessageWriter { stream: tx },
+            reader: MessageReader { stream: rx, bandwidth: bandwidth.clone() },
+            writer: MessageWriter { stream: tx, bandwidth },
         }
     }
 
@@ -109,14 +113,16 @@ impl MessageStream {
 
 impl From<TcpStream> for MessageStream {
     fn from(stream: TcpStream) -> Self {
-        MessageStream::new(stream)
+        MessageStream::new(stream, None)
     }
 }
 
 /// Reader of the TCP/IP connection.
 pub struct MessageReader {
     /// reader part or the TCP/IP network stream
-    stream: ReadHalf<TcpStream>
+    stream: ReadHalf<TcpStream>,
+    /// download rate limits
+    bandwidth: Option<PeerBandwidth>,
 }
 
 impl MessageReader {
@@ -135,6 +141,10 @@ impl MessageReader {
         self.stream.read_exact(&mut msg_content_bytes).await?;
         all_recv_bytes.extend(&msg_content_bytes);
 
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.downloaded(all_recv_bytes.len()).await;
+        }
+
         Ok(all_recv_bytes.try_into()?)
     }
 
@@ -148,7 +158,9 @@ impl MessageReader {
 }
 
 pub struct MessageWriter {
-    stream: WriteHalf<TcpStream>
+    stream: WriteHalf<TcpStream>,
+    /// upload rate limits
+    bandwidth: Option<PeerBandwidth>,
 }
 
 impl MessageWriter {
@@ -159,8 +171,10 @@ impl MessageWriter {
     ///
     /// In case all bytes are successfully written to network stream a raw binary
     /// message is returned as a result.
-    #[inline]
     pub async fn write_message(&mut self, bytes: &BinaryChunk) -> Result<(), StreamError> {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.uploaded(bytes.raw().len()).await;
+        }
         Ok(self.stream.write_all(bytes.raw()).await?)
     }
 }
@@ -175,6 +189,10 @@ pub struct EncryptedMessageWriter {
     nonce_local: Nonce,
     /// Outgoing message writer
     tx: MessageWriter,
+    /// Capture of the sent messages (if enabled for the peer)
+    capture: Option<PeerCapture>,
+    /// Network version negotiated with the peer, messages are encoded for this version
+    network_version: Option<NetworkVersion>,
     /// Logger
     log: Logger,
 }
@@ -182,12 +200,29 @@ pub struct EncryptedMessageWriter {
 impl EncryptedMessageWriter {
     pub fn new(tx: MessageWriter, precomputed_key: PrecomputedKey, nonce_local: Nonce, peer_id: PeerId, log: Logger) -> Self {
         let log = log.new(o!("peer" => peer_id));
-        EncryptedMessageWriter { tx, precomputed_key, nonce_local, log }
+        EncryptedMessageWriter { tx, precomputed_key, nonce_local, capture: None, network_version: None, log }
+    }
+
+    /// Capture all messages written from now on
+    pub fn set_capture(&mut self, capture: Option<PeerCapture>) {
+        self.capture = capture;
+    }
+
+    /// Encode all messages written from now on for the negotiated network version
+    pub fn set_network_version(&mut self, network_version: NetworkVersion) {
+        self.network_version = Some(network_version);
     }
 
-    pub async fn write_message<'a>(&'a mut self, message: &'a impl BinaryMessage) -> Result<(), StreamError> {
-        let message_bytes = message.as_bytes()?;
+    /// Encode, encrypt and write the message, returns size of the encoded message (without encryption overhead)
+    pub async fn write_message<'a>(&'a mut self, message: &'a impl BinaryMessage) -> Result<usize, StreamError> {
+        let message_bytes = match &self.network_version {
+            Some(network_version) => message.as_bytes_for_version(network_version)?,
+            None => message.as_bytes()?,
+        };
         trace!(self.log, "Writing message"; "message" => FnValue(|_| hex::encode(&message_bytes)));
+        if let Some(capture) = &self.capture {
+            capture.capture(Direction::Outgoing, &message_bytes);
+        }
 
         for chunk_content_bytes in message_bytes.chunks(CONTENT_LENGTH_MAX) {
             // encrypt
@@ -201,7 +236,7 @@ impl EncryptedMessageWriter {
             self.tx.write_message(&chunk).await?;
         }
 
-        Ok(())
+        Ok(message_bytes.len())
     }
 
     #[inline]
@@ -221,6 +256,10 @@ pub struct EncryptedMessageReader {
     nonce_remote: Nonce,
     /// Incoming message reader
     rx: MessageReader,
+    /// Capture of the received messages (if enabled for the peer)
+    capture: Option<PeerCapture>,
+    /// Network version negotiated with the peer, messages are decoded for this version
+    network_version: Option<NetworkVersion>,
     /// Logger
     log: Logger,
 }
@@ -229,7 +268,17 @@ impl EncryptedMessageReader {
     /// Create new encrypted message from async reader and peer data
     pub fn new(rx: MessageReader, precomputed_key: PrecomputedKey, nonce_remote: Nonce, peer_id: PeerId, log: Logger) -> Self {
         let log = log.new(o!("peer" => peer_id));
-        EncryptedMessageReader { rx, precomputed_key, nonce_remote, log }
+        EncryptedMessageReader { rx, precomputed_key, nonce_remote, capture: None, network_version: None, log }
+    }
+
+    /// Capture all messages read from now on
+    pub fn set_capture(&mut self, capture: Option<PeerCapture>) {
+        self.capture = capture;
+    }
+
+    /// Decode all messages read from now on for the negotiated network version
+    pub fn set_network_version(&mut self, network_version: NetworkVersion) {
+        self.network_version = Some(network_version);
     }
 
     /// Consume content of inner message reader into specific message
@@ -257,11 +306,19 @@ impl EncryptedMessageReader {
                     input_data.append(&mut message_decrypted);
 
                     if input_remaining == 0 {
-                        match M::from_bytes(input_data.clone()) {
-                            Ok(message) => break Ok(message),
-                            Err(BinaryReaderError::Underflow { bytes }) => input_remaining += bytes,
-                            Err(e) => break Err(e.into()),
+                        let message = match &self.network_version {
+                            Some(network_version) => M::from_bytes_for_version(input_data.clone(), network_version),
+                            None => M::from_bytes(input_data.clone()),
+                        };
+                        if let Err(BinaryReaderError::Underflow { bytes }) = message {
+                            input_remaining += bytes;
+                            continue;
+                        }
+                        // whole message was received, raw bytes are captured even if the message can not be decoded
+                        if let Some(capture) = &self.capture {
+                            capture.capture(Direction::Incoming, &input_data);
                         }
+                        break message.map_err(StreamError::from);
                     }
                 }
                 Err(error) => {
@@ -0,0 +1,144 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Length prefixed bincode records, the file format shared by the p2p captures and the event recordings.
+//!
+//! File starts with a version byte, followed by records. Each record is a big-endian `u32` length
+//! and a bincode encoded value.
+
+use std::io::{self, Read, Write};
+
+use failure::Fail;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Max size of one record, just to protect reader from reading of corrupted files
+pub const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Possible errors of writing or reading of records
+#[derive(Debug, Fail)]
+pub enum RecordError {
+    #[fail(display = "I/O error: {}", error)]
+    IoError {
+        error: io::Error
+    },
+    #[fail(display = "Failed to encode/decode record, reason: {}", reason)]
+    EncodingError {
+        reason: String
+    },
+    #[fail(display = "Unsupported version: {}", version)]
+    UnsupportedVersion {
+        version: u8
+    },
+}
+
+impl From<io::Error> for RecordError {
+    fn from(error: io::Error) -> Self {
+        RecordError::IoError { error }
+    }
+}
+
+impl From<bincode::Error> for RecordError {
+    fn from(error: bincode::Error) -> Self {
+        RecordError::EncodingError { reason: format!("{:?}", error) }
+    }
+}
+
+/// Writes records after the version byte
+pub struct RecordWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(mut writer: W, version: u8) -> Result<Self, RecordError> {
+        writer.write_all(&[version])?;
+        Ok(RecordWriter { writer })
+    }
+
+    pub fn write<T: Serialize>(&mut self, record: &T) -> Result<(), RecordError> {
+        let bytes = bincode::serialize(record)?;
+        if bytes.len() > MAX_RECORD_SIZE {
+            return Err(RecordError::EncodingError { reason: format!("Record is too big: {} bytes", bytes.len()) });
+        }
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecordError> {
+        self.writer.flush().map_err(RecordError::from)
+    }
+
+    /// Flush records and return underlying writer
+    pub fn into_inner(mut self) -> Result<W, RecordError> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads records in the written order
+pub struct RecordReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// Fails if the file was written with the other `version`
+    pub fn new(mut reader: R, version: u8) -> Result<Self, RecordError> {
+        let mut read_version = [0u8; 1];
+        reader.read_exact(&mut read_version)?;
+        if read_version[0] != version {
+            return Err(RecordError::UnsupportedVersion { version: read_version[0] });
+        }
+        Ok(RecordReader { reader })
+    }
+
+    /// Reads next record, returns `None` at the end of the file
+    pub fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>, RecordError> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Err(RecordError::EncodingError { reason: format!("Record is too big: {} bytes", len) });
+        }
+        let mut bytes = vec![0u8; len];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_records() -> Result<(), failure::Error> {
+        let mut writer = RecordWriter::new(Vec::new(), 3)?;
+        writer.write(&(1u32, "first".to_string()))?;
+        writer.write(&(2u32, "second".to_string()))?;
+        let mut bytes = writer.into_inner()?;
+        assert_eq!(3, bytes[0]);
+
+        let mut reader = RecordReader::new(Cursor::new(bytes.clone()), 3)?;
+        assert_eq!(Some((1u32, "first".to_string())), reader.read()?);
+        assert_eq!(Some((2u32, "second".to_string())), reader.read()?);
+        assert_eq!(None, reader.read::<(u32, String)>()?);
+
+        // other version
+        assert!(RecordReader::new(Cursor::new(bytes.clone()), 4).is_err());
+        // truncated record
+        bytes.pop();
+        let mut reader = RecordReader::new(Cursor::new(bytes), 3)?;
+        assert!(reader.read::<(u32, String)>().is_ok());
+        assert!(reader.read::<(u32, String)>().is_err());
+        // corrupted length
+        let mut reader = RecordReader::new(Cursor::new(vec![3, 0xff, 0xff, 0xff, 0xff]), 3)?;
+        assert!(reader.read::<(u32, String)>().is_err());
+        Ok(())
+    }
+}
@@ -71,6 +71,30 @@ impl BootstrapInfo {
     }
 }
 
+// GET /chains/<chain_id>/is_bootstrapped
+
+/// Synchronisation status of the chain
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainSyncState {
+    Synced,
+    Unsynced,
+    Stuck,
+}
+
+/// Bootstrapped status of the chain
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IsBootstrappedInfo {
+    bootstrapped: bool,
+    sync_state: ChainSyncState,
+}
+
+impl IsBootstrappedInfo {
+    pub fn new(bootstrapped: bool, sync_state: ChainSyncState) -> Self {
+        Self { bootstrapped, sync_state }
+    }
+}
+
 
 // GET /monitor/heads/<chain_id>?(next_protocol=<Protocol_hash>)*
 
@@ -140,6 +164,21 @@ mod tests {
         }
     }
 
+    mod is_bootstrapped {
+        use super::*;
+
+        #[test]
+        fn encoded_custom() -> Result<(), serde_json::Error> {
+            let original = IsBootstrappedInfo::new(true, ChainSyncState::Stuck);
+            custom_encoded(original, "{\"bootstrapped\":true,\"sync_state\":\"stuck\"}")
+        }
+
+        #[test]
+        fn decoded_custom() -> Result<(), serde_json::Error> {
+            custom_decoded("{\"bootstrapped\":false,\"sync_state\":\"unsynced\"}", IsBootstrappedInfo::new(false, ChainSyncState::Unsynced))
+        }
+    }
+
     mod active_chain {
         use super::*;
 
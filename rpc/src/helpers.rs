@@ -23,6 +23,7 @@ use tezos_messages::ts_to_rfc3339;
 
 use crate::ContextList;
 use crate::encoding::base_types::{TimeStamp, UniString};
+use crate::encoding::monitor::BootstrapInfo;
 use crate::rpc_actor::RpcCollectedStateRef;
 
 #[macro_export]
@@ -179,6 +180,50 @@ impl Stream for MonitorHeadStream {
     }
 }
 
+/// Streams current head of the node until the node is bootstrapped
+pub struct MonitorBootstrappedStream {
+    pub state: RpcCollectedStateRef,
+    pub last_sent_head: Option<BlockHash>,
+    pub finished: bool,
+}
+
+impl Stream for MonitorBootstrappedStream {
+    type Item = Result<String, serde_json::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<String, serde_json::Error>>> {
+        // Note: the stream ends with the first head after the node is bootstrapped
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        let state = self.state.read().unwrap();
+        let is_bootstrapped = *state.is_bootstrapped();
+        let current_head = state.current_head().as_ref()
+            .map(|current_head| (current_head.header().hash.clone(), current_head.header().header.timestamp()));
+        drop(state);
+
+        match current_head {
+            Some((hash, timestamp)) if is_bootstrapped || self.last_sent_head.as_ref() != Some(&hash) => {
+                let bootstrap_info = BootstrapInfo::new(
+                    HashType::BlockHash.bytes_to_string(&hash).into(),
+                    TimeStamp::Rfc(ts_to_rfc3339(timestamp)),
+                );
+                self.last_sent_head = Some(hash);
+                self.finished = is_bootstrapped;
+
+                // serialize the struct to a json string to yield by the stream
+                let mut bootstrap_info = serde_json::to_string(&bootstrap_info)?;
+                bootstrap_info.push('\n');
+                Poll::Ready(Some(Ok(bootstrap_info)))
+            }
+            _ => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
 impl FullBlockInfo {
     pub fn new(val: &BlockApplied, chain_id: &str) -> Self {
         let header: &BlockHeader = &val.header().header;
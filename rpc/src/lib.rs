@@ -6,6 +6,7 @@ use slog::{Logger, warn};
 
 use crypto::hash::HashType;
 pub use services::mempool_services::MempoolOperations;
+pub use services::sandbox_services::SandboxConfiguration;
 pub use storage::persistent::{ContextList, ContextMap};
 
 use crate::rpc_actor::RpcCollectedStateRef;
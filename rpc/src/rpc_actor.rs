@@ -10,7 +10,7 @@ use slog::{Logger, warn};
 use tokio::runtime::Handle;
 
 use crypto::hash::ChainId;
-use shell::shell_channel::{BlockApplied, CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use shell::shell_channel::{BlockApplied, CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic, SyncState};
 use storage::persistent::PersistentStorage;
 use storage::StorageInitInfo;
 use tezos_api::environment::TezosEnvironmentConfiguration;
@@ -20,6 +20,7 @@ use tezos_wrapper::TezosApiConnectionPool;
 use crate::encoding::base_types::TimeStamp;
 use crate::helpers::current_time_timestamp;
 use crate::server::{RpcServiceEnvironment, spawn_server};
+use crate::services::sandbox_services::SandboxConfiguration;
 
 pub type RpcServerRef = ActorRef<RpcServerMsg>;
 
@@ -38,6 +39,10 @@ pub struct RpcCollectedState {
     current_mempool_state: Option<CurrentMempoolState>,
     #[get = "pub(crate)"]
     head_update_time: TimeStamp,
+    #[get = "pub(crate)"]
+    sync_state: SyncState,
+    #[get = "pub(crate)"]
+    is_bootstrapped: bool,
 }
 
 /// Actor responsible for managing HTTP REST API and server, and to share parts of inner actor
@@ -60,7 +65,8 @@ impl RpcServer {
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
         tezos_env: TezosEnvironmentConfiguration,
         network_version: NetworkVersion,
-        init_storage_data: &StorageInitInfo) -> Result<RpcServerRef, CreateError> {
+        init_storage_data: &StorageInitInfo,
+        sandbox: Option<SandboxConfiguration>) -> Result<RpcServerRef, CreateError> {
 
         // TODO: refactor - call load_current_head in pre_start
         let shared_state = Arc::new(RwLock::new(RpcCollectedState {
@@ -68,6 +74,8 @@ impl RpcServer {
             chain_id: init_storage_data.chain_id.clone(),
             current_mempool_state: None,
             head_update_time: current_time_timestamp(),
+            sync_state: SyncState::Unsynced,
+            is_bootstrapped: false,
         }));
         let actor_ref = sys.actor_of_props::<RpcServer>(
             Self::name(),
@@ -86,6 +94,7 @@ impl RpcServer {
                 tezos_readonly_api,
                 &init_storage_data.genesis_block_header_hash,
                 shared_state,
+                sandbox,
                 &sys.log(),
             );
             let inner_log = sys.log();
@@ -143,6 +152,11 @@ impl Receive<ShellChannelMsg> for RpcServer {
                 let current_state = &mut *self.state.write().unwrap();
                 current_state.current_mempool_state = Some(result);
             }
+            ShellChannelMsg::SyncStateChanged(sync_state) => {
+                let current_state = &mut *self.state.write().unwrap();
+                current_state.sync_state = sync_state.sync_state;
+                current_state.is_bootstrapped = sync_state.is_bootstrapped;
+            }
             _ => (/* Not yet implemented, do nothing */),
         }
     }
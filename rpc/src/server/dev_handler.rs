@@ -6,7 +6,7 @@ use slog::warn;
 
 use crate::{empty, make_json_response, result_to_json_response, ServiceResult, unwrap_block_hash};
 use crate::server::{HasSingleValue, Params, Query, RpcServiceEnvironment};
-use crate::services::base_services;
+use crate::services::{base_services, sandbox_services};
 
 pub async fn dev_blocks(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
     warn!(env.log(), "Getting dev_blocks");
@@ -73,3 +73,29 @@ pub async fn dev_stats_memory(_: Request<Body>, _: Params, _: Query, env: RpcSer
         }
     }
 }
+
+pub async fn sandbox_activate_protocol(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request: sandbox_services::ActivateProtocolRequest = serde_json::from_slice(&body)?;
+
+    let result = match env.sandbox() {
+        Some(sandbox) => sandbox_services::activate_protocol(request, sandbox, &env).await,
+        None => Err(failure::format_err!("Protocol can be activated only in the sandbox environment")),
+    };
+    result_to_json_response(result, env.log())
+}
+
+pub async fn sandbox_bake_block(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request: sandbox_services::BakeBlockRequest = if body.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_slice(&body)?
+    };
+
+    let result = match env.sandbox() {
+        Some(sandbox) => sandbox_services::bake_block(request, sandbox, &env).await,
+        None => Err(failure::format_err!("Blocks can be baked only in the sandbox environment")),
+    };
+    result_to_json_response(result, env.log())
+}
@@ -6,17 +6,11 @@ use chrono::prelude::*;
 use hyper::{Body, Request};
 use slog::warn;
 
-use crypto::hash::HashType;
-use shell::shell_channel::BlockApplied;
 use tezos_api::ffi::JsonRpcRequest;
-use tezos_messages::ts_to_rfc3339;
 
 use crate::{
     empty,
-    encoding::{
-        base_types::*,
-        monitor::BootstrapInfo,
-    },
+    encoding::base_types::*,
     make_json_response,
     make_json_stream_response,
     result_option_to_json_response,
@@ -34,19 +28,13 @@ fn timestamp() -> TimeStamp {
 }
 
 pub async fn bootstrapped(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> HResult {
-    let state_read = env.state().read().unwrap();
-
-    let bootstrap_info = match state_read.current_head().as_ref() {
-        Some(current_head) => {
-            let current_head: BlockApplied = current_head.clone();
-            let block = HashType::BlockHash.bytes_to_string(&current_head.header().hash);
-            let timestamp = ts_to_rfc3339(current_head.header().header.timestamp());
-            BootstrapInfo::new(block.into(), TimeStamp::Rfc(timestamp))
-        }
-        None => BootstrapInfo::new(String::new().into(), TimeStamp::Integral(0))
-    };
+    make_json_stream_response(base_services::get_bootstrapped_monitor(env.state()))
+}
 
-    make_json_response(&bootstrap_info)
+pub async fn is_bootstrapped(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> HResult {
+    let _chain_id = params.get_str("chain_id").unwrap();
+
+    make_json_response(&base_services::get_is_bootstrapped(env.state()))
 }
 
 pub async fn commit_hash(_: Request<Body>, _: Params, _: Query, _: RpcServiceEnvironment) -> HResult {
@@ -213,6 +201,33 @@ pub async fn mempool_pending_operations(_: Request<Body>, params: Params, _: Que
     }
 }
 
+pub async fn mempool_ban_operation(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let operation_hash_raw = hyper::body::aggregate(req).await?;
+    let operation_hash: String = serde_json::from_reader(&mut operation_hash_raw.reader())?;
+
+    result_to_json_response(
+        services::mempool_services::ban_operation(&operation_hash, env.shell_channel().clone()),
+        env.log(),
+    )
+}
+
+pub async fn mempool_unban_operation(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let operation_hash_raw = hyper::body::aggregate(req).await?;
+    let operation_hash: String = serde_json::from_reader(&mut operation_hash_raw.reader())?;
+
+    result_to_json_response(
+        services::mempool_services::unban_operation(&operation_hash, env.shell_channel().clone()),
+        env.log(),
+    )
+}
+
+pub async fn mempool_unban_all_operations(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    result_to_json_response(
+        services::mempool_services::unban_all_operations(env.shell_channel().clone()),
+        env.log(),
+    )
+}
+
 pub async fn inject_operation(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let operation_data_raw = hyper::body::aggregate(req).await?;
     let operation_data: String = serde_json::from_reader(&mut operation_data_raw.reader())?;
@@ -225,14 +240,16 @@ pub async fn inject_operation(req: Request<Body>, _: Params, _: Query, env: RpcS
     )
 }
 
-pub async fn inject_block(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+pub async fn inject_block(req: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let body = hyper::body::to_bytes(req.into_body()).await?;
     let body = String::from_utf8(body.to_vec())?;
+    let is_async = query.contains_key("async");
+    let force = query.contains_key("force");
 
     let shell_channel = env.shell_channel();
 
     result_to_json_response(
-        services::mempool_services::inject_block(&body, env.persistent_storage(), env.state(), shell_channel.clone(), env.log()),
+        services::mempool_services::inject_block(&body, is_async, force, shell_channel.clone(), env.log()).await,
         env.log(),
     )
 }
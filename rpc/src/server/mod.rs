@@ -22,6 +22,7 @@ use tezos_wrapper::TezosApiConnectionPool;
 
 use crate::empty;
 use crate::rpc_actor::{RpcCollectedStateRef, RpcServerRef};
+use crate::services::sandbox_services::SandboxConfiguration;
 
 mod handler;
 mod dev_handler;
@@ -50,6 +51,9 @@ pub struct RpcServiceEnvironment {
     log: Logger,
     #[get = "pub(crate)"]
     tezos_readonly_api: Arc<TezosApiConnectionPool>,
+    /// Block production settings, available only in the sandbox environment
+    #[get = "pub(crate)"]
+    sandbox: Option<SandboxConfiguration>,
 }
 
 impl RpcServiceEnvironment {
@@ -63,6 +67,7 @@ impl RpcServiceEnvironment {
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
         genesis_hash: &BlockHash,
         state: RpcCollectedStateRef,
+        sandbox: Option<SandboxConfiguration>,
         log: &Logger) -> Self {
         Self {
             sys,
@@ -75,6 +80,7 @@ impl RpcServiceEnvironment {
             state,
             log: log.clone(),
             tezos_readonly_api,
+            sandbox,
         }
     }
 }
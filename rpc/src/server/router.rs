@@ -22,6 +22,7 @@ pub(crate) fn create_routes() -> PathTree<Handler> {
     routes.handle("/monitor/valid_blocks", handler::valid_blocks);
     routes.handle("/monitor/heads/:chain_id", handler::head_chain);
     routes.handle("/chains/:chain_id/chain_id", handler::get_chain_id);
+    routes.handle("/chains/:chain_id/is_bootstrapped", handler::is_bootstrapped);
     routes.handle("/chains/:chain_id/blocks/:block_id", handler::chains_block_id);
     routes.handle("/chains/:chain_id/blocks/:block_id/header", handler::chains_block_id_header);
     routes.handle("/chains/:chain_id/blocks/:block_id/header/shell", handler::chains_block_id_header_shell);
@@ -36,6 +37,9 @@ pub(crate) fn create_routes() -> PathTree<Handler> {
     routes.handle("/chains/:chain_id/blocks/:block_id/helpers/preapply/block", handler::preapply_block);
     routes.handle("/chains/:chain_id/blocks/:block_id/votes/listings", handler::votes_listings);
     routes.handle("/chains/:chain_id/mempool/pending_operations", handler::mempool_pending_operations);
+    routes.handle("/chains/:chain_id/mempool/ban_operation", handler::mempool_ban_operation);
+    routes.handle("/chains/:chain_id/mempool/unban_operation", handler::mempool_unban_operation);
+    routes.handle("/chains/:chain_id/mempool/unban_all_operations", handler::mempool_unban_all_operations);
     routes.handle("/chains/:chain_id/blocks/:block_id/protocols", handler::get_block_protocols);
     routes.handle("/chains/:chain_id/blocks/:block_id/hash", handler::get_block_hash);
     routes.handle("/chains/:chain_id/blocks/:block_id/operation_hashes", handler::get_block_operation_hashes);
@@ -50,6 +54,8 @@ pub(crate) fn create_routes() -> PathTree<Handler> {
     routes.handle("/dev/chains/main/actions/blocks/:block_hash", dev_handler::dev_action_cursor);
     routes.handle("/dev/chains/main/actions/contracts/:contract_address", dev_handler::dev_action_cursor);
     routes.handle("/dev/context/:id", dev_handler::dev_context);
+    routes.handle("/dev/sandbox/activate_protocol", dev_handler::sandbox_activate_protocol);
+    routes.handle("/dev/sandbox/bake_block", dev_handler::sandbox_bake_block);
     routes.handle("/stats/memory", dev_handler::dev_stats_memory);
     //routes.handle("/stats/storage", dev_handler::dev_stats_storage);
 
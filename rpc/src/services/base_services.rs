@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use slog::Logger;
 
 use crypto::hash::{chain_id_to_b58_string, HashType};
-use shell::shell_channel::BlockApplied;
+use shell::shell_channel::{BlockApplied, SyncState};
 use shell::stats::memory::{Memory, MemoryData, MemoryStatsResult};
 use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ContextActionRecordValue, ContextActionStorage, num_from_slice};
 use storage::block_storage::BlockJsonData;
@@ -22,7 +22,8 @@ use tezos_messages::p2p::encoding::version::NetworkVersion;
 use tezos_messages::protocol::{RpcJsonMap, UniversalValue};
 
 use crate::ContextList;
-use crate::helpers::{BlockHeaderInfo, BlockHeaderShellInfo, FullBlockInfo, get_action_types, get_block_hash_by_block_id, get_context_protocol_params, MonitorHeadStream, NodeVersion, PagedResult, Protocols};
+use crate::encoding::monitor::{ChainSyncState, IsBootstrappedInfo};
+use crate::helpers::{BlockHeaderInfo, BlockHeaderShellInfo, FullBlockInfo, get_action_types, get_block_hash_by_block_id, get_context_protocol_params, MonitorBootstrappedStream, MonitorHeadStream, NodeVersion, PagedResult, Protocols};
 use crate::rpc_actor::RpcCollectedStateRef;
 
 // Serialize, Deserialize,
@@ -151,6 +152,26 @@ pub(crate) fn get_current_head_monitor_header(state: &RpcCollectedStateRef) -> R
     }))
 }
 
+/// Get stream of the current heads, which ends when the node is bootstrapped
+pub(crate) fn get_bootstrapped_monitor(state: &RpcCollectedStateRef) -> MonitorBootstrappedStream {
+    MonitorBootstrappedStream {
+        state: state.clone(),
+        last_sent_head: None,
+        finished: false,
+    }
+}
+
+/// Get bootstrapped status of the node
+pub(crate) fn get_is_bootstrapped(state: &RpcCollectedStateRef) -> IsBootstrappedInfo {
+    let state = state.read().unwrap();
+    let sync_state = match state.sync_state() {
+        SyncState::Synced => ChainSyncState::Synced,
+        SyncState::Unsynced => ChainSyncState::Unsynced,
+        SyncState::Stuck => ChainSyncState::Stuck,
+    };
+    IsBootstrappedInfo::new(*state.is_bootstrapped(), sync_state)
+}
+
 /// Get information about block
 pub(crate) fn get_full_block(block_id: &str, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Option<FullBlockInfo>, failure::Error> {
     let block = get_block_by_block_id(block_id, persistent_storage, state)?;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, SystemTime};
 
 use failure::format_err;
@@ -8,7 +8,7 @@ use serde_json::Value;
 use slog::Logger;
 
 use crypto::hash::{HashType, OperationHash, ProtocolHash};
-use shell::shell_channel::{CurrentMempoolState, MempoolOperationReceived, ShellChannelRef, ShellChannelTopic, InjectBlock};
+use shell::shell_channel::{CurrentMempoolState, inject_block_result_callback, InjectBlock, MempoolOperationBan, MempoolOperationReceived, ShellChannelRef, ShellChannelTopic};
 use storage::mempool_storage::MempoolOperationType;
 use storage::MempoolStorage;
 use storage::persistent::PersistentStorage;
@@ -24,7 +24,7 @@ pub struct MempoolOperations {
     pub refused: Vec<Value>,
     pub branch_refused: Vec<Value>,
     pub branch_delayed: Vec<Value>,
-    // TODO: unprocessed - we dont have protocol data, because we can get it just from ffi now
+    // we dont have protocol data for unprocessed operations, because we can get it just from ffi now, so just raw data are returned
     pub unprocessed: Vec<Value>,
 }
 
@@ -50,7 +50,7 @@ pub fn get_pending_operations(
                 refused: convert_errored(&mempool.result.refused, &mempool.operations, &protocol)?,
                 branch_refused: convert_errored(&mempool.result.branch_refused, &mempool.operations, &protocol)?,
                 branch_delayed: convert_errored(&mempool.result.branch_delayed, &mempool.operations, &protocol)?,
-                unprocessed: vec![],
+                unprocessed: convert_pending(&mempool.pending, &mempool.operations)?,
             })
         }
         None => Ok(MempoolOperations::default())
@@ -119,6 +119,37 @@ fn convert_errored(errored: &Vec<Errored>, operations: &HashMap<OperationHash, O
     Ok(result)
 }
 
+fn convert_pending(pending: &HashSet<OperationHash>, operations: &HashMap<OperationHash, Operation>) -> Result<Vec<Value>, failure::Error> {
+    let mut result: Vec<Value> = Vec::new();
+
+    for p in pending {
+        let operation_hash = HashType::OperationHash.bytes_to_string(&p);
+        let operation = match operations.get(p) {
+            Some(b) => b,
+            None => return Err(format_err!("missing operation data for operation_hash: {}", &operation_hash))
+        };
+
+        let mut m = HashMap::new();
+        m.insert(String::from("branch"), Value::String(HashType::BlockHash.bytes_to_string(&operation.branch())));
+        m.insert(String::from("data"), Value::String(hex::encode(operation.data())));
+
+        result.push(
+            Value::Array(
+                vec![
+                    Value::String(operation_hash),
+                    serde_json::to_value(m)?,
+                ]
+            )
+        );
+    }
+
+    Ok(result)
+}
+
+/// Injected operations are kept in mempool storage (and restored after restart) for this time,
+/// which corresponds to the operations ttl of 60 blocks
+const INJECTED_OPERATION_TTL: Duration = Duration::from_secs(60 * 60);
+
 pub fn inject_operation(
     operation_data: &str,
     persistent_storage: &PersistentStorage,
@@ -129,7 +160,7 @@ pub fn inject_operation(
 
     let operation: Operation = Operation::from_bytes(hex::decode(operation_data)?)?;
     let operation_message = OperationMessage::new(operation.clone());
-    let ttl = SystemTime::now() + Duration::from_secs(60);
+    let ttl = SystemTime::now() + INJECTED_OPERATION_TTL;
     let operation_hash = operation.message_hash()?;
 
     mempool_storage.put(MempoolOperationType::Pending, operation_message, ttl)?;
@@ -146,26 +177,122 @@ pub fn inject_operation(
     Ok(HashType::OperationHash.bytes_to_string(&operation_hash))
 }
 
-pub fn inject_block(
+/// Request mempool to remove operation and to ignore it, until it is unbanned
+pub fn ban_operation(operation_hash: &str, shell_channel: ShellChannelRef) -> Result<(), failure::Error> {
+    let operation_hash = HashType::OperationHash.string_to_bytes(operation_hash)?;
+    publish_operation_ban(MempoolOperationBan::Ban(operation_hash), shell_channel);
+    Ok(())
+}
+
+/// Request mempool to stop ignoring banned operation
+pub fn unban_operation(operation_hash: &str, shell_channel: ShellChannelRef) -> Result<(), failure::Error> {
+    let operation_hash = HashType::OperationHash.string_to_bytes(operation_hash)?;
+    publish_operation_ban(MempoolOperationBan::Unban(operation_hash), shell_channel);
+    Ok(())
+}
+
+/// Request mempool to stop ignoring all banned operations
+pub fn unban_all_operations(shell_channel: ShellChannelRef) -> Result<(), failure::Error> {
+    publish_operation_ban(MempoolOperationBan::UnbanAll, shell_channel);
+    Ok(())
+}
+
+fn publish_operation_ban(ban: MempoolOperationBan, shell_channel: ShellChannelRef) {
+    shell_channel.tell(
+        Publish {
+            msg: ban.into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+}
+
+/// Max time to wait for the injected block to be applied
+const INJECT_BLOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Injected block operation in the format of tezos `/injection/block` rpc
+#[derive(Deserialize, Debug)]
+pub(crate) struct InjectedBlockOperation {
+    branch: String,
+    data: String,
+}
+
+/// Injected block in the format of tezos `/injection/block` rpc
+#[derive(Deserialize, Debug)]
+struct InjectedBlock {
+    data: String,
+    #[serde(default)]
+    operations: Option<Vec<Vec<InjectedBlockOperation>>>,
+}
+
+/// Decode operations of the injected block (ordered by validation passes)
+pub(crate) fn decode_block_operations(validation_passes: Vec<Vec<InjectedBlockOperation>>) -> Result<Vec<Vec<Operation>>, failure::Error> {
+    let mut operations = Vec::with_capacity(validation_passes.len());
+    for validation_pass in validation_passes {
+        let mut validation_pass_operations = Vec::with_capacity(validation_pass.len());
+        for operation in validation_pass {
+            let mut operation_bytes = HashType::BlockHash.string_to_bytes(&operation.branch)?;
+            operation_bytes.extend(hex::decode(&operation.data)?);
+            validation_pass_operations.push(Operation::from_bytes(operation_bytes)?);
+        }
+        operations.push(validation_pass_operations);
+    }
+    Ok(operations)
+}
+
+pub async fn inject_block(
     injection_data: &str,
-    _persistent_storage: &PersistentStorage,
-    _state: &RpcCollectedStateRef,
+    is_async: bool,
+    force: bool,
     shell_channel: ShellChannelRef,
     _log: &Logger) -> Result<String, failure::Error> {
+    let injection_data: InjectedBlock = serde_json::from_str(injection_data)?;
+
+    let header: BlockHeader = BlockHeader::from_bytes(hex::decode(&injection_data.data)?)?;
+    let operations = match injection_data.operations {
+        Some(validation_passes) => Some(decode_block_operations(validation_passes)?),
+        None => None
+    };
 
-    let injection_data_json: serde_json::Value = serde_json::from_str(injection_data)?;
+    inject_block_header(header, operations, is_async, force, shell_channel).await
+}
 
-    let header: BlockHeader = BlockHeader::from_bytes(hex::decode(injection_data_json["data"].to_string().replace("\"", ""))?)?;
+/// Publish block for the application and wait for the result (unless `is_async`), returns hash of the block
+pub(crate) async fn inject_block_header(
+    header: BlockHeader,
+    operations: Option<Vec<Vec<Operation>>>,
+    is_async: bool,
+    force: bool,
+    shell_channel: ShellChannelRef) -> Result<String, failure::Error> {
+    let block_hash = header.message_hash()?;
+
+    let (result_callback, result_receiver) = if is_async {
+        (None, None)
+    } else {
+        let (result_callback, result_receiver) = inject_block_result_callback();
+        (Some(result_callback), Some(result_receiver))
+    };
 
     shell_channel.tell(
         Publish {
             msg: InjectBlock {
-                block_header: header.clone(),
+                block_header: header,
+                operations,
+                force,
+                result_callback,
             }.into(),
             topic: ShellChannelTopic::ShellEvents.into(),
         }, None);
 
-    Ok(HashType::BlockHash.bytes_to_string(&header.message_hash().unwrap()))
+    // wait until the block is applied (unless async injection was requested)
+    if let Some(result_receiver) = result_receiver {
+        match tokio::time::timeout(INJECT_BLOCK_WAIT_TIMEOUT, result_receiver).await {
+            Ok(Ok(Ok(()))) => (),
+            Ok(Ok(Err(e))) => return Err(e.into()),
+            Ok(Err(_)) => return Err(format_err!("Block injection was cancelled")),
+            Err(_) => return Err(format_err!("Block was not applied within {} seconds", INJECT_BLOCK_WAIT_TIMEOUT.as_secs())),
+        }
+    }
+
+    Ok(HashType::BlockHash.bytes_to_string(&block_hash))
 }
 
 #[cfg(test)]
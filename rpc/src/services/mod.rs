@@ -6,4 +6,5 @@
 pub mod base_services;
 pub mod mempool_services;
 pub mod protocol;
+pub mod sandbox_services;
 pub mod stats_services;
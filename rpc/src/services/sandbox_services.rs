@@ -0,0 +1,585 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Block production for the sandbox environment.
+//!
+//! Protocol is activated (and blocks are baked) the same way as the tezos client does it:
+//! block is preapplied by the protocol runner, shell header from the preapplication is signed by the configured key
+//! and the block is injected through the shell, so a fully local chain does not need any external baker.
+
+use std::cmp;
+use std::time::Duration;
+
+use chrono::Utc;
+use failure::{bail, format_err};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crypto::blake2b;
+use crypto::ed25519::{block_header_watermark, SigningKey};
+use crypto::hash::{ChainId, HashType};
+use tezos_api::ffi::JsonRpcRequest;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::prelude::{BlockHeader, BlockHeaderBuilder};
+use tezos_messages::protocol::{get_constants_for_rpc, UniversalValue};
+
+use crate::helpers::get_context_protocol_params;
+use crate::server::RpcServiceEnvironment;
+use crate::services::mempool_services::{decode_block_operations, inject_block_header, InjectedBlockOperation};
+use crate::services::protocol;
+
+/// Tag of the `Activate` command of the genesis protocol
+const ACTIVATE_COMMAND_TAG: u8 = 0;
+/// Count of validation passes of the baked block (endorsements, votes, anonymous and manager operations)
+const VALIDATION_PASSES: usize = 4;
+/// Length of the seed nonce committed by the baked block
+const SEED_NONCE_LENGTH: usize = 32;
+/// Offset of the proof of work nonce in the protocol data (after the priority)
+const PROOF_OF_WORK_NONCE_OFFSET: usize = 2;
+/// Length of the block signature
+const SIGNATURE_LENGTH: usize = 64;
+/// Fitness of the activation block used by the tezos client
+const DEFAULT_ACTIVATION_FITNESS: [&str; 2] = ["01", "0000000000000001"];
+/// Block is baked at most this long before its timestamp, rpc waits until then
+const MAX_BAKING_DELAY: Duration = Duration::from_secs(60);
+
+/// Sandbox block production settings
+#[derive(Clone)]
+pub struct SandboxConfiguration {
+    /// Blocks are signed by this key, unless another key is provided in the request
+    pub signing_key: Option<SigningKey>,
+}
+
+/// Request to activate the protocol on top of the genesis block
+#[derive(Deserialize, Debug)]
+pub struct ActivateProtocolRequest {
+    protocol: String,
+    protocol_parameters: Value,
+    #[serde(default)]
+    fitness: Option<Vec<String>>,
+    /// Secret key of the genesis activator (`edsk` seed)
+    #[serde(default)]
+    secret_key: Option<String>,
+    #[serde(default)]
+    timestamp: Option<i64>,
+}
+
+/// Request to bake a block from the current mempool
+#[derive(Deserialize, Debug, Default)]
+pub struct BakeBlockRequest {
+    /// Secret key of the baking delegate (`edsk` seed)
+    #[serde(default)]
+    secret_key: Option<String>,
+    /// If not set, the best priority of the delegate is used
+    #[serde(default)]
+    priority: Option<u16>,
+    #[serde(default)]
+    timestamp: Option<i64>,
+}
+
+/// Block produced in the sandbox
+#[derive(Serialize, Debug)]
+pub struct SandboxBlock {
+    block_hash: String,
+    level: i32,
+    timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u16>,
+    /// Seed nonce committed by the block, which should be revealed in the next cycle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed_nonce: Option<String>,
+    operations: usize,
+}
+
+/// Result of `helpers/preapply/block`
+#[derive(Deserialize, Debug)]
+struct PreappliedBlock {
+    shell_header: PreappliedShellHeader,
+    operations: Vec<PreappliedOperations>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct PreappliedShellHeader {
+    level: i32,
+    proto: u8,
+    predecessor: String,
+    timestamp: String,
+    validation_pass: u8,
+    operations_hash: String,
+    fitness: Vec<String>,
+    context: String,
+}
+
+impl PreappliedShellHeader {
+    fn to_block_header(&self, protocol_data: Vec<u8>) -> Result<BlockHeader, failure::Error> {
+        BlockHeaderBuilder::default()
+            .level(self.level)
+            .proto(self.proto)
+            .predecessor(HashType::BlockHash.string_to_bytes(&self.predecessor)?)
+            .timestamp(chrono::DateTime::parse_from_rfc3339(&self.timestamp)?.timestamp())
+            .validation_pass(self.validation_pass)
+            .operations_hash(HashType::OperationListListHash.string_to_bytes(&self.operations_hash)?)
+            .fitness(decode_fitness(&self.fitness)?)
+            .context(HashType::ContextHash.string_to_bytes(&self.context)?)
+            .protocol_data(protocol_data)
+            .build()
+            .map_err(|e| format_err!("Failed to build block header: {}", e))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PreappliedOperations {
+    applied: Vec<InjectedBlockOperation>,
+}
+
+/// Constants of the active protocol needed for baking
+struct BakingConstants {
+    blocks_per_commitment: i32,
+    proof_of_work_threshold: u64,
+}
+
+/// Activate `protocol` by the block signed by the genesis activator, block is baked on top of the genesis block.
+pub(crate) async fn activate_protocol(
+    request: ActivateProtocolRequest,
+    sandbox: &SandboxConfiguration,
+    env: &RpcServiceEnvironment) -> Result<SandboxBlock, failure::Error> {
+    let signing_key = resolve_signing_key(&request.secret_key, sandbox)?;
+    let (head_level, _) = current_head(env)?;
+    if head_level != 0 {
+        bail!("Protocol can be activated only on top of the genesis block, current head level: {}", head_level);
+    }
+
+    let fitness = request.fitness.unwrap_or_else(|| DEFAULT_ACTIVATION_FITNESS.iter().map(|f| f.to_string()).collect());
+    let protocol_parameters = encode_json_as_binary(&request.protocol_parameters)?;
+    let timestamp = request.timestamp.unwrap_or_else(|| Utc::now().timestamp());
+
+    let protocol_data = json!({
+        "protocol": env.tezos_environment().genesis.protocol,
+        "content": {
+            "command": "activate",
+            "hash": request.protocol,
+            "fitness": fitness,
+            "protocol_parameters": hex::encode(&protocol_parameters),
+        },
+        "signature": zero_signature(),
+    });
+    let preapplied = preapply_block(protocol_data, vec![], timestamp, env)?;
+
+    // binary form of the genesis protocol command (Data.Command.encoding)
+    let mut command = vec![ACTIVATE_COMMAND_TAG];
+    command.extend(HashType::ProtocolHash.string_to_bytes(&request.protocol)?);
+    command.extend(encode_fitness(&decode_fitness(&fitness)?));
+    command.extend(protocol_parameters);
+
+    let header = sign_block_header(&preapplied.shell_header, command, &signing_key, &chain_id(env))?;
+    let (level, timestamp) = (header.level(), header.timestamp());
+    let block_hash = inject_block_header(header, Some(vec![]), false, false, env.shell_channel().clone()).await?;
+
+    Ok(SandboxBlock {
+        block_hash,
+        level,
+        timestamp,
+        priority: None,
+        seed_nonce: None,
+        operations: 0,
+    })
+}
+
+/// Bake block on top of the current head with the operations applied in the current mempool.
+pub(crate) async fn bake_block(
+    request: BakeBlockRequest,
+    sandbox: &SandboxConfiguration,
+    env: &RpcServiceEnvironment) -> Result<SandboxBlock, failure::Error> {
+    let signing_key = resolve_signing_key(&request.secret_key, sandbox)?;
+    let (head_level, next_protocol) = current_head(env)?;
+    let level = head_level + 1;
+    let constants = baking_constants(env)?;
+
+    // resolve baking slot of the delegate, block is not baked before its estimated time
+    let now = Utc::now().timestamp();
+    let (priority, timestamp) = match request.priority {
+        Some(priority) => (priority, request.timestamp.unwrap_or(now)),
+        None => {
+            let (priority, estimated_time) = resolve_baking_slot(&signing_key.public_key_hash(), env)?;
+            (priority, request.timestamp.unwrap_or_else(|| cmp::max(now, estimated_time.unwrap_or(now))))
+        }
+    };
+    let delay = baking_delay(timestamp, now)?;
+    if delay > Duration::from_secs(0) {
+        tokio::time::delay_for(delay).await;
+    }
+
+    // sandbox chain is activated at level 1, so the commitment is expected at every `blocks_per_commitment` level
+    let seed_nonce = if level % constants.blocks_per_commitment == 0 {
+        let mut seed_nonce = vec![0; SEED_NONCE_LENGTH];
+        rand::thread_rng().fill(&mut seed_nonce[..]);
+        Some(seed_nonce)
+    } else {
+        None
+    };
+    let seed_nonce_hash = seed_nonce.as_ref().map(|seed_nonce| blake2b::digest_256(seed_nonce));
+
+    let mut protocol_data = json!({
+        "protocol": next_protocol,
+        "priority": priority,
+        "proof_of_work_nonce": hex::encode(&[0; 8]),
+        "signature": zero_signature(),
+    });
+    if let Some(seed_nonce_hash) = &seed_nonce_hash {
+        protocol_data["seed_nonce_hash"] = Value::String(HashType::NonceHash.bytes_to_string(seed_nonce_hash));
+    }
+    let preapplied = preapply_block(protocol_data, mempool_operations(env)?, timestamp, env)?;
+
+    // proof of work is CPU bound, so it does not block the rpc executor
+    let contents = {
+        let shell_header = preapplied.shell_header.clone();
+        let seed_nonce_hash = seed_nonce_hash.clone();
+        let threshold = constants.proof_of_work_threshold;
+        tokio::task::spawn_blocking(move || find_proof_of_work(&shell_header, priority, &seed_nonce_hash, threshold)).await??
+    };
+    let header = sign_block_header(&preapplied.shell_header, contents, &signing_key, &chain_id(env))?;
+    let timestamp = header.timestamp();
+    let operations = decode_block_operations(preapplied.operations.into_iter().map(|operations| operations.applied).collect())?;
+    let operations_count = operations.iter().map(Vec::len).sum();
+    let block_hash = inject_block_header(header, Some(operations), false, false, env.shell_channel().clone()).await?;
+
+    Ok(SandboxBlock {
+        block_hash,
+        level,
+        timestamp,
+        priority: Some(priority),
+        seed_nonce: seed_nonce.map(hex::encode),
+        operations: operations_count,
+    })
+}
+
+/// How long to wait until the block with the `timestamp` can be baked, block too far in the future is rejected
+fn baking_delay(timestamp: i64, now: i64) -> Result<Duration, failure::Error> {
+    let delay = cmp::max(0, timestamp - now) as u64;
+    if delay > MAX_BAKING_DELAY.as_secs() {
+        bail!("Block timestamp {} is {}s in the future, block can be baked at most {}s ahead", timestamp, delay, MAX_BAKING_DELAY.as_secs());
+    }
+    Ok(Duration::from_secs(delay))
+}
+
+fn resolve_signing_key(secret_key: &Option<String>, sandbox: &SandboxConfiguration) -> Result<SigningKey, failure::Error> {
+    match secret_key {
+        Some(secret_key) => Ok(SigningKey::from_base58_seed(secret_key)?),
+        None => sandbox.signing_key.clone()
+            .ok_or_else(|| format_err!("Secret key was not provided and no sandbox signing key is configured")),
+    }
+}
+
+fn chain_id(env: &RpcServiceEnvironment) -> ChainId {
+    env.state().read().unwrap().chain_id().clone()
+}
+
+/// Level and next protocol of the current head
+fn current_head(env: &RpcServiceEnvironment) -> Result<(i32, String), failure::Error> {
+    let state = env.state().read().unwrap();
+    match state.current_head() {
+        Some(head) => {
+            let metadata: Value = serde_json::from_str(head.json_data().block_header_proto_metadata_json())?;
+            let next_protocol = metadata["next_protocol"].as_str()
+                .ok_or_else(|| format_err!("Next protocol is missing in the metadata of the current head"))?;
+            Ok((head.header().header.level(), next_protocol.to_string()))
+        }
+        None => bail!("Current head is not known yet"),
+    }
+}
+
+fn baking_constants(env: &RpcServiceEnvironment) -> Result<BakingConstants, failure::Error> {
+    let params = get_context_protocol_params("head", None, env.persistent_storage().context_storage(), env.persistent_storage(), env.state())?;
+    let constants = get_constants_for_rpc(&params.constants_data, params.protocol_hash)?
+        .ok_or_else(|| format_err!("Protocol constants are not available"))?;
+
+    let blocks_per_commitment = match constants.get("blocks_per_commitment") {
+        Some(UniversalValue::Number(value)) if *value > 0 => *value,
+        _ => bail!("Protocol constant blocks_per_commitment is not available"),
+    };
+    let proof_of_work_threshold = match constants.get("proof_of_work_threshold") {
+        // protocol compares threshold as unsigned, so -1 disables proof of work
+        Some(UniversalValue::NumberI64(value)) => *value as u64,
+        _ => bail!("Protocol constant proof_of_work_threshold is not available"),
+    };
+    Ok(BakingConstants { blocks_per_commitment, proof_of_work_threshold })
+}
+
+/// Best priority (and its estimated time) of the `delegate` for the next level
+fn resolve_baking_slot(delegate: &str, env: &RpcServiceEnvironment) -> Result<(u16, Option<i64>), failure::Error> {
+    let rights = protocol::check_and_get_baking_rights(
+        "main",
+        "head",
+        None,
+        Some(delegate),
+        None,
+        None,
+        false,
+        env.persistent_storage().context_storage(),
+        env.persistent_storage(),
+        env.state(),
+    )?.unwrap_or_default();
+
+    rights.iter()
+        .filter_map(|right| match (right.get("priority"), right.get("estimated_time")) {
+            (Some(UniversalValue::Number(priority)), Some(UniversalValue::TimestampRfc3339(estimated_time))) => Some((*priority as u16, Some(*estimated_time))),
+            (Some(UniversalValue::Number(priority)), _) => Some((*priority as u16, None)),
+            _ => None,
+        })
+        .min_by_key(|(priority, _)| *priority)
+        .ok_or_else(|| format_err!("Delegate {} has no baking rights for the next level", delegate))
+}
+
+/// Operations applied in the current mempool, ordered by validation passes
+fn mempool_operations(env: &RpcServiceEnvironment) -> Result<Vec<Vec<Value>>, failure::Error> {
+    let mut operations = vec![Vec::new(); VALIDATION_PASSES];
+
+    let state = env.state().read().unwrap();
+    let mempool = match state.current_mempool_state() {
+        Some(mempool) => mempool,
+        None => return Ok(operations),
+    };
+    let protocol = match &mempool.protocol {
+        Some(protocol) => HashType::ProtocolHash.bytes_to_string(protocol),
+        None => return Ok(operations),
+    };
+
+    for applied in &mempool.result.applied {
+        let operation = match mempool.operations.get(&applied.hash) {
+            Some(operation) => operation,
+            None => continue,
+        };
+        let mut json: Map<String, Value> = serde_json::from_str(&applied.protocol_data_json)?;
+        let validation_pass = match json.get("contents").and_then(|contents| contents[0]["kind"].as_str()) {
+            Some(kind) => match validation_pass_of_operation_kind(kind) {
+                Some(validation_pass) => validation_pass,
+                None => continue,
+            },
+            None => continue,
+        };
+        json.insert("protocol".to_string(), Value::String(protocol.clone()));
+        json.insert("branch".to_string(), Value::String(HashType::BlockHash.bytes_to_string(operation.branch())));
+        operations[validation_pass].push(Value::Object(json));
+    }
+
+    Ok(operations)
+}
+
+fn validation_pass_of_operation_kind(kind: &str) -> Option<usize> {
+    match kind {
+        "endorsement" => Some(0),
+        "proposals" | "ballot" => Some(1),
+        "seed_nonce_revelation" | "double_endorsement_evidence" | "double_baking_evidence" | "activate_account" => Some(2),
+        "reveal" | "transaction" | "origination" | "delegation" => Some(3),
+        _ => None,
+    }
+}
+
+fn preapply_block(protocol_data: Value, operations: Vec<Vec<Value>>, timestamp: i64, env: &RpcServiceEnvironment) -> Result<PreappliedBlock, failure::Error> {
+    let request = JsonRpcRequest {
+        body: json!({ "protocol_data": protocol_data, "operations": operations }).to_string(),
+        context_path: format!("/chains/main/blocks/head/helpers/preapply/block?timestamp={}", timestamp),
+    };
+    let response = protocol::preapply_block("main", "head", request, env)?;
+    Ok(serde_json::from_value(response)?)
+}
+
+/// Find proof of work nonce for the block, returns protocol data without the signature.
+///
+/// Header is encoded just once, only the nonce bytes are changed in the encoded header.
+fn find_proof_of_work(shell_header: &PreappliedShellHeader, priority: u16, seed_nonce_hash: &Option<Vec<u8>>, threshold: u64) -> Result<Vec<u8>, failure::Error> {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&priority.to_be_bytes());
+    contents.extend_from_slice(&0u64.to_be_bytes());
+    match seed_nonce_hash {
+        Some(seed_nonce_hash) => {
+            contents.push(0xff);
+            contents.extend_from_slice(seed_nonce_hash);
+        }
+        None => contents.push(0x00),
+    }
+
+    // proof of work is computed from the header with zero signature
+    let mut protocol_data = contents.clone();
+    protocol_data.extend_from_slice(&[0; SIGNATURE_LENGTH]);
+    let mut encoded = shell_header.to_block_header(protocol_data.clone())?.as_bytes()?;
+    if !encoded.ends_with(&protocol_data) {
+        bail!("Protocol data is not at the end of the encoded block header");
+    }
+    let nonce_offset = encoded.len() - protocol_data.len() + PROOF_OF_WORK_NONCE_OFFSET;
+
+    for proof_of_work_nonce in 0u64.. {
+        let nonce = proof_of_work_nonce.to_be_bytes();
+        encoded[nonce_offset..nonce_offset + nonce.len()].copy_from_slice(&nonce);
+
+        let stamp = blake2b::digest_256(&encoded);
+        let mut stamp_prefix = [0; 8];
+        stamp_prefix.copy_from_slice(&stamp[0..8]);
+        if u64::from_be_bytes(stamp_prefix) <= threshold {
+            contents[PROOF_OF_WORK_NONCE_OFFSET..PROOF_OF_WORK_NONCE_OFFSET + nonce.len()].copy_from_slice(&nonce);
+            return Ok(contents);
+        }
+    }
+    bail!("Proof of work nonce was not found")
+}
+
+/// Sign the block header with `protocol_data` (without signature), returns signed block header
+fn sign_block_header(shell_header: &PreappliedShellHeader, protocol_data: Vec<u8>, signing_key: &SigningKey, chain_id: &ChainId) -> Result<BlockHeader, failure::Error> {
+    let unsigned = shell_header.to_block_header(protocol_data.clone())?;
+    let signature = signing_key.sign(&block_header_watermark(chain_id), &unsigned.as_bytes()?);
+
+    let mut signed = protocol_data;
+    signed.extend(signature);
+    shell_header.to_block_header(signed)
+}
+
+fn zero_signature() -> String {
+    HashType::SignatureEd25519.bytes_to_string(&[0; 64])
+}
+
+fn decode_fitness(fitness: &[String]) -> Result<Vec<Vec<u8>>, failure::Error> {
+    Ok(fitness.iter().map(hex::decode).collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Binary form of the fitness (dynamic list of dynamic bytes)
+fn encode_fitness(fitness: &[Vec<u8>]) -> Vec<u8> {
+    let mut elements = Vec::new();
+    for element in fitness {
+        elements.extend_from_slice(&(element.len() as u32).to_be_bytes());
+        elements.extend_from_slice(element);
+    }
+    let mut encoded = (elements.len() as u32).to_be_bytes().to_vec();
+    encoded.extend(elements);
+    encoded
+}
+
+/// Binary form of the json value (`Data_encoding.json`), which is a dynamic bson document
+fn encode_json_as_binary(value: &Value) -> Result<Vec<u8>, failure::Error> {
+    let document = match value {
+        Value::Object(_) | Value::Array(_) => bson_document(value),
+        _ => bail!("Protocol parameters must be a json object"),
+    };
+    let mut encoded = (document.len() as u32).to_be_bytes().to_vec();
+    encoded.extend(document);
+    Ok(encoded)
+}
+
+/// Bson document made of the json object or array (array elements are keyed by their index)
+fn bson_document(value: &Value) -> Vec<u8> {
+    let elements: Vec<(String, &Value)> = match value {
+        Value::Object(object) => object.iter().map(|(key, value)| (key.clone(), value)).collect(),
+        Value::Array(array) => array.iter().enumerate().map(|(index, value)| (index.to_string(), value)).collect(),
+        _ => vec![],
+    };
+
+    let mut body = Vec::new();
+    for (key, value) in elements {
+        let (tag, encoded) = match value {
+            Value::Number(number) => (0x01, number.as_f64().unwrap_or_default().to_le_bytes().to_vec()),
+            Value::String(string) => {
+                let mut encoded = ((string.len() + 1) as i32).to_le_bytes().to_vec();
+                encoded.extend_from_slice(string.as_bytes());
+                encoded.push(0);
+                (0x02, encoded)
+            }
+            Value::Object(_) => (0x03, bson_document(value)),
+            Value::Array(_) => (0x04, bson_document(value)),
+            Value::Bool(boolean) => (0x08, vec![*boolean as u8]),
+            Value::Null => (0x0a, vec![]),
+        };
+        body.push(tag);
+        body.extend_from_slice(key.as_bytes());
+        body.push(0);
+        body.extend(encoded);
+    }
+
+    // document size includes the size itself and the trailing zero
+    let mut document = ((body.len() + 5) as i32).to_le_bytes().to_vec();
+    document.extend(body);
+    document.push(0);
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_fitness() -> Result<(), failure::Error> {
+        let fitness = decode_fitness(&["01".to_string(), "0000000000000001".to_string()])?;
+        let expected = hex::decode(concat!(
+            "00000011", // dynamic size
+            "00000001", "01",
+            "00000008", "0000000000000001",
+        ))?;
+        assert_eq!(expected, encode_fitness(&fitness));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_json_as_bson() -> Result<(), failure::Error> {
+        let encoded = encode_json_as_binary(&json!({ "a": "b", "l": [true], "n": 1 }))?;
+        let expected = hex::decode(concat!(
+            "00000025", // dynamic size
+            "25000000", // document size
+            "026100", "02000000", "6200", // "a": "b"
+            "046c00", "09000000", "083000", "01", "00", // "l": [true]
+            "016e00", "000000000000f03f", // "n": 1.0
+            "00",
+        ))?;
+        assert_eq!(expected, encoded);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_proof_of_work() -> Result<(), failure::Error> {
+        let shell_header = PreappliedShellHeader {
+            level: 2,
+            proto: 1,
+            predecessor: HashType::BlockHash.bytes_to_string(&[1; 32]),
+            timestamp: "2020-08-24T10:00:00Z".to_string(),
+            validation_pass: 4,
+            operations_hash: HashType::OperationListListHash.bytes_to_string(&[2; 32]),
+            fitness: vec!["01".to_string(), "0000000000000002".to_string()],
+            context: HashType::ContextHash.bytes_to_string(&[3; 32]),
+        };
+        let threshold = u64::MAX >> 6;
+        let contents = find_proof_of_work(&shell_header, 0, &Some(vec![4; 32]), threshold)?;
+        assert_eq!(2 + 8 + 1 + 32, contents.len());
+
+        // stamp of the header built from the found contents satisfies the threshold
+        let mut protocol_data = contents.clone();
+        protocol_data.extend_from_slice(&[0; SIGNATURE_LENGTH]);
+        let stamp = blake2b::digest_256(&shell_header.to_block_header(protocol_data)?.as_bytes()?);
+        let mut stamp_prefix = [0; 8];
+        stamp_prefix.copy_from_slice(&stamp[0..8]);
+        assert!(u64::from_be_bytes(stamp_prefix) <= threshold);
+
+        // disabled proof of work accepts the first nonce
+        assert_eq!(&[0; 8], &find_proof_of_work(&shell_header, 0, &None, u64::MAX)?[2..10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_baking_delay() -> Result<(), failure::Error> {
+        let now = 1_600_000_000;
+        assert_eq!(Duration::from_secs(0), baking_delay(now - 10, now)?);
+        assert_eq!(Duration::from_secs(0), baking_delay(now, now)?);
+        assert_eq!(Duration::from_secs(30), baking_delay(now + 30, now)?);
+        assert_eq!(MAX_BAKING_DELAY, baking_delay(now + MAX_BAKING_DELAY.as_secs() as i64, now)?);
+        assert!(baking_delay(now + MAX_BAKING_DELAY.as_secs() as i64 + 1, now).is_err());
+        assert!(baking_delay(now + 86400, now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation_pass_of_operation_kind() {
+        assert_eq!(Some(0), validation_pass_of_operation_kind("endorsement"));
+        assert_eq!(Some(2), validation_pass_of_operation_kind("seed_nonce_revelation"));
+        assert_eq!(Some(3), validation_pass_of_operation_kind("transaction"));
+        assert_eq!(None, validation_pass_of_operation_kind("unknown"));
+    }
+}
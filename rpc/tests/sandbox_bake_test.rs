@@ -0,0 +1,59 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! End-to-end test of the sandbox block production, runs against the tezedge node started with
+//! `--network=sandbox` and `--sandbox-baker-secret-key` of the first bootstrap account (see `light_node/etc/tezedge_sandbox`).
+
+use std::env;
+
+use bytes::buf::BufExt;
+use hyper::{Body, Client, Method, Request};
+use serde_json::{json, Value};
+
+/// Carthage protocol activated in the sandbox
+const PROTOCOL: &str = "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb";
+/// Secret key of the genesis activator of the sandbox (`edpkuSLWfVU1Vq7Jg9FucPyKmma6otcMHac9zG4oU1KMHSTBpJuGQ2`)
+const ACTIVATOR_SECRET_KEY: &str = "edsk31vznjHSSpGExDMHYASz45VZqXN4DPxvsa4hAyY8dHM28cZzp6";
+
+#[ignore]
+#[tokio::test]
+async fn test_sandbox_activate_protocol_and_bake_block() {
+    let protocol_parameters: Value = serde_json::from_str(include_str!("../../light_node/etc/tezedge_sandbox/006-carthage-protocol-parameters.json"))
+        .expect("Failed to parse protocol parameters");
+
+    let activated = rpc_call(Method::POST, "dev/sandbox/activate_protocol", Some(json!({
+        "protocol": PROTOCOL,
+        "protocol_parameters": protocol_parameters,
+        "secret_key": ACTIVATOR_SECRET_KEY,
+    }))).await;
+    assert_eq!(1, activated["level"], "Unexpected activation result: {}", activated);
+
+    // block is baked by the configured sandbox baker with its best priority
+    let baked = rpc_call(Method::POST, "dev/sandbox/bake_block", Some(json!({}))).await;
+    assert_eq!(2, baked["level"], "Unexpected baking result: {}", baked);
+
+    let head = rpc_call(Method::GET, "chains/main/blocks/head/header", None).await;
+    assert_eq!(baked["block_hash"], head["hash"]);
+    assert_eq!(2, head["level"]);
+    assert_eq!(PROTOCOL, head["protocol"]);
+}
+
+async fn rpc_call(method: Method, rpc_path: &str, body: Option<Value>) -> Value {
+    let url = format!("{}/{}", tezedge_node_rpc_context_root(), rpc_path);
+    let request = Request::builder()
+        .method(method)
+        .uri(&url)
+        .header("content-type", "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .expect("Failed to build request");
+
+    let response = Client::new().request(request).await
+        .unwrap_or_else(|e| panic!("Request url: {:?} failed: {}, in the case of network or connection error, please, check rpc/README.md for CONTEXT_ROOT configurations", url, e));
+    let body = hyper::body::aggregate(response.into_body()).await.expect("Failed to read response body");
+    serde_json::from_reader(&mut body.reader()).expect("Response is not a valid json")
+}
+
+fn tezedge_node_rpc_context_root() -> String {
+    env::var("TEZEDGE_NODE_RPC_CONTEXT_ROOT")
+        .unwrap_or_else(|_| "http://localhost:18732".to_string())
+}
@@ -4,32 +4,67 @@
 //! Sends blocks to the `protocol_runner`.
 //! This actor is responsible for correct applying of blocks with Tezos protocol in context
 //! This actor is aslo responsible for correct initialization of genesis in storage.
-
+//!
+//! Blocks are applied in a pipeline:
+//! - prefetch thread loads next complete blocks (header and operations) from storage,
+//! - block applier thread sends blocks to the protocol,
+//! - store thread stores results of the application and notifies other actors.
+//!
+//! So storage reads and writes are not on the critical path of the block application.
+//!
+//! Block injected together with all its operations is applied directly by the block applier thread
+//! and it is stored only if the protocol accepted it. Block rejected by the protocol is marked as invalid
+//! and it is skipped, so the block applier thread does not fail on it again and again.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, sync_channel, SyncSender};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use failure::{Error, Fail};
+use failure::{Error, Fail, format_err};
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
 use crypto::hash::{BlockHash, HashType};
 use storage::{BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, initialize_storage_with_genesis_block, OperationsMetaStorage, OperationsStorage, OperationsStorageReader, StorageError, StorageInitInfo, store_applied_block_result, store_commit_genesis_result};
+use storage::block_meta_storage::Meta;
+use storage::BlockHeaderWithHash;
 use storage::persistent::PersistentStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
-use tezos_api::ffi::ApplyBlockRequest;
+use tezos_api::ffi::{ApplyBlockRequest, ApplyBlockResponse};
+use tezos_messages::p2p::binary_message::MessageHash;
+use tezos_messages::p2p::encoding::prelude::{Operation, OperationsForBlocksMessage};
 use tezos_wrapper::service::{IpcCmdServer, ProtocolController, ProtocolServiceError};
 
-use crate::shell_channel::{BlockApplied, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::shell_channel::{AllBlockOperationsReceived, BlockApplied, BlockReceived, dispatch_inject_block_result, InjectBlock, InjectBlockError, InjectBlockOneshotResultCallback, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::state::block_state::BlockchainState;
+use crate::state::operations_state::OperationsState;
 use crate::subscription::subscribe_to_shell_events;
+use crate::supervision::Supervisor;
+use crate::validation;
 
 /// This command triggers feeding of completed blocks to the tezos protocol
 #[derive(Clone, Debug)]
 pub struct FeedChainToProtocol;
 
+/// How many complete blocks are prefetched from storage ahead of the applied block
+const BLOCK_PREFETCH_QUEUE_SIZE: usize = 16;
+/// How many applied blocks can wait for storing of their results
+const BLOCK_STORE_QUEUE_SIZE: usize = 16;
+/// How often are block application statistics logged
+const FEED_STATS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
+/// Callbacks of injected blocks waiting for the result of the application
+type InjectedBlockCallbacks = Arc<Mutex<HashMap<BlockHash, InjectBlockOneshotResultCallback>>>;
+/// Blocks injected together with all their operations, waiting for the validation by the protocol
+type InjectedBlocks = Arc<Mutex<VecDeque<InjectBlock>>>;
+/// Blocks rejected by the protocol, they are never applied again
+type InvalidBlocks = Arc<Mutex<HashSet<BlockHash>>>;
 
 /// Feeds blocks and operations to the tezos protocol (ocaml code).
 #[actor(FeedChainToProtocol, ShellChannelMsg)]
@@ -40,6 +75,10 @@ pub struct ChainFeeder {
     block_applier_run: Arc<AtomicBool>,
     /// Block applier thread
     block_applier_thread: SharedJoinHandle,
+    /// Injected blocks waiting for the result of the application
+    injected_block_callbacks: InjectedBlockCallbacks,
+    /// Complete injected blocks waiting for the block applier thread
+    injected_blocks: InjectedBlocks,
 }
 
 /// Reference to [chain feeder](ChainFeeder) actor
@@ -54,6 +93,7 @@ impl ChainFeeder {
     /// This actor spawns a new thread in which it will periodically monitor [`persistent_storage`](PersistentStorage).
     /// Purpose of the monitoring thread is to detect whether it is possible to apply blocks received by the p2p layer.
     /// If the block can be applied, it is sent via IPC to the `protocol_runner`, where it is then applied by calling a tezos ffi.
+    /// The thread is supervised by [`supervisor`](Supervisor), so it is restarted on failure.
     pub fn actor(
         sys: &impl ActorRefFactory,
         shell_channel: ShellChannelRef,
@@ -61,44 +101,39 @@ impl ChainFeeder {
         init_storage_data: &StorageInitInfo,
         tezos_env: &TezosEnvironmentConfiguration,
         ipc_server: IpcCmdServer,
+        supervisor: &Supervisor,
         log: Logger) -> Result<ChainFeederRef, CreateError> {
         let apply_block_run = Arc::new(AtomicBool::new(true));
+        let injected_block_callbacks = Arc::new(Mutex::new(HashMap::new()));
+        let injected_blocks = Arc::new(Mutex::new(VecDeque::new()));
         let block_applier_thread = {
             let apply_block_run = apply_block_run.clone();
+            let injected_block_callbacks = injected_block_callbacks.clone();
+            let injected_blocks = injected_blocks.clone();
+            // invalid blocks are remembered across restarts of the block applier thread
+            let invalid_blocks = Arc::new(Mutex::new(HashSet::new()));
             let shell_channel = shell_channel.clone();
             let persistent_storage = persistent_storage.clone();
             let init_storage_data = init_storage_data.clone();
             let tezos_env = tezos_env.clone();
 
-            thread::spawn(move || -> Result<(), Error>{
-                let mut block_storage = BlockStorage::new(&persistent_storage);
-                let mut block_meta_storage = BlockMetaStorage::new(&persistent_storage);
-                let operations_storage = OperationsStorage::new(&persistent_storage);
-                let mut operations_meta_storage = OperationsMetaStorage::new(&persistent_storage);
-                let mut ipc_server = ipc_server;
-
-                while apply_block_run.load(Ordering::Acquire) {
-                    match ipc_server.accept() {
-                        Ok(protocol_controller) =>
-                            match feed_chain_to_protocol(&tezos_env, &init_storage_data, &apply_block_run, &shell_channel, &mut block_storage, &mut block_meta_storage, &operations_storage, &mut operations_meta_storage, protocol_controller, &log) {
-                                Ok(()) => debug!(log, "Feed chain to protocol finished"),
-                                Err(err) => {
-                                    if apply_block_run.load(Ordering::Acquire) {
-                                        warn!(log, "Error while feeding chain to protocol"; "reason" => format!("{:?}", err));
-                                    }
-                                }
-                            }
-                        Err(err) => warn!(log, "No connection from protocol runner"; "reason" => format!("{:?}", err)),
-                    }
-                }
+            let mut block_storage = BlockStorage::new(&persistent_storage);
+            let mut block_meta_storage = BlockMetaStorage::new(&persistent_storage);
+            let mut operations_meta_storage = OperationsMetaStorage::new(&persistent_storage);
+            let mut ipc_server = ipc_server;
 
+            supervisor.spawn("chain-feeder", apply_block_run.clone(), log.clone(), move || {
+                let protocol_controller = ipc_server.accept()
+                    .map_err(|err| format_err!("No connection from protocol runner, reason: {:?}", err))?;
+                feed_chain_to_protocol(&tezos_env, &init_storage_data, &apply_block_run, &shell_channel, &injected_block_callbacks, &injected_blocks, &invalid_blocks, &persistent_storage, &mut block_storage, &mut block_meta_storage, &mut operations_meta_storage, protocol_controller, &log)?;
+                debug!(log, "Feed chain to protocol finished");
                 Ok(())
             })
         };
 
         let myself = sys.actor_of_props::<ChainFeeder>(
             ChainFeeder::name(),
-            Props::new_args((shell_channel, apply_block_run, Arc::new(Mutex::new(Some(block_applier_thread))))),
+            Props::new_args((shell_channel, apply_block_run, Arc::new(Mutex::new(Some(block_applier_thread))), injected_block_callbacks, injected_blocks)),
         )?;
 
         Ok(myself)
@@ -112,22 +147,47 @@ impl ChainFeeder {
 
     fn process_shell_channel_message(&mut self, _ctx: &Context<ChainFeederMsg>, msg: ShellChannelMsg) -> Result<(), Error> {
         match msg {
+            ShellChannelMsg::InjectBlock(inject_data) => {
+                if inject_data.operations.is_some() {
+                    // complete block is validated by the protocol before it is stored
+                    self.injected_blocks.lock().unwrap().push_back(inject_data);
+                } else if let Some(result_callback) = inject_data.result_callback {
+                    let block_hash = inject_data.block_header.message_hash()?;
+                    let mut injected_block_callbacks = self.injected_block_callbacks.lock().unwrap();
+                    forget_finished_callbacks(&mut injected_block_callbacks);
+                    injected_block_callbacks.insert(block_hash, result_callback);
+                }
+                self.wake_up_block_applier();
+            }
+            ShellChannelMsg::AllBlockOperationsReceived(_) => {
+                self.wake_up_block_applier();
+            }
             ShellChannelMsg::ShuttingDown(_) => {
+                // in-flight block is finished, its result is stored and block applier thread stops
                 self.block_applier_run.store(false, Ordering::Release);
+                self.wake_up_block_applier();
             }
             _ => ()
         }
 
         Ok(())
     }
+
+    fn wake_up_block_applier(&self) {
+        if let Some(join_handle) = self.block_applier_thread.lock().unwrap().as_ref() {
+            join_handle.thread().unpark();
+        }
+    }
 }
 
-impl ActorFactoryArgs<(ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle)> for ChainFeeder {
-    fn create_args((shell_channel, block_applier_run, block_applier_thread): (ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle)) -> Self {
+impl ActorFactoryArgs<(ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, InjectedBlockCallbacks, InjectedBlocks)> for ChainFeeder {
+    fn create_args((shell_channel, block_applier_run, block_applier_thread, injected_block_callbacks, injected_blocks): (ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, InjectedBlockCallbacks, InjectedBlocks)) -> Self {
         ChainFeeder {
             shell_channel,
             block_applier_run,
             block_applier_thread,
+            injected_block_callbacks,
+            injected_blocks,
         }
     }
 }
@@ -177,9 +237,7 @@ impl Receive<FeedChainToProtocol> for ChainFeeder {
     type Msg = ChainFeederMsg;
 
     fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: FeedChainToProtocol, _sender: Sender) {
-        if let Some(join_handle) = self.block_applier_thread.lock().unwrap().as_ref() {
-            join_handle.thread().unpark();
-        }
+        self.wake_up_block_applier();
     }
 }
 
@@ -196,6 +254,10 @@ pub enum FeedChainError {
     ProtocolServiceError {
         error: ProtocolServiceError
     },
+    #[fail(display = "Block application pipeline failed! Reason: {}", reason)]
+    PipelineError {
+        reason: String
+    },
 }
 
 impl From<StorageError> for FeedChainError {
@@ -213,17 +275,18 @@ impl From<ProtocolServiceError> for FeedChainError {
 fn feed_chain_to_protocol(
     tezos_env: &TezosEnvironmentConfiguration,
     init_storage_data: &StorageInitInfo,
-    apply_block_run: &AtomicBool,
+    apply_block_run: &Arc<AtomicBool>,
     shell_channel: &ShellChannelRef,
+    injected_block_callbacks: &InjectedBlockCallbacks,
+    injected_blocks: &InjectedBlocks,
+    invalid_blocks: &InvalidBlocks,
+    persistent_storage: &PersistentStorage,
     block_storage: &mut BlockStorage,
     block_meta_storage: &mut BlockMetaStorage,
-    operations_storage: &OperationsStorage,
     operations_meta_storage: &mut OperationsMetaStorage,
     protocol_controller: ProtocolController,
     log: &Logger,
 ) -> Result<(), FeedChainError> {
-    let block_hash_encoding = HashType::BlockHash;
-
     // at first we initialize protocol runtime and ffi context
     initialize_protocol_context(
         &apply_block_run,
@@ -246,110 +309,551 @@ fn feed_chain_to_protocol(
         }
     };
 
+    let stats = Arc::new(Mutex::new(FeedStats::new()));
+
     // now we can start applying block
     while apply_block_run.load(Ordering::Acquire) {
-        match block_meta_storage.get(&current_head_hash)? {
-            Some(mut current_head_meta) => {
-                if current_head_meta.is_applied() {
-                    // Current head is already applied, so we should move to successor
-                    // or in case no successor is available do nothing.
-                    match current_head_meta.successor() {
-                        Some(successor_hash) => {
-                            current_head_hash = successor_hash.clone();
-                            continue;
-                        }
-                        None => ( /* successor is not yet available, we do nothing for now */ )
-                    }
-                } else {
-                    // Current head is not applied, so we should apply it now.
-                    // But first let's fetch current head data from block storage..
-                    match block_storage.get(&current_head_hash)? {
-                        Some(current_head) => {
-                            // Good, we have block data available, let's' look is we have all operations
-                            // available. If yes we will apply them. If not, we will do nothing.
-                            if operations_meta_storage.is_complete(&current_head.hash)? {
-                                debug!(log, "Applying block"; "block_header_hash" => block_hash_encoding.bytes_to_string(&current_head.hash));
-                                let operations = operations_storage.get_operations(&current_head_hash)?
-                                    .drain(..)
-                                    .map(Some)
-                                    .collect();
-
-                                // apply block and it's operations
-                                let (predecessor, predecessor_additional_data) = match block_storage.get_with_additional_data(&current_head.header.predecessor())? {
-                                    None => {
-                                        warn!(log, "No data was found in database for the predecessor, so we try to apply it first"; "predecessor_block_header_hash" => block_hash_encoding.bytes_to_string(&current_head.header.predecessor()));
-                                        current_head_hash = current_head.header.predecessor().clone();
-                                        continue;
-                                    }
-                                    Some(predecesor_data) => predecesor_data
-                                };
-
-                                let apply_block_result = protocol_controller.apply_block(
-                                    ApplyBlockRequest {
-                                        chain_id: init_storage_data.chain_id.clone(),
-                                        block_header: (&*current_head.header).clone(),
-                                        pred_header: (&*predecessor.header).clone(),
-                                        operations: ApplyBlockRequest::convert_operations(&operations),
-                                        max_operations_ttl: predecessor_additional_data.max_operations_ttl() as i32,
-                                    }
-                                )?;
-                                debug!(
-                                    log,
-                                    "Block was applied";
-                                    "block_header_hash" => block_hash_encoding.bytes_to_string(&current_head.hash),
-                                    "context_hash" => HashType::ContextHash.bytes_to_string(&apply_block_result.context_hash),
-                                    "validation_result_message" => &apply_block_result.validation_result_message
-                                );
-
-
-                                // store result
-                                let (block_json_data, _) = store_applied_block_result(
-                                    block_storage,
-                                    block_meta_storage,
-                                    &current_head.hash,
-                                    apply_block_result,
-                                    &mut current_head_meta,
-                                )?;
-
-                                // notify listeners
-                                if apply_block_run.load(Ordering::Acquire) {
-                                    // notify others that the block successfully applied
-                                    shell_channel.tell(
-                                        Publish {
-                                            msg: BlockApplied::new(current_head, block_json_data).into(),
-                                            topic: ShellChannelTopic::ShellEvents.into(),
-                                        }, None);
-                                }
-
-                                // Current head is already applied, so we should move to successor
-                                // or in case no successor is available do nothing.
-                                match current_head_meta.successor() {
-                                    Some(successor_hash) => {
-                                        current_head_hash = successor_hash.clone();
-                                        continue;
-                                    }
-                                    None => ( /* successor is not yet available, we do nothing for now */ )
-                                }
-                            } else {
-                                // we don't have all operations available, do nothing
-                            }
-                        }
-                        None => ( /* it's possible that data was not yet written do the storage, so don't panic! */ )
-                    }
+        // injected blocks go first, because their injectors are waiting for the result
+        loop {
+            let inject_data = match injected_blocks.lock().unwrap().pop_front() {
+                Some(inject_data) => inject_data,
+                None => break,
+            };
+            if let Some(block_hash) = apply_injected_block(init_storage_data, shell_channel, persistent_storage, &protocol_controller, inject_data, log)? {
+                current_head_hash = block_hash;
+            }
+        }
+
+        let progress = apply_blocks_pipelined(
+            init_storage_data,
+            apply_block_run,
+            shell_channel,
+            injected_block_callbacks,
+            invalid_blocks,
+            persistent_storage,
+            &protocol_controller,
+            &stats,
+            current_head_hash,
+            log,
+        )?;
+        {
+            let mut stats = stats.lock().unwrap();
+            if stats.should_log() {
+                stats.log_and_reset(log);
+            }
+        }
+
+        match progress {
+            FeedProgress::ApplyPredecessor(predecessor_hash) => {
+                warn!(log, "No data was found in database for the predecessor, so we try to apply it first"; "predecessor_block_header_hash" => HashType::BlockHash.bytes_to_string(&predecessor_hash));
+                current_head_hash = predecessor_hash;
+            }
+            FeedProgress::Waiting(block_hash) => {
+                current_head_hash = block_hash;
+
+                // This should be hit only in case that the current branch is applied
+                // and no successor was available to continue the apply cycle. In that case
+                // this thread will be stopped and will wait until it's waked again.
+                thread::park();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Where to continue after the pipeline run
+enum FeedProgress {
+    /// Nothing to apply for now, continue from this block, when woken up
+    Waiting(BlockHash),
+    /// Predecessor was not applied yet, so it should be applied first
+    ApplyPredecessor(BlockHash),
+}
+
+/// Message from the prefetch thread
+enum PrefetchedBlock {
+    /// Complete block ready to be applied
+    Block {
+        block: BlockHeaderWithHash,
+        meta: Meta,
+        operations: Vec<Option<OperationsForBlocksMessage>>,
+    },
+    /// Prefetching stopped at this block (block is not complete or has no successor yet)
+    Stopped(BlockHash),
+}
+
+/// Result of the block application, which should be stored
+struct AppliedBlock {
+    block: BlockHeaderWithHash,
+    meta: Meta,
+    apply_block_result: ApplyBlockResponse,
+}
+
+/// Applies all available blocks starting from `start_block_hash`.
+///
+/// Prefetch and store threads live just during one run, when the run finishes, all applied blocks are stored.
+fn apply_blocks_pipelined(
+    init_storage_data: &StorageInitInfo,
+    apply_block_run: &Arc<AtomicBool>,
+    shell_channel: &ShellChannelRef,
+    injected_block_callbacks: &InjectedBlockCallbacks,
+    invalid_blocks: &InvalidBlocks,
+    persistent_storage: &PersistentStorage,
+    protocol_controller: &ProtocolController,
+    stats: &Arc<Mutex<FeedStats>>,
+    start_block_hash: BlockHash,
+    log: &Logger,
+) -> Result<FeedProgress, FeedChainError> {
+    let (prefetch_sender, prefetch_receiver) = sync_channel(BLOCK_PREFETCH_QUEUE_SIZE);
+    let (store_sender, store_receiver) = sync_channel(BLOCK_STORE_QUEUE_SIZE);
+
+    let prefetch_thread = {
+        let apply_block_run = apply_block_run.clone();
+        let persistent_storage = persistent_storage.clone();
+        let invalid_blocks = invalid_blocks.clone();
+        let stats = stats.clone();
+        let start_block_hash = start_block_hash.clone();
+        spawn_pipeline_thread("chain-feeder-prefetch", move || prefetch_blocks(&apply_block_run, &persistent_storage, &invalid_blocks, &stats, start_block_hash, prefetch_sender))?
+    };
+    let store_thread = {
+        let apply_block_run = apply_block_run.clone();
+        let persistent_storage = persistent_storage.clone();
+        let shell_channel = shell_channel.clone();
+        let injected_block_callbacks = injected_block_callbacks.clone();
+        let stats = stats.clone();
+        spawn_pipeline_thread("chain-feeder-store", move || store_applied_blocks(&apply_block_run, &persistent_storage, &shell_channel, &injected_block_callbacks, &stats, store_receiver))?
+    };
+
+    let result = apply_prefetched_blocks(init_storage_data, apply_block_run, injected_block_callbacks, invalid_blocks, persistent_storage, protocol_controller, stats, start_block_hash, &prefetch_receiver, &store_sender, log);
+
+    // stop prefetching and wait until all applied blocks are stored
+    drop(prefetch_receiver);
+    drop(store_sender);
+    let prefetch_result = join_pipeline_thread(prefetch_thread);
+    let store_result = join_pipeline_thread(store_thread);
+
+    let progress = result?;
+    prefetch_result?;
+    store_result?;
+    Ok(progress)
+}
+
+fn apply_prefetched_blocks(
+    init_storage_data: &StorageInitInfo,
+    apply_block_run: &AtomicBool,
+    injected_block_callbacks: &InjectedBlockCallbacks,
+    invalid_blocks: &InvalidBlocks,
+    persistent_storage: &PersistentStorage,
+    protocol_controller: &ProtocolController,
+    stats: &Mutex<FeedStats>,
+    start_block_hash: BlockHash,
+    prefetch_receiver: &Receiver<PrefetchedBlock>,
+    store_sender: &SyncSender<AppliedBlock>,
+    log: &Logger,
+) -> Result<FeedProgress, FeedChainError> {
+    let block_hash_encoding = HashType::BlockHash;
+    let block_storage = BlockStorage::new(persistent_storage);
+
+    // predecessor header and its max_operations_ttl are kept in memory, because its result could not be stored yet
+    let mut predecessor: Option<(BlockHeaderWithHash, i32)> = None;
+    let mut progress = FeedProgress::Waiting(start_block_hash);
+
+    for prefetched in prefetch_receiver.iter() {
+        if !apply_block_run.load(Ordering::Acquire) {
+            break;
+        }
+
+        let (block, meta, operations) = match prefetched {
+            PrefetchedBlock::Block { block, meta, operations } => (block, meta, operations),
+            PrefetchedBlock::Stopped(block_hash) => {
+                progress = FeedProgress::Waiting(block_hash);
+                break;
+            }
+        };
+
+        let (predecessor_header, max_operations_ttl) = match predecessor.take() {
+            Some((predecessor_header, max_operations_ttl)) if &predecessor_header.hash == block.header.predecessor() => (predecessor_header, max_operations_ttl),
+            _ => match block_storage.get_with_additional_data(&block.header.predecessor())? {
+                Some((predecessor_header, additional_data)) => (predecessor_header, additional_data.max_operations_ttl() as i32),
+                None => {
+                    progress = FeedProgress::ApplyPredecessor(block.header.predecessor().clone());
+                    break;
                 }
             }
-            None => warn!(log, "No meta info record was found in database for the current head"; "block_header_hash" => block_hash_encoding.bytes_to_string(&current_head_hash))
+        };
+
+        debug!(log, "Applying block"; "block_header_hash" => block_hash_encoding.bytes_to_string(&block.hash));
+        let apply_started = Instant::now();
+        let apply_block_result = match protocol_controller.apply_block(
+            ApplyBlockRequest {
+                chain_id: init_storage_data.chain_id.clone(),
+                block_header: (&*block.header).clone(),
+                pred_header: (&*predecessor_header.header).clone(),
+                operations: ApplyBlockRequest::convert_operations(&operations),
+                max_operations_ttl,
+            }
+        ) {
+            Ok(apply_block_result) => apply_block_result,
+            Err(ProtocolServiceError::ProtocolError { reason }) => {
+                // block is invalid, so we skip it and wait for another branch
+                warn!(log, "Block was rejected by the protocol"; "block_header_hash" => block_hash_encoding.bytes_to_string(&block.hash), "reason" => format!("{}", reason));
+                invalid_blocks.lock().unwrap().insert(block.hash.clone());
+                dispatch_injected_block_result(injected_block_callbacks, &block.hash, Err(InjectBlockError { reason: format!("{}", reason) }));
+                progress = FeedProgress::Waiting(predecessor_header.hash);
+                break;
+            }
+            Err(e) => {
+                dispatch_injected_block_result(injected_block_callbacks, &block.hash, Err(InjectBlockError { reason: format!("{}", e) }));
+                return Err(e.into());
+            }
+        };
+        debug!(
+            log,
+            "Block was applied";
+            "block_header_hash" => block_hash_encoding.bytes_to_string(&block.hash),
+            "context_hash" => HashType::ContextHash.bytes_to_string(&apply_block_result.context_hash),
+            "validation_result_message" => &apply_block_result.validation_result_message
+        );
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.apply += apply_started.elapsed();
+            stats.blocks += 1;
+            stats.level = block.header.level();
+            if stats.should_log() {
+                stats.log_and_reset(log);
+            }
         }
 
-        // This should be hit only in case that the current branch is applied
-        // and no successor was available to continue the apply cycle. In that case
-        // this thread will be stopped and will wait until it's waked again.
-        thread::park();
+        predecessor = Some((block.clone(), apply_block_result.max_operations_ttl));
+        progress = FeedProgress::Waiting(block.hash.clone());
+
+        // store result asynchronously
+        if store_sender.send(AppliedBlock { block, meta, apply_block_result }).is_err() {
+            // store thread failed, error is resolved on join
+            break;
+        }
+    }
+
+    Ok(progress)
+}
+
+/// Loads complete blocks from storage (following successors) and sends them to the block applier
+fn prefetch_blocks(
+    apply_block_run: &AtomicBool,
+    persistent_storage: &PersistentStorage,
+    invalid_blocks: &InvalidBlocks,
+    stats: &Mutex<FeedStats>,
+    mut current_head_hash: BlockHash,
+    prefetch_sender: SyncSender<PrefetchedBlock>,
+) -> Result<(), FeedChainError> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let block_meta_storage = BlockMetaStorage::new(persistent_storage);
+    let operations_storage = OperationsStorage::new(persistent_storage);
+    let operations_meta_storage = OperationsMetaStorage::new(persistent_storage);
+
+    while apply_block_run.load(Ordering::Acquire) {
+        let load_started = Instant::now();
+        let current_head_meta = match block_meta_storage.get(&current_head_hash)? {
+            Some(current_head_meta) => current_head_meta,
+            None => break,
+        };
+
+        if !current_head_meta.is_applied() {
+            // Current head is not applied, so we should apply it now,
+            // but only if we have block data and all operations available.
+            let current_head = match block_storage.get(&current_head_hash)? {
+                Some(current_head) => current_head,
+                None => break, /* it's possible that data was not yet written do the storage, so don't panic! */
+            };
+            if !operations_meta_storage.is_complete(&current_head.hash)? {
+                break;
+            }
+            let operations = operations_storage.get_operations(&current_head_hash)?
+                .drain(..)
+                .map(Some)
+                .collect();
+            stats.lock().unwrap().load += load_started.elapsed();
+
+            let prefetched = PrefetchedBlock::Block {
+                block: current_head,
+                meta: current_head_meta.clone(),
+                operations,
+            };
+            if prefetch_sender.send(prefetched).is_err() {
+                // block applier finished
+                return Ok(());
+            }
+        }
+
+        // move to successor, or in case no valid successor is available stop for now
+        match current_head_meta.successor() {
+            Some(successor_hash) if !invalid_blocks.lock().unwrap().contains(successor_hash) => current_head_hash = successor_hash.clone(),
+            _ => break,
+        }
+    }
+
+    // block applier could be already finished, so we dont care about the result
+    let _ = prefetch_sender.send(PrefetchedBlock::Stopped(current_head_hash));
+    Ok(())
+}
+
+/// Stores results of the applied blocks and notifies other actors
+fn store_applied_blocks(
+    apply_block_run: &AtomicBool,
+    persistent_storage: &PersistentStorage,
+    shell_channel: &ShellChannelRef,
+    injected_block_callbacks: &InjectedBlockCallbacks,
+    stats: &Mutex<FeedStats>,
+    store_receiver: Receiver<AppliedBlock>,
+) -> Result<(), FeedChainError> {
+    let mut block_storage = BlockStorage::new(persistent_storage);
+    let mut block_meta_storage = BlockMetaStorage::new(persistent_storage);
+
+    for AppliedBlock { block, mut meta, apply_block_result } in store_receiver.iter() {
+        let store_started = Instant::now();
+        let (block_json_data, _) = store_applied_block_result(
+            &mut block_storage,
+            &mut block_meta_storage,
+            &block.hash,
+            apply_block_result,
+            &mut meta,
+        )?;
+        stats.lock().unwrap().store += store_started.elapsed();
+        dispatch_injected_block_result(injected_block_callbacks, &block.hash, Ok(()));
+
+        // notify listeners
+        if apply_block_run.load(Ordering::Acquire) {
+            // notify others that the block successfully applied
+            shell_channel.tell(
+                Publish {
+                    msg: BlockApplied::new(block, block_json_data).into(),
+                    topic: ShellChannelTopic::ShellEvents.into(),
+                }, None);
+        }
     }
 
     Ok(())
 }
 
+/// Validates block injected together with all its operations by the protocol and stores it only if it was applied,
+/// so invalid injected block never gets to the storage (and to the block application pipeline).
+///
+/// Rejection of the block is sent to the injector, error is returned only if protocol runner or storage fails.
+/// Returns hash of the applied block.
+fn apply_injected_block(
+    init_storage_data: &StorageInitInfo,
+    shell_channel: &ShellChannelRef,
+    persistent_storage: &PersistentStorage,
+    protocol_controller: &ProtocolController,
+    inject_data: InjectBlock,
+    log: &Logger,
+) -> Result<Option<BlockHash>, FeedChainError> {
+    let InjectBlock { block_header, operations, force, result_callback } = inject_data;
+    let block = match BlockHeaderWithHash::new(block_header) {
+        Ok(block) => block,
+        Err(e) => {
+            dispatch_inject_block_result(&result_callback, Err(InjectBlockError { reason: format!("{}", e) }));
+            return Ok(None);
+        }
+    };
+
+    let block_operations = operations.unwrap_or_default();
+    let (predecessor, max_operations_ttl, operations) = match prevalidate_injected_block(init_storage_data, persistent_storage, &block, &block_operations, force) {
+        Ok(Some(prevalidated)) => prevalidated,
+        Ok(None) => {
+            // already applied block is not applied again
+            dispatch_inject_block_result(&result_callback, Ok(()));
+            return Ok(None);
+        }
+        Err(e) => {
+            dispatch_inject_block_result(&result_callback, Err(InjectBlockError { reason: format!("{}", e) }));
+            return Ok(None);
+        }
+    };
+
+    debug!(log, "Applying injected block"; "block_header_hash" => HashType::BlockHash.bytes_to_string(&block.hash));
+    let apply_block_result = match protocol_controller.apply_block(
+        ApplyBlockRequest {
+            chain_id: init_storage_data.chain_id.clone(),
+            block_header: (&*block.header).clone(),
+            pred_header: (&*predecessor.header).clone(),
+            operations: block_operations,
+            max_operations_ttl,
+        }
+    ) {
+        Ok(apply_block_result) => apply_block_result,
+        Err(ProtocolServiceError::ProtocolError { reason }) => {
+            warn!(log, "Injected block was rejected by the protocol"; "block_header_hash" => HashType::BlockHash.bytes_to_string(&block.hash), "reason" => format!("{}", reason));
+            dispatch_inject_block_result(&result_callback, Err(InjectBlockError { reason: format!("{}", reason) }));
+            return Ok(None);
+        }
+        Err(e) => {
+            dispatch_inject_block_result(&result_callback, Err(InjectBlockError { reason: format!("{}", e) }));
+            return Err(e.into());
+        }
+    };
+
+    // block is valid, so now it is stored the same way as a block received from peers
+    let mut block_storage = BlockStorage::new(persistent_storage);
+    let mut block_meta_storage = BlockMetaStorage::new(persistent_storage);
+    BlockchainState::new(persistent_storage, &init_storage_data.chain_id).process_block_header(&block, log.clone())?;
+    OperationsState::new(persistent_storage, &init_storage_data.chain_id).process_injected_block(&block, &operations)?;
+    let mut meta = block_meta_storage.get(&block.hash)?.ok_or(StorageError::MissingKey)?;
+    let (block_json_data, _) = store_applied_block_result(
+        &mut block_storage,
+        &mut block_meta_storage,
+        &block.hash,
+        apply_block_result,
+        &mut meta,
+    )?;
+    dispatch_inject_block_result(&result_callback, Ok(()));
+
+    // notify others that new block was received and applied
+    shell_channel.tell(
+        Publish {
+            msg: BlockReceived {
+                hash: block.hash.clone(),
+                level: block.header.level(),
+            }.into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+    shell_channel.tell(
+        Publish {
+            msg: AllBlockOperationsReceived {
+                hash: block.hash.clone(),
+                level: block.header.level(),
+            }.into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+    let block_hash = block.hash.clone();
+    shell_channel.tell(
+        Publish {
+            msg: BlockApplied::new(block, block_json_data).into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+
+    Ok(Some(block_hash))
+}
+
+/// Checks injected block before it is sent to the protocol.
+///
+/// Returns `None` if the block is already applied, otherwise predecessor of the block, its `max_operations_ttl`
+/// and operations of the block prepared for storing.
+fn prevalidate_injected_block(
+    init_storage_data: &StorageInitInfo,
+    persistent_storage: &PersistentStorage,
+    block: &BlockHeaderWithHash,
+    operations: &[Vec<Operation>],
+    force: bool,
+) -> Result<Option<(BlockHeaderWithHash, i32, Vec<OperationsForBlocksMessage>)>, Error> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let block_meta_storage = BlockMetaStorage::new(persistent_storage);
+
+    if block_meta_storage.is_applied(&block.hash)? {
+        return Ok(None);
+    }
+
+    BlockchainState::new(persistent_storage, &init_storage_data.chain_id).validate_block_header(block, None)?;
+
+    // injected block has to be better than our current head, unless the injection is forced
+    if !force {
+        if let Some((current_head_hash, _)) = block_meta_storage.load_current_head()? {
+            if let Some(current_head) = block_storage.get(&current_head_hash)? {
+                if validation::compare_fitness(block.header.fitness(), current_head.header.fitness()) != cmp::Ordering::Greater {
+                    return Err(format_err!("Fitness of the injected block is not greater than fitness of the current head"));
+                }
+            }
+        }
+    }
+
+    let (predecessor, additional_data) = block_storage.get_with_additional_data(block.header.predecessor())?
+        .ok_or_else(|| format_err!("Predecessor of the injected block is not applied"))?;
+    let operations = validation::operations_for_block_messages(&block.hash, &block.header, operations)?;
+
+    Ok(Some((predecessor, additional_data.max_operations_ttl() as i32, operations)))
+}
+
+fn spawn_pipeline_thread<F>(name: &str, f: F) -> Result<JoinHandle<Result<(), FeedChainError>>, FeedChainError>
+    where F: FnOnce() -> Result<(), FeedChainError> + Send + 'static
+{
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .map_err(|e| FeedChainError::PipelineError { reason: format!("failed to spawn thread {}: {}", name, e) })
+}
+
+fn join_pipeline_thread(join_handle: JoinHandle<Result<(), FeedChainError>>) -> Result<(), FeedChainError> {
+    join_handle.join()
+        .map_err(|_| FeedChainError::PipelineError { reason: "pipeline thread panicked".to_string() })?
+}
+
+/// Statistics of the block application, time spent in each stage of the pipeline
+struct FeedStats {
+    /// Count of applied blocks
+    blocks: u64,
+    /// Level of the last applied block
+    level: i32,
+    /// Time spent by loading of blocks from storage
+    load: Duration,
+    /// Time spent by applying of blocks in protocol
+    apply: Duration,
+    /// Time spent by storing of the results
+    store: Duration,
+    started: Instant,
+}
+
+impl FeedStats {
+    fn new() -> Self {
+        FeedStats {
+            blocks: 0,
+            level: 0,
+            load: Duration::default(),
+            apply: Duration::default(),
+            store: Duration::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn should_log(&self) -> bool {
+        self.started.elapsed() >= FEED_STATS_LOG_INTERVAL
+    }
+
+    fn log_and_reset(&mut self, log: &Logger) {
+        if self.blocks > 0 {
+            let average_ms = |duration: Duration| duration.as_secs_f64() * 1000.0 / self.blocks as f64;
+            info!(log, "Blocks applied";
+                "blocks" => self.blocks,
+                "level" => self.level,
+                "blocks_per_sec" => format!("{:.2}", self.blocks as f64 / self.started.elapsed().as_secs_f64()),
+                "avg_load_ms" => format!("{:.2}", average_ms(self.load)),
+                "avg_apply_ms" => format!("{:.2}", average_ms(self.apply)),
+                "avg_store_ms" => format!("{:.2}", average_ms(self.store)));
+        }
+        *self = FeedStats::new();
+    }
+}
+
+/// If the block was injected, send the result of its application to the injector
+fn dispatch_injected_block_result(injected_block_callbacks: &InjectedBlockCallbacks, block_hash: &BlockHash, result: Result<(), InjectBlockError>) {
+    let result_callback = {
+        let mut injected_block_callbacks = injected_block_callbacks.lock().unwrap();
+        forget_finished_callbacks(&mut injected_block_callbacks);
+        injected_block_callbacks.remove(block_hash)
+    };
+    dispatch_inject_block_result(&result_callback, result);
+}
+
+/// Forget callbacks, which were already resolved (e.g. block was rejected by chain manager),
+/// or whose injector gave up waiting (e.g. rpc timed out)
+fn forget_finished_callbacks(injected_block_callbacks: &mut HashMap<BlockHash, InjectBlockOneshotResultCallback>) {
+    injected_block_callbacks.retain(|_, callback| {
+        callback.lock().unwrap()
+            .as_ref()
+            .map(|sender| !sender.is_canceled())
+            .unwrap_or(false)
+    });
+}
+
 /// This initializes ocaml runtime and protocol context,
 /// if we start with new databazes without genesis,
 /// it ensures correct initialization of storage with genesis and his data.
@@ -5,30 +5,34 @@
 //! - tries to download most recent header from the other peers
 //! - also supplies downloaded data to other peers
 
-use std::cmp;
+use std::cmp::{self, Ordering};
 use std::collections::HashMap;
 use std::time::{Duration, Instant, SystemTime};
 
-use failure::Error;
+use failure::{Error, format_err};
 use itertools::Itertools;
 use riker::actors::*;
 use slog::{debug, info, trace, warn};
 
 use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped};
+use networking::p2p::network_channel::{BlacklistPeer, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageSent};
 use networking::p2p::peer::{PeerRef, SendMessage};
 use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockStorage, BlockStorageReader, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError};
 use storage::block_meta_storage::BlockMetaStorageReader;
 use storage::mempool_storage::MempoolOperationType;
 use storage::persistent::PersistentStorage;
-use tezos_messages::p2p::binary_message::MessageHash;
+use tezos_messages::p2p::binary_message::{BinaryMessage, MessageHash};
 use tezos_messages::p2p::encoding::prelude::*;
 
 use crate::Head;
-use crate::shell_channel::{AllBlockOperationsReceived, BlockReceived, CurrentMempoolState, MempoolOperationReceived, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
-use crate::state::block_state::{BlockchainState, MissingBlock};
+use crate::mempool_prevalidator::MempoolLimits;
+use crate::shell_channel::{AllBlockOperationsReceived, BlockReceived, CurrentMempoolState, dispatch_inject_block_result, InjectBlock, InjectBlockError, MempoolOperationReceived, PeerStatistics, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::state::block_state::{BlockchainState, BlockPrevalidationError, MissingBlock};
 use crate::state::operations_state::{MissingOperations, OperationsState};
+use crate::state::synchronisation_state::SynchronisationState;
+use crate::stats::peer_stats::{PeerStats, RequestKind};
 use crate::subscription::*;
+use crate::validation;
 
 /// Limit to how many blocks to request in a batch
 const BLOCK_HEADERS_BATCH_SIZE: usize = 10;
@@ -42,6 +46,8 @@ const CHECK_CHAIN_COMPLETENESS_INTERVAL: Duration = Duration::from_secs(30);
 const ASK_CURRENT_BRANCH_INTERVAL: Duration = Duration::from_secs(15);
 /// How often to print stats in logs
 const LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to publish latency and throughput of the peers
+const PEER_STATISTICS_INTERVAL: Duration = Duration::from_secs(5);
 /// After this time we will disconnect peer if his current head level stays the same
 const CURRENT_HEAD_LEVEL_UPDATE_TIMEOUT: Duration = Duration::from_secs(120);
 /// After this time peer will be disconnected if it fails to respond to our request
@@ -53,6 +59,8 @@ const STALLED_CHAIN_COMPLETENESS_TIMEOUT: Duration = Duration::from_secs(240);
 const BLOCK_HASH_ENCODING: HashType = HashType::BlockHash;
 /// Mempool operation time to live
 const MEMPOOL_OPERATION_TTL: Duration = Duration::from_secs(60);
+/// Injected block, which was not applied within this time, is forgotten
+const INJECTED_BLOCK_TIMEOUT: Duration = Duration::from_secs(120);
 
 /// Message commands [`ChainManager`] to disconnect stalled peers.
 #[derive(Clone, Debug)]
@@ -74,6 +82,25 @@ pub struct AskPeersAboutCurrentBranch;
 #[derive(Clone, Debug)]
 pub struct LogStats;
 
+/// Message commands [`ChainManager`] to publish latency and throughput of the peers to the shell channel.
+#[derive(Clone, Debug)]
+pub struct PublishPeerStatistics;
+
+/// Parameters of the synchronisation heuristic (see OCaml `synchronisation_threshold` and `latency`)
+#[derive(Clone, Debug)]
+pub struct SynchronisationThreshold {
+    /// Number of peers with recent heads needed to consider node as synchronised
+    pub peers: usize,
+    /// Max age of the peer head to be considered as recent
+    pub latency: Duration,
+}
+
+impl SynchronisationThreshold {
+    pub fn new(peers: usize, latency: Duration) -> Self {
+        SynchronisationThreshold { peers, latency }
+    }
+}
+
 /// This struct holds info about local and remote "current" head
 #[derive(Clone, Debug)]
 struct CurrentHead {
@@ -125,7 +152,7 @@ struct Stats {
 }
 
 /// Purpose of this actor is to perform chain synchronization.
-#[actor(DisconnectStalledPeers, CheckChainCompleteness, CheckMempoolCompleteness, AskPeersAboutCurrentBranch, LogStats, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
+#[actor(DisconnectStalledPeers, CheckChainCompleteness, CheckMempoolCompleteness, AskPeersAboutCurrentBranch, LogStats, PublishPeerStatistics, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
 pub struct ChainManager {
     /// All events generated by the network layer will end up in this channel
     network_channel: NetworkChannelRef,
@@ -147,6 +174,10 @@ pub struct ChainManager {
     operations_state: OperationsState,
     /// Current head information
     current_head: CurrentHead,
+    /// Synchronisation state of the node
+    sync_state: SynchronisationState,
+    /// Mempool limits
+    mempool_limits: MempoolLimits,
     // current last known mempool state
     current_mempool_state: Option<CurrentMempoolState>,
     /// Internal stats
@@ -155,6 +186,8 @@ pub struct ChainManager {
     shutting_down: bool,
     /// Indicates node mode
     is_sandbox: bool,
+    /// Hashes of injected blocks, which were not applied yet, with the time of the injection
+    injected_blocks: HashMap<BlockHash, Instant>,
 }
 
 /// Reference to [chain manager](ChainManager) actor.
@@ -162,10 +195,10 @@ pub type ChainManagerRef = ActorRef<ChainManagerMsg>;
 
 impl ChainManager {
     /// Create new actor instance.
-    pub fn actor(sys: &impl ActorRefFactory, network_channel: NetworkChannelRef, shell_channel: ShellChannelRef, persistent_storage: &PersistentStorage, chain_id: &ChainId, is_sandbox: bool) -> Result<ChainManagerRef, CreateError> {
+    pub fn actor(sys: &impl ActorRefFactory, network_channel: NetworkChannelRef, shell_channel: ShellChannelRef, persistent_storage: &PersistentStorage, chain_id: &ChainId, is_sandbox: bool, synchronisation_threshold: SynchronisationThreshold, mempool_limits: MempoolLimits) -> Result<ChainManagerRef, CreateError> {
         sys.actor_of_props::<ChainManager>(
             ChainManager::name(),
-            Props::new_args((network_channel, shell_channel, persistent_storage.clone(), chain_id.clone(), is_sandbox)),
+            Props::new_args((network_channel, shell_channel, persistent_storage.clone(), chain_id.clone(), is_sandbox, synchronisation_threshold, mempool_limits)),
         )
     }
 
@@ -212,7 +245,7 @@ impl ChainManager {
             peers.values_mut()
                 .filter(|peer| peer.current_head_level.is_some())
                 .filter(|peer| peer.available_block_queue_capacity() > 0)
-                .sorted_by_key(|peer| peer.available_block_queue_capacity()).rev()
+                .sorted_by(|a, b| compare_peers(a, b, a.available_block_queue_capacity(), b.available_block_queue_capacity()))
                 .for_each(|peer| {
                     let mut missing_blocks = chain_state.drain_missing_blocks(peer.available_block_queue_capacity(), peer.current_head_level.unwrap());
                     if !missing_blocks.is_empty() {
@@ -232,6 +265,8 @@ impl ChainManager {
 
                         if !queued_blocks.is_empty() {
                             peer.block_request_last = Instant::now();
+                            queued_blocks.iter()
+                                .for_each(|block_hash| peer.stats.request_sent(RequestKind::BlockHeaders, block_hash.clone(), peer.block_request_last));
                             tell_peer(GetBlockHeadersMessage::new(queued_blocks).into(), peer);
                         }
                     }
@@ -243,7 +278,7 @@ impl ChainManager {
             peers.values_mut()
                 .filter(|peer| peer.current_head_level.is_some())
                 .filter(|peer| peer.available_block_operations_queue_capacity() > 0)
-                .sorted_by_key(|peer| peer.available_block_operations_queue_capacity()).rev()
+                .sorted_by(|a, b| compare_peers(a, b, a.available_block_operations_queue_capacity(), b.available_block_operations_queue_capacity()))
                 .for_each(|peer| {
                     let missing_operations = operations_state.drain_missing_block_operations(peer.available_block_operations_queue_capacity(), peer.current_head_level.unwrap());
                     if !missing_operations.is_empty() {
@@ -262,6 +297,11 @@ impl ChainManager {
 
                         if !queued_operations.is_empty() {
                             peer.block_operations_request_last = Instant::now();
+                            for missing_operation in &queued_operations {
+                                for validation_pass in &missing_operation.validation_passes {
+                                    peer.stats.request_sent(RequestKind::OperationsForBlocks, operations_request_key(&missing_operation.block_hash, *validation_pass), peer.block_operations_request_last);
+                                }
+                            }
                             queued_operations.iter()
                                 .for_each(|&missing_operation| tell_peer(GetOperationsForBlocksMessage::new(missing_operation.into()).into(), peer));
                         }
@@ -283,6 +323,7 @@ impl ChainManager {
             peers,
             chain_state,
             operations_state,
+            network_channel,
             shell_channel,
             block_storage,
             operations_storage,
@@ -293,11 +334,11 @@ impl ChainManager {
         } = self;
 
         match msg {
-            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_metadata, .. }) => {
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, network_version }) => {
                 let log = ctx.system.log().new(slog::o!("peer" => peer.name().to_string()));
 
                 debug!(log, "Requesting current branch");
-                let peer = PeerState::new(peer, peer_metadata);
+                let peer = PeerState::new(peer, peer_id, peer_metadata, network_version);
                 // store peer
                 let actor_uri = peer.peer_ref.uri().clone();
                 self.peers.insert(actor_uri.clone(), peer);
@@ -310,35 +351,55 @@ impl ChainManager {
 
                 match peers.get_mut(received.peer.uri()) {
                     Some(peer) => {
+                        peer.stats.message_received(received.message.as_bytes_for_version(&peer.network_version).map(|bytes| bytes.len()).unwrap_or(0));
+
                         for message in received.message.messages() {
                             match message {
                                 PeerMessage::CurrentBranch(message) => {
                                     debug!(log, "Received current branch");
-                                    if message.current_branch().current_head().level() > 0 {
-                                        // schedule predecessor
-                                        chain_state.push_missing_block(
-                                            MissingBlock::with_level_guess(
-                                                message.current_branch().current_head().predecessor().clone(),
-                                                message.current_branch().current_head().level() - 1,
-                                            )
-                                        )?;
+                                    let peer_current_head = message.current_branch().current_head();
 
-                                        // schedule current_head
-                                        chain_state.push_missing_block(
-                                            MissingBlock::with_level(
-                                                message.current_branch().current_head().message_hash()?,
-                                                message.current_branch().current_head().level(),
-                                            )
-                                        )?
-                                    }
+                                    // branch, which is not better than our current head, is not downloaded
+                                    if is_better_than_local_head(peer_current_head, current_head, block_storage)? {
+                                        if message.current_branch().current_head().level() > 0 {
+                                            // schedule predecessor
+                                            chain_state.push_missing_block(
+                                                MissingBlock::with_level_guess(
+                                                    message.current_branch().current_head().predecessor().clone(),
+                                                    message.current_branch().current_head().level() - 1,
+                                                )
+                                            )?;
+
+                                            // schedule current_head
+                                            chain_state.push_missing_block(
+                                                MissingBlock::with_level(
+                                                    message.current_branch().current_head().message_hash()?,
+                                                    message.current_branch().current_head().level(),
+                                                )
+                                            )?
+                                        }
 
-                                    // schedule history - we try to prioritize download from the beginning, so the history is reversed here
-                                    chain_state.push_missing_history(
-                                        message.current_branch().history().iter().cloned().rev().collect(),
-                                        message.current_branch().current_head().level(),
-                                    )?;
+                                        // schedule history - we try to prioritize download from the beginning, so the history is reversed here
+                                        chain_state.push_missing_history(
+                                            message.current_branch().history().iter().cloned().rev().collect(),
+                                            message.current_branch().current_head().level(),
+                                        )?;
 
-                                    let peer_current_head = message.current_branch().current_head();
+                                        // notify others that new block was received
+                                        shell_channel.tell(
+                                            Publish {
+                                                msg: BlockReceived {
+                                                    hash: peer_current_head.message_hash()?,
+                                                    level: peer_current_head.level(),
+                                                }.into(),
+                                                topic: ShellChannelTopic::ShellEvents.into(),
+                                            }, Some(ctx.myself().into()));
+
+                                        // trigger CheckChainCompleteness
+                                        ctx.myself().tell(CheckChainCompleteness, None);
+                                    } else {
+                                        debug!(log, "Ignoring current branch, its fitness is not greater than fitness of our current head"; "level" => peer_current_head.level());
+                                    }
 
                                     // if needed, update remote current head
                                     if current_head.need_update_remote_level(message.current_branch().current_head().level()) {
@@ -351,21 +412,9 @@ impl ChainManager {
                                     // update peer stats
                                     if peer.current_head_level.is_none() || (message.current_branch().current_head().level() > peer.current_head_level.unwrap()) {
                                         peer.current_head_level = Some(message.current_branch().current_head().level());
+                                        peer.current_head_timestamp = Some(message.current_branch().current_head().timestamp());
                                         peer.current_head_update_last = Instant::now();
                                     }
-
-                                    // notify others that new block was received
-                                    shell_channel.tell(
-                                        Publish {
-                                            msg: BlockReceived {
-                                                hash: peer_current_head.message_hash()?,
-                                                level: peer_current_head.level(),
-                                            }.into(),
-                                            topic: ShellChannelTopic::ShellEvents.into(),
-                                        }, Some(ctx.myself().into()));
-
-                                    // trigger CheckChainCompleteness
-                                    ctx.myself().tell(CheckChainCompleteness, None);
                                 }
                                 PeerMessage::GetCurrentBranch(message) => {
                                     debug!(log, "Current branch requested by a peer");
@@ -382,10 +431,25 @@ impl ChainManager {
                                 PeerMessage::BlockHeader(message) => {
                                     let block_header_with_hash = BlockHeaderWithHash::new(message.block_header().clone()).unwrap();
                                     match peer.queued_block_headers.remove(&block_header_with_hash.hash) {
-                                        Some(_) => {
+                                        Some(missing_block) => {
                                             trace!(log, "Received block header");
                                             peer.block_response_last = Instant::now();
 
+                                            // pre-validate block header before we store it
+                                            match chain_state.validate_block_header(&block_header_with_hash, missing_block.level()) {
+                                                Ok(_) => (),
+                                                Err(BlockPrevalidationError::Invalid(error)) => {
+                                                    warn!(log, "Received invalid block header"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_header_with_hash.hash), "reason" => format!("{}", error));
+                                                    // let other peers to provide us with the block
+                                                    peer.stats.request_failed(RequestKind::BlockHeaders, block_header_with_hash.hash.clone());
+                                                    chain_state.push_missing_block(missing_block)?;
+                                                    blacklist_peer(network_channel, peer, format!("{}", error));
+                                                    break;
+                                                }
+                                                Err(BlockPrevalidationError::StorageError(error)) => return Err(error.into()),
+                                            }
+                                            peer.stats.response_received(RequestKind::BlockHeaders, block_header_with_hash.hash.clone(), peer.block_response_last);
+
                                             let is_new_block =
                                                 chain_state.process_block_header(&block_header_with_hash, log.clone())
                                                     .and(operations_state.process_block_header(&block_header_with_hash))?;
@@ -446,6 +510,21 @@ impl ChainManager {
                                                 peer.block_operations_response_last = Instant::now();
                                                 trace!(log, "Received operations validation pass"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
 
+                                                // pre-validate operations before we store them
+                                                match chain_state.validate_block_operations(&operations) {
+                                                    Ok(_) => (),
+                                                    Err(BlockPrevalidationError::Invalid(error)) => {
+                                                        warn!(log, "Received invalid operations"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash), "reason" => format!("{}", error));
+                                                        // return validation pass to the queue, so it will be re-scheduled when peer is stopped
+                                                        missing_operations.validation_passes.insert(operations.operations_for_block().validation_pass());
+                                                        peer.stats.request_failed(RequestKind::OperationsForBlocks, operations_request_key(&block_hash, operations.operations_for_block().validation_pass()));
+                                                        blacklist_peer(network_channel, peer, format!("{}", error));
+                                                        break;
+                                                    }
+                                                    Err(BlockPrevalidationError::StorageError(error)) => return Err(error.into()),
+                                                }
+                                                peer.stats.response_received(RequestKind::OperationsForBlocks, operations_request_key(&block_hash, operations.operations_for_block().validation_pass()), peer.block_operations_response_last);
+
                                                 if operations_state.process_block_operations(&operations)? {
                                                     // update stats
                                                     stats.unseen_block_operations_last = Instant::now();
@@ -493,15 +572,36 @@ impl ChainManager {
                                 PeerMessage::CurrentHead(message) => {
                                     debug!(log, "Current head received");
                                     if chain_state.get_chain_id() == message.chain_id() {
+                                        // current head is also sent without the request, then it is not measured
+                                        peer.stats.response_received(RequestKind::CurrentHead, message.chain_id().clone(), Instant::now());
+
+                                        // update peer stats
+                                        let peer_current_head = message.current_block_header();
+                                        if peer.current_head_level.is_none() || (peer_current_head.level() > peer.current_head_level.unwrap()) {
+                                            peer.current_head_level = Some(peer_current_head.level());
+                                            peer.current_head_timestamp = Some(peer_current_head.timestamp());
+                                            peer.current_head_update_last = Instant::now();
+                                        }
+
+                                        // mempool operations are not accepted until node is bootstrapped
+                                        if !self.sync_state.is_bootstrapped() {
+                                            continue;
+                                        }
+
                                         let peer_current_mempool = message.current_mempool();
 
+                                        // peer cannot flood our mempool, so we accept only limited count of operations from one peer
+                                        let available_quota = peer.available_mempool_operations_quota(self.mempool_limits.max_operations_per_peer);
+                                        if available_quota < peer_current_mempool.known_valid().len() + peer_current_mempool.pending().len() {
+                                            debug!(log, "Peer exceeded mempool operations quota, some of advertised operations are ignored"; "quota" => self.mempool_limits.max_operations_per_peer);
+                                        }
+
                                         // all operations (known_valid + pending) should be added to pending and validated afterwards
                                         // enqueue mempool operations for retrieval
-                                        peer_current_mempool.known_valid().iter().cloned()
-                                            .for_each(|operation_hash| {
-                                                peer.missing_mempool_operations.push((operation_hash, MempoolOperationType::Pending));
-                                            });
-                                        peer_current_mempool.pending().iter().cloned()
+                                        peer_current_mempool.known_valid().iter()
+                                            .chain(peer_current_mempool.pending().iter())
+                                            .take(available_quota)
+                                            .cloned()
                                             .for_each(|operation_hash| {
                                                 peer.missing_mempool_operations.push((operation_hash, MempoolOperationType::Pending));
                                             });
@@ -527,6 +627,7 @@ impl ChainManager {
                                         Some((op_type, op_ttl)) => {
                                             // store mempool operation
                                             peer.mempool_operations_response_last = Instant::now();
+                                            peer.mempool_operations_received += 1;
                                             mempool_storage.put(op_type.clone(), message.clone(), op_ttl)?;
 
                                             // trigger CheckMempoolCompleteness
@@ -555,9 +656,19 @@ impl ChainManager {
                     None => debug!(log, "Received message from non-existing peer")
                 }
             }
+            NetworkChannelMsg::PeerMessageSent(PeerMessageSent { peer, bytes }) => {
+                if let Some(peer) = peers.get_mut(peer.uri()) {
+                    peer.stats.message_sent(bytes);
+                }
+                // sent message does not change peers heads
+                return Ok(());
+            }
             _ => (),
         }
 
+        // peers heads could change, so we need to re-evaluate synchronisation state
+        self.update_sync_state(ctx);
+
         Ok(())
     }
 
@@ -570,6 +681,27 @@ impl ChainManager {
                 });
                 self.stats.applied_block_level = Some(message.header().header.level());
                 self.stats.applied_block_last = Some(Instant::now());
+                self.sync_state.update_local_head_timestamp(message.header().header.timestamp());
+                self.update_sync_state(ctx);
+
+                // mempool quotas are reset with every new head
+                self.peers.values_mut().for_each(|peer| peer.mempool_operations_received = 0);
+
+                if self.injected_blocks.remove(&message.header().hash).is_some() {
+                    // injected block was applied, so let peers know about our new head
+                    let ChainManager { peers, chain_state, current_head, current_mempool_state, .. } = self;
+                    if let Some(current_head_local) = &current_head.local {
+                        peers.values_mut()
+                            .for_each(|peer| {
+                                let msg = CurrentHeadMessage::new(
+                                    chain_state.get_chain_id().clone(),
+                                    (*message.header().header).clone(),
+                                    resolve_mempool_to_send_to_peer(peer, current_mempool_state, current_head_local),
+                                );
+                                tell_peer(msg.into(), peer)
+                            });
+                    }
+                }
             }
             ShellChannelMsg::MempoolStateChanged(new_mempool_state) => {
                 // prepare mempool/header to send to peers
@@ -607,30 +739,9 @@ impl ChainManager {
                 }
             }
             ShellChannelMsg::InjectBlock(inject_data) => {
-                let block_header_with_hash = BlockHeaderWithHash::new(inject_data.block_header.clone()).unwrap();
-                let log = ctx.system.log().new(slog::o!("injection" => "block".to_string()));
-
-                let is_new_block =
-                    self.chain_state.process_block_header(&block_header_with_hash, log.clone())
-                        .and(self.operations_state.process_block_header(&block_header_with_hash))?;
-
-                if is_new_block {
-                    // update stats
-                    self.stats.unseen_block_last = Instant::now();
-                    self.stats.unseen_block_count += 1;
-
-                    // trigger CheckChainCompleteness
-                    ctx.myself().tell(CheckChainCompleteness, None);
-
-                    // notify others that new block was received
-                    self.shell_channel.tell(
-                        Publish {
-                            msg: BlockReceived {
-                                hash: block_header_with_hash.hash,
-                                level: block_header_with_hash.header.level(),
-                            }.into(),
-                            topic: ShellChannelTopic::ShellEvents.into(),
-                        }, Some(ctx.myself().into()));
+                if let Err(e) = self.process_injected_block(ctx, &inject_data) {
+                    dispatch_inject_block_result(&inject_data.result_callback, Err(InjectBlockError { reason: format!("{}", e) }));
+                    return Err(e);
                 }
             }
             ShellChannelMsg::ShuttingDown(_) => {
@@ -643,6 +754,84 @@ impl ChainManager {
         Ok(())
     }
 
+    /// Pre-validate and store injected block header, its operations are then downloaded from peers
+    /// and block is applied by [chain feeder](crate::chain_feeder::ChainFeeder).
+    ///
+    /// Block injected together with all its operations is validated and applied by the chain feeder directly,
+    /// and it is stored only if it was successfully applied, so here it is just remembered for the announcement to peers.
+    fn process_injected_block(&mut self, ctx: &Context<ChainManagerMsg>, inject_data: &InjectBlock) -> Result<(), Error> {
+        let block_header_with_hash = BlockHeaderWithHash::new(inject_data.block_header.clone())?;
+
+        if inject_data.operations.is_some() {
+            self.injected_blocks.insert(block_header_with_hash.hash.clone(), Instant::now());
+            return Ok(());
+        }
+
+        // already applied block is not applied again, so the injector would wait for nothing
+        if self.block_meta_storage.is_applied(&block_header_with_hash.hash)? {
+            dispatch_inject_block_result(&inject_data.result_callback, Ok(()));
+            return Ok(());
+        }
+
+        // pre-validate injected block header before we store it
+        self.chain_state.validate_block_header(&block_header_with_hash, None)?;
+
+        // injected block has to be better than our current head, unless the injection is forced
+        if !inject_data.force {
+            if let Some(current_head_local) = &self.current_head.local {
+                if let Some(current_head) = self.block_storage.get(&current_head_local.hash)? {
+                    if validation::compare_fitness(block_header_with_hash.header.fitness(), current_head.header.fitness()) != cmp::Ordering::Greater {
+                        return Err(format_err!("Fitness of the injected block is not greater than fitness of the current head"));
+                    }
+                }
+            }
+        }
+
+        // store block header
+        let log = ctx.system.log().new(slog::o!("injection" => "block".to_string()));
+        self.chain_state.process_block_header(&block_header_with_hash, log)?;
+
+        let is_new_block = self.operations_state.process_block_header(&block_header_with_hash)?;
+        self.injected_blocks.insert(block_header_with_hash.hash.clone(), Instant::now());
+
+        if is_new_block {
+            // update stats
+            self.stats.unseen_block_last = Instant::now();
+            self.stats.unseen_block_count += 1;
+
+            // trigger CheckChainCompleteness
+            ctx.myself().tell(CheckChainCompleteness, None);
+
+            // notify others that new block was received
+            self.shell_channel.tell(
+                Publish {
+                    msg: BlockReceived {
+                        hash: block_header_with_hash.hash.clone(),
+                        level: block_header_with_hash.header.level(),
+                    }.into(),
+                    topic: ShellChannelTopic::ShellEvents.into(),
+                }, Some(ctx.myself().into()));
+        }
+
+        Ok(())
+    }
+
+    /// Re-evaluate synchronisation heuristic and notify others if synchronisation state changed
+    fn update_sync_state(&mut self, ctx: &Context<ChainManagerMsg>) {
+        let peer_head_timestamps = self.peers.values()
+            .filter_map(|peer| peer.current_head_timestamp)
+            .collect::<Vec<_>>();
+
+        if let Some(sync_state_changed) = self.sync_state.update(peer_head_timestamps, validation::now_timestamp()) {
+            info!(ctx.system.log(), "Synchronisation state changed"; "sync_state" => format!("{:?}", sync_state_changed.sync_state), "bootstrapped" => sync_state_changed.is_bootstrapped);
+            self.shell_channel.tell(
+                Publish {
+                    msg: sync_state_changed.into(),
+                    topic: ShellChannelTopic::ShellEvents.into(),
+                }, Some(ctx.myself().into()));
+        }
+    }
+
     fn hydrate_state(&mut self, ctx: &Context<ChainManagerMsg>) {
         info!(ctx.system.log(), "Hydrating block state");
         self.chain_state.hydrate().expect("Failed to hydrate chain state");
@@ -653,6 +842,11 @@ impl ChainManager {
         info!(ctx.system.log(), "Loading current head");
         self.current_head.local = self.block_meta_storage.load_current_head().expect("Failed to load current head")
             .map(|(hash, level)| Head { hash, level });
+        if let Some(current_head_local) = &self.current_head.local {
+            if let Some(current_head) = self.block_storage.get(&current_head_local.hash).expect("Failed to load current head header") {
+                self.sync_state.update_local_head_timestamp(current_head.header.timestamp());
+            }
+        }
 
         let (local_head, local_head_level) = self.current_head.local_debug_info();
         info!(
@@ -665,8 +859,8 @@ impl ChainManager {
     }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainId, bool)> for ChainManager {
-    fn create_args((network_channel, shell_channel, persistent_storage, chain_id, is_sandbox): (NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainId, bool)) -> Self {
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainId, bool, SynchronisationThreshold, MempoolLimits)> for ChainManager {
+    fn create_args((network_channel, shell_channel, persistent_storage, chain_id, is_sandbox, synchronisation_threshold, mempool_limits): (NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainId, bool, SynchronisationThreshold, MempoolLimits)) -> Self {
         ChainManager {
             network_channel,
             shell_channel,
@@ -681,6 +875,8 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Ch
                 local: None,
                 remote: None,
             },
+            sync_state: SynchronisationState::new(&synchronisation_threshold),
+            mempool_limits,
             current_mempool_state: None,
             shutting_down: false,
             stats: Stats {
@@ -692,6 +888,7 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Ch
                 hydrated_state_last: None,
             },
             is_sandbox,
+            injected_blocks: HashMap::new(),
         }
     }
 }
@@ -725,6 +922,12 @@ impl Actor for ChainManager {
             ctx.myself(),
             None,
             LogStats.into());
+        ctx.schedule::<Self::Msg, _>(
+            PEER_STATISTICS_INTERVAL,
+            PEER_STATISTICS_INTERVAL,
+            ctx.myself(),
+            None,
+            PublishPeerStatistics.into());
 
         let peer_timeout = if self.is_sandbox {
             SILENT_PEER_TIMEOUT_SANDBOX
@@ -782,6 +985,10 @@ impl Receive<LogStats> for ChainManager {
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: LogStats, _sender: Sender) {
         let log = ctx.system.log();
+
+        // forget injected blocks, which failed to apply or were never completed
+        self.injected_blocks.retain(|_, injected| injected.elapsed() < INJECTED_BLOCK_TIMEOUT);
+
         let (local, local_level) = &self.current_head.local_debug_info();
         let (remote, remote_level) = &self.current_head.remote_debug_info();
         info!(log, "Head info";
@@ -798,6 +1005,7 @@ impl Receive<LogStats> for ChainManager {
         for peer in self.peers.values() {
             debug!(log, "Peer state info";
                 "actor_ref" => format!("{}", peer.peer_ref),
+                "network_version" => format!("{:?}", peer.network_version),
                 "queued_block_headers" => peer.queued_block_headers.len(),
                 "queued_block_operations" => peer.queued_block_operations.len(),
                 "block_request_secs" => peer.block_request_last.elapsed().as_secs(),
@@ -807,12 +1015,34 @@ impl Receive<LogStats> for ChainManager {
                 "mempool_operations_request_secs" => peer.mempool_operations_request_last.elapsed().as_secs(),
                 "mempool_operations_response_secs" => peer.mempool_operations_response_last.elapsed().as_secs(),
                 "current_head_level" => peer.current_head_level,
-                "current_head_update_secs" => peer.current_head_update_last.elapsed().as_secs());
+                "current_head_update_secs" => peer.current_head_update_last.elapsed().as_secs(),
+                "score" => peer.stats.score());
         }
         info!(log, "Various info"; "peer_count" => self.peers.len(), "hydrated_state_secs" => self.stats.hydrated_state_last.map(|i| i.elapsed().as_secs()));
     }
 }
 
+impl Receive<PublishPeerStatistics> for ChainManager {
+    type Msg = ChainManagerMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: PublishPeerStatistics, _sender: Sender) {
+        let now = Instant::now();
+        let peers = self.peers.values_mut()
+            .map(|peer| {
+                // requests not answered on time are failed
+                peer.stats.expire_requests(now, SILENT_PEER_TIMEOUT);
+                peer.stats.snapshot(peer.peer_id.clone())
+            })
+            .collect();
+
+        self.shell_channel.tell(
+            Publish {
+                msg: PeerStatistics { peers }.into(),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, Some(ctx.myself().into()));
+    }
+}
+
 impl Receive<DisconnectStalledPeers> for ChainManager {
     type Msg = ChainManagerMsg;
 
@@ -902,10 +1132,17 @@ impl Receive<ShellChannelMsg> for ChainManager {
 impl Receive<AskPeersAboutCurrentBranch> for ChainManager {
     type Msg = ChainManagerMsg;
 
-    fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: AskPeersAboutCurrentBranch, _sender: Sender) {
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: AskPeersAboutCurrentBranch, _sender: Sender) {
         let ChainManager { peers, chain_state, .. } = self;
         peers.iter_mut()
-            .for_each(|(_, peer)| tell_peer(GetCurrentBranchMessage::new(chain_state.get_chain_id().clone()).into(), peer))
+            .for_each(|(_, peer)| {
+                tell_peer(GetCurrentBranchMessage::new(chain_state.get_chain_id().clone()).into(), peer);
+                // current head is requested just to measure the latency of the peer
+                request_current_head(chain_state.get_chain_id(), peer);
+            });
+
+        // heads get older over time, so we need to re-evaluate synchronisation state
+        self.update_sync_state(ctx);
     }
 }
 
@@ -913,6 +1150,10 @@ impl Receive<AskPeersAboutCurrentBranch> for ChainManager {
 struct PeerState {
     /// Reference to peer actor
     peer_ref: PeerRef,
+    /// Peer id of the remote peer
+    peer_id: String,
+    /// Network version negotiated with the peer, messages can be encoded differently for different versions
+    network_version: NetworkVersion,
     // Has peer enabled mempool
     mempool_enabled: bool,
 
@@ -922,6 +1163,8 @@ struct PeerState {
     queued_block_operations: HashMap<BlockHash, MissingOperations>,
     /// Level of the current head received from peer
     current_head_level: Option<i32>,
+    /// Timestamp of the current head received from peer
+    current_head_timestamp: Option<i64>,
     /// Last time we received updated head from peer
     current_head_update_last: Instant,
     /// Last time we requested block from the peer
@@ -943,18 +1186,26 @@ struct PeerState {
     /// Queued mempool operations. This map holds an operation hash and
     /// a tuple of type of a mempool operation with its time to live.
     queued_mempool_operations: HashMap<OperationHash, (MempoolOperationType, SystemTime)>,
+    /// Count of mempool operations received from the peer since the last applied block
+    mempool_operations_received: usize,
+    /// Latency of our requests and throughput of the peer
+    stats: PeerStats,
 }
 
 impl PeerState {
-    fn new(peer_ref: PeerRef, peer_metadata: MetadataMessage) -> Self {
+    fn new(peer_ref: PeerRef, peer_id: String, peer_metadata: MetadataMessage, network_version: NetworkVersion) -> Self {
         PeerState {
             peer_ref,
+            peer_id,
+            network_version,
             mempool_enabled: !peer_metadata.disable_mempool(),
             queued_block_headers: HashMap::new(),
             queued_block_operations: HashMap::new(),
             missing_mempool_operations: Vec::new(),
             queued_mempool_operations: HashMap::default(),
+            mempool_operations_received: 0,
             current_head_level: None,
+            current_head_timestamp: None,
             current_head_update_last: Instant::now(),
             block_request_last: Instant::now(),
             block_response_last: Instant::now(),
@@ -962,13 +1213,20 @@ impl PeerState {
             block_operations_response_last: Instant::now(),
             mempool_operations_request_last: Instant::now(),
             mempool_operations_response_last: Instant::now(),
+            stats: PeerStats::default(),
         }
     }
 
+    fn available_mempool_operations_quota(&self, max_operations_per_peer: usize) -> usize {
+        let used_quota = self.mempool_operations_received + self.missing_mempool_operations.len() + self.queued_mempool_operations.len();
+        max_operations_per_peer.saturating_sub(used_quota)
+    }
+
     fn available_block_queue_capacity(&self) -> usize {
         let queued_count = self.queued_block_headers.len();
-        if queued_count < BLOCK_HEADERS_BATCH_SIZE {
-            BLOCK_HEADERS_BATCH_SIZE - queued_count
+        let batch_size = scaled_batch_size(BLOCK_HEADERS_BATCH_SIZE, self.stats.success_ratio(RequestKind::BlockHeaders));
+        if queued_count < batch_size {
+            batch_size - queued_count
         } else {
             0
         }
@@ -976,8 +1234,9 @@ impl PeerState {
 
     fn available_block_operations_queue_capacity(&self) -> usize {
         let queued_count = self.queued_block_operations.len();
-        if queued_count < BLOCK_OPERATIONS_BATCH_SIZE {
-            BLOCK_OPERATIONS_BATCH_SIZE - queued_count
+        let batch_size = scaled_batch_size(BLOCK_OPERATIONS_BATCH_SIZE, self.stats.success_ratio(RequestKind::OperationsForBlocks));
+        if queued_count < batch_size {
+            batch_size - queued_count
         } else {
             0
         }
@@ -997,6 +1256,54 @@ fn tell_peer(msg: PeerMessageResponse, peer: &mut PeerState) {
     peer.peer_ref.tell(SendMessage::new(msg), None);
 }
 
+fn request_current_head(chain_id: &ChainId, peer: &mut PeerState) {
+    peer.stats.request_sent(RequestKind::CurrentHead, chain_id.clone(), Instant::now());
+    tell_peer(GetCurrentHeadMessage::new(chain_id.clone()).into(), peer);
+}
+
+/// Requests for the block operations are measured for each validation pass
+fn operations_request_key(block_hash: &BlockHash, validation_pass: i8) -> Vec<u8> {
+    let mut key = block_hash.clone();
+    key.push(validation_pass as u8);
+    key
+}
+
+/// Peers failing our requests get smaller batches, but at least one request
+fn scaled_batch_size(batch_size: usize, success_ratio: f64) -> usize {
+    cmp::max(1, (batch_size as f64 * success_ratio).round() as usize)
+}
+
+/// Order peers for the download, peers with the higher score go first, then the peers with more available capacity
+fn compare_peers(a: &PeerState, b: &PeerState, a_capacity: usize, b_capacity: usize) -> Ordering {
+    b.stats.score().partial_cmp(&a.stats.score())
+        .unwrap_or(Ordering::Equal)
+        .then(b_capacity.cmp(&a_capacity))
+}
+
+/// Notify peer manager that peer sent us invalid data, peer will be disconnected and blacklisted
+fn blacklist_peer(network_channel: &NetworkChannelRef, peer: &PeerState, reason: String) {
+    network_channel.tell(
+        Publish {
+            msg: BlacklistPeer {
+                peer: peer.peer_ref.clone(),
+                reason,
+            }.into(),
+            topic: NetworkChannelTopic::NetworkEvents.into(),
+        }, None);
+}
+
+/// Branch of the peer is worth downloading only if its head has greater fitness than our current head (like in the tezos shell)
+fn is_better_than_local_head(peer_current_head: &BlockHeader, current_head: &CurrentHead, block_storage: &BlockStorage) -> Result<bool, StorageError> {
+    let local_head = match &current_head.local {
+        Some(local_head) => block_storage.get(&local_head.hash)?,
+        None => None,
+    };
+    Ok(match local_head {
+        Some(local_head) => validation::compare_fitness(peer_current_head.fitness(), local_head.header.fitness()) == cmp::Ordering::Greater,
+        None => true,
+    })
+}
+
 fn resolve_mempool_to_send(mempool_state: &CurrentMempoolState) -> Mempool {
     // collect for mempool
     let known_valid = mempool_state.result.applied.iter().map(|a| a.hash.clone()).collect::<Vec<OperationHash>>();
@@ -2,15 +2,18 @@
 // SPDX-License-Identifier: MIT
 
 //! Listens for events from the `protocol_runner`.
+//!
+//! On shutdown, listener keeps processing events until the `protocol_runner` closes the event stream,
+//! so context actions of the in-flight block are not lost.
 
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use failure::Error;
 use riker::actors::*;
-use slog::{crit, debug, Logger, warn, info};
+use slog::{debug, Logger, warn, info};
 
 use crypto::hash::HashType;
 use storage::{BlockStorage, ContextActionStorage};
@@ -19,15 +22,25 @@ use storage::persistent::PersistentStorage;
 use tezos_context::channel::ContextAction;
 use tezos_wrapper::service::IpcEvtServer;
 
+use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
+use crate::subscription::subscribe_to_shell_events;
+use crate::supervision::{join_with_timeout, Supervisor};
+
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
 
+/// How long to wait for the listener thread, when the actor is stopped
+const LISTENER_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// This actor listens for events generated by the `protocol_runner`.
-#[actor]
+#[actor(ShellChannelMsg)]
 pub struct ContextListener {
-    /// Thread where blocks are applied will run until this is set to `false`
+    /// All events from shell will be published to this channel
+    shell_channel: ShellChannelRef,
+    /// Context event listener thread will run until this is set to `false`
     listener_run: Arc<AtomicBool>,
     /// Context event listener thread
     listener_thread: SharedJoinHandle,
+    log: Logger,
 }
 
 /// Reference to [context listener](ContextListener) actor.
@@ -38,47 +51,40 @@ impl ContextListener {
     ///
     /// This actor spawns a new thread in which it listens for incoming events from the `protocol_runner`.
     /// Events are received from IPC channel provided by [`event_server`](IpcEvtServer).
+    /// The thread is supervised by [`supervisor`](Supervisor), so it is restarted on failure.
     pub fn actor(
         sys: &impl ActorRefFactory,
+        shell_channel: ShellChannelRef,
         persistent_storage: &PersistentStorage,
         mut event_server: IpcEvtServer,
+        supervisor: &Supervisor,
         log: Logger,
         store_context_action: bool
     ) -> Result<ContextListenerRef, CreateError> {
-        let context_storage = persistent_storage.context_storage();
         let listener_run = Arc::new(AtomicBool::new(true));
         let block_applier_thread = {
             let listener_run = listener_run.clone();
+            let log = log.clone();
             let persistent_storage = persistent_storage.clone();
-
-            thread::spawn(move || -> Result<(), Error> {
-                let mut context: Box<dyn ContextApi> = Box::new(TezedgeContext::new(BlockStorage::new(&persistent_storage), context_storage));
-                let mut context_action_storage = ContextActionStorage::new(&persistent_storage);
-                while listener_run.load(Ordering::Acquire) {
-                    match listen_protocol_events(
-                        &listener_run,
-                        &mut event_server,
-                        &mut context_action_storage,
-                        &mut context,
-                        &log,
-                        store_context_action,
-                    ) {
-                        Ok(()) => info!(log, "Context listener finished"),
-                        Err(err) => {
-                            if listener_run.load(Ordering::Acquire) {
-                                crit!(log, "Error process context event"; "reason" => format!("{:?}", err))
-                            }
-                        }
-                    }
-                }
-
+            let mut context_action_storage = ContextActionStorage::new(&persistent_storage);
+
+            supervisor.spawn("context-listener", listener_run.clone(), log.clone(), move || {
+                let mut context: Box<dyn ContextApi> = Box::new(TezedgeContext::new(BlockStorage::new(&persistent_storage), persistent_storage.context_storage()));
+                listen_protocol_events(
+                    &mut event_server,
+                    &mut context_action_storage,
+                    &mut context,
+                    &log,
+                    store_context_action,
+                )?;
+                info!(log, "Context listener finished");
                 Ok(())
             })
         };
 
         let myself = sys.actor_of_props::<ContextListener>(
             ContextListener::name(),
-            Props::new_args((listener_run, Arc::new(Mutex::new(Some(block_applier_thread)))))
+            Props::new_args((shell_channel, listener_run, Arc::new(Mutex::new(Some(block_applier_thread))), log))
         )?;
 
         Ok(myself)
@@ -91,11 +97,13 @@ impl ContextListener {
     }
 }
 
-impl ActorFactoryArgs<(Arc<AtomicBool>, SharedJoinHandle)> for ContextListener {
-    fn create_args((listener_run, listener_thread): (Arc<AtomicBool>, SharedJoinHandle)) -> Self {
+impl ActorFactoryArgs<(ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, Logger)> for ContextListener {
+    fn create_args((shell_channel, listener_run, listener_thread, log): (ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, Logger)) -> Self {
         ContextListener {
+            shell_channel,
             listener_run,
             listener_thread,
+            log,
         }
     }
 }
@@ -103,12 +111,23 @@ impl ActorFactoryArgs<(Arc<AtomicBool>, SharedJoinHandle)> for ContextListener {
 impl Actor for ContextListener {
     type Msg = ContextListenerMsg;
 
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+    }
+
     fn post_stop(&mut self) {
         self.listener_run.store(false, Ordering::Release);
 
-        let _ = self.listener_thread.lock().unwrap()
-            .take().expect("Thread join handle is missing")
-            .join().expect("Failed to join context listener thread");
+        let join_handle = self.listener_thread.lock().unwrap()
+            .take().expect("Thread join handle is missing");
+        join_handle.thread().unpark();
+        // listener could be blocked by reading from the protocol runner, so shutdown does not wait for it forever
+        match join_with_timeout(join_handle, LISTENER_JOIN_TIMEOUT) {
+            Some(result) => {
+                let _ = result.expect("Failed to join context listener thread");
+            }
+            None => warn!(self.log, "Context listener thread was not finished in time, it is detached"; "timeout_ms" => LISTENER_JOIN_TIMEOUT.as_millis() as u64),
+        }
     }
 
     fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
@@ -116,6 +135,17 @@ impl Actor for ContextListener {
     }
 }
 
+impl Receive<ShellChannelMsg> for ContextListener {
+    type Msg = ContextListenerMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        if let ShellChannelMsg::ShuttingDown(_) = msg {
+            // thread is not unparked here, so it can drain remaining events from the protocol runner
+            self.listener_run.store(false, Ordering::Release);
+        }
+    }
+}
+
 fn store_action(storage: &mut ContextActionStorage, should_store: bool, action: ContextAction) -> Result<(), Error> {
     if !should_store { return Ok(()); }
     match &action {
@@ -134,8 +164,8 @@ fn store_action(storage: &mut ContextActionStorage, should_store: bool, action:
     }
 }
 
+/// Processes events from the `protocol_runner`, until it sends `Shutdown` or closes the connection.
 fn listen_protocol_events(
-    apply_block_run: &AtomicBool,
     event_server: &mut IpcEvtServer,
     context_action_storage: &mut ContextActionStorage,
     context: &mut Box<dyn ContextApi>,
@@ -150,7 +180,7 @@ fn listen_protocol_events(
 
     let mut context_diff: ContextDiff = context.init_from_start();
 
-    while apply_block_run.load(Ordering::Acquire) {
+    loop {
         match rx.receive() {
             Ok(ContextAction::Shutdown) => break,
             Ok(msg) => {
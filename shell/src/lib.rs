@@ -15,7 +15,13 @@ pub mod chain_feeder;
 pub mod context_listener;
 pub mod chain_manager;
 pub mod peer_manager;
+pub mod peer_filter;
 pub mod mempool_prevalidator;
+pub mod validation;
+pub mod supervision;
+pub mod recording;
+pub mod recorder;
+pub mod replay;
 
 /// This struct holds info about head and his level
 #[derive(Clone, Debug)]
@@ -9,34 +9,69 @@
 //! Actor validates received operations and result of validate as a new MempoolState is send back to shell channel, where:
 //!     - is used by rpc_actor to show current mempool state - pending_operations
 //!     - is used by chain_manager to send new current head with current mempool to inform other peers throught P2P
+//!
+//! Prevalidation is postponed until the node is bootstrapped (see [`SyncStateChanged`](crate::shell_channel::SyncStateChanged)).
+//!
+//! Operations are persisted in [`MempoolStorage`], so on (re)start, not expired operations are restored from storage
+//! and revalidated against the current head.
+//!
+//! On a new head, operations included in the new block are removed, `applied` and `branch_delayed` operations are revalidated,
+//! `branch_refused` operations are revalidated only if the branch changed and `refused` operations are never revalidated.
+//! Operations can be banned (and unbanned) through [`MempoolOperationBan`], banned operations are removed and ignored.
 
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver as QueueReceiver, Sender as QueueSender};
-use std::thread;
 use std::thread::JoinHandle;
+use std::time::SystemTime;
 
-use failure::{Error, Fail};
+use failure::{Error, Fail, format_err};
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
 use crypto::hash::{BlockHash, HashType, OperationHash};
-use storage::{BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, MempoolStorage, StorageError, StorageInitInfo};
+use storage::{BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError, StorageInitInfo};
 use storage::mempool_storage::MempoolOperationType;
 use storage::persistent::PersistentStorage;
-use tezos_api::ffi::{Applied, BeginConstructionRequest, Errored, PrevalidatorWrapper, ValidateOperationRequest, ValidateOperationResult};
+use tezos_api::ffi::{Applied, BeginConstructionRequest, Errored, OperationProtocolDataJson, PrevalidatorWrapper, ValidateOperationRequest, ValidateOperationResult};
+use tezos_messages::p2p::binary_message::MessageHash;
 use tezos_messages::p2p::encoding::block_header::Level;
 use tezos_messages::p2p::encoding::prelude::Operation;
 use tezos_wrapper::service::{ProtocolController, ProtocolServiceError};
 use tezos_wrapper::TezosApiConnectionPool;
 
 use crate::Head;
-use crate::shell_channel::{CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::shell_channel::{CurrentMempoolState, MempoolOperationBan, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
 use crate::subscription::subscribe_to_shell_events;
+use crate::supervision::Supervisor;
 
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
 
+/// Limits of the mempool
+#[derive(Clone, Debug)]
+pub struct MempoolLimits {
+    /// Max count of operations in the mempool (validated and pending)
+    pub max_operations: usize,
+    /// Max size of operations in the mempool (validated and pending) in bytes
+    pub max_bytes: usize,
+    /// Max count of mempool operations accepted from one peer for one head
+    pub max_operations_per_peer: usize,
+}
+
+impl MempoolLimits {
+    pub fn new(max_operations: usize, max_bytes: usize, max_operations_per_peer: usize) -> Self {
+        MempoolLimits { max_operations, max_bytes, max_operations_per_peer }
+    }
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits::new(10_000, 32 * 1024 * 1024, 1_000)
+    }
+}
+
 /// Feeds blocks and operations to the tezos protocol (ocaml code).
 #[actor(ShellChannelMsg)]
 pub struct MempoolPrevalidator {
@@ -51,6 +86,8 @@ pub struct MempoolPrevalidator {
 enum Event {
     NewHead(BlockHash, Level),
     ValidateOperation(OperationHash, MempoolOperationType),
+    Ban(MempoolOperationBan),
+    Bootstrapped,
 }
 
 /// Reference to [chain feeder](ChainFeeder) actor
@@ -63,6 +100,8 @@ impl MempoolPrevalidator {
         persistent_storage: &PersistentStorage,
         init_storage_data: &StorageInitInfo,
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
+        limits: MempoolLimits,
+        supervisor: &Supervisor,
         log: Logger) -> Result<MempoolPrevalidatorRef, CreateError> {
 
         // spawn thread which processes event
@@ -74,37 +113,31 @@ impl MempoolPrevalidator {
             let init_storage_data = init_storage_data.clone();
             let validator_run = validator_run.clone();
 
-            thread::spawn(move || {
-                let mut block_storage = BlockStorage::new(&persistent_storage);
-                let mut block_meta_storage = BlockMetaStorage::new(&persistent_storage);
-                let mut mempool_storage = MempoolStorage::new(&persistent_storage);
-
-                while validator_run.load(Ordering::Acquire) {
-                    match tezos_readonly_api.pool.get() {
-                        Ok(protocol_controller) =>
-                            match process_prevalidation(
-                                &mut block_storage,
-                                &mut block_meta_storage,
-                                &mut mempool_storage,
-                                &init_storage_data,
-                                &validator_run,
-                                &shell_channel,
-                                &protocol_controller.api,
-                                &mut validator_event_receiver,
-                                &log,
-                            ) {
-                                Ok(()) => info!(log, "Mempool - prevalidation process finished"),
-                                Err(err) => {
-                                    if validator_run.load(Ordering::Acquire) {
-                                        warn!(log, "Mempool - error while process prevalidation"; "reason" => format!("{:?}", err));
-                                    }
-                                }
-                            }
-                        Err(err) => warn!(log, "Mempool - no protocol runner connection available (try next turn)!"; "pool_name" => tezos_readonly_api.pool_name.clone(), "reason" => format!("{:?}", err)),
-                    }
-                }
-
-                info!(log, "Mempool prevalidator thread finished");
+            let mut block_storage = BlockStorage::new(&persistent_storage);
+            let mut block_meta_storage = BlockMetaStorage::new(&persistent_storage);
+            let operations_storage = OperationsStorage::new(&persistent_storage);
+            let mut mempool_storage = MempoolStorage::new(&persistent_storage);
+            // prevalidation is postponed until the node is bootstrapped
+            let mut bootstrapped = false;
+
+            supervisor.spawn("mempool-prevalidator", validator_run.clone(), log.clone(), move || {
+                let protocol_controller = tezos_readonly_api.pool.get()
+                    .map_err(|err| format_err!("Mempool - no protocol runner connection available, pool_name: {}, reason: {:?}", tezos_readonly_api.pool_name, err))?;
+                process_prevalidation(
+                    &mut block_storage,
+                    &mut block_meta_storage,
+                    &operations_storage,
+                    &mut mempool_storage,
+                    &init_storage_data,
+                    &limits,
+                    &validator_run,
+                    &shell_channel,
+                    &protocol_controller.api,
+                    &mut validator_event_receiver,
+                    &mut bootstrapped,
+                    &log,
+                )?;
+                info!(log, "Mempool - prevalidation process finished");
                 Ok(())
             })
         };
@@ -138,6 +171,15 @@ impl MempoolPrevalidator {
                     Event::ValidateOperation(operation.operation_hash.clone(), operation.operation_type)
                 )?;
             }
+            ShellChannelMsg::MempoolOperationBan(ban) => {
+                self.validator_event_sender.lock().unwrap().send(Event::Ban(ban))?;
+            }
+            ShellChannelMsg::SyncStateChanged(sync_state) => {
+                if sync_state.is_bootstrapped {
+                    // start prevalidation
+                    self.validator_event_sender.lock().unwrap().send(Event::Bootstrapped)?;
+                }
+            }
             _ => ()
         }
 
@@ -197,6 +239,8 @@ impl Receive<ShellChannelMsg> for MempoolPrevalidator {
 ///     - are being processed sequentially, after validation, they are moved to `validation_result`
 /// - `operations`
 ///     - kind of cache, contains operation data
+/// - `banned`
+///     - operations banned through rpc, which are ignored
 #[derive(Clone, Debug)]
 pub struct MempoolState {
     prevalidator: Option<PrevalidatorWrapper>,
@@ -204,32 +248,111 @@ pub struct MempoolState {
 
     validation_result: ValidateOperationResult,
     operations: HashMap<OperationHash, Operation>,
-    // TODO: pendings limit
     pending: HashSet<OperationHash>,
+    banned: HashSet<OperationHash>,
+
+    limits: MempoolLimits,
+    /// Size of all operations in bytes
+    operations_bytes: usize,
+    /// Size of pending operations in bytes
+    pending_bytes: usize,
+    /// Ordering of applied operations resolved from their protocol data, so the json is parsed just once
+    orderings: HashMap<OperationHash, OperationOrdering>,
 }
 
 impl MempoolState {
-    fn new(prevalidator: Option<PrevalidatorWrapper>, predecessor: Option<Head>, pending_operations: HashMap<OperationHash, Operation>) -> MempoolState {
+    fn new(prevalidator: Option<PrevalidatorWrapper>, predecessor: Option<Head>, pending_operations: HashMap<OperationHash, Operation>, limits: MempoolLimits) -> MempoolState {
         MempoolState {
             prevalidator,
             predecessor,
             pending: pending_operations.keys().map(|oph| oph.clone()).collect(),
             validation_result: ValidateOperationResult::default(),
+            banned: HashSet::new(),
+            operations_bytes: pending_operations.values().map(operation_size).sum(),
+            pending_bytes: pending_operations.values().map(operation_size).sum(),
             operations: pending_operations,
+            limits,
+            orderings: HashMap::new(),
         }
     }
 
     fn add_result(&mut self, new_result: &ValidateOperationResult) -> bool {
-        self.validation_result.merge(&new_result)
+        let changed = self.validation_result.merge(&new_result);
+        if changed {
+            let MempoolState { validation_result, orderings, .. } = self;
+            for applied in &validation_result.applied {
+                if !orderings.contains_key(&applied.hash) {
+                    orderings.insert(applied.hash.clone(), OperationOrdering::from_protocol_data_json(&applied.protocol_data_json));
+                }
+            }
+            let applied: HashSet<&OperationHash> = validation_result.applied.iter().map(|applied| &applied.hash).collect();
+            orderings.retain(|oph, _| applied.contains(oph));
+
+            // keep applied operations ordered by priority, so the best operations are advertised first,
+            // but operations of the same source have to stay ordered by counter
+            validation_result.applied.sort_by_key(|applied| Reverse(orderings.get(&applied.hash).map_or(OperationPriority::LOWEST, |ordering| ordering.priority)));
+            keep_counter_order(&mut validation_result.applied, orderings);
+        }
+        changed
+    }
+
+    /// Adds operation to pending, returns `false` if there is no space (count or bytes) for pending operation
+    fn add_to_pending(&mut self, operation_hash: &OperationHash, operation: &Operation) -> bool {
+        let size = operation_size(operation);
+        if !self.pending.contains(operation_hash)
+            && (self.pending.len() >= self.limits.max_operations || self.pending_bytes + size > self.limits.max_bytes) {
+            return false;
+        }
+        if self.operations.insert(operation_hash.clone(), operation.clone()).is_none() {
+            self.operations_bytes += size;
+        }
+        if self.pending.insert(operation_hash.clone()) {
+            self.pending_bytes += size;
+        }
+        true
+    }
+
+    fn is_over_limits(&self) -> bool {
+        self.operations.len() > self.limits.max_operations || self.operations_bytes > self.limits.max_bytes
     }
 
-    fn add_to_pending(&mut self, operation_hash: &OperationHash, operation: &Operation) {
-        self.operations.insert(operation_hash.clone(), operation.clone());
-        let _ = self.pending.insert(operation_hash.clone());
+    /// Evicts validated operations with the lowest priority, until the mempool fits into the limits.
+    /// Refused operations are evicted first, then branch refused, branch delayed and at last applied with the lowest priority.
+    ///
+    /// Returns hashes of evicted operations.
+    fn evict_over_limits(&mut self) -> Vec<OperationHash> {
+        let mut evicted = Vec::new();
+        while self.is_over_limits() {
+            let candidate = if let Some(errored) = self.validation_result.refused.pop() {
+                errored.hash
+            } else if let Some(errored) = self.validation_result.branch_refused.pop() {
+                errored.hash
+            } else if let Some(errored) = self.validation_result.branch_delayed.pop() {
+                errored.hash
+            } else if let Some(applied) = self.validation_result.applied.pop() {
+                // applied are ordered by priority, so the last one has the lowest priority
+                applied.hash
+            } else {
+                // only pending operations left
+                break;
+            };
+
+            if let Some(operation) = self.operations.remove(&candidate) {
+                self.operations_bytes -= operation_size(&operation);
+            }
+            evicted.push(candidate);
+        }
+        evicted
     }
 
     fn remove_from_pending(&mut self, operation_hash: &OperationHash) -> bool {
-        self.pending.remove(operation_hash)
+        let removed = self.pending.remove(operation_hash);
+        if removed {
+            if let Some(operation) = self.operations.get(operation_hash) {
+                self.pending_bytes = self.pending_bytes.saturating_sub(operation_size(operation));
+            }
+        }
+        removed
     }
 
     /// Indicates, that pending operations can be handled
@@ -246,21 +369,86 @@ impl MempoolState {
         !contains
     }
 
-    /// Splits exists operations map to operations map with just pending operations and others
-    fn split_operations_to_pending_and_others(&self) -> (HashMap<OperationHash, Operation>, HashSet<OperationHash>) {
-        let mut pending = HashMap::new();
-        let mut others = HashSet::new();
+    fn is_banned(&self, operation_hash: &OperationHash) -> bool {
+        self.banned.contains(operation_hash)
+    }
 
-        // split
-        for (key, value) in self.operations.iter() {
-            if self.pending.contains(key) {
-                pending.insert(key.clone(), value.clone());
-            } else {
-                others.insert(key.clone());
-            }
+    /// Bans operation, which is removed from the mempool, returns `true` if the operation was in the mempool
+    fn ban(&mut self, operation_hash: &OperationHash) -> bool {
+        let _ = self.banned.insert(operation_hash.clone());
+
+        let mut removed = self.remove_from_pending(operation_hash);
+        let result = &mut self.validation_result;
+        result.applied.retain(|applied| &applied.hash != operation_hash);
+        result.refused.retain(|errored| &errored.hash != operation_hash);
+        result.branch_refused.retain(|errored| &errored.hash != operation_hash);
+        result.branch_delayed.retain(|errored| &errored.hash != operation_hash);
+        if let Some(operation) = self.operations.remove(operation_hash) {
+            self.operations_bytes -= operation_size(&operation);
+            removed = true;
         }
+        removed
+    }
 
-        (pending, others)
+    fn unban(&mut self, operation_hash: &OperationHash) -> bool {
+        self.banned.remove(operation_hash)
+    }
+
+    fn unban_all(&mut self) {
+        self.banned.clear();
+    }
+
+    /// Resets mempool state for a new head:
+    /// - operations included in the new head are removed,
+    /// - `applied` and `branch_delayed` operations are moved back to pending for revalidation,
+    /// - `branch_refused` operations are kept, or revalidated if `branch_changed`,
+    /// - `refused` operations are kept and never revalidated.
+    ///
+    /// Returns operations, which should be removed from mempool storage (included and refused).
+    fn reset_for_new_head(&mut self,
+                          prevalidator: Option<PrevalidatorWrapper>,
+                          predecessor: Option<Head>,
+                          included: &HashSet<OperationHash>,
+                          branch_changed: bool) -> HashSet<OperationHash> {
+        let old_result = std::mem::take(&mut self.validation_result);
+
+        // collect operations to revalidate
+        let mut pending: HashSet<OperationHash> = self.pending.drain().collect();
+        pending.extend(old_result.applied.into_iter().map(|applied| applied.hash));
+        pending.extend(old_result.branch_delayed.into_iter().map(|errored| errored.hash));
+
+        let mut result = ValidateOperationResult::default();
+        if branch_changed {
+            pending.extend(old_result.branch_refused.into_iter().map(|errored| errored.hash));
+        } else {
+            result.branch_refused = old_result.branch_refused.into_iter().filter(|errored| !included.contains(&errored.hash)).collect();
+        }
+        result.refused = old_result.refused.into_iter().filter(|errored| !included.contains(&errored.hash)).collect();
+        pending.retain(|oph| !included.contains(oph));
+
+        // operations to remove from storage
+        let mut to_delete: HashSet<OperationHash> = self.operations.keys()
+            .filter(|oph| included.contains(*oph))
+            .cloned()
+            .collect();
+        to_delete.extend(result.refused.iter().map(|errored| errored.hash.clone()));
+
+        // keep operation data just for operations, which are still in the mempool
+        let keep: HashSet<&OperationHash> = pending.iter()
+            .chain(result.refused.iter().map(|errored| &errored.hash))
+            .chain(result.branch_refused.iter().map(|errored| &errored.hash))
+            .collect();
+        self.operations.retain(|oph, _| keep.contains(oph));
+        self.operations_bytes = self.operations.values().map(operation_size).sum();
+        self.pending_bytes = pending.iter().filter_map(|oph| self.operations.get(oph)).map(operation_size).sum();
+        self.orderings.clear();
+
+        self.prevalidator = prevalidator;
+        self.predecessor = predecessor;
+        self.validation_result = result;
+        self.pending = pending;
+
+        to_delete
     }
 }
 
@@ -292,18 +480,24 @@ impl From<StorageError> for PrevalidationError {
 fn process_prevalidation(
     block_storage: &mut BlockStorage,
     block_meta_storage: &mut BlockMetaStorage,
+    operations_storage: &OperationsStorage,
     mempool_storage: &mut MempoolStorage,
     init_storage_data: &StorageInitInfo,
+    limits: &MempoolLimits,
     validator_run: &AtomicBool,
     shell_channel: &ShellChannelRef,
     protocol_controller: &ProtocolController,
     validator_event_receiver: &mut QueueReceiver<Event>,
+    bootstrapped: &mut bool,
     log: &Logger,
 ) -> Result<(), PrevalidationError> {
     info!(log, "Mempool prevalidator started processing");
 
     // hydrate state
-    let mut state = hydrate_state(&shell_channel, block_storage, block_meta_storage, mempool_storage, &protocol_controller, &init_storage_data, &log)?;
+    let mut state = hydrate_state(&shell_channel, block_storage, block_meta_storage, mempool_storage, &protocol_controller, &init_storage_data, limits, *bootstrapped, &log)?;
+
+    // the latest head received before the node was bootstrapped
+    let mut postponed_head: Option<(BlockHash, Level)> = None;
 
     // start receiving event
     while validator_run.load(Ordering::Acquire) {
@@ -311,41 +505,24 @@ fn process_prevalidation(
         if let Ok(event) = validator_event_receiver.recv() {
             match event {
                 Event::NewHead(header, level) => {
-                    // check if NewHeader is bigger than actual, if present
-                    if let Some(current_mempool_block) = &mut state.predecessor {
-                        if level <= current_mempool_block.level {
-                            warn!(log, "Mempool - new head has smaller level than actual mempool head, so we just ignore it!";
-                                        "received_level" => level,
-                                        "received_block_hash" => HashType::BlockHash.bytes_to_string(&header),
-                                        "current_mempool_level" => current_mempool_block.level,
-                                        "current_mempool_block_hash" => HashType::BlockHash.bytes_to_string(&current_mempool_block.hash));
-                            continue;
+                    if *bootstrapped {
+                        process_new_head(block_storage, operations_storage, mempool_storage, init_storage_data, shell_channel, protocol_controller, &mut state, header, level, log)?;
+                    } else {
+                        // until node is bootstrapped, we just remember the latest head
+                        trace!(log, "Mempool - node is not bootstrapped yet, so new head is postponed"; "received_level" => level);
+                        postponed_head = Some((header, level));
+                    }
+                }
+                Event::Bootstrapped => {
+                    if !*bootstrapped {
+                        info!(log, "Mempool - node is bootstrapped, starting prevalidation");
+                        *bootstrapped = true;
+                        if let Some((header, level)) = postponed_head.take() {
+                            process_new_head(block_storage, operations_storage, mempool_storage, init_storage_data, shell_channel, protocol_controller, &mut state, header, level, log)?;
                         }
                     }
-
-                    debug!(log, "Mempool - new higher head received, so begin construction a new context";
-                                "received_level" => level,
-                                "received_block_hash" => HashType::BlockHash.bytes_to_string(&header));
-
-                    // try to begin construction new context
-                    let (prevalidator, head) = begin_construction(block_storage, &protocol_controller, &init_storage_data, &header, &log)?;
-
-                    // recreate state, reuse just pendings
-                    let (pending_operations, mut operations_to_delete) = state.split_operations_to_pending_and_others();
-                    state = MempoolState::new(prevalidator, head, pending_operations);
-
-                    // notify other actors
-                    notify_mempool_changed(&shell_channel, &state);
-
-                    // clear unneeded operations from mempool storage
-                    operations_to_delete
-                        .drain()
-                        .for_each(|oph| {
-                            if let Err(err) = mempool_storage.delete(&oph) {
-                                warn!(log, "Mempool - delete operation failed"; "hash" => HashType::OperationHash.bytes_to_string(&oph), "error" => format!("{:?}", err))
-                            }
-                        });
                 }
+                Event::Ban(ban) => process_ban(mempool_storage, shell_channel, &mut state, ban, log),
                 Event::ValidateOperation(oph, mempool_operation_type) => {
                     // TODO: handling when operation not exists - can happen?
                     let operation = mempool_storage.get(mempool_operation_type, oph.clone())?;
@@ -353,11 +530,15 @@ fn process_prevalidation(
 
                         // TODO: handle and validate pre_filter with operation?
 
-                        if state.is_already_validated(&oph) {
+                        if state.is_banned(&oph) {
+                            debug!(log, "Mempool - received validate operation event - operation is banned"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
+                            mempool_storage.delete(&oph)?;
+                        } else if state.is_already_validated(&oph) {
                             debug!(log, "Mempool - received validate operation event - operation already validated"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
-                        } else {
-                            // just add operations to pendings
-                            state.add_to_pending(&oph, operation.operation());
+                        } else if !state.add_to_pending(&oph, operation.operation()) {
+                            // no space for new pending operation, so we just forget it
+                            debug!(log, "Mempool - received validate operation event - too many pending operations, operation ignored"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
+                            mempool_storage.delete(&oph)?;
                         }
                     } else {
                         debug!(log, "Mempool - received validate operation event - operations was previously validated and removed from mempool storage"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
@@ -367,12 +548,100 @@ fn process_prevalidation(
         }
 
         // 2. lets handle pending operations (if any)
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+        if *bootstrapped {
+            handle_pending_operations(&shell_channel, &protocol_controller, mempool_storage, &mut state, &log);
+        }
+    }
+
+    Ok(())
+}
+
+fn process_new_head(
+    block_storage: &mut BlockStorage,
+    operations_storage: &OperationsStorage,
+    mempool_storage: &mut MempoolStorage,
+    init_storage_data: &StorageInitInfo,
+    shell_channel: &ShellChannelRef,
+    protocol_controller: &ProtocolController,
+    state: &mut MempoolState,
+    header: BlockHash,
+    level: Level,
+    log: &Logger,
+) -> Result<(), PrevalidationError> {
+    // check if NewHeader is bigger than actual, if present
+    if let Some(current_mempool_block) = &state.predecessor {
+        if level <= current_mempool_block.level {
+            warn!(log, "Mempool - new head has smaller level than actual mempool head, so we just ignore it!";
+                        "received_level" => level,
+                        "received_block_hash" => HashType::BlockHash.bytes_to_string(&header),
+                        "current_mempool_level" => current_mempool_block.level,
+                        "current_mempool_block_hash" => HashType::BlockHash.bytes_to_string(&current_mempool_block.hash));
+            return Ok(());
+        }
+    }
+
+    debug!(log, "Mempool - new higher head received, so begin construction a new context";
+                "received_level" => level,
+                "received_block_hash" => HashType::BlockHash.bytes_to_string(&header));
+
+    // if new head is not a successor of the actual mempool head, branch changed
+    let branch_changed = match (&state.predecessor, block_storage.get(&header)?) {
+        (Some(current_mempool_block), Some(block)) => block.header.predecessor() != &current_mempool_block.hash,
+        _ => true,
+    };
+
+    // operations included in the new head
+    let mut included = HashSet::new();
+    for operations in operations_storage.get_operations(&header)? {
+        for operation in operations.operations() {
+            included.insert(operation.message_hash().map_err(StorageError::from)?);
+        }
     }
 
+    // try to begin construction new context
+    let (prevalidator, head) = begin_construction(block_storage, &protocol_controller, &init_storage_data, &header, &log)?;
+
+    // reset state, non-refused operations are revalidated against the new head
+    let mut operations_to_delete = state.reset_for_new_head(prevalidator, head, &included, branch_changed);
+
+    // notify other actors
+    notify_mempool_changed(&shell_channel, &state);
+
+    // clear unneeded operations from mempool storage
+    operations_to_delete
+        .drain()
+        .for_each(|oph| {
+            if let Err(err) = mempool_storage.delete(&oph) {
+                warn!(log, "Mempool - delete operation failed"; "hash" => HashType::OperationHash.bytes_to_string(&oph), "error" => format!("{:?}", err))
+            }
+        });
+
     Ok(())
 }
 
+fn process_ban(mempool_storage: &mut MempoolStorage, shell_channel: &ShellChannelRef, state: &mut MempoolState, ban: MempoolOperationBan, log: &Logger) {
+    match ban {
+        MempoolOperationBan::Ban(oph) => {
+            info!(log, "Mempool - operation banned"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
+            if let Err(err) = mempool_storage.delete(&oph) {
+                warn!(log, "Mempool - delete banned operation failed"; "hash" => HashType::OperationHash.bytes_to_string(&oph), "error" => format!("{:?}", err))
+            }
+            if state.ban(&oph) {
+                notify_mempool_changed(&shell_channel, &state);
+            }
+        }
+        MempoolOperationBan::Unban(oph) => {
+            if state.unban(&oph) {
+                info!(log, "Mempool - operation unbanned"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
+            }
+        }
+        MempoolOperationBan::UnbanAll => {
+            info!(log, "Mempool - all operations unbanned");
+            state.unban_all();
+        }
+    }
+}
+
 fn hydrate_state(
     shell_channel: &ShellChannelRef,
     block_storage: &mut BlockStorage,
@@ -380,6 +649,8 @@ fn hydrate_state(
     mempool_storage: &mut MempoolStorage,
     protocol_controller: &ProtocolController,
     init_storage_data: &StorageInitInfo,
+    limits: &MempoolLimits,
+    bootstrapped: bool,
     log: &Logger) -> Result<MempoolState, PrevalidationError> {
 
     // load current head
@@ -392,19 +663,28 @@ fn hydrate_state(
         None => (None, None)
     };
 
-    // read from Mempool_storage (just pending) -> add to queue for validation -> pending
-    let pending = mempool_storage.iter()?
+    // restore operations from previous run, expired operations are just removed
+    let expired = mempool_storage.delete_expired(SystemTime::now())?;
+    if !expired.is_empty() {
+        debug!(log, "Mempool - expired operations removed from mempool storage"; "expired" => expired.len());
+    }
+
+    // read from Mempool_storage (pending and known_valid) -> pending, all of them are revalidated against the current head
+    let pending: HashMap<OperationHash, Operation> = mempool_storage.iter()?
         .into_iter()
         .map(|(key, value)| (key, value.operation().clone()))
         .collect();
+    if !pending.is_empty() {
+        info!(log, "Mempool - operations restored from mempool storage"; "restored" => pending.len());
+    }
 
     // internal mempool state
-    let mut state = MempoolState::new(prevalidator, head, pending);
+    let mut state = MempoolState::new(prevalidator, head, pending, limits.clone());
 
-    // TODO: do we need this?
-    // and process it immediatly on startup, before any event received to clean old stored unprocessed operations
-    if state.can_handle_pending() {
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+    // and process it immediatly on startup, before any event received, so restored operations are advertised to peers again,
+    // if node is not bootstrapped yet, restored operations are validated after bootstrap
+    if bootstrapped && state.can_handle_pending() {
+        handle_pending_operations(&shell_channel, &protocol_controller, mempool_storage, &mut state, &log);
     }
 
     Ok(state)
@@ -444,7 +724,7 @@ fn begin_construction(block_storage: &mut BlockStorage,
     Ok(result)
 }
 
-fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controller: &ProtocolController, state: &mut MempoolState, log: &Logger) {
+fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controller: &ProtocolController, mempool_storage: &mut MempoolStorage, state: &mut MempoolState, log: &Logger) {
     debug!(log, "Mempool - handle_pending_operations "; "pendings" => state.pending.len(), "can" => state.can_handle_pending());
 
     if !state.can_handle_pending() {
@@ -500,6 +780,20 @@ fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controlle
             }
         });
 
+    // if mempool is full, lets evict operations with the lowest priority
+    let evicted = state.evict_over_limits();
+    if !evicted.is_empty() {
+        debug!(log, "Mempool - limits exceeded, operations evicted"; "evicted" => evicted.len());
+        evicted
+            .iter()
+            .for_each(|oph| {
+                if let Err(err) = mempool_storage.delete(oph) {
+                    warn!(log, "Mempool - delete evicted operation failed"; "hash" => HashType::OperationHash.bytes_to_string(oph), "error" => format!("{:?}", err))
+                }
+            });
+        state_changed = true;
+    }
+
     // lets notify actors about changed mempool
     if state_changed {
         notify_mempool_changed(&shell_channel, &state);
@@ -529,3 +823,281 @@ fn notify_mempool_changed(shell_channel: &ShellChannelRef, mempool_state: &Mempo
     );
 }
 
+/// Size of the operation in bytes (branch + data)
+fn operation_size(operation: &Operation) -> usize {
+    HashType::BlockHash.size() + operation.data().len()
+}
+
+/// Priority of the operation in mempool, operations are ordered by kind and then by fee/gas ratio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct OperationPriority {
+    kind: OperationKindPriority,
+    /// Fee (in mutez) per thousand units of gas
+    fee_per_kilo_gas: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum OperationKindPriority {
+    /// Operation could not be recognized
+    Unknown,
+    /// Manager operations (transaction, origination, delegation, reveal)
+    Manager,
+    /// Anonymous operations (nonce revelation, evidences, activations)
+    Anonymous,
+    /// Voting operations (proposals, ballot)
+    Voting,
+    /// Consensus operations (endorsements)
+    Consensus,
+}
+
+impl OperationKindPriority {
+    fn from_kind(kind: &str) -> Self {
+        match kind {
+            "endorsement" | "endorsement_with_slot" => OperationKindPriority::Consensus,
+            "proposals" | "ballot" => OperationKindPriority::Voting,
+            "seed_nonce_revelation" | "double_endorsement_evidence" | "double_baking_evidence" | "activate_account" => OperationKindPriority::Anonymous,
+            "reveal" | "transaction" | "origination" | "delegation" => OperationKindPriority::Manager,
+            _ => OperationKindPriority::Unknown,
+        }
+    }
+}
+
+impl OperationPriority {
+    const LOWEST: OperationPriority = OperationPriority { kind: OperationKindPriority::Unknown, fee_per_kilo_gas: 0 };
+
+    /// Resolve priority from operation protocol data json (`contents` with `kind`, `fee` and `gas_limit`)
+    fn from_protocol_data_json(protocol_data_json: &OperationProtocolDataJson) -> Self {
+        let protocol_data: serde_json::Value = match serde_json::from_str(protocol_data_json) {
+            Ok(protocol_data) => protocol_data,
+            Err(_) => return OperationPriority::LOWEST,
+        };
+        let contents = match protocol_data["contents"].as_array() {
+            Some(contents) if !contents.is_empty() => contents,
+            _ => return OperationPriority::LOWEST,
+        };
+
+        // batch has the priority of its lowest kind
+        let kind = contents.iter()
+            .map(|content| content["kind"].as_str().map_or(OperationKindPriority::Unknown, OperationKindPriority::from_kind))
+            .min()
+            .unwrap_or(OperationKindPriority::Unknown);
+
+        let (fee, gas) = contents.iter()
+            .fold((0u128, 0u128), |(fee, gas), content| {
+                (fee + json_as_u128(&content["fee"]), gas + json_as_u128(&content["gas_limit"]))
+            });
+        let fee_per_kilo_gas = (fee * 1000 / std::cmp::max(gas, 1)) as u64;
+
+        OperationPriority { kind, fee_per_kilo_gas }
+    }
+}
+
+/// Ordering of the applied operation: priority and the source with the counter of manager operations
+#[derive(Clone, Debug)]
+struct OperationOrdering {
+    priority: OperationPriority,
+    source_counter: Option<(String, u128)>,
+}
+
+impl OperationOrdering {
+    fn from_protocol_data_json(protocol_data_json: &OperationProtocolDataJson) -> Self {
+        let source_counter = serde_json::from_str::<serde_json::Value>(protocol_data_json).ok()
+            .and_then(|protocol_data| {
+                // counter of the batch is the counter of its first content
+                let content = protocol_data["contents"].as_array()?.first()?.clone();
+                Some((content["source"].as_str()?.to_string(), json_as_u128(&content["counter"])))
+            });
+        OperationOrdering {
+            priority: OperationPriority::from_protocol_data_json(protocol_data_json),
+            source_counter,
+        }
+    }
+}
+
+/// Operations of the same source have to be applied in the counter order, so after sorting by priority
+/// the operations of every source are reordered by counter within the positions occupied by that source
+fn keep_counter_order(applied: &mut Vec<Applied>, orderings: &HashMap<OperationHash, OperationOrdering>) {
+    let source_counter = |applied: &Applied| orderings.get(&applied.hash).and_then(|ordering| ordering.source_counter.clone());
+
+    let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (position, operation) in applied.iter().enumerate() {
+        if let Some((source, _)) = source_counter(operation) {
+            positions.entry(source).or_default().push(position);
+        }
+    }
+
+    for positions in positions.values().filter(|positions| positions.len() > 1) {
+        let mut operations: Vec<Applied> = positions.iter().map(|position| applied[*position].clone()).collect();
+        operations.sort_by_key(|operation| source_counter(operation).map(|(_, counter)| counter));
+        for (position, operation) in positions.iter().zip(operations) {
+            applied[*position] = operation;
+        }
+    }
+}
+
+/// Numbers (mutez, gas) are encoded as strings in protocol json
+fn json_as_u128(value: &serde_json::Value) -> u128 {
+    value.as_str()
+        .and_then(|value| value.parse::<u128>().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_api::ffi::OperationProtocolDataJsonWithErrorListJson;
+    use tezos_messages::p2p::binary_message::BinaryMessage;
+
+    use super::*;
+
+    #[test]
+    fn test_operation_priority_by_kind() {
+        let endorsement = OperationPriority::from_protocol_data_json(&r#"{"contents":[{"kind":"endorsement","level":100}],"signature":"sig"}"#.to_string());
+        let transaction = OperationPriority::from_protocol_data_json(&r#"{"contents":[{"kind":"transaction","fee":"100000","gas_limit":"10000","amount":"1"}],"signature":"sig"}"#.to_string());
+        let invalid = OperationPriority::from_protocol_data_json(&"not a json".to_string());
+
+        assert_eq!(OperationKindPriority::Consensus, endorsement.kind);
+        assert_eq!(OperationKindPriority::Manager, transaction.kind);
+        assert_eq!(10_000, transaction.fee_per_kilo_gas);
+        assert_eq!(OperationPriority::LOWEST, invalid);
+        assert!(endorsement > transaction);
+        assert!(transaction > invalid);
+    }
+
+    #[test]
+    fn test_operation_priority_by_fee() {
+        let cheap = OperationPriority::from_protocol_data_json(&r#"{"contents":[{"kind":"transaction","fee":"1000","gas_limit":"10000"}]}"#.to_string());
+        let expensive = OperationPriority::from_protocol_data_json(&r#"{"contents":[{"kind":"reveal","fee":"1000","gas_limit":"1000"},{"kind":"transaction","fee":"1000","gas_limit":"1000"}]}"#.to_string());
+
+        assert_eq!(100, cheap.fee_per_kilo_gas);
+        assert_eq!(1000, expensive.fee_per_kilo_gas);
+        assert!(expensive > cheap);
+    }
+
+    #[test]
+    fn test_evict_over_limits() {
+        let operation = |data: u8| Operation::from_bytes(merge_bytes(data)).unwrap();
+        let applied = |hash: u8, fee: u64| Applied {
+            hash: vec![hash; 32],
+            protocol_data_json: format!(r#"{{"contents":[{{"kind":"transaction","fee":"{}","gas_limit":"1000"}}]}}"#, fee),
+        };
+
+        let mut state = MempoolState::new(None, None, HashMap::new(), MempoolLimits::new(2, 1024, 10));
+        for hash in 1..=3 {
+            assert!(state.add_to_pending(&vec![hash; 32], &operation(hash)));
+            state.remove_from_pending(&vec![hash; 32]);
+        }
+        state.add_result(&ValidateOperationResult {
+            applied: vec![applied(1, 500), applied(2, 100), applied(3, 1000)],
+            refused: vec![],
+            branch_refused: vec![],
+            branch_delayed: vec![],
+        });
+
+        // operation with the lowest fee is evicted
+        assert_eq!(vec![vec![2; 32]], state.evict_over_limits());
+        assert_eq!(2, state.operations.len());
+        assert_eq!(vec![vec![3; 32], vec![1; 32]], state.validation_result.applied.iter().map(|a| a.hash.clone()).collect::<Vec<_>>());
+        assert!(state.evict_over_limits().is_empty());
+    }
+
+    #[test]
+    fn test_pending_limits_and_counter_order() {
+        let operation = |data: u8| Operation::from_bytes(merge_bytes(data)).unwrap();
+        let size = operation_size(&operation(1));
+
+        // pending operations are limited by bytes too
+        let mut state = MempoolState::new(None, None, HashMap::new(), MempoolLimits::new(10, 2 * size, 10));
+        assert!(state.add_to_pending(&vec![1; 32], &operation(1)));
+        assert!(state.add_to_pending(&vec![2; 32], &operation(2)));
+        assert!(!state.add_to_pending(&vec![3; 32], &operation(3)));
+        assert!(state.remove_from_pending(&vec![1; 32]));
+        assert_eq!(size, state.pending_bytes);
+        assert!(state.add_to_pending(&vec![3; 32], &operation(3)));
+
+        // operations of the same source keep the counter order, even if the later one pays more
+        let applied = |hash: u8, source: &str, counter: u32, fee: u64| Applied {
+            hash: vec![hash; 32],
+            protocol_data_json: format!(r#"{{"contents":[{{"kind":"transaction","source":"{}","counter":"{}","fee":"{}","gas_limit":"1000"}}]}}"#, source, counter, fee),
+        };
+        state.add_result(&ValidateOperationResult {
+            applied: vec![applied(4, "tz1a", 1, 100), applied(5, "tz1b", 7, 500), applied(6, "tz1a", 2, 1000)],
+            refused: vec![],
+            branch_refused: vec![],
+            branch_delayed: vec![],
+        });
+        assert_eq!(vec![vec![4; 32], vec![5; 32], vec![6; 32]], state.validation_result.applied.iter().map(|a| a.hash.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reset_for_new_head() {
+        let applied = |hash: u8| Applied { hash: vec![hash; 32], protocol_data_json: "{}".to_string() };
+        let errored = |hash: u8| Errored {
+            hash: vec![hash; 32],
+            is_endorsement: None,
+            protocol_data_json_with_error_json: OperationProtocolDataJsonWithErrorListJson {
+                protocol_data_json: "{}".to_string(),
+                error_json: "[]".to_string(),
+            },
+        };
+
+        let mut state = MempoolState::new(None, None, HashMap::new(), MempoolLimits::default());
+        for hash in 1..=6 {
+            assert!(state.add_to_pending(&vec![hash; 32], &Operation::from_bytes(merge_bytes(hash)).unwrap()));
+            if hash != 6 {
+                state.remove_from_pending(&vec![hash; 32]);
+            }
+        }
+        state.add_result(&ValidateOperationResult {
+            applied: vec![applied(1), applied(2)],
+            refused: vec![errored(3)],
+            branch_refused: vec![errored(4)],
+            branch_delayed: vec![errored(5)],
+        });
+
+        // operation 2 was included in the new head
+        let included = vec![vec![2; 32]].into_iter().collect();
+        let to_delete = state.reset_for_new_head(None, None, &included, false);
+
+        assert_eq!(vec![vec![2; 32], vec![3; 32]].into_iter().collect::<HashSet<_>>(), to_delete);
+        assert_eq!(vec![vec![1; 32], vec![5; 32], vec![6; 32]].into_iter().collect::<HashSet<_>>(), state.pending);
+        assert_eq!(vec![vec![3; 32]], state.validation_result.refused.iter().map(|e| e.hash.clone()).collect::<Vec<_>>());
+        assert_eq!(vec![vec![4; 32]], state.validation_result.branch_refused.iter().map(|e| e.hash.clone()).collect::<Vec<_>>());
+        assert!(state.validation_result.applied.is_empty());
+        assert!(state.validation_result.branch_delayed.is_empty());
+        assert_eq!(5, state.operations.len());
+        assert!(!state.operations.contains_key(&vec![2; 32]));
+
+        // branch changed, so branch refused are revalidated
+        let _ = state.reset_for_new_head(None, None, &HashSet::new(), true);
+        assert!(state.pending.contains(&vec![4; 32]));
+        assert!(state.validation_result.branch_refused.is_empty());
+        assert_eq!(1, state.validation_result.refused.len());
+    }
+
+    #[test]
+    fn test_ban_and_unban() {
+        let mut state = MempoolState::new(None, None, HashMap::new(), MempoolLimits::default());
+        let oph = vec![1; 32];
+        assert!(state.add_to_pending(&oph, &Operation::from_bytes(merge_bytes(1)).unwrap()));
+
+        assert!(state.ban(&oph));
+        assert!(state.is_banned(&oph));
+        assert!(state.pending.is_empty());
+        assert!(state.operations.is_empty());
+        assert_eq!(0, state.operations_bytes);
+
+        // banning unknown operation
+        assert!(!state.ban(&vec![2; 32]));
+
+        assert!(state.unban(&oph));
+        assert!(!state.is_banned(&oph));
+        state.unban_all();
+        assert!(!state.is_banned(&vec![2; 32]));
+    }
+
+    fn merge_bytes(data: u8) -> Vec<u8> {
+        let mut bytes = vec![0; HashType::BlockHash.size()];
+        bytes.push(data);
+        bytes
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Configured rules deciding which remote peers the node connects to and accepts connections from.
+//!
+//! Addresses can be allowed or denied by CIDR networks. Trusted peers (points or peer ids) are not subject
+//! to the allow/deny lists, they are never blacklisted and trusted points are always reconnected.
+//! In private mode the node connects to and accepts connections only from the trusted peers.
+//!
+//! Trust is never granted by the IP address alone: outgoing connections are trusted by the full address of the point,
+//! incoming connections by the peer id after the handshake. Peer ids of the trusted points are learned from the outgoing connections.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use failure::Fail;
+
+use networking::p2p::peer::PeerId;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid CIDR network: {}", value)]
+pub struct IpNetworkParseError {
+    value: String,
+}
+
+/// IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. Single IP address is a network with the full prefix length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Check if the network contains the `ip` address, IPv4 and IPv6 networks never contain address of the other family
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = IpNetworkParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || IpNetworkParseError { value: value.to_string() };
+
+        let (address, prefix_len) = match value.find('/') {
+            Some(index) => (&value[..index], Some(&value[index + 1..])),
+            None => (value, None),
+        };
+        let address = address.trim().parse::<IpAddr>().map_err(|_| error())?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse::<u8>().map_err(|_| error())?,
+            None => max_prefix_len,
+        };
+
+        if prefix_len > max_prefix_len {
+            Err(error())
+        } else {
+            Ok(IpNetwork { address, prefix_len })
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Allow/deny lists and trusted peers
+#[derive(Clone, Debug, Default)]
+pub struct PeerFilter {
+    /// If not empty, only addresses from these networks are allowed
+    allowed: Vec<IpNetwork>,
+    /// Addresses from these networks are never allowed
+    denied: Vec<IpNetwork>,
+    /// Trusted points (addresses where the trusted peers listen)
+    trusted_points: HashSet<SocketAddr>,
+    /// Trusted peer ids
+    trusted_peer_ids: Arc<HashSet<PeerId>>,
+}
+
+impl PeerFilter {
+    pub fn new(allowed: Vec<IpNetwork>, denied: Vec<IpNetwork>, trusted_points: HashSet<SocketAddr>, trusted_peer_ids: HashSet<PeerId>) -> Self {
+        PeerFilter {
+            allowed,
+            denied,
+            trusted_points,
+            trusted_peer_ids: Arc::new(trusted_peer_ids),
+        }
+    }
+
+    /// Add more trusted points
+    pub fn trust_points(&mut self, points: &[SocketAddr]) {
+        self.trusted_points.extend(points);
+    }
+
+    pub fn trusted_points(&self) -> &HashSet<SocketAddr> {
+        &self.trusted_points
+    }
+
+    pub fn trusted_peer_ids(&self) -> &Arc<HashSet<PeerId>> {
+        &self.trusted_peer_ids
+    }
+
+    pub fn is_trusted_point(&self, address: &SocketAddr) -> bool {
+        self.trusted_points.contains(address)
+    }
+
+    pub fn is_trusted_peer_id(&self, peer_id: &str) -> bool {
+        self.trusted_peer_ids.contains(peer_id)
+    }
+
+    /// Trust the peer id, e.g. of the peer listening at the trusted point, so its incoming connections are trusted too
+    pub fn trust_peer_id(&mut self, peer_id: &str) {
+        if !self.trusted_peer_ids.contains(peer_id) {
+            Arc::make_mut(&mut self.trusted_peer_ids).insert(peer_id.to_string());
+        }
+    }
+
+    /// Check the `ip` address against the allow/deny lists, deny list wins. Trusted peers are not checked by the lists.
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.denied.iter().any(|network| network.contains(ip)) {
+            false
+        } else {
+            self.allowed.is_empty() || self.allowed.iter().any(|network| network.contains(ip))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_network() -> Result<(), failure::Error> {
+        let network: IpNetwork = "10.1.0.0/16".parse()?;
+        assert!(network.contains(&"10.1.255.1".parse()?));
+        assert!(!network.contains(&"10.2.0.1".parse()?));
+        assert!(!network.contains(&"::1".parse()?));
+
+        let all: IpNetwork = "0.0.0.0/0".parse()?;
+        assert!(all.contains(&"192.168.1.1".parse()?));
+
+        let single: IpNetwork = "192.168.1.1".parse()?;
+        assert_eq!("192.168.1.1/32", single.to_string());
+        assert!(!single.contains(&"192.168.1.2".parse()?));
+
+        let network: IpNetwork = "fd00::/8".parse()?;
+        assert!(network.contains(&"fd12:3456::1".parse()?));
+        assert!(!network.contains(&"fe80::1".parse()?));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/".parse::<IpNetwork>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_filter() -> Result<(), failure::Error> {
+        let trusted: SocketAddr = "10.1.2.3:9732".parse()?;
+        let filter = PeerFilter::new(
+            vec!["10.0.0.0/8".parse()?],
+            vec!["10.1.0.0/16".parse()?],
+            vec![trusted].into_iter().collect(),
+            HashSet::new(),
+        );
+
+        assert!(filter.is_allowed(&"10.2.0.1".parse()?));
+        assert!(!filter.is_allowed(&"10.1.0.1".parse()?));
+        assert!(!filter.is_allowed(&"192.168.0.1".parse()?));
+        // trust is not granted by the IP address of the trusted point
+        assert!(!filter.is_allowed(&trusted.ip()));
+        assert!(filter.is_trusted_point(&trusted));
+        assert!(!filter.is_trusted_point(&"10.1.2.3:9733".parse()?));
+
+        let mut filter = filter;
+        let shared_peer_ids = filter.trusted_peer_ids().clone();
+        filter.trust_peer_id("idrRoknJh9zwEePNswF3MPGFzmKaVp");
+        assert!(filter.is_trusted_peer_id("idrRoknJh9zwEePNswF3MPGFzmKaVp"));
+        assert!(shared_peer_ids.is_empty());
+
+        assert!(PeerFilter::default().is_allowed(&"192.168.0.1".parse()?));
+        Ok(())
+    }
+}
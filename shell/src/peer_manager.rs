@@ -3,13 +3,13 @@
 
 //! Manages connected peers.
 
-use std::cmp;
+use std::cmp::{self, Ordering as CmpOrdering};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use dns_lookup::LookupError;
 use futures::lock::Mutex;
@@ -20,12 +20,21 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Handle;
 use tokio::time::timeout;
 
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated};
-use networking::p2p::peer::{Bootstrap, Peer, PeerRef, SendMessage};
+use networking::p2p::network_channel::{BlacklistPeer, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated};
+use networking::p2p::bandwidth::Bandwidth;
+use networking::p2p::capture::TrafficCapture;
+use networking::p2p::connected_peers::ConnectedPeers;
+use networking::p2p::peer::{Bootstrap, Local, Peer, PeerId, PeerRef, refuse_connection, SendMessage};
+use networking::p2p::socks5::Socks5Proxy;
+use storage::PointStorage;
+use storage::persistent::PersistentStorage;
 use tezos_api::identity::Identity;
+use tezos_messages::p2p::encoding::ack::NackMotive;
 use tezos_messages::p2p::encoding::prelude::*;
 
-use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
+use crate::peer_filter::PeerFilter;
+use crate::shell_channel::{PeerStatistics, ShellChannelMsg, ShellChannelRef};
+use crate::stats::peer_stats::PeerStats;
 use crate::subscription::*;
 
 /// Timeout for outgoing connections
@@ -36,6 +45,10 @@ const WHITELIST_INTERVAL: Duration = Duration::from_secs(1_800);
 const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
 /// Limit how often we allow to trigger check of a peer count
 const CHECK_PEER_COUNT_LIMIT: Duration = Duration::from_secs(5);
+/// Max count of potential peers sent to the remote peer, when connection is refused
+const NACK_PEERS_LIMIT: usize = 100;
+/// Max count of the remembered scores of the dialled points
+const POINT_SCORES_LIMIT: usize = 1000;
 
 /// Check peer threshold
 #[derive(Clone, Debug)]
@@ -82,8 +95,9 @@ impl Threshold {
 /// This actor is responsible for peer management.
 ///
 /// It monitors number of connected peers. If the number of connected peers is too low it tries to
-/// connect to more peers. If the number of connected peers is too high, then randomly selected peers
-/// are disconnected.
+/// connect to more peers, points with the better score from the previous connections are dialled first.
+/// If the number of connected peers is too high, then the peers with the lowest score are disconnected.
+/// Score of the peer is measured by the chain manager (see [`PeerStats::score`]).
 #[actor(CheckPeerCount, WhitelistAllIpAddresses, AcceptPeer, ConnectToPeer, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
 pub struct PeerManager {
     /// All events generated by the network layer will end up in this channel
@@ -100,18 +114,24 @@ pub struct PeerManager {
     initial_peers: HashSet<SocketAddr>,
     /// Indicates that mempool should be disabled
     disable_mempool: bool,
-    /// Indicates that p2p is working in private mode
+    /// Indicates that p2p is working in private mode, only trusted peers are connected and accepted
     private_node: bool,
+    /// Allow/deny lists and trusted peers
+    peer_filter: PeerFilter,
     /// List of potential peers to connect to
     potential_peers: HashSet<SocketAddr>,
+    /// Points (addresses of the remote peers) learned in the previous runs of the node
+    point_storage: PointStorage,
+    /// Last known score of the dialled points, used to choose the points to dial
+    point_scores: HashMap<SocketAddr, f64>,
     /// Tokio runtime
     tokio_executor: Handle,
     /// We will listen for incoming connection at this port
     listener_port: u16,
-    /// Tezos identity
-    identity: Identity,
-    /// Network/protocol version
-    network_version: NetworkVersion,
+    /// Local node info (identity, network version) shared by all peers
+    local: Arc<Local>,
+    /// Peer ids of the connected peers, only one connection to the same peer id is allowed
+    connected_peers: ConnectedPeers,
     /// Message receiver boolean indicating whether
     /// more connections should be accepted from network
     rx_run: Arc<AtomicBool>,
@@ -132,28 +152,40 @@ impl PeerManager {
     pub fn actor(sys: &impl ActorRefFactory,
                  network_channel: NetworkChannelRef,
                  shell_channel: ShellChannelRef,
+                 persistent_storage: &PersistentStorage,
                  tokio_executor: Handle,
                  bootstrap_addresses: &[String],
                  initial_peers: &[SocketAddr],
                  threshold: Threshold,
                  listener_port: u16,
                  identity: Identity,
-                 network_version: NetworkVersion,
+                 expected_pow: f64,
+                 network_versions: Vec<NetworkVersion>,
+                 bandwidth: Arc<Bandwidth>,
+                 capture: Option<Arc<TrafficCapture>>,
+                 proxy: Option<Socks5Proxy>,
+                 mut peer_filter: PeerFilter,
                  disable_mempool: bool,
                  private_node: bool,
     ) -> Result<PeerManagerRef, CreateError> {
+        if private_node {
+            // private node connects only to the trusted peers, initial peers are trusted
+            peer_filter.trust_points(initial_peers);
+        }
+
         sys.actor_of_props::<PeerManager>(
             PeerManager::name(),
             Props::new_args((
                 network_channel,
                 shell_channel,
+                PointStorage::new(persistent_storage),
                 tokio_executor,
                 bootstrap_addresses.to_vec(),
                 HashSet::from_iter(initial_peers.to_vec()),
                 threshold,
                 listener_port,
-                identity,
-                network_version,
+                Arc::new(Local::new(listener_port, &identity.public_key, &identity.secret_key, &identity.proof_of_work_stamp, expected_pow, network_versions, bandwidth, capture, proxy)),
+                peer_filter,
                 disable_mempool,
                 private_node)),
         )
@@ -170,14 +202,37 @@ impl PeerManager {
         if self.peers.is_empty() || self.discovery_last.filter(|discovery_last| discovery_last.elapsed() <= DISCOVERY_INTERVAL).is_none() {
             self.discovery_last = Some(Instant::now());
 
-            info!(log, "Doing peer DNS lookup"; "bootstrap_addresses" => format!("{:?}", &self.bootstrap_addresses));
-            dns_lookup_peers(&self.bootstrap_addresses, &log).iter()
-                .for_each(|address| {
-                    if !self.is_blacklisted(&address.ip()) {
-                        info!(log, "Found potential peer"; "address" => address);
-                        self.potential_peers.insert(*address);
-                    }
-                });
+            if self.private_node {
+                // private node does not discover new peers, only trusted points are connected
+                let disconnected = self.peer_filter.trusted_points().iter()
+                    .filter(|point| !self.is_connected(point))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                self.potential_peers.extend(disconnected);
+                return;
+            }
+
+            // prefer historically good points known from the previous runs
+            match self.point_storage.find_best(self.threshold.high, SystemTime::now()) {
+                Ok(points) => points.into_iter()
+                    .filter(|(address, _)| self.can_connect(address))
+                    .for_each(|(address, _)| {
+                        self.potential_peers.insert(address);
+                    }),
+                Err(e) => warn!(log, "Failed to load known points"; "reason" => format!("{}", e)),
+            }
+
+            if self.potential_peers.len() < self.threshold.low {
+                info!(log, "Doing peer DNS lookup"; "bootstrap_addresses" => format!("{:?}", &self.bootstrap_addresses));
+                dns_lookup_peers(&self.bootstrap_addresses, &log).iter()
+                    .for_each(|address| {
+                        if self.can_connect(address) {
+                            info!(log, "Found potential peer"; "address" => address);
+                            self.potential_peers.insert(*address);
+                            self.store_discovered_point(address, log);
+                        }
+                    });
+            }
 
             if self.potential_peers.is_empty() {
                 info!(log, "Using initial peers as a potential peers"; "initial_peers" => format!("{:?}", &self.initial_peers));
@@ -191,20 +246,16 @@ impl PeerManager {
     }
 
     /// Create new peer actor
-    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr) -> PeerRef {
+    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr, incoming: bool) -> PeerRef {
         let peer = Peer::actor(
             sys,
             self.network_channel.clone(),
-            self.listener_port,
-            &self.identity.public_key,
-            &self.identity.secret_key,
-            &self.identity.proof_of_work_stamp,
-            self.network_version.clone(),
+            self.local.clone(),
             self.tokio_executor.clone(),
             socket_address,
         ).unwrap();
 
-        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: socket_address.clone() });
+        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: socket_address.clone(), incoming, peer_id: None, private_node: false, score: None });
 
         self.network_channel.tell(
             Publish {
@@ -218,23 +269,110 @@ impl PeerManager {
         peer
     }
 
-    /// Check if given ip address is blacklisted to connect to
-    fn is_blacklisted(&self, ip_address: &IpAddr) -> bool {
-        self.ip_blacklist.contains(ip_address)
+    /// Potential peers sent to the remote peer in the NACK message, when connection is refused
+    fn potential_peers_for_nack(&self) -> Vec<String> {
+        if self.private_node {
+            // private node does not share the addresses of its (trusted) peers
+            return vec![];
+        }
+
+        self.peers.values()
+            .filter(|peer_state| !peer_state.private_node)
+            .map(|peer_state| peer_state.address)
+            .chain(self.potential_peers.iter().cloned())
+            .take(NACK_PEERS_LIMIT)
+            .map(|address| address.to_string())
+            .collect()
+    }
+
+    /// Check if we are allowed to dial the point, trusted points are never blacklisted nor denied
+    fn can_connect(&self, address: &SocketAddr) -> bool {
+        if self.peer_filter.is_trusted_point(address) {
+            true
+        } else if self.private_node {
+            false
+        } else {
+            !self.ip_blacklist.contains(&address.ip()) && self.peer_filter.is_allowed(&address.ip())
+        }
+    }
+
+    /// Check if the point is connected, incoming connection from the same IP address is considered to be the same peer
+    fn is_connected(&self, address: &SocketAddr) -> bool {
+        self.peers.values()
+            .any(|peer_state| peer_state.address == *address || (peer_state.incoming && peer_state.address.ip() == address.ip()))
+    }
+
+    /// Trusted peers are never blacklisted: dialled trusted point or trusted peer id (known after the handshake)
+    fn is_trusted(&self, peer_state: &PeerState) -> bool {
+        (!peer_state.incoming && self.peer_filter.is_trusted_point(&peer_state.address))
+            || peer_state.peer_id.as_ref().filter(|peer_id| self.peer_filter.is_trusted_peer_id(peer_id)).is_some()
+    }
+
+    /// Trusted points which are not connected are reconnected regardless of the peer count
+    fn reconnect_trusted_points(&mut self, ctx: &Context<PeerManagerMsg>) {
+        let disconnected = self.peer_filter.trusted_points().iter()
+            .filter(|point| !self.is_connected(point))
+            .cloned()
+            .collect::<Vec<_>>();
+        for address in disconnected {
+            self.potential_peers.remove(&address);
+            ctx.myself().tell(ConnectToPeer { address }, ctx.myself().into());
+        }
     }
 
     fn process_shell_channel_message(&mut self, ctx: &Context<PeerManagerMsg>, msg: ShellChannelMsg) -> Result<(), failure::Error> {
         match msg {
             ShellChannelMsg::ShuttingDown(_) => {
+                // stop accepting new peers
                 self.shutting_down = true;
+                self.rx_run.store(false, Ordering::Release);
                 unsubscribe_from_dead_letters(ctx.system.dead_letters(), ctx.myself());
             }
+            ShellChannelMsg::PeerStatistics(statistics) => self.update_scores(&statistics),
             _ => ()
         }
 
         Ok(())
     }
 
+    /// Update scores of the connected peers and remember the scores of the dialled points
+    fn update_scores(&mut self, statistics: &PeerStatistics) {
+        let scores = statistics.peers.iter()
+            .map(|stats| (&stats.peer_id, stats.score))
+            .collect::<HashMap<_, _>>();
+
+        let mut point_scores = Vec::new();
+        for peer_state in self.peers.values_mut() {
+            let score = peer_state.peer_id.as_ref().and_then(|peer_id| scores.get(peer_id));
+            if let Some(score) = score {
+                peer_state.score = Some(*score);
+                if !peer_state.incoming {
+                    point_scores.push((peer_state.address, *score));
+                }
+            }
+        }
+        point_scores.into_iter()
+            .for_each(|(address, score)| self.remember_point_score(address, score));
+    }
+
+    fn remember_point_score(&mut self, address: SocketAddr, score: f64) {
+        if self.point_scores.len() >= POINT_SCORES_LIMIT && !self.point_scores.contains_key(&address) {
+            // forget the worst point
+            let worst = self.point_scores.iter()
+                .min_by(|(_, a), (_, b)| compare_scores(**a, **b))
+                .map(|(address, _)| *address);
+            if let Some(worst) = worst {
+                self.point_scores.remove(&worst);
+            }
+        }
+        self.point_scores.insert(address, score);
+    }
+
+    /// Score of the point to dial, points without the known score have the score of the new peer
+    fn point_score(&self, address: &SocketAddr) -> f64 {
+        self.point_scores.get(address).cloned().unwrap_or_else(|| PeerStats::default().score())
+    }
+
     fn trigger_check_peer_count(&mut self, ctx: &Context<PeerManagerMsg>) {
         let should_trigger = self.check_peer_count_last
             .map(|check_peer_count_last| check_peer_count_last.elapsed() > CHECK_PEER_COUNT_LIMIT)
@@ -246,33 +384,70 @@ impl PeerManager {
         }
     }
 
-    fn process_potential_peers(&mut self, potential_peers: &[String]) {
+    /// Refuse incoming connection, remote peer receives NACK with the `motive` and the list of potential peers
+    fn refuse_peer(&self, ctx: &Context<PeerManagerMsg>, msg: AcceptPeer, motive: NackMotive) {
+        let bootstrap = Bootstrap::refused(msg.stream, msg.address, self.disable_mempool, self.private_node, motive, self.potential_peers_for_nack());
+        let local = self.local.clone();
+        let log = ctx.system.log();
+        self.tokio_executor.spawn(refuse_connection(bootstrap, local, log));
+    }
+
+    fn process_potential_peers(&mut self, potential_peers: &[String], log: &Logger) {
+        if self.private_node {
+            // private node connects only to the trusted points
+            return;
+        }
+
         let sock_addresses = potential_peers.iter()
             .filter_map(|str_ip_port| str_ip_port.parse().ok())
-            .filter(|address: &SocketAddr| !self.is_blacklisted(&address.ip()))
+            .filter(|address: &SocketAddr| self.can_connect(address))
             .collect::<Vec<_>>();
+        sock_addresses.iter().for_each(|address| self.store_discovered_point(address, log));
         self.potential_peers.extend(sock_addresses);
     }
+
+    fn store_discovered_point(&self, address: &SocketAddr, log: &Logger) {
+        if let Err(e) = self.point_storage.discovered(address) {
+            warn!(log, "Failed to store point"; "address" => address, "reason" => format!("{}", e));
+        }
+    }
+
+    /// Points we dialled are stored, addresses of the incoming connections are not points where the peers listen
+    fn outgoing_peer_address(&self, peer: &ActorUri) -> Option<SocketAddr> {
+        self.peers.get(peer)
+            .filter(|peer_state| !peer_state.incoming)
+            .map(|peer_state| peer_state.address)
+    }
+
+    /// Ban the point for the same time as its IP address is blacklisted
+    fn ban_point(&self, address: &SocketAddr, log: &Logger) {
+        if let Err(e) = self.point_storage.ban(address, SystemTime::now() + WHITELIST_INTERVAL) {
+            warn!(log, "Failed to ban point"; "address" => address, "reason" => format!("{}", e));
+        }
+    }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Vec<String>, HashSet<SocketAddr>, Threshold, u16, Identity, NetworkVersion, bool, bool)> for PeerManager {
-    fn create_args((network_channel, shell_channel, tokio_executor, bootstrap_addresses, initial_peers, threshold, listener_port, identity, network_version, disable_mempool, private_node):
-                   (NetworkChannelRef, ShellChannelRef, Handle, Vec<String>, HashSet<SocketAddr>, Threshold, u16, Identity, NetworkVersion, bool, bool)) -> Self
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PointStorage, Handle, Vec<String>, HashSet<SocketAddr>, Threshold, u16, Arc<Local>, PeerFilter, bool, bool)> for PeerManager {
+    fn create_args((network_channel, shell_channel, point_storage, tokio_executor, bootstrap_addresses, initial_peers, threshold, listener_port, local, peer_filter, disable_mempool, private_node):
+                   (NetworkChannelRef, ShellChannelRef, PointStorage, Handle, Vec<String>, HashSet<SocketAddr>, Threshold, u16, Arc<Local>, PeerFilter, bool, bool)) -> Self
     {
         PeerManager {
             network_channel,
             shell_channel,
+            point_storage,
             tokio_executor,
             bootstrap_addresses,
             initial_peers,
             threshold,
             listener_port,
-            identity,
-            network_version,
+            local,
+            connected_peers: ConnectedPeers::default(),
             disable_mempool,
             private_node,
+            peer_filter,
             rx_run: Arc::new(AtomicBool::new(true)),
             potential_peers: HashSet::new(),
+            point_scores: HashMap::new(),
             peers: HashMap::new(),
             ip_blacklist: HashSet::new(),
             discovery_last: None,
@@ -291,6 +466,12 @@ impl Actor for PeerManager {
         subscribe_to_shell_events(&self.shell_channel, ctx.myself());
         subscribe_to_dead_letters(ctx.system.dead_letters(), ctx.myself());
 
+        // bans survive the restart of the node
+        match self.point_storage.find_banned(SystemTime::now()) {
+            Ok(banned) => self.ip_blacklist.extend(banned.iter().map(SocketAddr::ip)),
+            Err(e) => warn!(ctx.system.log(), "Failed to load banned points"; "reason" => format!("{}", e)),
+        }
+
         ctx.schedule::<Self::Msg, _>(
             Duration::from_secs(3),
             Duration::from_secs(10),
@@ -371,6 +552,8 @@ impl Receive<CheckPeerCount> for PeerManager {
             return;
         }
 
+        self.reconnect_trusted_points(ctx);
+
         if self.peers.len() < self.threshold.low {
             // peer count is too low, try to connect to more peers
             warn!(ctx.system.log(), "Peer count is too low"; "actual" => self.peers.len(), "required" => self.threshold.low);
@@ -380,8 +563,9 @@ impl Receive<CheckPeerCount> for PeerManager {
 
             let num_required_peers = cmp::max((self.threshold.high + 3 * self.threshold.low) / 4 - self.peers.len(), self.threshold.low);
             let mut addresses_to_connect = self.potential_peers.iter().cloned().collect::<Vec<SocketAddr>>();
-            // randomize peers as a security measurement
+            // randomize peers as a security measurement, points with the same score stay in the random order
             addresses_to_connect.shuffle(&mut rand::thread_rng());
+            addresses_to_connect.sort_by(|a, b| compare_scores(self.point_score(b), self.point_score(a)));
             addresses_to_connect
                 .drain(0..cmp::min(num_required_peers, addresses_to_connect.len()))
                 .for_each(|address| {
@@ -392,10 +576,17 @@ impl Receive<CheckPeerCount> for PeerManager {
             // peer count is too high, disconnect some peers
             warn!(ctx.system.log(), "Peer count is too high. Some peers will be stopped"; "actual" => self.peers.len(), "limit" => self.threshold.high);
 
-            // stop some peers
-            self.peers.values()
+            // stop peers with the lowest score, trusted peers are kept
+            let mut peers_to_stop = self.peers.values()
+                .filter(|peer_state| !self.is_trusted(peer_state))
+                .collect::<Vec<_>>();
+            peers_to_stop.sort_by(|a, b| compare_scores(a.score(), b.score()));
+            peers_to_stop.into_iter()
                 .take(self.peers.len() - self.threshold.high)
-                .for_each(|peer_state| ctx.system.stop(peer_state.peer_ref.clone()))
+                .for_each(|peer_state| {
+                    debug!(ctx.system.log(), "Stopping peer with low score"; "ip" => peer_state.address, "score" => peer_state.score());
+                    ctx.system.stop(peer_state.peer_ref.clone())
+                })
         }
 
         self.check_peer_count_last = Some(Instant::now());
@@ -415,14 +606,18 @@ impl Receive<NetworkChannelMsg> for PeerManager {
                         PeerMessage::Advertise(message) => {
                             // extract potential peers from the advertise message
                             info!(ctx.system.log(), "Received advertise message"; "peer" => received.peer.name());
-                            self.process_potential_peers(message.id());
+                            self.process_potential_peers(message.id(), &ctx.system.log());
+                        }
+                        PeerMessage::Bootstrap if self.private_node => {
+                            // private node does not share the addresses of its (trusted) peers
+                            debug!(ctx.system.log(), "Ignoring bootstrap message in private mode"; "peer" => received.peer.name());
                         }
                         PeerMessage::Bootstrap => {
-                            // to a bootstrap message we will respond with list of potential peers
+                            // to a bootstrap message we will respond with list of potential peers, private peers are never advertised
                             info!(ctx.system.log(), "Received bootstrap message"; "peer" => received.peer.name());
                             let addresses = self.peers.values()
                                 .into_iter()
-                                .filter(|peer_state| peer_state.peer_ref != received.peer)
+                                .filter(|peer_state| peer_state.peer_ref != received.peer && !peer_state.private_node)
                                 .map(|peer_state| peer_state.address)
                                 .collect::<Vec<_>>();
                             let msg = AdvertiseMessage::new(&addresses);
@@ -432,20 +627,63 @@ impl Receive<NetworkChannelMsg> for PeerManager {
                     });
                 self.trigger_check_peer_count(ctx);
             }
-            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect }) => {
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, .. }) => {
+                if let Some(peer_state) = self.peers.get_mut(peer.uri()) {
+                    peer_state.peer_id = Some(peer_id.clone());
+                    peer_state.private_node = peer_metadata.private_node();
+                }
+                if let Some(address) = self.outgoing_peer_address(peer.uri()) {
+                    if self.peer_filter.is_trusted_point(&address) && !self.peer_filter.is_trusted_peer_id(&peer_id) {
+                        // incoming connections of the peer are trusted by its peer id
+                        info!(ctx.system.log(), "Trusting peer id of the trusted point"; "ip" => address, "peer_id" => &peer_id);
+                        self.peer_filter.trust_peer_id(&peer_id);
+                    }
+                    if let Err(e) = self.point_storage.connection_succeeded(&address, &peer_id, SystemTime::now()) {
+                        warn!(ctx.system.log(), "Failed to store point"; "address" => address, "reason" => format!("{}", e));
+                    }
+                }
+            }
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect, reason }) => {
                 // received message that bootstrap process failed for the peer
+                let dialled = self.peers.values().any(|peer_state| !peer_state.incoming && peer_state.address == address);
+                if dialled {
+                    // failed handshake counts as a failed connection to the point
+                    point_connection_failed(&self.point_storage, &address, &ctx.system.log());
+                }
                 match potential_peers_to_connect {
                     Some(peers) => {
                         info!(ctx.system.log(), "Received list of potential peers in the NACK message"; "ip" => format!("{}", address.ip()), "peers" => format!("{:?}", &peers));
-                        self.process_potential_peers(&peers);
+                        self.process_potential_peers(&peers, &ctx.system.log());
                         self.trigger_check_peer_count(ctx);
                     }
+                    None if dialled && self.peer_filter.is_trusted_point(&address) => {
+                        info!(ctx.system.log(), "Trusted peer failed at bootstrap process"; "ip" => format!("{}", address.ip()), "reason" => reason);
+                    }
                     None => {
-                        info!(ctx.system.log(), "Blacklisting IP because peer failed at bootstrap process"; "ip" => format!("{}", address.ip()));
+                        info!(ctx.system.log(), "Blacklisting IP because peer failed at bootstrap process"; "ip" => format!("{}", address.ip()), "reason" => reason);
                         self.ip_blacklist.insert(address.ip());
+                        if dialled {
+                            self.ban_point(&address, &ctx.system.log());
+                        }
                     }
                 }
             }
+            NetworkChannelMsg::BlacklistPeer(BlacklistPeer { peer, reason }) => {
+                // received message that peer misbehaved
+                if let Some(peer_state) = self.peers.get(peer.uri()) {
+                    if self.is_trusted(peer_state) {
+                        warn!(ctx.system.log(), "Trusted peer misbehaved, disconnecting without blacklisting"; "ip" => format!("{}", peer_state.address.ip()), "peer" => peer.name(), "reason" => reason);
+                        ctx.system.stop(peer);
+                        return;
+                    }
+                    warn!(ctx.system.log(), "Blacklisting IP because peer misbehaved"; "ip" => format!("{}", peer_state.address.ip()), "peer" => peer.name(), "reason" => reason);
+                    self.ip_blacklist.insert(peer_state.address.ip());
+                }
+                if let Some(address) = self.outgoing_peer_address(peer.uri()) {
+                    self.ban_point(&address, &ctx.system.log());
+                }
+                ctx.system.stop(peer);
+            }
             _ => ()
         }
     }
@@ -466,27 +704,35 @@ impl Receive<ConnectToPeer> for PeerManager {
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ConnectToPeer, _sender: Sender) {
         // received message instructing this actor that it should open new p2p connection to the remote peer
 
-        if self.is_blacklisted(&msg.address.ip()) {
-            debug!(ctx.system.log(), "Peer is blacklisted - will not connect"; "ip" => format!("{}", msg.address.ip()));
+        if self.shutting_down {
+            debug!(ctx.system.log(), "Node is shutting down - will not connect"; "ip" => format!("{}", msg.address.ip()));
+        } else if !self.can_connect(&msg.address) {
+            debug!(ctx.system.log(), "Peer is blacklisted or not allowed - will not connect"; "ip" => format!("{}", msg.address.ip()));
         } else {
-            let peer = self.create_peer(ctx, &msg.address);
+            let peer = self.create_peer(ctx, &msg.address, false);
             let system = ctx.system.clone();
             let disable_mempool = self.disable_mempool;
             let private_node = self.private_node;
+            let connected_peers = self.connected_peers.clone();
+            let potential_peers = self.potential_peers_for_nack();
+            let point_storage = self.point_storage.clone();
+            let local = self.local.clone();
 
             self.tokio_executor.spawn(async move {
                 info!(system.log(), "Connecting to IP"; "ip" => msg.address, "peer" => peer.name());
-                match timeout(CONNECT_TIMEOUT, TcpStream::connect(&msg.address)).await {
+                match timeout(CONNECT_TIMEOUT, local.connect(&msg.address)).await {
                     Ok(Ok(stream)) => {
                         info!(system.log(), "Connection successful"; "ip" => msg.address);
-                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node), None);
+                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node, connected_peers, potential_peers), None);
                     }
                     Ok(Err(e)) => {
-                        info!(system.log(), "Connection failed"; "ip" => msg.address, "peer" => peer.name(), "reason" => format!("{:?}", e));
+                        info!(system.log(), "Connection failed"; "ip" => msg.address, "peer" => peer.name(), "reason" => format!("{}", e));
+                        point_connection_failed(&point_storage, &msg.address, &system.log());
                         system.stop(peer);
                     }
                     Err(_) => {
                         info!(system.log(), "Connection timed out"; "ip" => msg.address, "peer" => peer.name());
+                        point_connection_failed(&point_storage, &msg.address, &system.log());
                         system.stop(peer);
                     }
                 }
@@ -499,19 +745,54 @@ impl Receive<AcceptPeer> for PeerManager {
     type Msg = PeerManagerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: AcceptPeer, _sender: Sender) {
-        if self.is_blacklisted(&msg.address.ip()) {
-            debug!(ctx.system.log(), "Peer is blacklisted - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
-        } else if self.peers.len() < self.threshold.high {
-            info!(ctx.system.log(), "Connection from"; "ip" => msg.address);
-            let peer = self.create_peer(ctx, &msg.address);
-            peer.tell(Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node), None);
+        // only IP address is known before the handshake, trusted peers are recognized by the peer id after the handshake
+        let refuse_motive = if self.shutting_down {
+            debug!(ctx.system.log(), "Node is shutting down - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
+            return;
+        } else if self.ip_blacklist.contains(&msg.address.ip()) {
+            debug!(ctx.system.log(), "Peer is blacklisted - will accept only trusted peer id"; "ip" => format!("{}", msg.address.ip()));
+            Some(NackMotive::NoMotive)
+        } else if !self.peer_filter.is_allowed(&msg.address.ip()) {
+            debug!(ctx.system.log(), "Peer is not allowed - will accept only trusted peer id"; "ip" => format!("{}", msg.address.ip()));
+            Some(NackMotive::NoMotive)
+        } else if self.private_node {
+            debug!(ctx.system.log(), "Private node - will accept only trusted peer id"; "ip" => format!("{}", msg.address.ip()));
+            Some(NackMotive::NoMotive)
+        } else if self.peers.len() >= self.threshold.high {
+            debug!(ctx.system.log(), "Peer limit was reached - will accept only trusted peer id"; "ip" => format!("{}", msg.address.ip()));
+            Some(NackMotive::TooManyConnections)
         } else {
-            debug!(ctx.system.log(), "Cannot accept incoming peer connection because peer limit was reached");
-            drop(msg.stream); // not needed, just wanted to be explicit here
+            None
+        };
+
+        match refuse_motive {
+            Some(motive) if self.peer_filter.trusted_peer_ids().is_empty() => {
+                debug!(ctx.system.log(), "No trusted peer ids - will refuse connection"; "ip" => format!("{}", msg.address.ip()));
+                self.refuse_peer(ctx, msg, motive);
+            }
+            refuse_motive => {
+                info!(ctx.system.log(), "Connection from"; "ip" => msg.address);
+                let peer = self.create_peer(ctx, &msg.address, true);
+                let mut bootstrap = Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node, self.connected_peers.clone(), self.potential_peers_for_nack());
+                if let Some(motive) = refuse_motive {
+                    // remote peer is accepted only if its peer id is trusted
+                    bootstrap = bootstrap.trusted_only(self.peer_filter.trusted_peer_ids().clone(), motive);
+                }
+                peer.tell(bootstrap, None);
+            }
         }
     }
 }
 
+/// Record failed connection (or handshake) to the point we dialled
+fn point_connection_failed(point_storage: &PointStorage, address: &SocketAddr, log: &Logger) {
+    match point_storage.connection_failed(address, SystemTime::now()) {
+        Ok(true) => info!(log, "Point removed, because it keeps failing"; "ip" => address),
+        Ok(false) => (),
+        Err(e) => warn!(log, "Failed to store point"; "ip" => address, "reason" => format!("{}", e)),
+    }
+}
+
 /// Start to listen for incoming connections indefinitely.
 async fn begin_listen_incoming(listener_port: u16, peer_manager: PeerManagerRef, rx_run: Arc<AtomicBool>) {
     let listener_address = format!("0.0.0.0:{}", listener_port).parse::<SocketAddr>().expect("Failed to parse listener address");
@@ -553,10 +834,30 @@ fn resolve_dns_name_to_peer_address(address: &str) -> Result<Vec<SocketAddr>, Lo
     Ok(addrs)
 }
 
+/// Order scores from the worst, invalid (NaN) scores are the worst
+fn compare_scores(a: f64, b: f64) -> CmpOrdering {
+    a.partial_cmp(&b).unwrap_or_else(|| b.is_nan().cmp(&a.is_nan()))
+}
+
 /// Holds information about a specific peer.
 struct PeerState {
     /// Reference to peer actor
     peer_ref: PeerRef,
     /// Peer IP address
     address: SocketAddr,
+    /// Peer connected to us
+    incoming: bool,
+    /// Peer id is known after the successful bootstrap
+    peer_id: Option<PeerId>,
+    /// Peer does not want to be advertised
+    private_node: bool,
+    /// Score measured by the chain manager, `None` until the first statistics are published
+    score: Option<f64>,
+}
+
+impl PeerState {
+    /// Peers without the measured score have the score of the new peer
+    fn score(&self) -> f64 {
+        self.score.unwrap_or_else(|| PeerStats::default().score())
+    }
 }
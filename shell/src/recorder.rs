@@ -0,0 +1,184 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Records network and shell events to a file, so they can be replayed later (see [`replay`](crate::replay)).
+//!
+//! Recorded are all shell channel messages, bootstrapped and disconnected peers and all messages received from peers.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use riker::actors::*;
+use slog::{info, warn};
+
+use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped, PeerCreated};
+use tezos_messages::p2p::encoding::prelude::NetworkVersion;
+
+use crate::recording::{encode, encode_for_version, RecordedEventKind, RecordingError, RecordingWriter};
+use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
+use crate::subscription::{subscribe_to_actor_terminated, subscribe_to_network_events, subscribe_to_shell_events};
+
+/// Recording written to a file
+pub type FileRecordingWriter = RecordingWriter<BufWriter<File>>;
+
+type SharedRecordingWriter = Arc<Mutex<Option<FileRecordingWriter>>>;
+
+/// Create new recording file, existing file is overwritten
+pub fn create_recording_file(path: &Path) -> Result<FileRecordingWriter, RecordingError> {
+    RecordingWriter::new(BufWriter::new(File::create(path)?))
+}
+
+/// Writes network and shell events to the recording.
+#[actor(NetworkChannelMsg, ShellChannelMsg, SystemEvent)]
+pub struct EventRecorder {
+    /// All events from network layer will be published to this channel
+    network_channel: NetworkChannelRef,
+    /// All events from shell will be published to this channel
+    shell_channel: ShellChannelRef,
+    /// Recording is stopped (set to `None`), when writing fails
+    writer: SharedRecordingWriter,
+    /// Addresses of the created peers
+    peer_addresses: HashMap<ActorUri, SocketAddr>,
+    /// Peer ids and negotiated network versions of the bootstrapped peers
+    peers: HashMap<ActorUri, (String, NetworkVersion)>,
+}
+
+/// Reference to [event recorder](EventRecorder) actor
+pub type EventRecorderRef = ActorRef<EventRecorderMsg>;
+
+impl EventRecorder {
+    pub fn actor(sys: &impl ActorRefFactory, network_channel: NetworkChannelRef, shell_channel: ShellChannelRef, writer: FileRecordingWriter) -> Result<EventRecorderRef, CreateError> {
+        sys.actor_of_props::<EventRecorder>(
+            EventRecorder::name(),
+            Props::new_args((network_channel, shell_channel, Arc::new(Mutex::new(Some(writer))))),
+        )
+    }
+
+    /// The `EventRecorder` is intended to serve as a singleton actor so that's why
+    /// we won't support multiple names per instance.
+    fn name() -> &'static str {
+        "event-recorder"
+    }
+
+    fn record(&self, ctx: &Context<EventRecorderMsg>, event: Result<RecordedEventKind, RecordingError>) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(recording) = writer.as_mut() {
+            if let Err(e) = event.and_then(|event| recording.write(event)) {
+                warn!(ctx.system.log(), "Failed to record event, recording is stopped"; "reason" => format!("{}", e));
+                let _ = recording.flush();
+                *writer = None;
+            }
+        }
+    }
+
+    fn flush(&self, ctx: &Context<EventRecorderMsg>) {
+        if let Some(recording) = self.writer.lock().unwrap().as_mut() {
+            if let Err(e) = recording.flush() {
+                warn!(ctx.system.log(), "Failed to flush recording"; "reason" => format!("{}", e));
+            }
+        }
+    }
+}
+
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, SharedRecordingWriter)> for EventRecorder {
+    fn create_args((network_channel, shell_channel, writer): (NetworkChannelRef, ShellChannelRef, SharedRecordingWriter)) -> Self {
+        EventRecorder {
+            network_channel,
+            shell_channel,
+            writer,
+            peer_addresses: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for EventRecorder {
+    type Msg = EventRecorderMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        subscribe_to_actor_terminated(ctx.system.sys_events(), ctx.myself());
+        subscribe_to_network_events(&self.network_channel, ctx.myself());
+        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+        info!(ctx.system.log(), "Recording of network and shell events started");
+    }
+
+    fn post_stop(&mut self) {
+        if let Some(recording) = self.writer.lock().unwrap().as_mut() {
+            let _ = recording.flush();
+        }
+    }
+
+    fn sys_recv(&mut self, ctx: &Context<Self::Msg>, msg: SystemMsg, sender: Option<BasicActorRef>) {
+        if let SystemMsg::Event(evt) = msg {
+            self.receive(ctx, evt, sender);
+        }
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<SystemEvent> for EventRecorder {
+    type Msg = EventRecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
+        if let SystemEvent::ActorTerminated(evt) = msg {
+            self.peer_addresses.remove(evt.actor.uri());
+            if let Some((peer_id, _)) = self.peers.remove(evt.actor.uri()) {
+                self.record(ctx, Ok(RecordedEventKind::PeerDisconnected { peer_id }));
+            }
+        }
+    }
+}
+
+impl Receive<NetworkChannelMsg> for EventRecorder {
+    type Msg = EventRecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: NetworkChannelMsg, _sender: Sender) {
+        match msg {
+            NetworkChannelMsg::PeerCreated(PeerCreated { peer, address }) => {
+                self.peer_addresses.insert(peer.uri().clone(), address);
+            }
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, network_version }) => {
+                self.peers.insert(peer.uri().clone(), (peer_id.clone(), network_version.clone()));
+                let peer_address = self.peer_addresses.get(peer.uri()).cloned()
+                    .unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+                let event = encode(&network_version)
+                    .map(|network_version| RecordedEventKind::PeerBootstrapped {
+                        peer_id,
+                        peer_address,
+                        disable_mempool: peer_metadata.disable_mempool(),
+                        private_node: peer_metadata.private_node(),
+                        network_version,
+                    });
+                self.record(ctx, event);
+            }
+            NetworkChannelMsg::PeerMessageReceived(received) => {
+                if let Some((peer_id, network_version)) = self.peers.get(received.peer.uri()).cloned() {
+                    // message is recorded as it was received, so the cached bytes are reused
+                    let event = encode_for_version(&*received.message, &network_version)
+                        .map(|message| RecordedEventKind::PeerMessageReceived { peer_id, message });
+                    self.record(ctx, event);
+                }
+            }
+            _ => ()
+        }
+    }
+}
+
+impl Receive<ShellChannelMsg> for EventRecorder {
+    type Msg = EventRecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        self.record(ctx, RecordedEventKind::try_from(&msg));
+        if let ShellChannelMsg::ShuttingDown(_) = msg {
+            self.flush(ctx);
+        }
+    }
+}
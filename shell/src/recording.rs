@@ -0,0 +1,329 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Compact file format for recorded network and shell events.
+//!
+//! Recording is a [`records`](networking::records) file of bincode encoded [`RecordedEvent`]s. P2P messages, block headers and operations are stored
+//! in their binary p2p encoding.
+//!
+//! Recordings are written by [`EventRecorder`](crate::recorder::EventRecorder) and replayed by [`Replayer`](crate::replay::Replayer).
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crypto::hash::{BlockHash, OperationHash, ProtocolHash};
+use networking::records::{RecordError, RecordReader, RecordWriter};
+use storage::block_storage::BlockJsonData;
+use storage::BlockHeaderWithHash;
+use tezos_api::ffi::ValidateOperationResult;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::block_header::Level;
+use tezos_messages::p2p::encoding::prelude::{BlockHeader, NetworkVersion, Operation, PeerMessageResponse};
+
+use crate::Head;
+use crate::shell_channel::{AllBlockOperationsReceived, BlockApplied, BlockReceived, CurrentMempoolState, InjectBlock, MempoolOperationBan, MempoolOperationReceived, PeerStatistics, ShellChannelMsg, ShuttingDown, SyncStateChanged, WorkerFailed};
+
+/// Version of the recording format, bumped whenever the encoding of the recorded events changes
+/// (version 2: peer statistics and negotiated network version of the connected peers,
+/// version 3: peer messages encoded for the negotiated network version)
+const RECORDING_VERSION: u8 = 3;
+
+/// Possible errors of writing or reading of recordings
+pub type RecordingError = RecordError;
+
+/// One recorded event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedEvent {
+    /// Time of the event since the start of the recording
+    pub elapsed: Duration,
+    pub event: RecordedEventKind,
+}
+
+/// Recorded network and shell events, peers are identified by their peer id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RecordedEventKind {
+    PeerBootstrapped {
+        peer_id: String,
+        peer_address: SocketAddr,
+        disable_mempool: bool,
+        private_node: bool,
+        /// Binary encoded negotiated `NetworkVersion`
+        network_version: Vec<u8>,
+    },
+    PeerDisconnected {
+        peer_id: String,
+    },
+    PeerMessageReceived {
+        peer_id: String,
+        /// Binary encoded [`PeerMessageResponse`] for the network version negotiated with the peer (see `PeerBootstrapped`)
+        message: Vec<u8>,
+    },
+    BlockApplied {
+        hash: BlockHash,
+        /// Binary encoded [`BlockHeader`]
+        header: Vec<u8>,
+        json_data: BlockJsonData,
+    },
+    BlockReceived(BlockReceived),
+    AllBlockOperationsReceived(AllBlockOperationsReceived),
+    MempoolOperationReceived(MempoolOperationReceived),
+    MempoolStateChanged {
+        head: Option<(BlockHash, Level)>,
+        protocol: Option<ProtocolHash>,
+        result: ValidateOperationResult,
+        /// Binary encoded [`Operation`]s
+        operations: Vec<(OperationHash, Vec<u8>)>,
+        pending: Vec<OperationHash>,
+    },
+    MempoolOperationBan(MempoolOperationBan),
+    InjectBlock {
+        /// Binary encoded [`BlockHeader`]
+        header: Vec<u8>,
+        /// Binary encoded [`Operation`]s ordered by validation passes
+        operations: Option<Vec<Vec<Vec<u8>>>>,
+        force: bool,
+    },
+    SyncStateChanged(SyncStateChanged),
+    WorkerFailed(WorkerFailed),
+    PeerStatistics(PeerStatistics),
+    ShuttingDown,
+}
+
+impl TryFrom<&ShellChannelMsg> for RecordedEventKind {
+    type Error = RecordingError;
+
+    fn try_from(msg: &ShellChannelMsg) -> Result<Self, Self::Error> {
+        let event = match msg {
+            ShellChannelMsg::BlockApplied(block) => RecordedEventKind::BlockApplied {
+                hash: block.header().hash.clone(),
+                header: encode(&*block.header().header)?,
+                json_data: block.json_data().clone(),
+            },
+            ShellChannelMsg::BlockReceived(msg) => RecordedEventKind::BlockReceived(msg.clone()),
+            ShellChannelMsg::AllBlockOperationsReceived(msg) => RecordedEventKind::AllBlockOperationsReceived(msg.clone()),
+            ShellChannelMsg::MempoolOperationReceived(msg) => RecordedEventKind::MempoolOperationReceived(msg.clone()),
+            ShellChannelMsg::MempoolStateChanged(state) => RecordedEventKind::MempoolStateChanged {
+                head: state.head.as_ref().map(|head| (head.hash.clone(), head.level)),
+                protocol: state.protocol.clone(),
+                result: state.result.clone(),
+                operations: state.operations.iter()
+                    .map(|(hash, operation)| Ok((hash.clone(), encode(operation)?)))
+                    .collect::<Result<_, RecordingError>>()?,
+                pending: state.pending.iter().cloned().collect(),
+            },
+            ShellChannelMsg::MempoolOperationBan(msg) => RecordedEventKind::MempoolOperationBan(msg.clone()),
+            ShellChannelMsg::InjectBlock(inject_data) => RecordedEventKind::InjectBlock {
+                header: encode(&inject_data.block_header)?,
+                operations: match &inject_data.operations {
+                    Some(operations) => Some(
+                        operations.iter()
+                            .map(|validation_pass| validation_pass.iter().map(|operation| encode(operation)).collect::<Result<Vec<_>, RecordingError>>())
+                            .collect::<Result<_, RecordingError>>()?
+                    ),
+                    None => None,
+                },
+                force: inject_data.force,
+            },
+            ShellChannelMsg::SyncStateChanged(msg) => RecordedEventKind::SyncStateChanged(msg.clone()),
+            ShellChannelMsg::WorkerFailed(msg) => RecordedEventKind::WorkerFailed(msg.clone()),
+            ShellChannelMsg::PeerStatistics(msg) => RecordedEventKind::PeerStatistics(msg.clone()),
+            ShellChannelMsg::ShuttingDown(_) => RecordedEventKind::ShuttingDown,
+        };
+        Ok(event)
+    }
+}
+
+impl RecordedEventKind {
+    /// Converts recorded shell event back to [`ShellChannelMsg`].
+    ///
+    /// Returns `None` for network events.
+    pub fn to_shell_msg(&self) -> Result<Option<ShellChannelMsg>, RecordingError> {
+        let msg = match self {
+            RecordedEventKind::BlockApplied { hash, header, json_data } => BlockApplied::new(
+                BlockHeaderWithHash { hash: hash.clone(), header: Arc::new(decode(header)?) },
+                json_data.clone(),
+            ).into(),
+            RecordedEventKind::BlockReceived(msg) => msg.clone().into(),
+            RecordedEventKind::AllBlockOperationsReceived(msg) => msg.clone().into(),
+            RecordedEventKind::MempoolOperationReceived(msg) => msg.clone().into(),
+            RecordedEventKind::MempoolStateChanged { head, protocol, result, operations, pending } => CurrentMempoolState {
+                head: head.as_ref().map(|(hash, level)| Head { hash: hash.clone(), level: *level }),
+                protocol: protocol.clone(),
+                result: result.clone(),
+                operations: operations.iter()
+                    .map(|(hash, operation)| Ok((hash.clone(), decode::<Operation>(operation)?)))
+                    .collect::<Result<_, RecordingError>>()?,
+                pending: pending.iter().cloned().collect(),
+            }.into(),
+            RecordedEventKind::MempoolOperationBan(msg) => msg.clone().into(),
+            RecordedEventKind::InjectBlock { header, operations, force } => InjectBlock {
+                block_header: decode::<BlockHeader>(header)?,
+                operations: match operations {
+                    Some(operations) => Some(
+                        operations.iter()
+                            .map(|validation_pass| validation_pass.iter().map(|operation| decode::<Operation>(operation)).collect::<Result<Vec<_>, RecordingError>>())
+                            .collect::<Result<_, RecordingError>>()?
+                    ),
+                    None => None,
+                },
+                force: *force,
+                result_callback: None,
+            }.into(),
+            RecordedEventKind::SyncStateChanged(msg) => msg.clone().into(),
+            RecordedEventKind::WorkerFailed(msg) => msg.clone().into(),
+            RecordedEventKind::PeerStatistics(msg) => msg.clone().into(),
+            RecordedEventKind::ShuttingDown => ShuttingDown.into(),
+            RecordedEventKind::PeerBootstrapped { .. }
+            | RecordedEventKind::PeerDisconnected { .. }
+            | RecordedEventKind::PeerMessageReceived { .. } => return Ok(None),
+        };
+        Ok(Some(msg))
+    }
+}
+
+/// Encodes p2p message for recording
+pub fn encode<M: BinaryMessage>(message: &M) -> Result<Vec<u8>, RecordingError> {
+    message.as_bytes()
+        .map_err(|e| RecordingError::EncodingError { reason: format!("{:?}", e) })
+}
+
+/// Decodes recorded p2p message
+pub fn decode<M: BinaryMessage>(bytes: &[u8]) -> Result<M, RecordingError> {
+    M::from_bytes(bytes.to_vec())
+        .map_err(|e| RecordingError::EncodingError { reason: format!("{:?}", e) })
+}
+
+/// Encodes p2p message for recording, as it is sent to (or received from) the peer with the negotiated network `version`
+pub fn encode_for_version<M: BinaryMessage>(message: &M, version: &NetworkVersion) -> Result<Vec<u8>, RecordingError> {
+    message.as_bytes_for_version(version)
+        .map_err(|e| RecordingError::EncodingError { reason: format!("{:?}", e) })
+}
+
+/// Decodes recorded peer message received from the peer with the negotiated network `version`
+pub fn decode_peer_message(bytes: &[u8], version: &NetworkVersion) -> Result<Arc<PeerMessageResponse>, RecordingError> {
+    PeerMessageResponse::from_bytes_for_version(bytes.to_vec(), version)
+        .map(Arc::new)
+        .map_err(|e| RecordingError::EncodingError { reason: format!("{:?}", e) })
+}
+
+/// Writes recorded events
+pub struct RecordingWriter<W: Write> {
+    writer: RecordWriter<W>,
+    started: Instant,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Starts new recording, elapsed time of the events is measured from now
+    pub fn new(writer: W) -> Result<Self, RecordingError> {
+        Ok(RecordingWriter { writer: RecordWriter::new(writer, RECORDING_VERSION)?, started: Instant::now() })
+    }
+
+    /// Writes event with the current elapsed time
+    pub fn write(&mut self, event: RecordedEventKind) -> Result<(), RecordingError> {
+        self.write_event(&RecordedEvent { elapsed: self.started.elapsed(), event })
+    }
+
+    pub fn write_event(&mut self, event: &RecordedEvent) -> Result<(), RecordingError> {
+        self.writer.write(event)
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecordingError> {
+        self.writer.flush()
+    }
+
+    /// Finish recording and return underlying writer
+    pub fn into_inner(self) -> Result<W, RecordingError> {
+        self.writer.into_inner()
+    }
+}
+
+/// Reads recorded events in the recorded order
+pub struct RecordingReader<R: Read> {
+    reader: RecordReader<R>,
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(reader: R) -> Result<Self, RecordingError> {
+        Ok(RecordingReader { reader: RecordReader::new(reader, RECORDING_VERSION)? })
+    }
+
+    /// Reads next event, returns `None` at the end of the recording
+    pub fn read(&mut self) -> Result<Option<RecordedEvent>, RecordingError> {
+        self.reader.read()
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = Result<RecordedEvent, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::shell_channel::SyncState;
+
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_recording() -> Result<(), failure::Error> {
+        let mut writer = RecordingWriter::new(Vec::new())?;
+        writer.write(RecordedEventKind::PeerBootstrapped {
+            peer_id: "idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string(),
+            peer_address: "127.0.0.1:9732".parse()?,
+            disable_mempool: false,
+            private_node: true,
+            network_version: encode(&NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 0, 1))?,
+        })?;
+        writer.write(RecordedEventKind::SyncStateChanged(SyncStateChanged { sync_state: SyncState::Synced, is_bootstrapped: true }))?;
+        writer.write(RecordedEventKind::PeerDisconnected { peer_id: "idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string() })?;
+        let bytes = writer.into_inner()?;
+
+        let events = RecordingReader::new(Cursor::new(bytes))?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(3, events.len());
+        assert!(events.windows(2).all(|pair| pair[0].elapsed <= pair[1].elapsed));
+        match &events[0].event {
+            RecordedEventKind::PeerBootstrapped { peer_id, peer_address, disable_mempool, private_node, network_version } => {
+                assert_eq!("idtqxHUjbjbCfaDn4jczoPGsnhacKX", peer_id);
+                assert_eq!(9732, peer_address.port());
+                assert!(!disable_mempool);
+                assert!(private_node);
+                assert_eq!(1, decode::<NetworkVersion>(network_version)?.p2p_version());
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        match &events[1].event {
+            RecordedEventKind::SyncStateChanged(changed) => {
+                assert_eq!(SyncState::Synced, changed.sync_state);
+                assert!(changed.is_bootstrapped);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_truncated_and_unsupported_recording() -> Result<(), failure::Error> {
+        let mut writer = RecordingWriter::new(Vec::new())?;
+        writer.write(RecordedEventKind::ShuttingDown)?;
+        let mut bytes = writer.into_inner()?;
+
+        // truncated record
+        bytes.pop();
+        let mut reader = RecordingReader::new(Cursor::new(bytes))?;
+        assert!(reader.read().is_err());
+
+        // unsupported version
+        assert!(RecordingReader::new(Cursor::new(vec![RECORDING_VERSION + 1])).is_err());
+        Ok(())
+    }
+}
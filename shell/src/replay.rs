@@ -0,0 +1,239 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Replays recorded events (see [`recorder`](crate::recorder)) into fresh actors, e.g. `ChainManager` and `PeerManager`
+//! created against an empty storage, so synchronisation issues can be reproduced without live peers.
+//!
+//! Events are sent directly to the target actors from a single thread, so they are received in the recorded order.
+//! Remote peers are replaced by stub actors, messages sent to them can be collected for inspection.
+//! Only the inputs of the target actors are replayed, events generated by the target actors themselves
+//! (e.g. `BlockReceived` or `SyncStateChanged`) are skipped.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender as QueueSender};
+use std::thread;
+use std::time::Instant;
+
+use failure::Fail;
+use riker::actors::*;
+use slog::{debug, Logger};
+
+use networking::p2p::network_channel::{NetworkChannelMsg, PeerBootstrapped, PeerMessageReceived};
+use networking::p2p::peer::{PeerMsg, PeerRef};
+use tezos_messages::p2p::encoding::prelude::{MetadataMessage, NetworkVersion, PeerMessageResponse};
+
+use crate::recording::{decode, decode_peer_message, RecordedEvent, RecordedEventKind, RecordingError, RecordingReader};
+use crate::shell_channel::ShellChannelMsg;
+
+/// Possible errors during replay
+#[derive(Debug, Fail)]
+pub enum ReplayError {
+    #[fail(display = "Failed to read recording, reason: {}", error)]
+    RecordingError {
+        error: RecordingError
+    },
+    #[fail(display = "Failed to create replay peer, reason: {}", reason)]
+    CreatePeerError {
+        reason: String
+    },
+}
+
+impl From<RecordingError> for ReplayError {
+    fn from(error: RecordingError) -> Self {
+        ReplayError::RecordingError { error }
+    }
+}
+
+/// Actor, which receives replayed network and shell events
+pub trait ReplayTarget {
+    fn replay_network_msg(&self, msg: NetworkChannelMsg);
+
+    fn replay_shell_msg(&self, msg: ShellChannelMsg);
+}
+
+impl<M> ReplayTarget for ActorRef<M>
+    where M: Message + From<NetworkChannelMsg> + From<ShellChannelMsg>
+{
+    fn replay_network_msg(&self, msg: NetworkChannelMsg) {
+        self.tell(msg, None);
+    }
+
+    fn replay_shell_msg(&self, msg: ShellChannelMsg) {
+        self.tell(msg, None);
+    }
+}
+
+/// How fast are events replayed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayPace {
+    /// Events are replayed one after another without any delay
+    Immediate,
+    /// Delays between events are the same as in the recording
+    Recorded,
+}
+
+/// Message sent by target actors to the replayed peer (peer id and the message)
+pub type SentPeerMessage = (String, Arc<PeerMessageResponse>);
+
+type SharedSentMessages = Arc<Mutex<QueueSender<SentPeerMessage>>>;
+
+/// Summary of the replay
+#[derive(Clone, Debug, Default)]
+pub struct ReplayStats {
+    /// Count of events sent to the target actors
+    pub replayed: usize,
+    /// Count of events, which are not inputs of the target actors, or belong to unknown peers
+    pub skipped: usize,
+}
+
+/// Replays recorded events into target actors
+pub struct Replayer {
+    sys: ActorSystem,
+    targets: Vec<Box<dyn ReplayTarget>>,
+    /// Replayed peers (with the negotiated network version) by peer id
+    peers: HashMap<String, (PeerRef, SocketAddr, NetworkVersion)>,
+    /// Counter for unique names of the replayed peers
+    peer_counter: usize,
+    sent_messages: Option<SharedSentMessages>,
+    log: Logger,
+}
+
+impl Replayer {
+    pub fn new(sys: &ActorSystem, targets: Vec<Box<dyn ReplayTarget>>, log: Logger) -> Self {
+        Replayer {
+            sys: sys.clone(),
+            targets,
+            peers: HashMap::new(),
+            peer_counter: 0,
+            sent_messages: None,
+            log,
+        }
+    }
+
+    /// Messages sent by target actors to the replayed peers will be sent to the returned receiver
+    pub fn collect_sent_messages(&mut self) -> Receiver<SentPeerMessage> {
+        let (sender, receiver) = channel();
+        self.sent_messages = Some(Arc::new(Mutex::new(sender)));
+        receiver
+    }
+
+    /// Replay all events from the recording
+    pub fn replay<R: Read>(&mut self, recording: RecordingReader<R>, pace: ReplayPace) -> Result<ReplayStats, ReplayError> {
+        let mut stats = ReplayStats::default();
+        let started = Instant::now();
+
+        for event in recording {
+            let RecordedEvent { elapsed, event } = event?;
+            if pace == ReplayPace::Recorded {
+                let now = started.elapsed();
+                if elapsed > now {
+                    thread::sleep(elapsed - now);
+                }
+            }
+
+            if self.replay_event(&event)? {
+                stats.replayed += 1;
+            } else {
+                stats.skipped += 1;
+            }
+        }
+
+        debug!(self.log, "Replay finished"; "replayed" => stats.replayed, "skipped" => stats.skipped, "duration_ms" => started.elapsed().as_millis() as u64);
+        Ok(stats)
+    }
+
+    /// Replay single event, returns `false` if event was skipped
+    pub fn replay_event(&mut self, event: &RecordedEventKind) -> Result<bool, ReplayError> {
+        match event {
+            RecordedEventKind::PeerBootstrapped { peer_id, peer_address, disable_mempool, private_node, network_version } => {
+                let network_version: NetworkVersion = decode(network_version)?;
+                let peer = self.create_peer(peer_id)?;
+                self.peers.insert(peer_id.clone(), (peer.clone(), *peer_address, network_version.clone()));
+                self.replay_network_msg(PeerBootstrapped::Success {
+                    peer,
+                    peer_id: peer_id.clone(),
+                    peer_metadata: MetadataMessage::new(*disable_mempool, *private_node),
+                    network_version,
+                }.into());
+                Ok(true)
+            }
+            RecordedEventKind::PeerDisconnected { peer_id } => {
+                match self.peers.remove(peer_id) {
+                    Some((peer, ..)) => {
+                        // target actors are notified by `ActorTerminated` system event
+                        self.sys.stop(peer);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            RecordedEventKind::PeerMessageReceived { peer_id, message } => {
+                match self.peers.get(peer_id).cloned() {
+                    Some((peer, peer_address, network_version)) => {
+                        self.replay_network_msg(PeerMessageReceived {
+                            peer,
+                            message: decode_peer_message(message, &network_version)?,
+                            peer_address,
+                        }.into());
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            // generated by the target actors
+            RecordedEventKind::BlockReceived(_)
+            | RecordedEventKind::AllBlockOperationsReceived(_)
+            | RecordedEventKind::MempoolOperationReceived(_)
+            | RecordedEventKind::SyncStateChanged(_)
+            | RecordedEventKind::WorkerFailed(_)
+            | RecordedEventKind::PeerStatistics(_) => Ok(false),
+            _ => match event.to_shell_msg()? {
+                Some(msg) => {
+                    self.targets.iter().for_each(|target| target.replay_shell_msg(msg.clone()));
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+    }
+
+    fn replay_network_msg(&self, msg: NetworkChannelMsg) {
+        self.targets.iter().for_each(|target| target.replay_network_msg(msg.clone()));
+    }
+
+    fn create_peer(&mut self, peer_id: &str) -> Result<PeerRef, ReplayError> {
+        self.peer_counter += 1;
+        self.sys.actor_of_props::<ReplayPeer>(
+            &format!("replay-peer-{}", self.peer_counter),
+            Props::new_args((peer_id.to_string(), self.sent_messages.clone())),
+        ).map_err(|e| ReplayError::CreatePeerError { reason: format!("{:?}", e) })
+    }
+}
+
+/// Stand-in for the remote peer, messages for the remote peer are just collected (if requested)
+struct ReplayPeer {
+    peer_id: String,
+    sent_messages: Option<SharedSentMessages>,
+}
+
+impl ActorFactoryArgs<(String, Option<SharedSentMessages>)> for ReplayPeer {
+    fn create_args((peer_id, sent_messages): (String, Option<SharedSentMessages>)) -> Self {
+        ReplayPeer { peer_id, sent_messages }
+    }
+}
+
+impl Actor for ReplayPeer {
+    type Msg = PeerMsg;
+
+    fn recv(&mut self, _ctx: &Context<Self::Msg>, msg: Self::Msg, _sender: Sender) {
+        if let PeerMsg::SendMessage(msg) = msg {
+            if let Some(sent_messages) = &self.sent_messages {
+                // nobody could be listening anymore, so we dont care about the result
+                let _ = sent_messages.lock().unwrap().send((self.peer_id.clone(), msg.message().clone()));
+            }
+        }
+    }
+}
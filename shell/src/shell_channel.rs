@@ -4,9 +4,14 @@
 //! Shell channel is used to transmit high level shell messages.
 
 use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use failure::Fail;
+use futures::channel::oneshot;
 use getset::Getters;
 use riker::actors::*;
+use serde::{Deserialize, Serialize};
 
 use crypto::hash::{BlockHash, OperationHash, ProtocolHash};
 use storage::block_storage::BlockJsonData;
@@ -16,6 +21,7 @@ use tezos_api::ffi::ValidateOperationResult;
 use tezos_messages::p2p::encoding::prelude::{Operation, BlockHeader};
 
 use crate::Head;
+use crate::stats::peer_stats::PeerStatsSnapshot;
 
 /// Message informing actors about successful block application by protocol
 #[derive(Clone, Debug, Getters)]
@@ -37,26 +43,37 @@ impl BlockApplied {
 pub struct ShuttingDown;
 
 /// Message informing actors about receiving block header
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BlockReceived {
     pub hash: BlockHash,
     pub level: i32,
 }
 
 /// Message informing actors about receiving all operations for a specific block
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AllBlockOperationsReceived {
     pub hash: BlockHash,
     pub level: i32,
 }
 
 // Notify actors that operations should by validated by mempool
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MempoolOperationReceived {
     pub operation_hash: OperationHash,
     pub operation_type: MempoolOperationType,
 }
 
+/// Request to ban or unban mempool operations
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MempoolOperationBan {
+    /// Operation is removed from the mempool and it is ignored, until it is unbanned
+    Ban(OperationHash),
+    /// Operation is not ignored anymore, but it has to be received or injected again
+    Unban(OperationHash),
+    /// All banned operations are unbanned
+    UnbanAll,
+}
+
 #[derive(Clone, Debug)]
 pub struct CurrentMempoolState {
     pub head: Option<Head>,
@@ -66,10 +83,78 @@ pub struct CurrentMempoolState {
     pub pending: HashSet<OperationHash>,
 }
 
+/// Block injection failed, block was not stored or was not applied
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Block injection failed, reason: {}", reason)]
+pub struct InjectBlockError {
+    pub reason: String,
+}
+
+/// Result of the block injection is sent back to the injector through this one-shot channel
+pub type InjectBlockOneshotResultCallback = Arc<Mutex<Option<oneshot::Sender<Result<(), InjectBlockError>>>>>;
+
+/// Create new callback for block injection result, returns callback and receiving part of the channel
+pub fn inject_block_result_callback() -> (InjectBlockOneshotResultCallback, oneshot::Receiver<Result<(), InjectBlockError>>) {
+    let (sender, receiver) = oneshot::channel();
+    (Arc::new(Mutex::new(Some(sender))), receiver)
+}
+
+/// Send result of the block injection to the injector. Only the first result is sent, other calls are ignored.
+pub fn dispatch_inject_block_result(result_callback: &Option<InjectBlockOneshotResultCallback>, result: Result<(), InjectBlockError>) {
+    if let Some(result_callback) = result_callback {
+        if let Some(sender) = result_callback.lock().unwrap().take() {
+            // receiver could already gave up waiting, so we dont care about the result
+            let _ = sender.send(result);
+        }
+    }
+}
+
+/// Synchronisation state of the node resolved by the synchronisation heuristic
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SyncState {
+    /// Node is synchronised with the network
+    Synced,
+    /// Node is not synchronised with the network
+    Unsynced,
+    /// Node is synchronised, but chain is not progressing (all peers are at the same old head)
+    Stuck,
+}
+
+/// Synchronisation state or bootstrapped flag of the node changed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncStateChanged {
+    pub sync_state: SyncState,
+    /// Node is bootstrapped, once it was at least once synchronised with the network
+    pub is_bootstrapped: bool,
+}
+
+/// Supervised worker thread failed (see [`Supervisor`](crate::supervision::Supervisor))
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkerFailed {
+    /// Name of the worker thread
+    pub worker: String,
+    pub reason: String,
+    /// Count of consecutive failures
+    pub failures: usize,
+    /// Worker is restarted after this delay, `None` means that supervisor gave up and node is shutting down
+    pub restart_in: Option<Duration>,
+}
+
+/// Latency and throughput of the connected peers measured by the chain manager
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerStatistics {
+    pub peers: Vec<PeerStatsSnapshot>,
+}
+
 #[derive(Clone, Debug)]
 pub struct InjectBlock {
     pub block_header: BlockHeader,
-    // TODO TE-196 - need an operations field? we'll see
+    /// Operations of the block ordered by validation passes. If `None`, operations will be downloaded from peers.
+    pub operations: Option<Vec<Vec<Operation>>>,
+    /// If `true`, block is accepted even if its fitness is not greater than fitness of the current head
+    pub force: bool,
+    /// Callback for the result of the block injection (`None` if nobody waits for the result)
+    pub result_callback: Option<InjectBlockOneshotResultCallback>,
 }
 
 /// Shell channel event message.
@@ -80,10 +165,38 @@ pub enum ShellChannelMsg {
     AllBlockOperationsReceived(AllBlockOperationsReceived),
     MempoolOperationReceived(MempoolOperationReceived),
     MempoolStateChanged(CurrentMempoolState),
+    MempoolOperationBan(MempoolOperationBan),
     InjectBlock(InjectBlock),
+    SyncStateChanged(SyncStateChanged),
+    WorkerFailed(WorkerFailed),
+    PeerStatistics(PeerStatistics),
     ShuttingDown(ShuttingDown),
 }
 
+impl From<SyncStateChanged> for ShellChannelMsg {
+    fn from(msg: SyncStateChanged) -> Self {
+        ShellChannelMsg::SyncStateChanged(msg)
+    }
+}
+
+impl From<MempoolOperationBan> for ShellChannelMsg {
+    fn from(msg: MempoolOperationBan) -> Self {
+        ShellChannelMsg::MempoolOperationBan(msg)
+    }
+}
+
+impl From<WorkerFailed> for ShellChannelMsg {
+    fn from(msg: WorkerFailed) -> Self {
+        ShellChannelMsg::WorkerFailed(msg)
+    }
+}
+
+impl From<PeerStatistics> for ShellChannelMsg {
+    fn from(msg: PeerStatistics) -> Self {
+        ShellChannelMsg::PeerStatistics(msg)
+    }
+}
+
 impl From<InjectBlock> for ShellChannelMsg {
     fn from(msg: InjectBlock) -> Self {
         ShellChannelMsg::InjectBlock(msg)
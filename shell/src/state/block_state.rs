@@ -4,6 +4,7 @@
 use std::cmp;
 use std::cmp::Ordering;
 
+use failure::Fail;
 use rand::prelude::ThreadRng;
 use rand::Rng;
 use slog::Logger;
@@ -11,8 +12,10 @@ use slog::Logger;
 use crypto::hash::{BlockHash, ChainId};
 use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockStorage, BlockStorageReader, IteratorMode, StorageError};
 use storage::persistent::PersistentStorage;
+use tezos_messages::p2p::encoding::prelude::*;
 
 use crate::collections::{BlockData, UniqueBlockData};
+use crate::validation::{self, PrevalidationError};
 
 /// Holds state of all known blocks
 pub struct BlockchainState {
@@ -57,6 +60,36 @@ impl BlockchainState {
         Ok(())
     }
 
+    /// Pre-validate block header against its predecessor before the header is stored.
+    ///
+    /// # Arguments
+    /// * `block_header` - received block header
+    /// * `expected_level` - level we expect for the block (if we know it)
+    pub fn validate_block_header(&self, block_header: &BlockHeaderWithHash, expected_level: Option<i32>) -> Result<(), BlockPrevalidationError> {
+        if let Some(expected_level) = expected_level {
+            if expected_level != block_header.header.level() {
+                return Err(PrevalidationError::UnexpectedLevel { level: block_header.header.level(), expected_level }.into());
+            }
+        }
+
+        let predecessor = self.block_storage.get(block_header.header.predecessor())?;
+        validation::check_block_header(
+            &block_header.header,
+            predecessor.as_ref().map(|predecessor| &*predecessor.header),
+            validation::now_timestamp(),
+        )?;
+        Ok(())
+    }
+
+    /// Pre-validate received block operations against stored block header.
+    ///
+    /// Block header must be already stored, otherwise operations are not expected.
+    pub fn validate_block_operations(&self, message: &OperationsForBlocksMessage) -> Result<(), BlockPrevalidationError> {
+        let block = self.block_storage.get(message.operations_for_block().hash())?.ok_or(StorageError::MissingKey)?;
+        validation::check_operations_for_block(&block.header, message)?;
+        Ok(())
+    }
+
     #[inline]
     pub fn drain_missing_blocks(&mut self, n: usize, level_max: i32) -> Vec<MissingBlock> {
         (0..cmp::min(self.missing_blocks.len(), n))
@@ -160,6 +193,29 @@ impl BlockchainState {
     }
 }
 
+/// Reason why block data were not accepted
+#[derive(Debug, Fail)]
+pub enum BlockPrevalidationError {
+    /// Block data are invalid, peer which sent them should be penalized
+    #[fail(display = "Invalid block data: {}", _0)]
+    Invalid(PrevalidationError),
+    /// Failed to read data needed for validation
+    #[fail(display = "Storage error: {:?}", _0)]
+    StorageError(StorageError),
+}
+
+impl From<PrevalidationError> for BlockPrevalidationError {
+    fn from(error: PrevalidationError) -> Self {
+        BlockPrevalidationError::Invalid(error)
+    }
+}
+
+impl From<StorageError> for BlockPrevalidationError {
+    fn from(error: StorageError) -> Self {
+        BlockPrevalidationError::StorageError(error)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MissingBlock {
     pub block_hash: BlockHash,
@@ -193,6 +249,12 @@ impl MissingBlock {
         }
     }
 
+    /// Level of the block, if we know it for sure
+    #[inline]
+    pub fn level(&self) -> Option<i32> {
+        self.level
+    }
+
     pub fn fits_to_max(&self, level_max: i32) -> bool {
         if let Some(level) = self.level {
             return level <= level_max;
@@ -66,6 +66,21 @@ impl OperationsState {
         self.operations_meta_storage.is_complete(message.operations_for_block().hash())
     }
 
+    /// Process injected block header together with all its operations.
+    /// Operations are stored directly, so they are never requested from peers.
+    ///
+    /// If all block operations were processed return `true`.
+    pub fn process_injected_block(&mut self, block_header: &BlockHeaderWithHash, operations: &[OperationsForBlocksMessage]) -> Result<bool, StorageError> {
+        if !self.operations_meta_storage.contains(&block_header.hash)? {
+            self.operations_meta_storage.put_block_header(block_header, &self.chain_id)?;
+        }
+        for message in operations {
+            self.operations_storage.put_operations(message)?;
+            self.operations_meta_storage.put_operations(message)?;
+        }
+        self.operations_meta_storage.is_complete(&block_header.hash)
+    }
+
     pub fn drain_missing_block_operations(&mut self, n: usize, level_max: i32) -> Vec<MissingOperations> {
         (0..cmp::min(self.missing_operations_for_blocks.len(), n))
             .filter_map(|_| {
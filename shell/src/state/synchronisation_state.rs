@@ -0,0 +1,171 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Synchronisation heuristic, which resolves if the node is synchronised with the network.
+//!
+//! Heuristic is the same as the OCaml `Synchronisation_heuristic`:
+//! - we take `threshold` most recent heads advertised by peers,
+//! - if the least recent of them is not older than `latency`, node is [synced](SyncState::Synced),
+//! - if all of them are older than `latency` and have the same timestamp, node is [stuck](SyncState::Stuck),
+//! - otherwise node is [unsynced](SyncState::Unsynced).
+//!
+//! In addition, our local head must not be behind the selected heads.
+//! Node becomes bootstrapped, when it is synced (or stuck) for the first time.
+
+use crate::chain_manager::SynchronisationThreshold;
+use crate::shell_channel::{SyncState, SyncStateChanged};
+
+pub struct SynchronisationState {
+    /// How many peers are needed to resolve synchronisation state
+    threshold: usize,
+    /// Max age (in seconds) of the head to be considered as recent
+    latency: i64,
+    /// Timestamp of the local current head
+    local_head_timestamp: Option<i64>,
+    sync_state: SyncState,
+    is_bootstrapped: bool,
+    /// Indicates that synchronisation state was already reported at least once
+    reported: bool,
+}
+
+impl SynchronisationState {
+    pub fn new(threshold: &SynchronisationThreshold) -> Self {
+        let mut state = SynchronisationState {
+            threshold: threshold.peers,
+            latency: threshold.latency.as_secs() as i64,
+            local_head_timestamp: None,
+            sync_state: SyncState::Unsynced,
+            is_bootstrapped: false,
+            reported: false,
+        };
+        // with zero threshold node is always synced
+        if state.threshold == 0 {
+            state.sync_state = SyncState::Synced;
+            state.is_bootstrapped = true;
+        }
+        state
+    }
+
+    pub fn update_local_head_timestamp(&mut self, timestamp: i64) {
+        self.local_head_timestamp = Some(timestamp);
+    }
+
+    /// Resolve synchronisation state from current heads of the peers.
+    ///
+    /// Returns `Some` if synchronisation state or bootstrapped flag changed, or if it was not reported yet.
+    pub fn update<I>(&mut self, peer_head_timestamps: I, now: i64) -> Option<SyncStateChanged>
+        where I: IntoIterator<Item=i64>
+    {
+        let sync_state = self.resolve_sync_state(peer_head_timestamps.into_iter().collect(), now);
+        let is_bootstrapped = self.is_bootstrapped || sync_state != SyncState::Unsynced;
+
+        if !self.reported || sync_state != self.sync_state || is_bootstrapped != self.is_bootstrapped {
+            self.sync_state = sync_state;
+            self.is_bootstrapped = is_bootstrapped;
+            self.reported = true;
+            Some(SyncStateChanged { sync_state, is_bootstrapped })
+        } else {
+            None
+        }
+    }
+
+    fn resolve_sync_state(&self, mut candidates: Vec<i64>, now: i64) -> SyncState {
+        if self.threshold == 0 {
+            return SyncState::Synced;
+        }
+        if candidates.len() < self.threshold {
+            return SyncState::Unsynced;
+        }
+        let local_head_timestamp = match self.local_head_timestamp {
+            Some(local_head_timestamp) => local_head_timestamp,
+            None => return SyncState::Unsynced,
+        };
+
+        // take most recent heads
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        candidates.truncate(self.threshold);
+        let most_recent = candidates[0];
+        let least_recent = candidates[self.threshold - 1];
+
+        if local_head_timestamp < least_recent {
+            // peers are ahead of us
+            SyncState::Unsynced
+        } else if least_recent >= now - self.latency {
+            SyncState::Synced
+        } else if least_recent == most_recent {
+            SyncState::Stuck
+        } else {
+            SyncState::Unsynced
+        }
+    }
+
+    pub fn sync_state(&self) -> SyncState {
+        self.sync_state
+    }
+
+    pub fn is_bootstrapped(&self) -> bool {
+        self.is_bootstrapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn state(peers: usize) -> SynchronisationState {
+        SynchronisationState::new(&SynchronisationThreshold::new(peers, Duration::from_secs(150)))
+    }
+
+    #[test]
+    fn test_zero_threshold_is_always_synced() {
+        let mut state = state(0);
+        assert!(state.is_bootstrapped());
+        assert_eq!(SyncState::Synced, state.sync_state());
+        // initial state is reported just once
+        assert!(state.update(vec![], 1000).is_some());
+        assert!(state.update(vec![], 1000).is_none());
+    }
+
+    #[test]
+    fn test_not_enough_peers() {
+        let mut state = state(2);
+        state.update_local_head_timestamp(1000);
+        assert!(state.update(vec![1000], 1000).is_some());
+        assert!(state.update(vec![1000], 1000).is_none());
+        assert_eq!(SyncState::Unsynced, state.sync_state());
+        assert!(!state.is_bootstrapped());
+    }
+
+    #[test]
+    fn test_synced_and_stays_bootstrapped() {
+        let mut state = state(2);
+        state.update_local_head_timestamp(950);
+
+        // peers are ahead of us
+        let changed = state.update(vec![1000, 990], 1000).expect("Expected initial sync state");
+        assert_eq!(SyncState::Unsynced, changed.sync_state);
+        assert!(!changed.is_bootstrapped);
+
+        // we caught up
+        state.update_local_head_timestamp(1000);
+        let changed = state.update(vec![1000, 990, 10], 1000).expect("Expected sync state change");
+        assert_eq!(SyncState::Synced, changed.sync_state);
+        assert!(changed.is_bootstrapped);
+
+        // time passed and peers have different old heads
+        let changed = state.update(vec![1000, 990], 2000).expect("Expected sync state change");
+        assert_eq!(SyncState::Unsynced, changed.sync_state);
+        assert!(changed.is_bootstrapped);
+    }
+
+    #[test]
+    fn test_stuck() {
+        let mut state = state(2);
+        state.update_local_head_timestamp(1000);
+        let changed = state.update(vec![1000, 1000], 2000).expect("Expected sync state change");
+        assert_eq!(SyncState::Stuck, changed.sync_state);
+        assert!(changed.is_bootstrapped);
+    }
+}
@@ -3,4 +3,5 @@
 
 //! This module contains all structs used to hold shell stats.
 
-pub mod memory;
\ No newline at end of file
+pub mod memory;
+pub mod peer_stats;
\ No newline at end of file
@@ -0,0 +1,304 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Quality of the connected peers measured by the chain manager: round-trip latency and success ratio
+//! of our requests and count of the messages and bytes exchanged with the peer.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Weight of the new sample in the exponential moving average of the latency
+const LATENCY_SMOOTHING: f64 = 0.2;
+/// Latency assumed for the peers, which did not respond to any request yet
+const DEFAULT_LATENCY: Duration = Duration::from_millis(500);
+/// Weight of the last request result in the exponential moving average of the success ratio,
+/// older results decay, so the peer recovers from the past failures
+const SUCCESS_SMOOTHING: f64 = 0.1;
+
+/// Requests with measured round-trip latency
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// `GetBlockHeaders` answered by `BlockHeader`
+    BlockHeaders,
+    /// `GetOperationsForBlocks` answered by `OperationsForBlocks`
+    OperationsForBlocks,
+    /// `GetCurrentHead` answered by `CurrentHead`
+    CurrentHead,
+}
+
+/// Statistics of the single kind of requests
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestStats {
+    /// Count of the sent requests
+    pub sent: u64,
+    /// Count of the requests answered on time
+    pub succeeded: u64,
+    /// Count of the requests answered with invalid data or not answered on time
+    pub failed: u64,
+    /// Exponential moving average of the round-trip latency
+    pub average_latency: Option<Duration>,
+    /// Latency of the last answered request
+    pub last_latency: Option<Duration>,
+    /// Exponential moving average of the request results (`1.0` success, `0.0` failure)
+    pub recent_success_ratio: f64,
+}
+
+impl Default for RequestStats {
+    fn default() -> Self {
+        RequestStats {
+            sent: 0,
+            succeeded: 0,
+            failed: 0,
+            average_latency: None,
+            last_latency: None,
+            recent_success_ratio: 1.0,
+        }
+    }
+}
+
+impl RequestStats {
+    /// Success ratio of the recent requests, peers without finished requests have ratio `1.0`
+    pub fn success_ratio(&self) -> f64 {
+        self.recent_success_ratio
+    }
+
+    fn record_failure(&mut self) {
+        self.failed += 1;
+        self.recent_success_ratio = smooth_success_ratio(self.recent_success_ratio, false);
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        self.succeeded += 1;
+        self.recent_success_ratio = smooth_success_ratio(self.recent_success_ratio, true);
+        self.last_latency = Some(latency);
+        self.average_latency = Some(match self.average_latency {
+            Some(average) => average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING),
+            None => latency,
+        });
+    }
+}
+
+fn smooth_success_ratio(ratio: f64, success: bool) -> f64 {
+    let sample = if success { 1.0 } else { 0.0 };
+    ratio * (1.0 - SUCCESS_SMOOTHING) + sample * SUCCESS_SMOOTHING
+}
+
+/// Snapshot of the peer statistics published to the shell channel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerStatsSnapshot {
+    pub peer_id: String,
+    pub block_headers: RequestStats,
+    pub operations_for_blocks: RequestStats,
+    pub current_head: RequestStats,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Score of the peer used for the peer selection, higher is better
+    pub score: f64,
+}
+
+/// Statistics of the single peer
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    requests: HashMap<RequestKind, RequestStats>,
+    /// Success ratio of the recent requests of all kinds
+    recent_success_ratio: f64,
+    /// Time when the pending requests were sent, requests are identified by the kind and the requested key (e.g. block hash)
+    pending: HashMap<(RequestKind, Vec<u8>), Instant>,
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        PeerStats {
+            requests: HashMap::new(),
+            recent_success_ratio: 1.0,
+            pending: HashMap::new(),
+            messages_received: 0,
+            messages_sent: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        }
+    }
+}
+
+impl PeerStats {
+    pub fn request_sent(&mut self, kind: RequestKind, key: Vec<u8>, now: Instant) {
+        self.requests.entry(kind).or_default().sent += 1;
+        self.pending.insert((kind, key), now);
+    }
+
+    /// Record the response to the pending request, returns the round-trip latency, or `None` if the request was not pending
+    pub fn response_received(&mut self, kind: RequestKind, key: Vec<u8>, now: Instant) -> Option<Duration> {
+        let sent = self.pending.remove(&(kind, key))?;
+        let latency = now.saturating_duration_since(sent);
+        self.requests.entry(kind).or_default().record_latency(latency);
+        self.recent_success_ratio = smooth_success_ratio(self.recent_success_ratio, true);
+        Some(latency)
+    }
+
+    /// Pending request was answered with the invalid data
+    pub fn request_failed(&mut self, kind: RequestKind, key: Vec<u8>) {
+        if self.pending.remove(&(kind, key)).is_some() {
+            self.requests.entry(kind).or_default().record_failure();
+            self.recent_success_ratio = smooth_success_ratio(self.recent_success_ratio, false);
+        }
+    }
+
+    /// Requests pending for longer than the `timeout` are counted as failed
+    pub fn expire_requests(&mut self, now: Instant, timeout: Duration) {
+        let PeerStats { requests, pending, recent_success_ratio, .. } = self;
+        pending.retain(|(kind, _), sent| {
+            let expired = now.saturating_duration_since(*sent) > timeout;
+            if expired {
+                requests.entry(*kind).or_default().record_failure();
+                *recent_success_ratio = smooth_success_ratio(*recent_success_ratio, false);
+            }
+            !expired
+        });
+    }
+
+    pub fn message_received(&mut self, bytes: usize) {
+        self.messages_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+
+    pub fn message_sent(&mut self, bytes: usize) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    pub fn requests(&self, kind: RequestKind) -> RequestStats {
+        self.requests.get(&kind).cloned().unwrap_or_default()
+    }
+
+    pub fn success_ratio(&self, kind: RequestKind) -> f64 {
+        self.requests.get(&kind).map(RequestStats::success_ratio).unwrap_or(1.0)
+    }
+
+    /// Score is the recent success ratio of all requests divided by the average latency (plus one second),
+    /// so reliable peers with low latency are preferred
+    pub fn score(&self) -> f64 {
+        let latencies = self.requests.values()
+            .filter_map(|stats| stats.average_latency)
+            .collect::<Vec<_>>();
+        let latency = if latencies.is_empty() {
+            DEFAULT_LATENCY
+        } else {
+            latencies.iter().sum::<Duration>() / latencies.len() as u32
+        };
+
+        self.recent_success_ratio / (1.0 + latency.as_secs_f64())
+    }
+
+    pub fn snapshot(&self, peer_id: String) -> PeerStatsSnapshot {
+        PeerStatsSnapshot {
+            peer_id,
+            block_headers: self.requests(RequestKind::BlockHeaders),
+            operations_for_blocks: self.requests(RequestKind::OperationsForBlocks),
+            current_head: self.requests(RequestKind::CurrentHead),
+            messages_received: self.messages_received,
+            messages_sent: self.messages_sent,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            score: self.score(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_latency_and_success_ratio() {
+        let mut stats = PeerStats::default();
+        let now = Instant::now();
+
+        stats.request_sent(RequestKind::BlockHeaders, vec![1], now);
+        stats.request_sent(RequestKind::BlockHeaders, vec![2], now);
+        stats.request_sent(RequestKind::BlockHeaders, vec![3], now);
+        assert_eq!(Some(Duration::from_millis(100)), stats.response_received(RequestKind::BlockHeaders, vec![1], now + Duration::from_millis(100)));
+        assert_eq!(Some(Duration::from_millis(200)), stats.response_received(RequestKind::BlockHeaders, vec![2], now + Duration::from_millis(200)));
+        // unexpected response is not measured
+        assert_eq!(None, stats.response_received(RequestKind::BlockHeaders, vec![1], now + Duration::from_millis(300)));
+        assert_eq!(None, stats.response_received(RequestKind::CurrentHead, vec![3], now + Duration::from_millis(300)));
+
+        // not answered request fails after the timeout
+        stats.expire_requests(now + Duration::from_secs(1), Duration::from_secs(10));
+        assert!((stats.success_ratio(RequestKind::BlockHeaders) - 1.0).abs() < f64::EPSILON);
+        stats.expire_requests(now + Duration::from_secs(11), Duration::from_secs(10));
+
+        let block_headers = stats.requests(RequestKind::BlockHeaders);
+        assert_eq!((3, 2, 1), (block_headers.sent, block_headers.succeeded, block_headers.failed));
+        assert_eq!(Some(Duration::from_millis(200)), block_headers.last_latency);
+        assert_eq!(Some(Duration::from_millis(120)), block_headers.average_latency);
+        assert!((stats.success_ratio(RequestKind::BlockHeaders) - 0.9).abs() < f64::EPSILON);
+        assert!((stats.success_ratio(RequestKind::OperationsForBlocks) - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_success_ratio_decays() {
+        let mut stats = PeerStats::default();
+        let now = Instant::now();
+        let request = |stats: &mut PeerStats, key: u8, success: bool| {
+            stats.request_sent(RequestKind::BlockHeaders, vec![key], now);
+            if success {
+                stats.response_received(RequestKind::BlockHeaders, vec![key], now);
+            } else {
+                stats.request_failed(RequestKind::BlockHeaders, vec![key]);
+            }
+        };
+
+        for key in 0..50 {
+            request(&mut stats, key, false);
+        }
+        assert!(stats.success_ratio(RequestKind::BlockHeaders) < 0.01);
+        let failed_score = stats.score();
+
+        // long history of failures does not outweigh recent successes
+        for key in 50..80 {
+            request(&mut stats, key, true);
+        }
+        assert!(stats.success_ratio(RequestKind::BlockHeaders) > 0.95);
+        assert!(stats.score() > failed_score);
+        assert_eq!((50, 30), (stats.requests(RequestKind::BlockHeaders).failed, stats.requests(RequestKind::BlockHeaders).succeeded));
+    }
+
+    #[test]
+    fn test_score_prefers_fast_and_reliable_peers() {
+        let now = Instant::now();
+        let peer = |latency: Duration, failed: bool| {
+            let mut stats = PeerStats::default();
+            stats.request_sent(RequestKind::OperationsForBlocks, vec![1], now);
+            stats.response_received(RequestKind::OperationsForBlocks, vec![1], now + latency);
+            stats.request_sent(RequestKind::OperationsForBlocks, vec![2], now);
+            if failed {
+                stats.request_failed(RequestKind::OperationsForBlocks, vec![2]);
+            } else {
+                stats.response_received(RequestKind::OperationsForBlocks, vec![2], now + latency);
+            }
+            stats
+        };
+
+        let fast = peer(Duration::from_millis(50), false);
+        let slow = peer(Duration::from_secs(2), false);
+        let unreliable = peer(Duration::from_millis(50), true);
+        assert!(fast.score() > slow.score());
+        assert!(fast.score() > unreliable.score());
+        assert!(fast.score() > PeerStats::default().score());
+
+        let mut stats = fast;
+        stats.message_received(100);
+        stats.message_sent(20);
+        let snapshot = stats.snapshot("idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string());
+        assert_eq!((1, 100, 1, 20), (snapshot.messages_received, snapshot.bytes_received, snapshot.messages_sent, snapshot.bytes_sent));
+        assert_eq!(2, snapshot.operations_for_blocks.succeeded);
+    }
+}
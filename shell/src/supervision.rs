@@ -0,0 +1,296 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Supervision of the worker threads spawned by shell actors.
+//!
+//! Worker is a function, which runs in a dedicated thread until it fails (returns error or panics).
+//! Supervisor restarts failed worker with exponential backoff, logs the failure and publishes [`WorkerFailed`] to the shell channel.
+//! After too many consecutive failures, supervisor gives up and requests shutdown of the node.
+//! Supervisor also keeps track of running workers, so shutdown can wait for them to finish.
+
+use std::cmp;
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use failure::{Error, format_err};
+use futures::channel::oneshot;
+use riker::actors::*;
+use slog::{crit, info, Logger, warn};
+
+use crate::shell_channel::{ShellChannelRef, ShellChannelTopic, WorkerFailed};
+
+/// Restart policy of the supervised workers
+#[derive(Clone, Debug)]
+pub struct SupervisionPolicy {
+    /// Delay before the first restart, it is doubled with each consecutive failure
+    pub initial_backoff: Duration,
+    /// Max delay before restart, worker running longer than this is considered as stable and failures are reset
+    pub max_backoff: Duration,
+    /// After this count of consecutive failures, supervisor gives up and requests node shutdown (`None` means restart forever)
+    pub max_failures: Option<usize>,
+}
+
+impl SupervisionPolicy {
+    pub fn new(initial_backoff: Duration, max_backoff: Duration, max_failures: Option<usize>) -> Self {
+        SupervisionPolicy { initial_backoff, max_backoff, max_failures }
+    }
+
+    /// Delay before restart after `failures` consecutive failures
+    fn backoff(&self, failures: usize) -> Duration {
+        let exponent = cmp::min(failures.saturating_sub(1), 16) as u32;
+        cmp::min(self.initial_backoff * 2u32.pow(exponent), self.max_backoff)
+    }
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> Self {
+        SupervisionPolicy::new(Duration::from_secs(1), Duration::from_secs(60), None)
+    }
+}
+
+/// Receiving part of the node shutdown request, resolved when some worker failed too many times
+pub type ShutdownRequestReceiver = oneshot::Receiver<WorkerFailed>;
+
+/// Spawns supervised worker threads
+#[derive(Clone)]
+pub struct Supervisor {
+    policy: SupervisionPolicy,
+    shell_channel: ShellChannelRef,
+    shutdown_request: Arc<Mutex<Option<oneshot::Sender<WorkerFailed>>>>,
+    /// Names of workers, which threads are running
+    running: Arc<Mutex<HashSet<&'static str>>>,
+}
+
+impl Supervisor {
+    /// Create new supervisor, returned receiver is resolved, when node should be shut down
+    pub fn new(policy: SupervisionPolicy, shell_channel: ShellChannelRef) -> (Supervisor, ShutdownRequestReceiver) {
+        let (sender, receiver) = oneshot::channel();
+        let supervisor = Supervisor {
+            policy,
+            shell_channel,
+            shutdown_request: Arc::new(Mutex::new(Some(sender))),
+            running: Arc::new(Mutex::new(HashSet::new())),
+        };
+        (supervisor, receiver)
+    }
+
+    /// Spawns a thread, which runs `worker` until `run` is set to `false`.
+    ///
+    /// Worker returning `Ok` is restarted immediately, failed (or panicked) worker is restarted after backoff.
+    /// Thread should be woken up by `unpark`, when `run` is set to `false`.
+    pub fn spawn<F>(&self, name: &'static str, run: Arc<AtomicBool>, log: Logger, mut worker: F) -> JoinHandle<Result<(), Error>>
+        where F: FnMut() -> Result<(), Error> + Send + 'static
+    {
+        let supervisor = self.clone();
+        supervisor.running.lock().unwrap().insert(name);
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                // worker is removed from running, when thread finishes
+                let _running_guard = RunningGuard { name, running: supervisor.running.clone() };
+                let mut failures = 0;
+
+                while run.load(Ordering::Acquire) {
+                    let started = Instant::now();
+                    let reason = match panic::catch_unwind(AssertUnwindSafe(|| worker())) {
+                        Ok(Ok(())) => {
+                            failures = 0;
+                            continue;
+                        }
+                        Ok(Err(err)) => format!("{:?}", err),
+                        Err(panic) => format!("panic: {}", panic_message(&panic)),
+                    };
+                    if !run.load(Ordering::Acquire) {
+                        // failures during shutdown are expected
+                        break;
+                    }
+
+                    // worker was running long enough, so this is a new failure
+                    if started.elapsed() >= supervisor.policy.max_backoff {
+                        failures = 0;
+                    }
+                    failures += 1;
+
+                    if supervisor.policy.max_failures.map_or(false, |max_failures| failures >= max_failures) {
+                        crit!(log, "Worker failed too many times, requesting node shutdown"; "worker" => name, "failures" => failures, "reason" => &reason);
+                        supervisor.notify_failure(name, &reason, failures, None);
+                        supervisor.request_shutdown(name, &reason, failures);
+                        return Err(format_err!("Worker {} failed {} times, last reason: {}", name, failures, reason));
+                    }
+
+                    let backoff = supervisor.policy.backoff(failures);
+                    warn!(log, "Worker failed, restarting"; "worker" => name, "failures" => failures, "restart_in_ms" => backoff.as_millis() as u64, "reason" => &reason);
+                    supervisor.notify_failure(name, &reason, failures, Some(backoff));
+                    sleep_while_running(&run, backoff);
+                }
+
+                info!(log, "Worker finished"; "worker" => name);
+                Ok(())
+            })
+            .expect("Failed to spawn supervised worker thread")
+    }
+
+    /// Waits until all `workers` are finished, but at most `timeout`.
+    ///
+    /// Returns workers, which are still running.
+    pub async fn wait_for_workers(&self, workers: &[&'static str], timeout: Duration) -> Vec<&'static str> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let unfinished: Vec<&'static str> = {
+                let running = self.running.lock().unwrap();
+                workers.iter().filter(|worker| running.contains(*worker)).cloned().collect()
+            };
+            if unfinished.is_empty() || Instant::now() >= deadline {
+                return unfinished;
+            }
+            tokio::time::delay_for(WAIT_FOR_WORKERS_POLL_INTERVAL).await;
+        }
+    }
+
+    fn notify_failure(&self, worker: &str, reason: &str, failures: usize, restart_in: Option<Duration>) {
+        self.shell_channel.tell(
+            Publish {
+                msg: WorkerFailed {
+                    worker: worker.to_string(),
+                    reason: reason.to_string(),
+                    failures,
+                    restart_in,
+                }.into(),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, None);
+    }
+
+    fn request_shutdown(&self, worker: &str, reason: &str, failures: usize) {
+        if let Some(sender) = self.shutdown_request.lock().unwrap().take() {
+            // shutdown could be already in progress, so we dont care about the result
+            let _ = sender.send(WorkerFailed {
+                worker: worker.to_string(),
+                reason: reason.to_string(),
+                failures,
+                restart_in: None,
+            });
+        }
+    }
+}
+
+const WAIT_FOR_WORKERS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Removes worker from running workers on drop (also when worker thread panics)
+struct RunningGuard {
+    name: &'static str,
+    running: Arc<Mutex<HashSet<&'static str>>>,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.running.lock() {
+            running.remove(self.name);
+        }
+    }
+}
+
+/// Joins the thread, but waits at most `timeout`.
+///
+/// Returns `None`, if the thread was not finished in time, such thread is detached.
+pub fn join_with_timeout<T: Send + 'static>(join_handle: JoinHandle<T>, timeout: Duration) -> Option<thread::Result<T>> {
+    let (sender, receiver) = mpsc::channel();
+    let joiner = thread::Builder::new()
+        .name(format!("join-{}", join_handle.thread().name().unwrap_or("worker")))
+        .spawn(move || {
+            // caller could already gave up waiting, so we dont care about the result
+            let _ = sender.send(join_handle.join());
+        });
+    if joiner.is_err() {
+        return None;
+    }
+    receiver.recv_timeout(timeout).ok()
+}
+
+/// Sleeps for `duration`, or until `run` is set to `false` (thread has to be unparked).
+///
+/// Worker threads are unparked by actors also when there is new work for them, such unpark does not cut the sleep short,
+/// but it is delivered again after the sleep, so the restarted worker does not miss it.
+fn sleep_while_running(run: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let mut unparked = false;
+    while run.load(Ordering::Acquire) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::park_timeout(deadline - now);
+        unparked |= Instant::now() < deadline;
+    }
+    if unparked {
+        thread::current().unpark();
+    }
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = SupervisionPolicy::new(Duration::from_secs(1), Duration::from_secs(10), None);
+        assert_eq!(Duration::from_secs(1), policy.backoff(1));
+        assert_eq!(Duration::from_secs(2), policy.backoff(2));
+        assert_eq!(Duration::from_secs(8), policy.backoff(4));
+        assert_eq!(Duration::from_secs(10), policy.backoff(5));
+        assert_eq!(Duration::from_secs(10), policy.backoff(1000));
+    }
+
+    #[test]
+    fn test_sleep_while_running_is_not_cut_short_by_unpark() {
+        let run = Arc::new(AtomicBool::new(true));
+        let duration = Duration::from_millis(200);
+        let sleeper = {
+            let run = run.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                sleep_while_running(&run, duration);
+                started.elapsed()
+            })
+        };
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(20));
+            sleeper.thread().unpark();
+        }
+        assert!(sleeper.join().unwrap() >= duration);
+
+        // stopped run ends the sleep immediately after unpark
+        let started = Instant::now();
+        let sleeper = {
+            let run = run.clone();
+            thread::spawn(move || sleep_while_running(&run, Duration::from_secs(60)))
+        };
+        run.store(false, Ordering::Release);
+        sleeper.thread().unpark();
+        sleeper.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = panic::catch_unwind(|| panic!("worker panicked")).unwrap_err();
+        assert_eq!("worker panicked", panic_message(&panic));
+
+        let panic = panic::catch_unwind(|| panic!("worker panicked {}", 1)).unwrap_err();
+        assert_eq!("worker panicked 1", panic_message(&panic));
+    }
+}
@@ -0,0 +1,454 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Shell-side pre-validation of block headers and operations received from peers.
+//!
+//! These checks are cheap and are done before anything is stored or sent to the `protocol_runner`,
+//! so a peer sending us inconsistent data can be penalized as soon as possible.
+
+use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Fail;
+
+use crypto::blake2b;
+use crypto::hash::{BlockHash, Hash, HashType};
+use tezos_messages::p2p::binary_message::{MessageHash, MessageHashError};
+use tezos_messages::p2p::encoding::block_header::Fitness;
+use tezos_messages::p2p::encoding::prelude::*;
+
+/// Max allowed size of a single operation data in bytes (same as in the tezos shell)
+pub const MAX_OPERATION_DATA_LENGTH: usize = 32 * 1024;
+/// Block with a timestamp this far in the future is still accepted
+pub const FUTURE_BLOCK_TOLERANCE: Duration = Duration::from_secs(15);
+
+/// Possible reasons for rejecting a block header or block operations
+#[derive(Debug, Fail)]
+pub enum PrevalidationError {
+    #[fail(display = "Block timestamp {} is in the future (now: {})", timestamp, now)]
+    FutureBlock {
+        timestamp: i64,
+        now: i64,
+    },
+    #[fail(display = "Block level {} does not follow predecessor level {}", level, predecessor_level)]
+    InvalidLevel {
+        level: i32,
+        predecessor_level: i32,
+    },
+    #[fail(display = "Block level {} does not match expected level {}", level, expected_level)]
+    UnexpectedLevel {
+        level: i32,
+        expected_level: i32,
+    },
+    #[fail(display = "Block timestamp {} is not after predecessor timestamp {}", timestamp, predecessor_timestamp)]
+    InvalidTimestamp {
+        timestamp: i64,
+        predecessor_timestamp: i64,
+    },
+    #[fail(display = "Block fitness {:?} is not greater than predecessor fitness {:?}", fitness, predecessor_fitness)]
+    InvalidFitness {
+        fitness: Fitness,
+        predecessor_fitness: Fitness,
+    },
+    #[fail(display = "Validation pass {} is out of range, block has {} validation passes", validation_pass, max_validation_pass)]
+    InvalidValidationPass {
+        validation_pass: i8,
+        max_validation_pass: u8,
+    },
+    #[fail(display = "Block has {} validation passes, but {} operation lists were provided", validation_passes, count)]
+    InvalidOperationListCount {
+        count: usize,
+        validation_passes: u8,
+    },
+    #[fail(display = "Operations path position {} does not match validation pass {}", position, validation_pass)]
+    InvalidOperationsPath {
+        position: usize,
+        validation_pass: i8,
+    },
+    #[fail(display = "Operations hash mismatch - expected: {}, computed: {}", expected, computed)]
+    InvalidOperationsHash {
+        expected: String,
+        computed: String,
+    },
+    #[fail(display = "Operation data size {} exceeds limit {}", size, max_size)]
+    OperationTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+    #[fail(display = "Failed to calculate operation hash, reason: {}", reason)]
+    HashError {
+        reason: MessageHashError,
+    },
+}
+
+impl From<MessageHashError> for PrevalidationError {
+    fn from(reason: MessageHashError) -> Self {
+        PrevalidationError::HashError { reason }
+    }
+}
+
+/// Current time as unix timestamp in seconds
+pub fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Check block header against its predecessor (if we know it).
+///
+/// # Arguments
+/// * `header` - checked block header
+/// * `predecessor` - header of the predecessor block, if is already stored
+/// * `now` - current unix timestamp in seconds
+pub fn check_block_header(header: &BlockHeader, predecessor: Option<&BlockHeader>, now: i64) -> Result<(), PrevalidationError> {
+    if header.timestamp() > now + FUTURE_BLOCK_TOLERANCE.as_secs() as i64 {
+        return Err(PrevalidationError::FutureBlock { timestamp: header.timestamp(), now });
+    }
+
+    if let Some(predecessor) = predecessor {
+        if header.level() != predecessor.level() + 1 {
+            return Err(PrevalidationError::InvalidLevel { level: header.level(), predecessor_level: predecessor.level() });
+        }
+        if header.timestamp() <= predecessor.timestamp() {
+            return Err(PrevalidationError::InvalidTimestamp { timestamp: header.timestamp(), predecessor_timestamp: predecessor.timestamp() });
+        }
+        if compare_fitness(header.fitness(), predecessor.fitness()) != Ordering::Greater {
+            return Err(PrevalidationError::InvalidFitness { fitness: header.fitness().clone(), predecessor_fitness: predecessor.fitness().clone() });
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that operations received for one validation pass of the block fits to the block header.
+///
+/// Operations hashes are checked against `operations_hash` of the header with the provided merkle path.
+pub fn check_operations_for_block(header: &BlockHeader, message: &OperationsForBlocksMessage) -> Result<(), PrevalidationError> {
+    let validation_pass = message.operations_for_block().validation_pass();
+    if validation_pass < 0 || validation_pass as u8 >= header.validation_pass() {
+        return Err(PrevalidationError::InvalidValidationPass { validation_pass, max_validation_pass: header.validation_pass() });
+    }
+
+    for operation in message.operations() {
+        if operation.data().len() > MAX_OPERATION_DATA_LENGTH {
+            return Err(PrevalidationError::OperationTooLarge { size: operation.data().len(), max_size: MAX_OPERATION_DATA_LENGTH });
+        }
+    }
+
+    let operation_list_hash = operation_list_hash(message.operations())?;
+    let (computed, position) = compute_path(message.operation_hashes_path(), &operation_list_hash);
+    if position != validation_pass as usize {
+        return Err(PrevalidationError::InvalidOperationsPath { position, validation_pass });
+    }
+    if &computed != header.operations_hash() {
+        return Err(PrevalidationError::InvalidOperationsHash {
+            expected: HashType::OperationListListHash.bytes_to_string(header.operations_hash()),
+            computed: HashType::OperationListListHash.bytes_to_string(&computed),
+        });
+    }
+
+    Ok(())
+}
+
+/// Prepare operations of the block with merkle paths (as if they were received from peers) and check them against the block header.
+///
+/// `operations` are ordered by validation passes, every validation pass of the block has to be provided.
+pub fn operations_for_block_messages(block_hash: &BlockHash, header: &BlockHeader, operations: &[Vec<Operation>]) -> Result<Vec<OperationsForBlocksMessage>, PrevalidationError> {
+    if operations.len() != header.validation_pass() as usize {
+        return Err(PrevalidationError::InvalidOperationListCount { count: operations.len(), validation_passes: header.validation_pass() });
+    }
+
+    let operation_list_hashes = operations.iter()
+        .map(|validation_pass| operation_list_hash(validation_pass))
+        .collect::<Result<Vec<_>, _>>()?;
+    let messages = operations.iter().enumerate()
+        .map(|(validation_pass, operations)| OperationsForBlocksMessage::new(
+            OperationsForBlock::new(block_hash.clone(), validation_pass as i8),
+            merkle_path(&operation_list_hashes, validation_pass),
+            operations.clone(),
+        ))
+        .collect::<Vec<_>>();
+    for message in &messages {
+        check_operations_for_block(header, message)?;
+    }
+    Ok(messages)
+}
+
+/// Compare fitness the same way as tezos does - shorter fitness is lower,
+/// otherwise elements are compared one by one (shorter element is lower, then bytes are compared).
+pub fn compare_fitness(a: &Fitness, b: &Fitness) -> Ordering {
+    a.len().cmp(&b.len())
+        .then_with(|| {
+            a.iter().zip(b.iter())
+                .map(|(a, b)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        })
+}
+
+#[inline]
+fn leaf(data: &[u8]) -> Hash {
+    blake2b::digest_256(data)
+}
+
+#[inline]
+fn node(left: &[u8], right: &[u8]) -> Hash {
+    let mut data = Vec::with_capacity(left.len() + right.len());
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    blake2b::digest_256(&data)
+}
+
+/// Compute merkle tree root hash of the leaves.
+///
+/// Leaves are padded to the nearest power of two by repeating the last leaf, so at every level of the tree
+/// the odd last node is paired with the hash of the padding subtree, not with a copy of itself
+/// (e.g. for six leaves the right subtree is `node(node(l4, l5), node(l5, l5))`).
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        blake2b::digest_256(&[])
+    } else {
+        subtree_root(&padded_leaves(leaves))
+    }
+}
+
+/// Compute merkle path to the leaf at `index`, resulting path can be checked against [merkle_root].
+///
+/// `index` must be lower than count of the leaves.
+pub fn merkle_path(leaves: &[Hash], index: usize) -> Path {
+    fn walk(nodes: &[Hash], index: usize) -> Path {
+        if nodes.len() == 1 {
+            return Path::Op;
+        }
+        let (left, right) = nodes.split_at(nodes.len() / 2);
+        if index < left.len() {
+            Path::Left(Box::new(PathLeft::new(walk(left, index), subtree_root(right))))
+        } else {
+            Path::Right(Box::new(PathRight::new(subtree_root(left), walk(right, index - left.len()))))
+        }
+    }
+
+    assert!(index < leaves.len(), "index must be lower than count of the leaves");
+    walk(&padded_leaves(leaves), index)
+}
+
+/// Operations hash (operation list list hash) of the block with provided operations
+pub fn operations_hash(operations: &[Vec<Operation>]) -> Result<Hash, PrevalidationError> {
+    let operation_list_hashes = operations.iter()
+        .map(|validation_pass| operation_list_hash(validation_pass))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(merkle_root(&operation_list_hashes))
+}
+
+/// Operation list hash of operations from single validation pass
+pub fn operation_list_hash(operations: &[Operation]) -> Result<Hash, PrevalidationError> {
+    let operation_hashes = operations.iter()
+        .map(|operation| operation.message_hash())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(merkle_root(&operation_hashes))
+}
+
+/// Hash leaves and pad them to the nearest power of two by repeating the last leaf
+fn padded_leaves(leaves: &[Hash]) -> Vec<Hash> {
+    let mut nodes: Vec<Hash> = leaves.iter().map(|l| leaf(l)).collect();
+    if let Some(last) = nodes.last().cloned() {
+        nodes.resize(leaves.len().next_power_of_two(), last);
+    }
+    nodes
+}
+
+/// Root of the (already padded) subtree
+fn subtree_root(nodes: &[Hash]) -> Hash {
+    if nodes.len() == 1 {
+        nodes[0].clone()
+    } else {
+        let (left, right) = nodes.split_at(nodes.len() / 2);
+        node(&subtree_root(left), &subtree_root(right))
+    }
+}
+
+/// Compute root hash and position of `hash` in the merkle tree described by `path`.
+fn compute_path(path: &Path, hash: &Hash) -> (Hash, usize) {
+    fn walk(path: &Path, hash: &Hash) -> (Hash, usize, usize) {
+        match path {
+            Path::Op => (leaf(hash), 1, 0),
+            Path::Left(left) => {
+                let (h, depth, position) = walk(left.path(), hash);
+                (node(&h, left.right()), depth * 2, position)
+            }
+            Path::Right(right) => {
+                let (h, depth, position) = walk(right.path(), hash);
+                (node(right.left(), &h), depth * 2, position + depth)
+            }
+        }
+    }
+
+    let (root, _, position) = walk(path, hash);
+    (root, position)
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_messages::p2p::binary_message::BinaryMessage;
+
+    use super::*;
+
+    fn header(level: i32, timestamp: i64, fitness: Fitness) -> BlockHeader {
+        BlockHeaderBuilder::default()
+            .level(level)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(timestamp)
+            .validation_pass(4)
+            .operations_hash(vec![0; 32])
+            .fitness(fitness)
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compare_fitness() {
+        assert_eq!(Ordering::Greater, compare_fitness(&vec![vec![0], vec![0, 2]], &vec![vec![0], vec![0, 1]]));
+        assert_eq!(Ordering::Greater, compare_fitness(&vec![vec![0], vec![0, 0, 1]], &vec![vec![0], vec![2, 0]]));
+        assert_eq!(Ordering::Greater, compare_fitness(&vec![vec![0], vec![0]], &vec![vec![9]]));
+        assert_eq!(Ordering::Equal, compare_fitness(&vec![vec![1], vec![2]], &vec![vec![1], vec![2]]));
+        assert_eq!(Ordering::Less, compare_fitness(&vec![], &vec![vec![0]]));
+    }
+
+    #[test]
+    fn test_check_block_header() {
+        let predecessor = header(10, 1000, vec![vec![0], vec![0, 1]]);
+        let now = 2000;
+
+        assert!(check_block_header(&header(11, 1060, vec![vec![0], vec![0, 2]]), Some(&predecessor), now).is_ok());
+        assert!(check_block_header(&header(11, 1060, vec![vec![0], vec![0, 2]]), None, now).is_ok());
+        assert!(check_block_header(&header(11, now + 10, vec![vec![0], vec![0, 2]]), Some(&predecessor), now).is_ok());
+
+        match check_block_header(&header(12, 1060, vec![vec![0], vec![0, 2]]), Some(&predecessor), now) {
+            Err(PrevalidationError::InvalidLevel { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match check_block_header(&header(11, 1000, vec![vec![0], vec![0, 2]]), Some(&predecessor), now) {
+            Err(PrevalidationError::InvalidTimestamp { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match check_block_header(&header(11, 1060, vec![vec![0], vec![0, 1]]), Some(&predecessor), now) {
+            Err(PrevalidationError::InvalidFitness { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match check_block_header(&header(11, now + 60, vec![vec![0], vec![0, 2]]), None, now) {
+            Err(PrevalidationError::FutureBlock { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_merkle_root_pads_with_last_leaf() {
+        let leaves: Vec<Hash> = (0..3u8).map(|i| vec![i; 32]).collect();
+        let padded: Vec<Hash> = vec![leaves[0].clone(), leaves[1].clone(), leaves[2].clone(), leaves[2].clone()];
+        assert_eq!(merkle_root(&leaves), merkle_root(&padded));
+
+        let expected = node(
+            &node(&leaf(&leaves[0]), &leaf(&leaves[1])),
+            &node(&leaf(&leaves[2]), &leaf(&leaves[2])),
+        );
+        assert_eq!(expected, merkle_root(&leaves));
+    }
+
+    #[test]
+    fn test_merkle_root_non_power_of_two() {
+        let leaves: Vec<Hash> = (0..7u8).map(|i| vec![i; 32]).collect();
+        let l: Vec<Hash> = leaves.iter().map(|l| leaf(l)).collect();
+        let n01 = node(&l[0], &l[1]);
+        let n23 = node(&l[2], &l[3]);
+        let left = node(&n01, &n23);
+
+        // five leaves
+        let n44 = node(&l[4], &l[4]);
+        assert_eq!(node(&left, &node(&n44, &n44)), merkle_root(&leaves[..5]));
+
+        // six leaves, odd last node of the second level is paired with the padding, not with itself
+        let n45 = node(&l[4], &l[5]);
+        let n55 = node(&l[5], &l[5]);
+        assert_eq!(node(&left, &node(&n45, &n55)), merkle_root(&leaves[..6]));
+        assert_ne!(node(&left, &node(&n45, &n45)), merkle_root(&leaves[..6]));
+
+        // seven leaves
+        let n66 = node(&l[6], &l[6]);
+        assert_eq!(node(&left, &node(&n45, &n66)), merkle_root(&leaves[..7]));
+    }
+
+    #[test]
+    fn test_merkle_path() {
+        for count in 1..10u8 {
+            let leaves: Vec<Hash> = (0..count).map(|i| vec![i; 32]).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let path = merkle_path(&leaves, index);
+                assert_eq!((root.clone(), index), compute_path(&path, leaf));
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_operations_hash() {
+        // operations hash of a block with four empty validation passes
+        let empty_list = merkle_root(&[]);
+        let root = merkle_root(&vec![empty_list.clone(), empty_list.clone(), empty_list.clone(), empty_list]);
+        assert_eq!("LLoa7bxRTKaQN2bLYoitYB6bU2DvLnBAqrVjZcvJ364cTcX2PZYKU", HashType::OperationListListHash.bytes_to_string(&root));
+        assert_eq!(root, operations_hash(&vec![vec![], vec![], vec![], vec![]]).unwrap());
+    }
+
+    #[test]
+    fn test_operations_for_block_messages() -> Result<(), failure::Error> {
+        let block_hash = vec![1; 32];
+        let block_header = BlockHeaderBuilder::default()
+            .level(11)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(1060)
+            .validation_pass(4)
+            .operations_hash(operations_hash(&vec![vec![], vec![], vec![], vec![]])?)
+            .fitness(vec![vec![0], vec![0, 2]])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap();
+
+        let messages = operations_for_block_messages(&block_hash, &block_header, &vec![vec![], vec![], vec![], vec![]])?;
+        assert_eq!(4, messages.len());
+        for (validation_pass, message) in messages.iter().enumerate() {
+            assert_eq!(&block_hash, message.operations_for_block().hash());
+            assert_eq!(validation_pass as i8, message.operations_for_block().validation_pass());
+        }
+
+        match operations_for_block_messages(&block_hash, &block_header, &vec![vec![], vec![]]) {
+            Err(PrevalidationError::InvalidOperationListCount { count: 2, validation_passes: 4 }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_path() -> Result<(), failure::Error> {
+        let message_bytes = hex::decode("000000660061b12238a7c3577d725939970800ade6b82d94a231e855b46af46c37850dd02452030ffe7601035ca2892f983c10203656479cfd2f8a4ea656f300cd9d68f74aa625870f7c09f7c4d76ace86e1a7e1c7dc0a0c7edcaa8b284949320081131976a87760c300")?;
+        let messages = PeerMessageResponse::from_bytes(message_bytes)?;
+        let message = match messages.messages().get(0) {
+            Some(PeerMessage::OperationsForBlocks(message)) => message,
+            other => panic!("Unexpected message: {:?}", other),
+        };
+
+        let empty_list = merkle_root(&[]);
+        let (root, position) = compute_path(message.operation_hashes_path(), &empty_list);
+        assert_eq!(3, position);
+
+        let left = HashType::OperationListListHash.string_to_bytes("LLobFmsoFEGPP3q9ZxpE84rH1vPC1uKqEV8L1x8zUjGwanEYuHBVB")?;
+        let left_of_op = HashType::OperationListListHash.string_to_bytes("LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc")?;
+        assert_eq!(left_of_op, leaf(&empty_list));
+        assert_eq!(node(&left, &node(&left_of_op, &leaf(&empty_list))), root);
+        Ok(())
+    }
+}
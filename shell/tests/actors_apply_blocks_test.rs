@@ -24,8 +24,9 @@ use slog::{info, Logger};
 use crypto::hash::{HashType, OperationHash};
 use shell::chain_feeder::ChainFeeder;
 use shell::context_listener::ContextListener;
-use shell::mempool_prevalidator::MempoolPrevalidator;
-use shell::shell_channel::{CurrentMempoolState, MempoolOperationReceived, ShellChannel, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use shell::mempool_prevalidator::{MempoolLimits, MempoolPrevalidator};
+use shell::shell_channel::{CurrentMempoolState, MempoolOperationReceived, ShellChannel, ShellChannelRef, ShellChannelTopic, ShuttingDown, SyncState, SyncStateChanged};
+use shell::supervision::{SupervisionPolicy, Supervisor};
 use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, MempoolStorage, OperationsMetaStorage, OperationsStorage, resolve_storage_init_chain_data};
 use storage::context::{ContextApi, ContextIndex, TezedgeContext};
 use storage::mempool_storage::MempoolOperationType;
@@ -132,17 +133,30 @@ fn test_actors_apply_blocks_and_check_context_and_mempool() -> Result<(), failur
     let actor_system = SystemBuilder::new().name("test_actors_apply_blocks_and_check_context").log(log.clone()).create().expect("Failed to create actor system");
     let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
     let _ = test_actor::TestActor::actor(&actor_system, shell_channel.clone(), test_result_sender);
-    let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_protocol_events.expect("Context listener needs event server"), log.clone(), false).expect("Failed to create context event listener");
-    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_protocol_commands, log.clone()).expect("Failed to create chain feeder");
+    let (supervisor, _) = Supervisor::new(SupervisionPolicy::default(), shell_channel.clone());
+    let _ = ContextListener::actor(&actor_system, shell_channel.clone(), &persistent_storage, apply_protocol_events.expect("Context listener needs event server"), &supervisor, log.clone(), false).expect("Failed to create context event listener");
+    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_protocol_commands, &supervisor, log.clone()).expect("Failed to create chain feeder");
     let _ = MempoolPrevalidator::actor(
         &actor_system,
         shell_channel.clone(),
         &persistent_storage,
         &init_storage_data,
         tezos_readonly_api.clone(),
+        MempoolLimits::default(),
+        &supervisor,
         log.clone(),
     ).expect("Failed to create chain feeder");
 
+    // there is no chain manager, so we need to start mempool prevalidation manually
+    shell_channel.tell(
+        Publish {
+            msg: SyncStateChanged {
+                sync_state: SyncState::Synced,
+                is_bootstrapped: true,
+            }.into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+
     // we have stored 1326 request, apply just 1324, and 1325,1326 will be used for mempool test
     let requests = test_data::read_apply_block_requests_until_1326();
 
@@ -0,0 +1,259 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+#![feature(test)]
+extern crate test;
+
+/// Integration test for block injection, checks that block rejected by the protocol is not stored
+/// and that the chain feeder keeps running after it.
+
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use riker::actors::*;
+use riker::system::SystemBuilder;
+
+use shell::chain_feeder::ChainFeeder;
+use shell::context_listener::ContextListener;
+use shell::shell_channel::{inject_block_result_callback, InjectBlock, InjectBlockError, ShellChannel, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use shell::supervision::{SupervisionPolicy, Supervisor};
+use storage::{BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, resolve_storage_init_chain_data};
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{TEZOS_ENV, TezosEnvironmentConfiguration};
+use tezos_api::ffi::{ApplyBlockRequest, TezosRuntimeConfiguration};
+use tezos_messages::p2p::binary_message::MessageHash;
+use tezos_messages::p2p::encoding::prelude::{BlockHeader, BlockHeaderBuilder};
+use tezos_wrapper::service::{ExecutableProtocolRunner, ProtocolEndpointConfiguration, ProtocolRunnerEndpoint};
+
+mod common;
+
+const INJECT_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[ignore]
+#[test]
+fn test_inject_invalid_block_does_not_stop_chain_feeder() -> Result<(), failure::Error> {
+    // logger
+    let log_level = common::log_level();
+    let log = common::create_logger(log_level.clone());
+
+    // environement
+    let tezos_env: &TezosEnvironmentConfiguration = TEZOS_ENV.get(&test_data::TEZOS_NETWORK).expect("no environment configuration");
+
+    // storage
+    let storage_db_path = "__shell_chain_feeder_test_inject_invalid_block";
+    let context_db_path = common::prepare_empty_dir("__shell_chain_feeder_test_inject_invalid_block_context");
+    let tmp_storage = TmpStorage::create(common::prepare_empty_dir(storage_db_path))?;
+    let persistent_storage = tmp_storage.storage();
+
+    let storage_db_path = PathBuf::from(storage_db_path);
+    let context_db_path = PathBuf::from(context_db_path);
+    let init_storage_data = resolve_storage_init_chain_data(&tezos_env, &storage_db_path, &context_db_path, &None, log.clone())
+        .expect("Failed to resolve init storage chain data");
+
+    // apply block protocol runner endpoint
+    let apply_protocol_runner = common::protocol_runner_executable_path();
+    let mut apply_protocol_runner_endpoint = ProtocolRunnerEndpoint::<ExecutableProtocolRunner>::new(
+        "test_protocol_runner_endpoint",
+        ProtocolEndpointConfiguration::new(
+            TezosRuntimeConfiguration {
+                log_enabled: common::is_ocaml_log_enabled(),
+                no_of_ffi_calls_treshold_for_gc: common::no_of_ffi_calls_treshold_for_gc(),
+                debug_mode: false,
+            },
+            tezos_env.clone(),
+            false,
+            &context_db_path,
+            &apply_protocol_runner,
+            log_level,
+            true,
+        ),
+        log.clone(),
+    );
+    let (apply_restarting_feature, apply_protocol_commands, apply_protocol_events) = match apply_protocol_runner_endpoint.start_in_restarting_mode() {
+        Ok(restarting_feature) => {
+            let ProtocolRunnerEndpoint {
+                commands,
+                events,
+                ..
+            } = apply_protocol_runner_endpoint;
+            (restarting_feature, commands, events)
+        }
+        Err(e) => panic!("Error to start test_protocol_runner_endpoint: {} - error: {:?}", apply_protocol_runner.as_os_str().to_str().unwrap_or("-none-"), e)
+    };
+
+    // run actor's
+    let (failed_workers_sender, failed_workers_receiver) = channel();
+    let actor_system = SystemBuilder::new().name("test_inject_invalid_block").log(log.clone()).create().expect("Failed to create actor system");
+    let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+    let _ = test_actor::TestActor::actor(&actor_system, shell_channel.clone(), failed_workers_sender);
+    let (supervisor, _) = Supervisor::new(SupervisionPolicy::default(), shell_channel.clone());
+    let _ = ContextListener::actor(&actor_system, shell_channel.clone(), &persistent_storage, apply_protocol_events.expect("Context listener needs event server"), &supervisor, log.clone(), false).expect("Failed to create context event listener");
+    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_protocol_commands, &supervisor, log.clone()).expect("Failed to create chain feeder");
+
+    // wait for genesis
+    let block_storage = BlockStorage::new(&persistent_storage);
+    let block_meta_storage = BlockMetaStorage::new(&persistent_storage);
+    let started = Instant::now();
+    while !block_meta_storage.is_applied(&init_storage_data.genesis_block_header_hash)? {
+        assert!(started.elapsed() < INJECT_BLOCK_TIMEOUT, "Genesis was not applied");
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // block at level 1 with corrupted protocol data passes shell checks, but it is rejected by the protocol
+    let request = test_data::read_apply_block_request_level_1()?;
+    let valid_header = request.block_header.clone();
+    let invalid_header = BlockHeaderBuilder::default()
+        .level(valid_header.level())
+        .proto(valid_header.proto())
+        .predecessor(valid_header.predecessor().clone())
+        .timestamp(valid_header.timestamp())
+        .validation_pass(valid_header.validation_pass())
+        .operations_hash(valid_header.operations_hash().clone())
+        .fitness(valid_header.fitness().clone())
+        .context(valid_header.context().clone())
+        .protocol_data(vec![0xff; valid_header.protocol_data().len()])
+        .build()
+        .unwrap();
+    let invalid_block_hash = invalid_header.message_hash()?;
+
+    // 1. invalid block is rejected and not stored
+    let result = inject_block(&shell_channel, invalid_header, &request)?;
+    assert!(result.is_err(), "Invalid block should be rejected");
+    assert!(block_storage.get(&invalid_block_hash)?.is_none());
+
+    // 2. chain feeder still applies valid blocks
+    let valid_block_hash = valid_header.message_hash()?;
+    let result = inject_block(&shell_channel, valid_header, &request)?;
+    assert!(result.is_ok(), "Valid block should be applied, but: {:?}", result);
+    assert!(block_meta_storage.is_applied(&valid_block_hash)?);
+
+    // 3. block applier thread did not fail
+    assert!(failed_workers_receiver.try_recv().is_err(), "Chain feeder worker should not fail");
+
+    // clean up
+    apply_restarting_feature.store(false, Ordering::Release);
+    shell_channel.tell(
+        Publish {
+            msg: ShuttingDown.into(),
+            topic: ShellChannelTopic::ShellCommands.into(),
+        }, None,
+    );
+    thread::sleep(Duration::from_secs(2));
+    let _ = actor_system.shutdown();
+
+    Ok(())
+}
+
+/// Inject block with operations from the request and wait for the result
+fn inject_block(shell_channel: &ShellChannelRef, block_header: BlockHeader, request: &ApplyBlockRequest) -> Result<Result<(), InjectBlockError>, failure::Error> {
+    let (result_callback, result_receiver) = inject_block_result_callback();
+    shell_channel.tell(
+        Publish {
+            msg: InjectBlock {
+                block_header,
+                operations: Some(request.operations.clone()),
+                force: false,
+                result_callback: Some(result_callback),
+            }.into(),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, None);
+
+    let (result_sender, result) = channel();
+    thread::spawn(move || {
+        let _ = result_sender.send(futures::executor::block_on(result_receiver));
+    });
+    Ok(result.recv_timeout(INJECT_BLOCK_TIMEOUT)??)
+}
+
+mod test_data {
+    use std::{env, io};
+    use std::fs::File;
+    use std::path::Path;
+
+    use tezos_api::environment::TezosEnvironment;
+    use tezos_api::ffi::{ApplyBlockRequest, FfiMessage, RustBytes};
+
+    pub const TEZOS_NETWORK: TezosEnvironment = TezosEnvironment::Carthagenet;
+
+    pub fn read_apply_block_request_level_1() -> Result<ApplyBlockRequest, failure::Error> {
+        let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("tests")
+            .join("resources")
+            .join("apply_block_request_until_1326.zip");
+        let file = File::open(path).expect("Couldn't open file: tests/resources/apply_block_request_until_1326.zip");
+        let mut archive = zip::ZipArchive::new(file)?;
+
+        let mut file = archive.by_index(0)?;
+        let mut writer: Vec<u8> = vec![];
+        io::copy(&mut file, &mut writer)?;
+        let request: RustBytes = hex::decode(String::from_utf8(writer)?)?;
+        Ok(ApplyBlockRequest::from_rust_bytes(request)?)
+    }
+}
+
+mod test_actor {
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::Sender as QueueSender;
+
+    use riker::actors::*;
+
+    use shell::shell_channel::{ShellChannelMsg, ShellChannelRef, ShellChannelTopic, WorkerFailed};
+
+    /// Collects failures of the supervised workers
+    #[actor(ShellChannelMsg)]
+    pub(crate) struct TestActor {
+        failed_workers: Arc<Mutex<QueueSender<WorkerFailed>>>,
+        shell_channel: ShellChannelRef,
+    }
+
+    pub type TestActorRef = ActorRef<TestActorMsg>;
+
+    impl Actor for TestActor {
+        type Msg = TestActorMsg;
+
+        fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+            self.shell_channel.tell(Subscribe {
+                actor: Box::new(ctx.myself()),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, ctx.myself().into());
+        }
+
+        fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Option<BasicActorRef>) {
+            self.receive(ctx, msg, sender);
+        }
+    }
+
+    impl ActorFactoryArgs<(ShellChannelRef, Arc<Mutex<QueueSender<WorkerFailed>>>)> for TestActor {
+        fn create_args((shell_channel, failed_workers): (ShellChannelRef, Arc<Mutex<QueueSender<WorkerFailed>>>)) -> Self {
+            Self {
+                shell_channel,
+                failed_workers,
+            }
+        }
+    }
+
+    impl Receive<ShellChannelMsg> for TestActor {
+        type Msg = TestActorMsg;
+
+        fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+            if let ShellChannelMsg::WorkerFailed(failed) = msg {
+                let _ = self.failed_workers.lock().unwrap().send(failed);
+            }
+        }
+    }
+
+    impl TestActor {
+        pub fn name() -> &'static str { "test-actor" }
+
+        pub fn actor(sys: &ActorSystem, shell_channel: ShellChannelRef, failed_workers: QueueSender<WorkerFailed>) -> Result<TestActorRef, CreateError> {
+            Ok(
+                sys.actor_of_props::<TestActor>(
+                    Self::name(),
+                    Props::new_args((shell_channel, Arc::new(Mutex::new(failed_workers)))),
+                )?
+            )
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use riker::system::SystemBuilder;
+
+use networking::p2p::network_channel::NetworkChannel;
+use shell::chain_manager::{ChainManager, SynchronisationThreshold};
+use shell::mempool_prevalidator::MempoolLimits;
+use shell::recording::{encode, encode_for_version, RecordedEventKind, RecordingReader, RecordingWriter};
+use shell::replay::{ReplayPace, Replayer};
+use shell::shell_channel::{ShellChannel, SyncState, SyncStateChanged};
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{TEZOS_ENV, TezosEnvironment};
+use tezos_messages::p2p::encoding::prelude::{GetCurrentBranchMessage, NetworkVersion, PeerMessage, PeerMessageResponse};
+
+mod common;
+
+const PEER_ID: &str = "idtqxHUjbjbCfaDn4jczoPGsnhacKX";
+
+#[test]
+fn test_replay_recorded_events_to_chain_manager() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let tmp_storage = TmpStorage::create(common::prepare_empty_dir("__shell_event_replay_test"))?;
+    let tezos_env = TEZOS_ENV.get(&TezosEnvironment::Carthagenet)
+        .expect("no environment configuration");
+    let chain_id = tezos_env.main_chain_id()
+        .expect("Failed to resolve chain id");
+
+    // record events
+    let network_version = NetworkVersion::new(tezos_env.version.clone(), 0, 1);
+    let mut recording = RecordingWriter::new(Vec::new())?;
+    recording.write(RecordedEventKind::PeerBootstrapped {
+        peer_id: PEER_ID.to_string(),
+        peer_address: "127.0.0.1:9732".parse()?,
+        disable_mempool: false,
+        private_node: false,
+        network_version: encode(&network_version)?,
+    })?;
+    recording.write(RecordedEventKind::PeerMessageReceived {
+        peer_id: PEER_ID.to_string(),
+        message: encode_for_version(&PeerMessageResponse::from(GetCurrentBranchMessage::new(chain_id.clone())), &network_version)?,
+    })?;
+    // generated by chain manager, so it is not replayed
+    recording.write(RecordedEventKind::SyncStateChanged(SyncStateChanged { sync_state: SyncState::Synced, is_bootstrapped: true }))?;
+    // unknown peer
+    recording.write(RecordedEventKind::PeerMessageReceived {
+        peer_id: "idsg8yoDoReK8NBzjUKmfYnY1JSPoP".to_string(),
+        message: encode_for_version(&PeerMessageResponse::from(GetCurrentBranchMessage::new(chain_id.clone())), &network_version)?,
+    })?;
+    let recording = recording.into_inner()?;
+
+    // fresh chain manager with empty storage
+    let actor_system = SystemBuilder::new().name("event_replay_test").log(log.clone()).create().expect("Failed to create actor system");
+    let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+    let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+    let chain_manager = ChainManager::actor(
+        &actor_system,
+        network_channel,
+        shell_channel,
+        tmp_storage.storage(),
+        &chain_id,
+        false,
+        SynchronisationThreshold::new(0, Duration::from_secs(150)),
+        MempoolLimits::default(),
+    ).expect("Failed to create chain manager");
+
+    // replay
+    let mut replayer = Replayer::new(&actor_system, vec![Box::new(chain_manager)], log.clone());
+    let sent_messages = replayer.collect_sent_messages();
+    let stats = replayer.replay(RecordingReader::new(Cursor::new(recording))?, ReplayPace::Immediate)?;
+    assert_eq!(2, stats.replayed);
+    assert_eq!(2, stats.skipped);
+
+    // chain manager asks bootstrapped peer for its current branch
+    let (peer_id, message) = sent_messages.recv_timeout(Duration::from_secs(5))?;
+    assert_eq!(PEER_ID, peer_id);
+    match message.messages().first() {
+        Some(PeerMessage::GetCurrentBranch(message)) => assert_eq!(chain_id, message.chain_id),
+        message => panic!("Unexpected message sent to peer: {:?}", message),
+    }
+
+    let _ = actor_system.shutdown();
+    Ok(())
+}
@@ -0,0 +1,806 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Simulated peer network for shell integration tests.
+//!
+//! [`SimulatedPeer`] is an in-process fake peer, which speaks the real encrypted p2p protocol over localhost
+//! and serves a [`ScriptedChain`]. [`SimulatedNode`] runs `PeerManager` and `ChainManager` connected to the simulated peers.
+//! There is no protocol runner, downloaded blocks are just marked as applied (see [`SimulatedBlockApplier`]),
+//! so the chain manager follows the best downloaded branch.
+//!
+//! Behaviour of the node is observed through published shell events, behaviour of the peers through [`SimulatedPeerEvent`]s.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{channel, Receiver as QueueReceiver, Sender as QueueSender};
+use std::time::{Duration, Instant};
+
+use failure::{bail, format_err};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use riker::actors::*;
+use riker::system::SystemBuilder;
+use slog::{debug, Logger, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::delay_for;
+
+use crypto::crypto_box::{precompute, random_keypair};
+use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
+use crypto::nonce::{generate_nonces, Nonce, NoncePair};
+use networking::p2p::bandwidth::Bandwidth;
+use networking::p2p::network_channel::NetworkChannel;
+use networking::p2p::stream::{EncryptedMessageReader, EncryptedMessageWriter, MessageStream};
+use shell::chain_manager::{ChainManager, SynchronisationThreshold};
+use shell::mempool_prevalidator::MempoolLimits;
+use shell::peer_filter::PeerFilter;
+use shell::peer_manager::{PeerManager, Threshold};
+use shell::shell_channel::{BlockApplied, ShellChannel, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use shell::validation;
+use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, initialize_storage_with_genesis_block, OperationsMetaStorage, resolve_storage_init_chain_data, StorageError, store_applied_block_result, store_commit_genesis_result};
+use storage::persistent::PersistentStorage;
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{TEZOS_ENV, TezosEnvironment, TezosEnvironmentConfiguration};
+use tezos_api::ffi::{ApplyBlockRequest, ApplyBlockResponse, CommitGenesisResult, FfiMessage};
+use tezos_api::identity::Identity;
+use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryMessage, MessageHash};
+use tezos_messages::p2p::encoding::block_header::Fitness;
+use tezos_messages::p2p::encoding::prelude::*;
+
+use crate::common;
+
+/// Simulated network uses carthagenet, because scripted chains are built from the stored carthagenet blocks
+pub const TEZOS_NETWORK: TezosEnvironment = TezosEnvironment::Carthagenet;
+
+/// Proof of work stamp used by the simulated peers and nodes
+const PROOF_OF_WORK_STAMP: &str = "000000000000000000000000000000000000000000000000";
+/// How often simulated peer advertises its current head (and mempool)
+const CURRENT_HEAD_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn tezos_env() -> &'static TezosEnvironmentConfiguration {
+    TEZOS_ENV.get(&TEZOS_NETWORK).expect("no environment configuration")
+}
+
+pub fn network_version() -> NetworkVersion {
+    NetworkVersion::new(tezos_env().version.clone(), 0, 1)
+}
+
+/// Runtime for the simulated peers and for the p2p layer of the simulated nodes
+pub fn create_runtime() -> io::Result<Runtime> {
+    Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .thread_name("simulated-network")
+        .build()
+}
+
+/// Wait for the item matching the predicate, all other items are dropped
+fn wait_for<T, F>(receiver: &QueueReceiver<T>, timeout: Duration, mut predicate: F) -> Option<T>
+    where F: FnMut(&T) -> bool
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        match receiver.recv_timeout(deadline - now) {
+            Ok(item) => if predicate(&item) {
+                return Some(item);
+            },
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Fitness greater by one (the last element of the fitness is incremented as a big endian number)
+fn increment_fitness(fitness: &Fitness) -> Fitness {
+    let mut fitness = fitness.clone();
+    if let Some(last) = fitness.last_mut() {
+        for byte in last.iter_mut().rev() {
+            let (incremented, overflow) = byte.overflowing_add(1);
+            *byte = incremented;
+            if !overflow {
+                return fitness;
+            }
+        }
+        last.insert(0, 1);
+    }
+    fitness
+}
+
+/// Block served by the simulated peers
+#[derive(Clone, Debug)]
+pub struct ScriptedBlock {
+    pub header: BlockHeaderWithHash,
+    /// Operations by validation pass
+    pub operations: Vec<Vec<Operation>>,
+}
+
+impl ScriptedBlock {
+    pub fn level(&self) -> i32 {
+        self.header.header.level()
+    }
+}
+
+/// Chain served by the simulated peers.
+///
+/// Chain consists of the current branch (blocks ordered by level) and all the known blocks,
+/// so blocks from the abandoned branches are still served after a fork.
+#[derive(Clone, Debug)]
+pub struct ScriptedChain {
+    chain_id: ChainId,
+    branch: Vec<BlockHash>,
+    blocks: HashMap<BlockHash, ScriptedBlock>,
+}
+
+impl ScriptedChain {
+    /// Chain made of the first `length` carthagenet blocks (starting at level 1)
+    pub fn carthagenet(length: usize) -> Result<Self, failure::Error> {
+        let path = Path::new(&env::var("CARGO_MANIFEST_DIR")?)
+            .join("tests")
+            .join("resources")
+            .join("apply_block_request_until_1326.zip");
+        let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+        if length > archive.len() {
+            bail!("Only {} carthagenet blocks are available, requested: {}", archive.len(), length);
+        }
+
+        let mut blocks = Vec::with_capacity(length);
+        for index in 0..length {
+            let mut request = String::new();
+            archive.by_index(index)?.read_to_string(&mut request)?;
+            let request = ApplyBlockRequest::from_rust_bytes(hex::decode(request.trim())?)?;
+            blocks.push(ScriptedBlock {
+                header: BlockHeaderWithHash::new(request.block_header)?,
+                operations: request.operations,
+            });
+        }
+
+        Self::new(tezos_env().main_chain_id()?, blocks)
+    }
+
+    /// Create chain from blocks ordered by level
+    pub fn new(chain_id: ChainId, blocks: Vec<ScriptedBlock>) -> Result<Self, failure::Error> {
+        for (predecessor, block) in blocks.iter().zip(blocks.iter().skip(1)) {
+            if block.header.header.predecessor() != &predecessor.header.hash {
+                bail!("Block at level {} is not a successor of the previous block", block.level());
+            }
+        }
+
+        Ok(ScriptedChain {
+            chain_id,
+            branch: blocks.iter().map(|block| block.header.hash.clone()).collect(),
+            blocks: blocks.into_iter().map(|block| (block.header.hash.clone(), block)).collect(),
+        })
+    }
+
+    pub fn chain_id(&self) -> &ChainId {
+        &self.chain_id
+    }
+
+    /// Blocks of the current branch ordered by level
+    pub fn branch(&self) -> impl DoubleEndedIterator<Item=&ScriptedBlock> {
+        self.branch.iter().map(move |hash| &self.blocks[hash])
+    }
+
+    pub fn head(&self) -> &ScriptedBlock {
+        self.branch().last().expect("Scripted chain is empty")
+    }
+
+    pub fn block(&self, hash: &BlockHash) -> Option<&ScriptedBlock> {
+        self.blocks.get(hash)
+    }
+
+    /// Chain with the current branch cut after the `level`, blocks above the `level` are forgotten
+    pub fn truncate(&self, level: i32) -> Self {
+        let branch = self.branch().filter(|block| block.level() <= level)
+            .map(|block| block.header.hash.clone())
+            .collect::<Vec<_>>();
+        let mut blocks = self.blocks.clone();
+        blocks.retain(|hash, _| branch.contains(hash));
+
+        ScriptedChain {
+            chain_id: self.chain_id.clone(),
+            branch,
+            blocks,
+        }
+    }
+
+    /// Chain with the alternative branch starting at the `level`.
+    ///
+    /// Blocks of the alternative branch carry the same operations as the original blocks,
+    /// but they are one second younger and their fitness is higher, so the alternative branch is better.
+    /// (Alternative branch truncated before the original head is worse than the original branch.)
+    pub fn fork(&self, level: i32) -> Result<Self, failure::Error> {
+        let position = self.branch().position(|block| block.level() == level)
+            .ok_or_else(|| format_err!("Level {} is not in the current branch", level))?;
+
+        let mut fork = self.clone();
+        fork.branch.truncate(position);
+
+        let mut predecessor = self.blocks[&self.branch[position]].header.header.predecessor().clone();
+        for original in self.branch().skip(position) {
+            let header = &original.header.header;
+            let forked = BlockHeaderBuilder::default()
+                .level(header.level())
+                .proto(header.proto())
+                .predecessor(predecessor)
+                .timestamp(header.timestamp() + 1)
+                .validation_pass(header.validation_pass())
+                .operations_hash(header.operations_hash().clone())
+                .fitness(increment_fitness(header.fitness()))
+                .context(header.context().clone())
+                .protocol_data(header.protocol_data().clone())
+                .build()
+                .map_err(failure::err_msg)?;
+            let forked = BlockHeaderWithHash::new(forked)?;
+
+            predecessor = forked.hash.clone();
+            fork.branch.push(forked.hash.clone());
+            fork.blocks.insert(forked.hash.clone(), ScriptedBlock { header: forked, operations: original.operations.clone() });
+        }
+
+        Ok(fork)
+    }
+
+    fn current_branch(&self) -> CurrentBranchMessage {
+        let history = self.branch.iter().rev().skip(1).cloned().collect();
+        CurrentBranchMessage::new(self.chain_id.clone(), CurrentBranch::new((*self.head().header.header).clone(), history))
+    }
+
+    fn operations_for_block(&self, request: &OperationsForBlock) -> Option<OperationsForBlocksMessage> {
+        let block = self.blocks.get(request.hash())?;
+        let validation_pass = request.validation_pass();
+        if validation_pass < 0 || validation_pass as usize >= block.operations.len() {
+            return None;
+        }
+
+        let operation_list_hashes = block.operations.iter()
+            .map(|operations| validation::operation_list_hash(operations))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        Some(OperationsForBlocksMessage::new(
+            request.clone(),
+            validation::merkle_path(&operation_list_hashes, validation_pass as usize),
+            block.operations[validation_pass as usize].clone(),
+        ))
+    }
+}
+
+/// How does the simulated peer respond to the requests
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerBehaviour {
+    /// Peer serves everything it knows
+    Honest,
+    /// Peer advertises its chain, but never serves blocks, block operations or mempool operations
+    Stalled,
+}
+
+/// What happened to the simulated peer
+#[derive(Debug)]
+pub enum SimulatedPeerEvent {
+    /// Node connected to the peer and handshake was successful
+    Connected,
+    /// Peer received message from the node
+    MessageReceived(PeerMessage),
+    /// Node disconnected from the peer
+    Disconnected,
+}
+
+/// Fake peer listening on localhost
+pub struct SimulatedPeer {
+    address: SocketAddr,
+    shared: Arc<PeerShared>,
+    events: QueueReceiver<SimulatedPeerEvent>,
+}
+
+impl SimulatedPeer {
+    /// Start listening for the node connections on a random localhost port
+    pub fn start(runtime: &Runtime, chain: ScriptedChain, behaviour: PeerBehaviour, log: Logger) -> Result<Self, failure::Error> {
+        let listener = StdTcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let mut listener = runtime.enter(|| TcpListener::from_std(listener))?;
+
+        let (public_key, secret_key) = random_keypair();
+        let (events_sender, events) = channel();
+        let shared = Arc::new(PeerShared {
+            address,
+            public_key,
+            secret_key,
+            behaviour,
+            chain: RwLock::new(chain),
+            mempool: RwLock::new(Vec::new()),
+            connections: Mutex::new(Vec::new()),
+            events: Mutex::new(events_sender),
+            log: log.new(slog::o!("simulated_peer" => address.to_string())),
+        });
+
+        let peer = shared.clone();
+        runtime.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(peer.clone().serve(stream));
+                    }
+                    Err(e) => {
+                        warn!(peer.log, "Simulated peer stopped to accept connections"; "reason" => format!("{}", e));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(SimulatedPeer { address, shared, events })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Wait for the event matching the predicate, all other events are dropped
+    pub fn wait_for<F>(&self, timeout: Duration, predicate: F) -> Option<SimulatedPeerEvent>
+        where F: FnMut(&SimulatedPeerEvent) -> bool
+    {
+        wait_for(&self.events, timeout, predicate)
+    }
+
+    /// Switch to another chain and advertise its current branch to the connected nodes
+    pub fn switch_chain(&self, chain: ScriptedChain) {
+        *self.shared.chain.write().unwrap() = chain;
+        self.shared.advertise(|chain| chain.current_branch().into());
+    }
+
+    /// Add operations to the mempool and advertise them to the connected nodes, returns hashes of the added operations
+    pub fn add_mempool_operations(&self, operations: Vec<Operation>) -> Result<Vec<OperationHash>, failure::Error> {
+        let operations = operations.into_iter()
+            .map(|operation| Ok((operation.message_hash()?, operation)))
+            .collect::<Result<Vec<_>, failure::Error>>()?;
+        let hashes = operations.iter().map(|(hash, _)| hash.clone()).collect();
+
+        self.shared.mempool.write().unwrap().extend(operations);
+        let shared = self.shared.clone();
+        self.shared.advertise(|chain| shared.current_head(chain).into());
+        Ok(hashes)
+    }
+}
+
+/// State of the simulated peer shared by all its connections
+struct PeerShared {
+    address: SocketAddr,
+    public_key: String,
+    secret_key: String,
+    behaviour: PeerBehaviour,
+    chain: RwLock<ScriptedChain>,
+    mempool: RwLock<Vec<(OperationHash, Operation)>>,
+    /// Outgoing message queues of the open connections
+    connections: Mutex<Vec<UnboundedSender<PeerMessageResponse>>>,
+    events: Mutex<QueueSender<SimulatedPeerEvent>>,
+    log: Logger,
+}
+
+impl PeerShared {
+    async fn serve(self: Arc<Self>, stream: TcpStream) {
+        match self.handshake(stream).await {
+            Ok((rx, tx)) => {
+                self.notify(SimulatedPeerEvent::Connected);
+                self.communicate(rx, tx).await;
+                self.notify(SimulatedPeerEvent::Disconnected);
+            }
+            Err(e) => warn!(self.log, "Handshake with node failed"; "reason" => format!("{}", e)),
+        }
+    }
+
+    /// The same handshake as the one in `networking::p2p::peer`, simulated peer is always the accepting side
+    async fn handshake(&self, stream: TcpStream) -> Result<(EncryptedMessageReader, EncryptedMessageWriter), failure::Error> {
+        let (mut rx, mut tx) = MessageStream::from(stream).split();
+
+        let connection_message = ConnectionMessage::new(
+            self.address.port(),
+            &self.public_key,
+            PROOF_OF_WORK_STAMP,
+            &Nonce::random().get_bytes(),
+            vec![network_version()]);
+        let sent = BinaryChunk::from_content(&connection_message.as_bytes()?)?;
+        tx.write_message(&sent).await?;
+        let received = rx.read_message().await?;
+        let connection_message = ConnectionMessage::from_bytes(received.content().to_vec())?;
+
+        let NoncePair { local, remote } = generate_nonces(sent.raw(), received.raw(), true);
+        let node_public_key = connection_message.public_key();
+        let node_peer_id = HashType::CryptoboxPublicKeyHash.bytes_to_string(node_public_key);
+        let precomputed_key = precompute(&hex::encode(node_public_key), &self.secret_key)?;
+
+        let mut tx = EncryptedMessageWriter::new(tx, precomputed_key.clone(), local, node_peer_id.clone(), self.log.clone());
+        let mut rx = EncryptedMessageReader::new(rx, precomputed_key, remote, node_peer_id, self.log.clone());
+        if let Some(negotiated) = NetworkVersion::negotiate(&[network_version()], connection_message.versions()) {
+            tx.set_network_version(negotiated.clone());
+            rx.set_network_version(negotiated);
+        }
+
+        tx.write_message(&MetadataMessage::new(false, false)).await?;
+        let _ = rx.read_message::<MetadataMessage>().await?;
+        tx.write_message(&AckMessage::Ack).await?;
+        match rx.read_message::<AckMessage>().await? {
+            AckMessage::Ack => Ok((rx, tx)),
+            ack => bail!("Node refused connection: {:?}", ack),
+        }
+    }
+
+    async fn communicate(self: &Arc<Self>, mut rx: EncryptedMessageReader, mut tx: EncryptedMessageWriter) {
+        let (outgoing, mut outgoing_rx) = unbounded::<PeerMessageResponse>();
+        self.connections.lock().unwrap().push(outgoing.clone());
+
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing_rx.next().await {
+                if tx.write_message(&message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // advertise current head regularly, like a real node does
+        let peer = self.clone();
+        let advertiser = outgoing.clone();
+        tokio::spawn(async move {
+            loop {
+                delay_for(CURRENT_HEAD_INTERVAL).await;
+                let current_head = peer.current_head(&peer.chain.read().unwrap());
+                if advertiser.unbounded_send(current_head.into()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            match rx.read_message::<PeerMessageResponse>().await {
+                Ok(received) => {
+                    for message in received.messages() {
+                        self.notify(SimulatedPeerEvent::MessageReceived(message.clone()));
+                        for response in self.respond(message) {
+                            let _ = outgoing.unbounded_send(response);
+                        }
+                    }
+                }
+                Err(e) => {
+                    debug!(self.log, "Connection with node closed"; "reason" => format!("{}", e));
+                    break;
+                }
+            }
+        }
+
+        outgoing.close_channel();
+        let _ = writer.await;
+    }
+
+    fn respond(&self, message: &PeerMessage) -> Vec<PeerMessageResponse> {
+        let chain = self.chain.read().unwrap();
+        match message {
+            PeerMessage::GetCurrentBranch(request) if &request.chain_id == chain.chain_id() => {
+                vec![chain.current_branch().into()]
+            }
+            PeerMessage::GetCurrentHead(request) if request.chain_id() == chain.chain_id() => {
+                vec![self.current_head(&chain).into()]
+            }
+            _ if self.behaviour == PeerBehaviour::Stalled => vec![],
+            PeerMessage::GetBlockHeaders(request) => {
+                request.get_block_headers().iter()
+                    .filter_map(|hash| chain.block(hash))
+                    .map(|block| BlockHeaderMessage::from((*block.header.header).clone()).into())
+                    .collect()
+            }
+            PeerMessage::GetOperationsForBlocks(request) => {
+                request.get_operations_for_blocks().iter()
+                    .filter_map(|request| chain.operations_for_block(request))
+                    .map(|operations| operations.into())
+                    .collect()
+            }
+            PeerMessage::GetOperations(request) => {
+                let mempool = self.mempool.read().unwrap();
+                request.get_operations().iter()
+                    .filter_map(|hash| mempool.iter().find(|(operation_hash, _)| operation_hash == hash))
+                    .map(|(_, operation)| OperationMessage::new(operation.clone()).into())
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn current_head(&self, chain: &ScriptedChain) -> CurrentHeadMessage {
+        let known_valid = self.mempool.read().unwrap().iter().map(|(hash, _)| hash.clone()).collect();
+        CurrentHeadMessage::new(chain.chain_id().clone(), (*chain.head().header.header).clone(), Mempool::new(known_valid, vec![]))
+    }
+
+    /// Send message to all connected nodes
+    fn advertise<F>(&self, message: F)
+        where F: Fn(&ScriptedChain) -> PeerMessageResponse
+    {
+        let chain = self.chain.read().unwrap();
+        self.connections.lock().unwrap()
+            .retain(|connection| connection.unbounded_send(message(&chain)).is_ok());
+    }
+
+    fn notify(&self, event: SimulatedPeerEvent) {
+        // nobody could be listening anymore, so we dont care about the result
+        let _ = self.events.lock().unwrap().send(event);
+    }
+}
+
+/// Node running `PeerManager` and `ChainManager` on top of the storage initialized with the genesis block
+pub struct SimulatedNode {
+    actor_system: ActorSystem,
+    shell_events: QueueReceiver<ShellChannelMsg>,
+    _tmp_storage: TmpStorage,
+}
+
+impl SimulatedNode {
+    /// Start node, which connects to the `peers`
+    pub fn start(name: &str, runtime: &Runtime, peers: &[SocketAddr], log: Logger) -> Result<Self, failure::Error> {
+        let tmp_storage = TmpStorage::create(common::prepare_empty_dir(name))?;
+        initialize_storage_with_genesis(tmp_storage.storage(), &log)?;
+
+        let actor_system = SystemBuilder::new().name(name).log(log.clone()).create().expect("Failed to create actor system");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+
+        let (shell_events_sender, shell_events) = channel();
+        let _ = ShellEventCollector::actor(&actor_system, shell_channel.clone(), shell_events_sender).expect("Failed to create shell event collector");
+        let _ = SimulatedBlockApplier::actor(&actor_system, shell_channel.clone(), tmp_storage.storage()).expect("Failed to create block applier");
+        let _ = ChainManager::actor(
+            &actor_system,
+            network_channel.clone(),
+            shell_channel.clone(),
+            tmp_storage.storage(),
+            &tezos_env().main_chain_id()?,
+            false,
+            SynchronisationThreshold::new(0, Duration::from_secs(150)),
+            MempoolLimits::default(),
+        ).expect("Failed to create chain manager");
+
+        let (public_key, secret_key) = random_keypair();
+        let identity = Identity {
+            peer_id: HashType::CryptoboxPublicKeyHash.bytes_to_string(&hex::decode(&public_key)?),
+            public_key,
+            secret_key,
+            proof_of_work_stamp: PROOF_OF_WORK_STAMP.to_string(),
+        };
+        let _ = PeerManager::actor(
+            &actor_system,
+            network_channel,
+            shell_channel,
+            tmp_storage.storage(),
+            runtime.handle().clone(),
+            &[],
+            peers,
+            Threshold::new(peers.len(), peers.len()),
+            StdTcpListener::bind("127.0.0.1:0")?.local_addr()?.port(),
+            identity,
+            // simulated peers do not compute proof of work
+            0.0,
+            vec![network_version()],
+            Arc::new(Bandwidth::default()),
+            None,
+            None,
+            PeerFilter::default(),
+            false,
+            false,
+        ).expect("Failed to create peer manager");
+
+        Ok(SimulatedNode {
+            actor_system,
+            shell_events,
+            _tmp_storage: tmp_storage,
+        })
+    }
+
+    /// Wait for the shell event matching the predicate, all other events are dropped
+    pub fn wait_for_shell_event<F>(&self, timeout: Duration, predicate: F) -> Option<ShellChannelMsg>
+        where F: FnMut(&ShellChannelMsg) -> bool
+    {
+        wait_for(&self.shell_events, timeout, predicate)
+    }
+
+    pub fn shutdown(self) {
+        let _ = self.actor_system.shutdown();
+    }
+}
+
+/// Store genesis block, like the chain feeder does after the genesis commit
+fn initialize_storage_with_genesis(persistent_storage: &PersistentStorage, log: &Logger) -> Result<(), failure::Error> {
+    let init_data = resolve_storage_init_chain_data(tezos_env(), &PathBuf::new(), &PathBuf::new(), &None, log.clone())?;
+    let mut block_storage = BlockStorage::new(persistent_storage);
+    let mut block_meta_storage = BlockMetaStorage::new(persistent_storage);
+    let mut operations_meta_storage = OperationsMetaStorage::new(persistent_storage);
+
+    // blocks are never applied, so context hash does not matter
+    let _ = initialize_storage_with_genesis_block(&mut block_storage, &init_data, tezos_env(), &vec![0; 32], log.clone())?;
+    let _ = store_commit_genesis_result(
+        &mut block_storage,
+        &mut block_meta_storage,
+        &mut operations_meta_storage,
+        &init_data,
+        CommitGenesisResult {
+            block_header_proto_json: "{}".to_string(),
+            block_header_proto_metadata_json: "{}".to_string(),
+            operations_proto_metadata_json: "{}".to_string(),
+        },
+    )?;
+    Ok(())
+}
+
+/// Forwards all shell events to the test
+#[actor(ShellChannelMsg)]
+struct ShellEventCollector {
+    shell_channel: ShellChannelRef,
+    shell_events: Arc<Mutex<QueueSender<ShellChannelMsg>>>,
+}
+
+impl ShellEventCollector {
+    fn actor(sys: &ActorSystem, shell_channel: ShellChannelRef, shell_events: QueueSender<ShellChannelMsg>) -> Result<ActorRef<ShellEventCollectorMsg>, CreateError> {
+        sys.actor_of_props::<ShellEventCollector>(
+            "shell-event-collector",
+            Props::new_args((shell_channel, Arc::new(Mutex::new(shell_events)))),
+        )
+    }
+}
+
+impl ActorFactoryArgs<(ShellChannelRef, Arc<Mutex<QueueSender<ShellChannelMsg>>>)> for ShellEventCollector {
+    fn create_args((shell_channel, shell_events): (ShellChannelRef, Arc<Mutex<QueueSender<ShellChannelMsg>>>)) -> Self {
+        ShellEventCollector { shell_channel, shell_events }
+    }
+}
+
+impl Actor for ShellEventCollector {
+    type Msg = ShellEventCollectorMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.shell_channel.tell(Subscribe {
+            actor: Box::new(ctx.myself()),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, ctx.myself().into());
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<ShellChannelMsg> for ShellEventCollector {
+    type Msg = ShellEventCollectorMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        // nobody could be listening anymore, so we dont care about the result
+        let _ = self.shell_events.lock().unwrap().send(msg);
+    }
+}
+
+/// Marks complete downloaded blocks as applied (without the protocol) and publishes `BlockApplied`, like the chain feeder does.
+///
+/// Block is applied only when its predecessor is already applied, so branches are applied from the oldest block.
+#[actor(ShellChannelMsg)]
+struct SimulatedBlockApplier {
+    shell_channel: ShellChannelRef,
+    block_storage: BlockStorage,
+    block_meta_storage: BlockMetaStorage,
+    operations_meta_storage: OperationsMetaStorage,
+    /// Received blocks, which were not applied yet
+    waiting: HashSet<BlockHash>,
+}
+
+impl SimulatedBlockApplier {
+    fn actor(sys: &ActorSystem, shell_channel: ShellChannelRef, persistent_storage: &PersistentStorage) -> Result<ActorRef<SimulatedBlockApplierMsg>, CreateError> {
+        sys.actor_of_props::<SimulatedBlockApplier>(
+            "simulated-block-applier",
+            Props::new_args((shell_channel, persistent_storage.clone())),
+        )
+    }
+
+    /// Apply all waiting blocks, which can be applied
+    fn apply_waiting_blocks(&mut self) -> Result<(), StorageError> {
+        loop {
+            let mut progress = false;
+            for block_hash in self.waiting.iter().cloned().collect::<Vec<_>>() {
+                if self.apply_block(&block_hash)? {
+                    self.waiting.remove(&block_hash);
+                    progress = true;
+                }
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    fn apply_block(&mut self, block_hash: &BlockHash) -> Result<bool, StorageError> {
+        let block = match self.block_storage.get(block_hash)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        if !self.operations_meta_storage.is_complete(block_hash)? || !self.block_meta_storage.is_applied(block.header.predecessor())? {
+            return Ok(false);
+        }
+        let mut meta = match self.block_meta_storage.get(block_hash)? {
+            Some(meta) => meta,
+            None => return Ok(false),
+        };
+        if meta.is_applied() {
+            return Ok(true);
+        }
+
+        let (block_json_data, _) = store_applied_block_result(
+            &mut self.block_storage,
+            &mut self.block_meta_storage,
+            block_hash,
+            ApplyBlockResponse {
+                validation_result_message: "simulated".to_string(),
+                context_hash: block.header.context().clone(),
+                block_header_proto_json: "{}".to_string(),
+                block_header_proto_metadata_json: "{}".to_string(),
+                operations_proto_metadata_json: "{}".to_string(),
+                max_operations_ttl: 60,
+                last_allowed_fork_level: 0,
+                forking_testchain: false,
+                forking_testchain_data: None,
+            },
+            &mut meta,
+        )?;
+        self.shell_channel.tell(
+            Publish {
+                msg: BlockApplied::new(block, block_json_data).into(),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, None);
+        Ok(true)
+    }
+}
+
+impl ActorFactoryArgs<(ShellChannelRef, PersistentStorage)> for SimulatedBlockApplier {
+    fn create_args((shell_channel, persistent_storage): (ShellChannelRef, PersistentStorage)) -> Self {
+        SimulatedBlockApplier {
+            shell_channel,
+            block_storage: BlockStorage::new(&persistent_storage),
+            block_meta_storage: BlockMetaStorage::new(&persistent_storage),
+            operations_meta_storage: OperationsMetaStorage::new(&persistent_storage),
+            waiting: HashSet::new(),
+        }
+    }
+}
+
+impl Actor for SimulatedBlockApplier {
+    type Msg = SimulatedBlockApplierMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.shell_channel.tell(Subscribe {
+            actor: Box::new(ctx.myself()),
+            topic: ShellChannelTopic::ShellEvents.into(),
+        }, ctx.myself().into());
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<ShellChannelMsg> for SimulatedBlockApplier {
+    type Msg = SimulatedBlockApplierMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        match msg {
+            ShellChannelMsg::BlockReceived(received) => {
+                self.waiting.insert(received.hash);
+            }
+            ShellChannelMsg::AllBlockOperationsReceived(received) => {
+                self.waiting.insert(received.hash);
+            }
+            _ => return,
+        }
+        if let Err(e) = self.apply_waiting_blocks() {
+            warn!(ctx.system.log(), "Failed to apply simulated blocks"; "reason" => format!("{:?}", e));
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use serial_test::serial;
+
+use shell::shell_channel::ShellChannelMsg;
+use tezos_messages::p2p::encoding::prelude::PeerMessage;
+
+use crate::simulated_network::{create_runtime, PeerBehaviour, ScriptedChain, SimulatedNode, SimulatedPeer, SimulatedPeerEvent};
+
+mod common;
+mod simulated_network;
+
+const BLOCKS_TIMEOUT: Duration = Duration::from_secs(60);
+/// Node asks peers for their current branch every 15s, so ignored branch would be downloaded within this time
+const FORK_IGNORED_TIMEOUT: Duration = Duration::from_secs(20);
+/// Stalled peers are checked every 15s and disconnected after 30s of silence
+const STALLED_PEER_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[test]
+#[serial]
+fn test_bootstrap_from_multiple_peers() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let runtime = create_runtime()?;
+    let chain = ScriptedChain::carthagenet(30)?;
+
+    let peer_short = SimulatedPeer::start(&runtime, chain.truncate(15), PeerBehaviour::Honest, log.clone())?;
+    let peer_long = SimulatedPeer::start(&runtime, chain.clone(), PeerBehaviour::Honest, log.clone())?;
+    let node = SimulatedNode::start("__shell_simulated_bootstrap", &runtime, &[peer_short.address(), peer_long.address()], log)?;
+
+    // node should download all the blocks of the longest chain
+    let mut missing_levels = chain.branch().map(|block| block.level()).collect::<HashSet<_>>();
+    while !missing_levels.is_empty() {
+        match node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::BlockReceived(_))) {
+            Some(ShellChannelMsg::BlockReceived(block)) => {
+                if chain.block(&block.hash).is_some() {
+                    missing_levels.remove(&block.level);
+                }
+            }
+            _ => panic!("Blocks were not received, missing levels: {:?}", missing_levels),
+        }
+    }
+
+    let head = chain.head().header.hash.clone();
+    assert!(
+        node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::AllBlockOperationsReceived(received) if received.hash == head)).is_some(),
+        "Operations of the head were not received"
+    );
+
+    node.shutdown();
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_switch_to_forked_branch() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let runtime = create_runtime()?;
+    let chain = ScriptedChain::carthagenet(20)?;
+    let fork = chain.fork(15)?;
+
+    let peer = SimulatedPeer::start(&runtime, chain.clone(), PeerBehaviour::Honest, log.clone())?;
+    let node = SimulatedNode::start("__shell_simulated_fork", &runtime, &[peer.address()], log)?;
+
+    let head = chain.head().header.hash.clone();
+    assert!(
+        node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::BlockApplied(applied) if applied.header().hash == head)).is_some(),
+        "Original branch was not applied"
+    );
+
+    // peer switches to the alternative branch with higher fitness, node should switch to it too
+    peer.switch_chain(fork.clone());
+    let forked_head = fork.head().header.hash.clone();
+    assert_ne!(head, forked_head);
+    assert!(
+        node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::BlockApplied(applied) if applied.header().hash == forked_head)).is_some(),
+        "Forked branch was not applied"
+    );
+
+    node.shutdown();
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_ignore_forked_branch_with_lower_fitness() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let runtime = create_runtime()?;
+    let chain = ScriptedChain::carthagenet(20)?;
+    // fitness of the forked head at level 18 is lower than fitness of the original head at level 20
+    let fork = chain.fork(15)?.truncate(18);
+
+    let peer = SimulatedPeer::start(&runtime, chain.clone(), PeerBehaviour::Honest, log.clone())?;
+    let node = SimulatedNode::start("__shell_simulated_lower_fork", &runtime, &[peer.address()], log)?;
+
+    let head = chain.head().header.hash.clone();
+    assert!(
+        node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::BlockApplied(applied) if applied.header().hash == head)).is_some(),
+        "Original branch was not applied"
+    );
+
+    // peer switches to the worse alternative branch, node should not even download it
+    peer.switch_chain(fork.clone());
+    let forked_blocks = fork.branch().filter(|block| chain.block(&block.header.hash).is_none())
+        .map(|block| block.header.hash.clone())
+        .collect::<HashSet<_>>();
+    assert!(!forked_blocks.is_empty());
+    let adopted = node.wait_for_shell_event(FORK_IGNORED_TIMEOUT, |event| match event {
+        ShellChannelMsg::BlockReceived(received) => forked_blocks.contains(&received.hash),
+        ShellChannelMsg::BlockApplied(applied) => forked_blocks.contains(&applied.header().hash),
+        _ => false,
+    });
+    assert!(adopted.is_none(), "Forked branch with lower fitness was adopted: {:?}", adopted);
+
+    node.shutdown();
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_disconnect_stalled_peer() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let runtime = create_runtime()?;
+    let chain = ScriptedChain::carthagenet(20)?;
+
+    let peer = SimulatedPeer::start(&runtime, chain, PeerBehaviour::Stalled, log.clone())?;
+    let node = SimulatedNode::start("__shell_simulated_stalled", &runtime, &[peer.address()], log)?;
+
+    assert!(peer.wait_for(BLOCKS_TIMEOUT, |event| matches!(event, SimulatedPeerEvent::Connected)).is_some(), "Node did not connect to the peer");
+    assert!(
+        peer.wait_for(BLOCKS_TIMEOUT, |event| matches!(event, SimulatedPeerEvent::MessageReceived(PeerMessage::GetBlockHeaders(_)))).is_some(),
+        "Node did not request block headers"
+    );
+    assert!(
+        peer.wait_for(STALLED_PEER_TIMEOUT, |event| matches!(event, SimulatedPeerEvent::Disconnected)).is_some(),
+        "Stalled peer was not disconnected"
+    );
+
+    node.shutdown();
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_fetch_mempool_operations_from_peer() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let runtime = create_runtime()?;
+    let chain = ScriptedChain::carthagenet(20)?;
+
+    // operations of the next block are not known to the node yet, so they are good candidates for the mempool
+    let next_block = ScriptedChain::carthagenet(21)?.head().clone();
+    assert!(next_block.operations.iter().any(|operations| !operations.is_empty()), "Block at level {} has no operations", next_block.level());
+    let operations = next_block.operations.into_iter().flatten().collect::<Vec<_>>();
+
+    let peer = SimulatedPeer::start(&runtime, chain, PeerBehaviour::Honest, log.clone())?;
+    let node = SimulatedNode::start("__shell_simulated_mempool", &runtime, &[peer.address()], log)?;
+    assert!(peer.wait_for(BLOCKS_TIMEOUT, |event| matches!(event, SimulatedPeerEvent::Connected)).is_some(), "Node did not connect to the peer");
+
+    let mut missing_operations = peer.add_mempool_operations(operations)?.into_iter().collect::<HashSet<_>>();
+    while !missing_operations.is_empty() {
+        match node.wait_for_shell_event(BLOCKS_TIMEOUT, |event| matches!(event, ShellChannelMsg::MempoolOperationReceived(_))) {
+            Some(ShellChannelMsg::MempoolOperationReceived(received)) => {
+                missing_operations.remove(&received.operation_hash);
+            }
+            _ => panic!("Mempool operations were not received, missing: {}", missing_operations.len()),
+        }
+    }
+
+    node.shutdown();
+    Ok(())
+}
@@ -20,6 +20,9 @@ pub type BlockMetaStorageKV = dyn KeyValueStoreWithSchema<BlockMetaStorage> + Sy
 pub trait BlockMetaStorageReader: Sync + Send {
     /// Load local head (block with highest level) from dedicated storage
     fn load_current_head(&self) -> Result<Option<(BlockHash, Level)>, StorageError>;
+
+    /// Check if the block was already applied
+    fn is_applied(&self, block_hash: &BlockHash) -> Result<bool, StorageError>;
 }
 
 #[derive(Clone)]
@@ -132,6 +135,10 @@ impl BlockMetaStorage {
 }
 
 impl BlockMetaStorageReader for BlockMetaStorage {
+    fn is_applied(&self, block_hash: &BlockHash) -> Result<bool, StorageError> {
+        Ok(self.get(block_hash)?.map(|meta| meta.is_applied()).unwrap_or(false))
+    }
+
     fn load_current_head(&self) -> Result<Option<(BlockHash, Level)>, StorageError> {
         self.iter(IteratorMode::End)
             .and_then(|meta_iterator|
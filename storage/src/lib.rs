@@ -25,6 +25,7 @@ pub use crate::operations_storage::{OperationKey, OperationsStorage, OperationsS
 use crate::persistent::{CommitLogError, DBError, Decoder, Encoder, SchemaError};
 pub use crate::persistent::database::{Direction, IteratorMode};
 use crate::persistent::sequence::SequenceError;
+pub use crate::point_storage::{PointInfo, PointStorage, PointStorageKV};
 pub use crate::system_storage::SystemStorage;
 
 pub mod persistent;
@@ -34,6 +35,7 @@ pub mod block_storage;
 pub mod block_meta_storage;
 pub mod context_action_storage;
 pub mod mempool_storage;
+pub mod point_storage;
 pub mod system_storage;
 pub mod skip_list;
 pub mod context;
@@ -382,6 +384,7 @@ pub mod tests_common {
                 Lane::descriptor(),
                 ListValue::descriptor(),
                 MempoolStorage::descriptor(),
+                PointStorage::descriptor(),
                 ContextActionStorage::descriptor()
             ])?;
             let clog = open_cl(&path, vec![
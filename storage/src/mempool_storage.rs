@@ -137,6 +137,22 @@ impl MempoolStorage {
         }
         Ok(operations)
     }
+
+    /// Deletes all operations, which `time_to_live` expired before `now`.
+    ///
+    /// Returns hashes of deleted operations.
+    pub fn delete_expired(&self, now: SystemTime) -> Result<Vec<OperationHash>, StorageError> {
+        let mut expired = Vec::new();
+        for (key, value) in self.kv.iterator(IteratorMode::Start)? {
+            let (key, value) = (key?, value?);
+            if value.time_to_live < now {
+                self.kv.delete(&key)
+                    .map_err(StorageError::from)?;
+                expired.push(key.operation_hash);
+            }
+        }
+        Ok(expired)
+    }
 }
 
 impl KeyValueSchema for MempoolStorage {
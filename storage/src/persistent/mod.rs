@@ -13,6 +13,7 @@ pub use database::{DBError, KeyValueStoreWithSchema};
 pub use schema::{CommitLogDescriptor, CommitLogSchema, KeyValueSchema};
 
 use crate::persistent::sequence::Sequences;
+use crate::StorageError;
 use crate::skip_list::{Bucket, DatabaseBackedSkipList, TypedSkipList};
 
 pub mod sequence;
@@ -97,6 +98,13 @@ impl PersistentStorage {
 
     #[inline]
     pub fn context_storage(&self) -> ContextList { self.cs.clone() }
+
+    /// Flush commit logs and database to disk
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.clog.flush()?;
+        self.kv.flush().map_err(DBError::from)?;
+        Ok(())
+    }
 }
 
 impl Drop for PersistentStorage {
@@ -0,0 +1,266 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{IteratorMode, StorageError};
+use crate::persistent::{BincodeEncoded, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+
+/// Convenience type for point storage database
+pub type PointStorageKV = dyn KeyValueStoreWithSchema<PointStorage> + Sync + Send;
+
+/// Point (address of the remote peer) is removed after this count of failed connections in a row
+pub const MAX_POINT_CONSECUTIVE_FAILURES: u32 = 5;
+
+/// Max count of the stored points, the worst points are evicted when the storage is full
+pub const MAX_STORED_POINTS: usize = 1000;
+
+/// Everything we learned about the point (address where the remote peer listens) from the previous connections
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PointInfo {
+    /// Peer id of the peer, which was listening at the point when we were connected last time
+    pub peer_id: Option<String>,
+    /// Last successful connection
+    pub last_seen: Option<SystemTime>,
+    pub successes: u32,
+    pub failures: u32,
+    /// Failed connections since the last successful connection
+    pub consecutive_failures: u32,
+    /// Point is banned until this time
+    pub banned_until: Option<SystemTime>,
+}
+
+impl Default for PointInfo {
+    fn default() -> Self {
+        PointInfo {
+            peer_id: None,
+            last_seen: None,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            banned_until: None,
+        }
+    }
+}
+
+impl PointInfo {
+    pub fn is_banned(&self, now: SystemTime) -> bool {
+        self.banned_until.filter(|banned_until| *banned_until > now).is_some()
+    }
+
+    /// Historically good points first: less failures in a row, more successes, seen more recently
+    pub fn cmp_preference(&self, other: &PointInfo) -> Ordering {
+        self.consecutive_failures.cmp(&other.consecutive_failures)
+            .then_with(|| other.successes.cmp(&self.successes))
+            .then_with(|| other.last_seen.cmp(&self.last_seen))
+    }
+}
+
+impl BincodeEncoded for PointInfo {}
+
+/// Point ordered by the preference, the best point is the least one
+struct RankedPoint<'a>(&'a SocketAddr, &'a PointInfo);
+
+impl Ord for RankedPoint<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.1.cmp_preference(other.1).then_with(|| self.0.cmp(other.0))
+    }
+}
+
+impl PartialOrd for RankedPoint<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedPoint<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedPoint<'_> {}
+
+/// Persistent database of the points, which survives the node restart.
+///
+/// All points are kept also in memory (loaded on the first access), so the database is not scanned on every lookup.
+/// Memory copy is shared only by the clones of the same `PointStorage`.
+#[derive(Clone)]
+pub struct PointStorage {
+    kv: Arc<PointStorageKV>,
+    points: Arc<Mutex<Option<HashMap<SocketAddr, PointInfo>>>>,
+}
+
+impl PointStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv(), points: Arc::new(Mutex::new(None)) }
+    }
+
+    #[inline]
+    pub fn get(&self, address: &SocketAddr) -> Result<Option<PointInfo>, StorageError> {
+        self.with_points(|points| Ok(points.get(address).cloned()))
+    }
+
+    /// Store the point, new point is stored only if there is a free space for it (see `discovered`)
+    pub fn put(&self, address: &SocketAddr, point: &PointInfo) -> Result<(), StorageError> {
+        self.with_points(|points| self.store(points, address, point.clone()).map(|_| ()))
+    }
+
+    pub fn delete(&self, address: &SocketAddr) -> Result<(), StorageError> {
+        self.with_points(|points| {
+            self.kv.delete(&address.to_string())?;
+            points.remove(address);
+            Ok(())
+        })
+    }
+
+    /// Store newly discovered point, already known point is not modified.
+    ///
+    /// When the storage is full, the worst stored point (failing, least successful, not seen for the longest time) is evicted,
+    /// but only if it is worse than the new point. Otherwise the new point is not stored.
+    ///
+    /// Returns `true` if point was stored.
+    pub fn discovered(&self, address: &SocketAddr) -> Result<bool, StorageError> {
+        self.with_points(|points| {
+            if points.contains_key(address) {
+                Ok(false)
+            } else {
+                self.store(points, address, PointInfo::default())
+            }
+        })
+    }
+
+    /// Record successful connection to the peer `peer_id` listening at the point
+    pub fn connection_succeeded(&self, address: &SocketAddr, peer_id: &str, now: SystemTime) -> Result<(), StorageError> {
+        self.update(address, |point| {
+            point.peer_id = Some(peer_id.to_string());
+            point.last_seen = Some(now);
+            point.successes = point.successes.saturating_add(1);
+            point.consecutive_failures = 0;
+        })
+    }
+
+    /// Record failed connection to the point, point which keeps failing is removed.
+    /// Point banned at the time `now` is kept until the ban expires, so the ban is not lost.
+    ///
+    /// Returns `true` if point was removed.
+    pub fn connection_failed(&self, address: &SocketAddr, now: SystemTime) -> Result<bool, StorageError> {
+        self.with_points(|points| {
+            let mut point = points.get(address).cloned().unwrap_or_default();
+            point.failures = point.failures.saturating_add(1);
+            point.consecutive_failures = point.consecutive_failures.saturating_add(1);
+
+            if point.consecutive_failures >= MAX_POINT_CONSECUTIVE_FAILURES && !point.is_banned(now) {
+                self.kv.delete(&address.to_string())?;
+                points.remove(address);
+                Ok(true)
+            } else {
+                self.store(points, address, point)?;
+                Ok(false)
+            }
+        })
+    }
+
+    /// Ban the point until `banned_until`
+    pub fn ban(&self, address: &SocketAddr, banned_until: SystemTime) -> Result<(), StorageError> {
+        self.update(address, |point| point.banned_until = Some(banned_until))
+    }
+
+    /// Returns all stored points
+    pub fn iter(&self) -> Result<Vec<(SocketAddr, PointInfo)>, StorageError> {
+        self.with_points(|points| Ok(points.iter().map(|(address, point)| (*address, point.clone())).collect()))
+    }
+
+    /// Returns at most `limit` points, which are not banned, historically good points first
+    pub fn find_best(&self, limit: usize, now: SystemTime) -> Result<Vec<(SocketAddr, PointInfo)>, StorageError> {
+        self.with_points(|points| {
+            // keep only the `limit` best points, the worst of them is on the top of the heap
+            let mut best = BinaryHeap::with_capacity(limit.saturating_add(1).min(points.len()));
+            for (address, point) in points.iter().filter(|(_, point)| !point.is_banned(now)) {
+                best.push(RankedPoint(address, point));
+                if best.len() > limit {
+                    best.pop();
+                }
+            }
+            Ok(best.into_sorted_vec()
+                .into_iter()
+                .map(|RankedPoint(address, point)| (*address, point.clone()))
+                .collect())
+        })
+    }
+
+    /// Returns points, which are banned at the time `now`
+    pub fn find_banned(&self, now: SystemTime) -> Result<Vec<SocketAddr>, StorageError> {
+        self.with_points(|points| Ok(points.iter()
+            .filter(|(_, point)| point.is_banned(now))
+            .map(|(address, _)| *address)
+            .collect()))
+    }
+
+    /// Modify the stored (or default) point by `f` and store it, all under the single lock
+    fn update<F>(&self, address: &SocketAddr, f: F) -> Result<(), StorageError>
+        where F: FnOnce(&mut PointInfo) {
+        self.with_points(|points| {
+            let mut point = points.get(address).cloned().unwrap_or_default();
+            f(&mut point);
+            self.store(points, address, point).map(|_| ())
+        })
+    }
+
+    /// Store the point, the worst point is evicted if the new point does not fit into the storage.
+    ///
+    /// Returns `false` if storage is full of the points, which are better than the new point.
+    fn store(&self, points: &mut HashMap<SocketAddr, PointInfo>, address: &SocketAddr, point: PointInfo) -> Result<bool, StorageError> {
+        if !points.contains_key(address) && points.len() >= MAX_STORED_POINTS {
+            let worst = points.iter()
+                .map(|(address, point)| RankedPoint(address, point))
+                .max()
+                .filter(|RankedPoint(_, worst)| worst.cmp_preference(&point) == Ordering::Greater)
+                .map(|RankedPoint(address, _)| *address);
+            match worst {
+                Some(worst) => {
+                    self.kv.delete(&worst.to_string())?;
+                    points.remove(&worst);
+                }
+                None => return Ok(false),
+            }
+        }
+
+        self.kv.put(&address.to_string(), &point)?;
+        points.insert(*address, point);
+        Ok(true)
+    }
+
+    /// Run `f` on the memory copy of the points, points are loaded from the database on the first access (invalid keys are skipped)
+    fn with_points<T, F>(&self, f: F) -> Result<T, StorageError>
+        where F: FnOnce(&mut HashMap<SocketAddr, PointInfo>) -> Result<T, StorageError> {
+        let mut points = self.points.lock().unwrap();
+        if points.is_none() {
+            let mut loaded = HashMap::new();
+            for (key, value) in self.kv.iterator(IteratorMode::Start)? {
+                let (key, value) = (key?, value?);
+                if let Ok(address) = key.parse() {
+                    loaded.insert(address, value);
+                }
+            }
+            *points = Some(loaded);
+        }
+        f(points.as_mut().expect("Points were loaded"))
+    }
+}
+
+impl KeyValueSchema for PointStorage {
+    type Key = String;
+    type Value = PointInfo;
+
+    #[inline]
+    fn name() -> &'static str {
+        "point_storage"
+    }
+}
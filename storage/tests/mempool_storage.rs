@@ -1,7 +1,7 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use failure::Error;
 
@@ -32,6 +32,27 @@ fn mempool_storage_read_write() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn mempool_storage_delete_expired() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__mempool_storage_delete_expired")?;
+    let mut storage = MempoolStorage::new(tmp_storage.storage());
+
+    let operation = make_test_operation_message()?;
+    let operation_hash = operation.message_hash()?.clone();
+    let now = SystemTime::now();
+
+    storage.put_pending(operation.clone(), now + Duration::from_secs(60))?;
+    assert!(storage.delete_expired(now)?.is_empty());
+    assert_eq!(1, storage.iter()?.len());
+
+    let expired = storage.delete_expired(now + Duration::from_secs(120))?;
+    assert_eq!(vec![operation_hash.clone()], expired);
+    assert!(storage.find(&operation_hash)?.is_none());
+    assert!(storage.iter()?.is_empty());
+
+    Ok(())
+}
+
 fn make_test_operation_message() -> Result<OperationMessage, Error> {
     let message_bytes = hex::decode("10490b79070cf19175cd7e3b9c1ee66f6e85799980404b119132ea7e58a4a97e000008c387fa065a181d45d47a9b78ddc77e92a881779ff2cbabbf9646eade4bf1405a08e00b725ed849eea46953b10b5cdebc518e6fd47e69b82d2ca18c4cf6d2f312dd08")?;
     let operation = Operation::from_bytes(message_bytes)?;
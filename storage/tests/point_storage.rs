@@ -0,0 +1,115 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+
+use storage::point_storage::{MAX_POINT_CONSECUTIVE_FAILURES, MAX_STORED_POINTS};
+use storage::PointStorage;
+use storage::tests_common::TmpStorage;
+
+#[test]
+fn point_storage_prefers_good_points() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__point_storage_prefers_good_points")?;
+    let storage = PointStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+
+    let good: SocketAddr = "127.0.0.1:9732".parse()?;
+    let unknown: SocketAddr = "127.0.0.2:9732".parse()?;
+    let failing: SocketAddr = "127.0.0.3:9732".parse()?;
+
+    storage.discovered(&unknown)?;
+    storage.connection_succeeded(&good, "idrRoknJh9zwEePNswF3MPGFzmKaVp", now)?;
+    storage.connection_failed(&failing, now)?;
+    // discovered point does not overwrite the known point
+    storage.discovered(&good)?;
+
+    let best = storage.find_best(10, now)?.into_iter().map(|(address, _)| address).collect::<Vec<_>>();
+    assert_eq!(vec![good, unknown, failing], best);
+    assert_eq!(Some("idrRoknJh9zwEePNswF3MPGFzmKaVp".to_string()), storage.get(&good)?.unwrap().peer_id);
+    assert_eq!(1, storage.find_best(1, now)?.len());
+
+    Ok(())
+}
+
+#[test]
+fn point_storage_ban_and_expire() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__point_storage_ban_and_expire")?;
+    let storage = PointStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+
+    let banned: SocketAddr = "127.0.0.1:9732".parse()?;
+    storage.ban(&banned, now + Duration::from_secs(60))?;
+    assert_eq!(vec![banned], storage.find_banned(now)?);
+    assert!(storage.find_best(10, now)?.is_empty());
+    assert!(storage.find_banned(now + Duration::from_secs(120))?.is_empty());
+
+    let failing: SocketAddr = "127.0.0.2:9732".parse()?;
+    for _ in 1..MAX_POINT_CONSECUTIVE_FAILURES {
+        assert!(!storage.connection_failed(&failing, now)?);
+    }
+    assert!(storage.connection_failed(&failing, now)?);
+    assert!(storage.get(&failing)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn point_storage_keeps_banned_point_on_failures() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__point_storage_keeps_banned_point_on_failures")?;
+    let storage = PointStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+    let banned_until = now + Duration::from_secs(60);
+
+    // banned point keeps failing, but it is not removed, so the ban is not lost
+    let banned: SocketAddr = "127.0.0.1:9732".parse()?;
+    storage.ban(&banned, banned_until)?;
+    for _ in 0..=MAX_POINT_CONSECUTIVE_FAILURES {
+        assert!(!storage.connection_failed(&banned, now)?);
+    }
+    let point = storage.get(&banned)?.expect("Banned point should be kept");
+    assert_eq!(Some(banned_until), point.banned_until);
+    assert_eq!(MAX_POINT_CONSECUTIVE_FAILURES + 1, point.consecutive_failures);
+    assert_eq!(vec![banned], storage.find_banned(now)?);
+
+    // after the ban expires, failing point is removed
+    assert!(storage.connection_failed(&banned, banned_until)?);
+    assert!(storage.get(&banned)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn point_storage_evicts_worst_points() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__point_storage_evicts_worst_points")?;
+    let storage = PointStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+    let point = |index: usize| -> SocketAddr { ([10, 0, (index / 256) as u8, (index % 256) as u8], 9732).into() };
+
+    for index in 0..MAX_STORED_POINTS {
+        assert!(storage.discovered(&point(index))?);
+    }
+    storage.connection_failed(&point(0), now)?;
+    storage.connection_succeeded(&point(1), "idrRoknJh9zwEePNswF3MPGFzmKaVp", now)?;
+
+    // failing point is evicted for the new point
+    let new: SocketAddr = "127.0.0.1:9732".parse()?;
+    assert!(storage.discovered(&new)?);
+    assert!(storage.get(&point(0))?.is_none());
+    assert_eq!(MAX_STORED_POINTS, storage.iter()?.len());
+
+    // storage is full of the points, which are not worse than the new point
+    let rejected: SocketAddr = "127.0.0.2:9732".parse()?;
+    assert!(!storage.discovered(&rejected)?);
+    assert!(storage.get(&rejected)?.is_none());
+    assert_eq!(MAX_STORED_POINTS, storage.iter()?.len());
+
+    // points are persisted, not only kept in memory
+    let reloaded = PointStorage::new(tmp_storage.storage());
+    assert_eq!(MAX_STORED_POINTS, reloaded.iter()?.len());
+    assert_eq!(point(1), reloaded.find_best(1, now)?[0].0);
+
+    Ok(())
+}
@@ -45,6 +45,7 @@ fn test_storage() -> Result<(), Error> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: true,
+        expected_pow: 0.0,
     };
 
     // initialize empty storage
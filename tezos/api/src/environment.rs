@@ -73,6 +73,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: false,
+        expected_pow: 26.0,
     });
 
     env.insert(TezosEnvironment::Babylonnet, TezosEnvironmentConfiguration {
@@ -93,6 +94,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: true,
+        expected_pow: 26.0,
     });
 
     env.insert(TezosEnvironment::Carthagenet, TezosEnvironmentConfiguration {
@@ -114,6 +116,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: true,
+        expected_pow: 26.0,
     });
 
     env.insert(TezosEnvironment::Mainnet, TezosEnvironmentConfiguration {
@@ -136,6 +139,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             ],
         },
         enable_testchain: false,
+        expected_pow: 26.0,
     });
 
     env.insert(TezosEnvironment::Zeronet, TezosEnvironmentConfiguration {
@@ -154,6 +158,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: true,
+        expected_pow: 26.0,
     });
 
     env.insert(TezosEnvironment::Sandbox, TezosEnvironmentConfiguration {
@@ -171,6 +176,7 @@ fn init() -> HashMap<TezosEnvironment, TezosEnvironmentConfiguration> {
             voted_protocol_overrides: vec![],
         },
         enable_testchain: false,
+        expected_pow: 0.0,
     });
 
     env
@@ -216,6 +222,8 @@ pub struct TezosEnvironmentConfiguration {
     pub protocol_overrides: ProtocolOverrides,
     /// if network has enabled switching test chains by default
     pub enable_testchain: bool,
+    /// minimal proof of work of the peer identity required during the handshake - see node_config_file.ml (expected_pow)
+    pub expected_pow: f64,
 }
 
 impl TezosEnvironmentConfiguration {
@@ -1,8 +1,40 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use failure::Fail;
+use hex::FromHexError;
 use serde::{Deserialize, Serialize};
 
+use crypto::crypto_box::{public_key_from_secret_key, random_keypair};
+use crypto::hash::HashType;
+use crypto::proof_of_work::{check_proof_of_work, generate_proof_of_work, PowError};
+
+#[derive(Fail, Debug)]
+pub enum IdentityError {
+    #[fail(display = "Invalid hex value of the {}: {}", field, reason)]
+    InvalidHex {
+        field: &'static str,
+        reason: FromHexError,
+    },
+    #[fail(display = "Public key does not match the secret key")]
+    PublicKeyMismatch,
+    #[fail(display = "Peer id does not match the public key, expected: {}, found: {}", expected, found)]
+    PeerIdMismatch {
+        expected: String,
+        found: String,
+    },
+    #[fail(display = "Proof of work error: {}", error)]
+    ProofOfWorkError {
+        error: PowError,
+    },
+}
+
+impl From<PowError> for IdentityError {
+    fn from(error: PowError) -> Self {
+        IdentityError::ProofOfWorkError { error }
+    }
+}
+
 /// This node identity information
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Identity {
@@ -10,4 +42,92 @@ pub struct Identity {
     pub public_key: String,
     pub secret_key: String,
     pub proof_of_work_stamp: String,
-}
\ No newline at end of file
+}
+
+impl Identity {
+    /// Generate new identity with the random crypto_box keypair and the proof of work stamp satisfying `expected_pow`.
+    ///
+    /// Proof of work is searched in `threads` threads, `progress` is periodically called with the count of stamps tried so far.
+    pub fn generate<F: FnMut(u64)>(expected_pow: f64, threads: usize, progress: F) -> Result<Self, IdentityError> {
+        let (public_key, secret_key) = random_keypair();
+        let public_key_bytes = decode_hex("public key", &public_key)?;
+        let proof_of_work_stamp = generate_proof_of_work(&public_key_bytes, expected_pow, threads, progress)?;
+
+        Ok(Identity {
+            peer_id: peer_id(&public_key_bytes),
+            public_key,
+            secret_key,
+            proof_of_work_stamp: hex::encode(&proof_of_work_stamp),
+        })
+    }
+
+    /// Check that keys belong together, peer id is derived from the public key and the proof of work stamp satisfies `expected_pow`
+    pub fn validate(&self, expected_pow: f64) -> Result<(), IdentityError> {
+        let public_key = decode_hex("public key", &self.public_key)?;
+        let proof_of_work_stamp = decode_hex("proof of work stamp", &self.proof_of_work_stamp)?;
+
+        let derived_public_key = public_key_from_secret_key(&self.secret_key)
+            .map_err(|reason| IdentityError::InvalidHex { field: "secret key", reason })?;
+        if derived_public_key != self.public_key.to_lowercase() {
+            return Err(IdentityError::PublicKeyMismatch);
+        }
+
+        let expected_peer_id = peer_id(&public_key);
+        if expected_peer_id != self.peer_id {
+            return Err(IdentityError::PeerIdMismatch { expected: expected_peer_id, found: self.peer_id.clone() });
+        }
+
+        check_proof_of_work(&public_key, &proof_of_work_stamp, expected_pow)?;
+        Ok(())
+    }
+}
+
+/// Peer id is base58 encoded blake2b digest of the crypto_box public key
+pub fn peer_id(public_key: &[u8]) -> String {
+    HashType::CryptoboxPublicKeyHash.bytes_to_string(public_key)
+}
+
+fn decode_hex(field: &'static str, value: &str) -> Result<Vec<u8>, IdentityError> {
+    hex::decode(value).map_err(|reason| IdentityError::InvalidHex { field, reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tezos_identity() -> Identity {
+        // identity generated by the tezos node with the expected difficulty 26 (docker/identities/identity_ocaml.json)
+        Identity {
+            peer_id: "idrRoknJh9zwEePNswF3MPGFzmKaVp".to_string(),
+            public_key: "5fd7ba1d15650abc9a510c37a8bee566b708fe6577f0d832b903e8f13d71c94a".to_string(),
+            secret_key: "213d61d6e41b443bec6bf0d00f17f59dfb4bdca409c9c03887cc5196be12502e".to_string(),
+            proof_of_work_stamp: "4b1354dcfc087e52c8fb510317b9464c297b8a55b79bfc95".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate_tezos_identity() -> Result<(), failure::Error> {
+        let identity = tezos_identity();
+        identity.validate(26.0)?;
+        assert!(matches!(identity.validate(27.0), Err(IdentityError::ProofOfWorkError { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_invalid_identity() {
+        let mut identity = tezos_identity();
+        identity.peer_id = "idtqxHUjbjbCfaDn4jczoPGsnhacKX".to_string();
+        assert!(matches!(identity.validate(0.0), Err(IdentityError::PeerIdMismatch { .. })));
+
+        let mut identity = tezos_identity();
+        identity.secret_key = random_keypair().1;
+        assert!(matches!(identity.validate(0.0), Err(IdentityError::PublicKeyMismatch)));
+    }
+
+    #[test]
+    fn test_generate_identity() -> Result<(), failure::Error> {
+        let identity = Identity::generate(8.0, 2, |_| ())?;
+        identity.validate(8.0)?;
+        Ok(())
+    }
+}
@@ -265,6 +265,14 @@ impl Encoding {
 /// Indicates that type has it's own ser/de schema.
 pub trait HasEncoding {
     fn encoding() -> Encoding;
+
+    /// Schema used with the peer, which negotiated the p2p network version (`distributed_db_version`, `p2p_version`).
+    ///
+    /// Types whose encoding differs between the network versions override this, the schema is the same for all versions by default.
+    /// Types containing such types override this too, so the version is passed to the nested encodings.
+    fn encoding_for_version(_distributed_db_version: u16, _p2p_version: u16) -> Encoding {
+        Self::encoding()
+    }
 }
 
 #[cfg(test)]
@@ -12,11 +12,12 @@ use crypto::hash::Hash;
 use tezos_encoding::binary_reader::{BinaryReader, BinaryReaderError};
 use tezos_encoding::binary_writer;
 use tezos_encoding::de::from_value as deserialize_from_value;
-use tezos_encoding::encoding::HasEncoding;
+use tezos_encoding::encoding::{Encoding, HasEncoding};
 use tezos_encoding::json_writer::JsonWriter;
 use tezos_encoding::ser;
 
 use crate::p2p::binary_message::MessageHashError::SerializationError;
+use crate::p2p::encoding::version::NetworkVersion;
 
 /// Size in bytes of the content length field
 pub const CONTENT_LENGTH_FIELD_BYTES: usize = 2;
@@ -28,12 +29,22 @@ pub mod cache {
 
     use serde::{Deserialize, Deserializer};
 
+    use crate::p2p::encoding::version::NetworkVersion;
+
+    /// Cached bytes are keyed by the network version they were encoded for, `None` stands for the default encoding
     pub trait CacheReader {
-        fn get(&self) -> Option<Vec<u8>>;
+        fn get(&self, version: Option<&NetworkVersion>) -> Option<Vec<u8>>;
     }
 
     pub trait CacheWriter {
-        fn put(&mut self, body: &[u8]);
+        fn put(&mut self, version: Option<&NetworkVersion>, body: &[u8]);
+    }
+
+    /// Cache key of the network version: (`distributed_db_version`, `p2p_version`)
+    type VersionKey = Option<(u16, u16)>;
+
+    fn version_key(version: Option<&NetworkVersion>) -> VersionKey {
+        version.map(|version| (version.distributed_db_version(), version.p2p_version()))
     }
 
     pub trait CachedData {
@@ -43,20 +54,22 @@ pub mod cache {
 
     #[derive(Clone, Default)]
     pub struct BinaryDataCache {
-        data: Option<Vec<u8>>
+        data: Option<(VersionKey, Vec<u8>)>
     }
 
     impl CacheReader for BinaryDataCache {
         #[inline]
-        fn get(&self) -> Option<Vec<u8>> {
-            self.data.as_ref().cloned()
+        fn get(&self, version: Option<&NetworkVersion>) -> Option<Vec<u8>> {
+            self.data.as_ref()
+                .filter(|(key, _)| *key == version_key(version))
+                .map(|(_, data)| data.clone())
         }
     }
 
     impl CacheWriter for BinaryDataCache {
         #[inline]
-        fn put(&mut self, body: &[u8]) {
-            self.data.replace(body.to_vec());
+        fn put(&mut self, version: Option<&NetworkVersion>, body: &[u8]) {
+            self.data.replace((version_key(version), body.to_vec()));
         }
     }
 
@@ -80,8 +93,8 @@ pub mod cache {
 
     impl fmt::Debug for BinaryDataCache {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match self.get() {
-                Some(data) => write!(f, "BinaryDataCache {{ has_value: true, len: {} }}", data.len()),
+            match &self.data {
+                Some((_, data)) => write!(f, "BinaryDataCache {{ has_value: true, len: {} }}", data.len()),
                 None => write!(f, "BinaryDataCache {{ has_value: false }}"),
             }
         }
@@ -101,14 +114,14 @@ pub mod cache {
 
     impl CacheReader for NeverCache {
         #[inline]
-        fn get(&self) -> Option<Vec<u8>> {
+        fn get(&self, _: Option<&NetworkVersion>) -> Option<Vec<u8>> {
             None
         }
     }
 
     impl CacheWriter for NeverCache {
         #[inline]
-        fn put(&mut self, _: &[u8]) {
+        fn put(&mut self, _: Option<&NetworkVersion>, _: &[u8]) {
             // ..
         }
     }
@@ -132,6 +145,14 @@ pub trait BinaryMessage: Sized {
 
     /// Create new struct from bytes.
     fn from_bytes(buf: Vec<u8>) -> Result<Self, BinaryReaderError>;
+
+    /// Produce bytes from the struct for the peer with the negotiated network `version`.
+    ///
+    /// Cached bytes are used only if they were encoded (or received) for the same version.
+    fn as_bytes_for_version(&self, version: &NetworkVersion) -> Result<Vec<u8>, ser::Error>;
+
+    /// Create new struct from bytes received from the peer with the negotiated network `version`.
+    fn from_bytes_for_version(buf: Vec<u8>, version: &NetworkVersion) -> Result<Self, BinaryReaderError>;
 }
 
 impl<T> BinaryMessage for T
@@ -139,22 +160,44 @@ impl<T> BinaryMessage for T
 
     #[inline]
     fn as_bytes(&self) -> Result<Vec<u8>, ser::Error> {
-        match self.cache_reader().get() {
-            Some(data) => Ok(data),
-            None => binary_writer::write(self, &Self::encoding())
-        }
+        write_with_encoding(self, None, Self::encoding)
     }
 
     #[inline]
     fn from_bytes(buf: Vec<u8>) -> Result<Self, BinaryReaderError> {
-        let body = buf.clone();
-        let value = BinaryReader::new().read(buf, &Self::encoding())?;
-        let mut myself: Self = deserialize_from_value(&value)?;
-        if let Some(cache_writer) = myself.cache_writer() {
-            cache_writer.put(&body);
-        }
-        Ok(myself)
+        read_with_encoding(buf, None, &Self::encoding())
+    }
+
+    #[inline]
+    fn as_bytes_for_version(&self, version: &NetworkVersion) -> Result<Vec<u8>, ser::Error> {
+        write_with_encoding(self, Some(version), || Self::encoding_for_version(version.distributed_db_version(), version.p2p_version()))
+    }
+
+    #[inline]
+    fn from_bytes_for_version(buf: Vec<u8>, version: &NetworkVersion) -> Result<Self, BinaryReaderError> {
+        read_with_encoding(buf, Some(version), &Self::encoding_for_version(version.distributed_db_version(), version.p2p_version()))
+    }
+}
+
+#[inline]
+fn write_with_encoding<T, F>(message: &T, version: Option<&NetworkVersion>, encoding: F) -> Result<Vec<u8>, ser::Error>
+    where T: cache::CachedData + Serialize, F: FnOnce() -> Encoding {
+    match message.cache_reader().get(version) {
+        Some(data) => Ok(data),
+        None => binary_writer::write(message, &encoding())
+    }
+}
+
+#[inline]
+fn read_with_encoding<T>(buf: Vec<u8>, version: Option<&NetworkVersion>, encoding: &Encoding) -> Result<T, BinaryReaderError>
+    where T: cache::CachedData + DeserializeOwned {
+    let body = buf.clone();
+    let value = BinaryReader::new().read(buf, encoding)?;
+    let mut myself: T = deserialize_from_value(&value)?;
+    if let Some(cache_writer) = myself.cache_writer() {
+        cache_writer.put(version, &body);
     }
+    Ok(myself)
 }
 
 
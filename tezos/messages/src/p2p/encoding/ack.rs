@@ -20,7 +20,7 @@ pub enum AckMessage {
     Nack(NackInfo),
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum NackMotive {
     NoMotive,
     TooManyConnections,
@@ -30,7 +30,7 @@ pub enum NackMotive {
     AlreadyConnected
 }
 
-#[derive(Serialize, Deserialize, Getters, PartialEq)]
+#[derive(Serialize, Deserialize, Getters, PartialEq, Clone)]
 pub struct NackInfo {
     #[get = "pub"]
     motive: NackMotive,
@@ -113,6 +113,16 @@ pub struct PathRight {
     body: BinaryDataCache,
 }
 
+impl PathRight {
+    pub fn new(left: Hash, path: Path) -> Self {
+        PathRight {
+            left,
+            path,
+            body: Default::default()
+        }
+    }
+}
+
 impl HasEncoding for PathRight {
     fn encoding() -> Encoding {
         Encoding::Obj(vec![
@@ -145,6 +155,16 @@ pub struct PathLeft {
     body: BinaryDataCache,
 }
 
+impl PathLeft {
+    pub fn new(path: Path, right: Hash) -> Self {
+        PathLeft {
+            path,
+            right,
+            body: Default::default()
+        }
+    }
+}
+
 impl HasEncoding for PathLeft {
     fn encoding() -> Encoding {
         Encoding::Obj(vec![
@@ -58,41 +58,59 @@ impl CachedData for PeerMessageResponse {
 }
 
 lazy_static! {
-    static ref ENCODING: Encoding = Encoding::Obj(vec![
-                Field::new("messages", Encoding::dynamic(Encoding::list(
-                    Encoding::Tags(
-                        size_of::<u16>(),
-                        TagMap::new(&[
-                            Tag::new(0x01, "Disconnect", Encoding::Unit),
-                            Tag::new(0x02, "Bootstrap", Encoding::Unit),
-                            Tag::new(0x03, "Advertise", AdvertiseMessage::encoding()),
-                            Tag::new(0x04, "SwapRequest", SwapMessage::encoding()),
-                            Tag::new(0x05, "SwapAck", SwapMessage::encoding()),
-                            Tag::new(0x10, "GetCurrentBranch", GetCurrentBranchMessage::encoding()),
-                            Tag::new(0x11, "CurrentBranch", CurrentBranchMessage::encoding()),
-                            Tag::new(0x12, "Deactivate", DeactivateMessage::encoding()),
-                            Tag::new(0x13, "GetCurrentHead", GetCurrentHeadMessage::encoding()),
-                            Tag::new(0x14, "CurrentHead", CurrentHeadMessage::encoding()),
-                            Tag::new(0x20, "GetBlockHeaders", GetBlockHeadersMessage::encoding()),
-                            Tag::new(0x21, "BlockHeader", BlockHeaderMessage::encoding()),
-                            Tag::new(0x30, "GetOperations", GetOperationsMessage::encoding()),
-                            Tag::new(0x31, "Operation", OperationMessage::encoding()),
-                            Tag::new(0x40, "GetProtocols", GetProtocolsMessage::encoding()),
-                            Tag::new(0x41, "Protocol", ProtocolMessage::encoding()),
-                            Tag::new(0x50, "GetOperationHashesForBlocks", GetOperationHashesForBlocksMessage::encoding()),
-                            Tag::new(0x51, "OperationHashesForBlocks", OperationHashesForBlocksMessage::encoding()),
-                            Tag::new(0x60, "GetOperationsForBlocks", GetOperationsForBlocksMessage::encoding()),
-                            Tag::new(0x61, "OperationsForBlocks", OperationsForBlocksMessage::encoding()),
-                        ])
-                    )
-                )))
-            ]);
+    static ref ENCODING: Encoding = peer_message_response_encoding(None);
+}
+
+/// Encoding of the messages for the network version (`distributed_db_version`, `p2p_version`), `None` means the default encoding
+fn peer_message_response_encoding(version: Option<(u16, u16)>) -> Encoding {
+    macro_rules! versioned {
+        ($t:ty) => {
+            match version {
+                Some((distributed_db_version, p2p_version)) => <$t>::encoding_for_version(distributed_db_version, p2p_version),
+                None => <$t>::encoding(),
+            }
+        }
+    }
+
+    Encoding::Obj(vec![
+        Field::new("messages", Encoding::dynamic(Encoding::list(
+            Encoding::Tags(
+                size_of::<u16>(),
+                TagMap::new(&[
+                    Tag::new(0x01, "Disconnect", Encoding::Unit),
+                    Tag::new(0x02, "Bootstrap", Encoding::Unit),
+                    Tag::new(0x03, "Advertise", versioned!(AdvertiseMessage)),
+                    Tag::new(0x04, "SwapRequest", versioned!(SwapMessage)),
+                    Tag::new(0x05, "SwapAck", versioned!(SwapMessage)),
+                    Tag::new(0x10, "GetCurrentBranch", versioned!(GetCurrentBranchMessage)),
+                    Tag::new(0x11, "CurrentBranch", versioned!(CurrentBranchMessage)),
+                    Tag::new(0x12, "Deactivate", versioned!(DeactivateMessage)),
+                    Tag::new(0x13, "GetCurrentHead", versioned!(GetCurrentHeadMessage)),
+                    Tag::new(0x14, "CurrentHead", versioned!(CurrentHeadMessage)),
+                    Tag::new(0x20, "GetBlockHeaders", versioned!(GetBlockHeadersMessage)),
+                    Tag::new(0x21, "BlockHeader", versioned!(BlockHeaderMessage)),
+                    Tag::new(0x30, "GetOperations", versioned!(GetOperationsMessage)),
+                    Tag::new(0x31, "Operation", versioned!(OperationMessage)),
+                    Tag::new(0x40, "GetProtocols", versioned!(GetProtocolsMessage)),
+                    Tag::new(0x41, "Protocol", versioned!(ProtocolMessage)),
+                    Tag::new(0x50, "GetOperationHashesForBlocks", versioned!(GetOperationHashesForBlocksMessage)),
+                    Tag::new(0x51, "OperationHashesForBlocks", versioned!(OperationHashesForBlocksMessage)),
+                    Tag::new(0x60, "GetOperationsForBlocks", versioned!(GetOperationsForBlocksMessage)),
+                    Tag::new(0x61, "OperationsForBlocks", versioned!(OperationsForBlocksMessage)),
+                ])
+            )
+        )))
+    ])
 }
 
 impl HasEncoding for PeerMessageResponse {
     fn encoding() -> Encoding {
         ENCODING.clone()
     }
+
+    fn encoding_for_version(distributed_db_version: u16, p2p_version: u16) -> Encoding {
+        peer_message_response_encoding(Some((distributed_db_version, p2p_version)))
+    }
 }
 
 impl From<PeerMessage> for PeerMessageResponse {
@@ -32,6 +32,18 @@ impl NetworkVersion {
         NetworkVersion { chain_name, distributed_db_version, p2p_version, body: Default::default() }
     }
 
+    pub fn chain_name(&self) -> &str {
+        &self.chain_name
+    }
+
+    pub fn distributed_db_version(&self) -> u16 {
+        self.distributed_db_version
+    }
+
+    pub fn p2p_version(&self) -> u16 {
+        self.p2p_version
+    }
+
     /// Returns true if version is compatibile.
     ///
     /// The version is compatible in case the `chain_name` and `distributed_db_version` version are the same.
@@ -39,6 +51,19 @@ impl NetworkVersion {
     pub fn supports(&self, other: &NetworkVersion) -> bool {
         self.chain_name == other.chain_name && self.distributed_db_version == other.distributed_db_version
     }
+
+    /// Select the best version supported by both sides of the connection.
+    ///
+    /// Candidates are the compatible pairs of the `supported` and `remote` versions (see [`supports`](NetworkVersion::supports))
+    /// with the lower of both p2p versions. The candidate with the highest `distributed_db_version` and then the highest
+    /// `p2p_version` is selected, so both sides negotiate the same version. Returns `None` if there is no compatible version.
+    pub fn negotiate(supported: &[NetworkVersion], remote: &[NetworkVersion]) -> Option<NetworkVersion> {
+        supported.iter()
+            .flat_map(|local| remote.iter()
+                .filter(move |remote| local.supports(remote))
+                .map(move |remote| NetworkVersion::new(local.chain_name.clone(), local.distributed_db_version, local.p2p_version.min(remote.p2p_version))))
+            .max_by_key(|version| (version.distributed_db_version, version.p2p_version))
+    }
 }
 
 impl HasEncoding for NetworkVersion {
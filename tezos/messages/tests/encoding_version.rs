@@ -0,0 +1,69 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::binary_message::cache::CachedData;
+use tezos_messages::p2p::encoding::prelude::*;
+
+const CHAIN_NAME: &str = "TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z";
+
+fn version(chain_name: &str, distributed_db_version: u16, p2p_version: u16) -> NetworkVersion {
+    NetworkVersion::new(chain_name.to_string(), distributed_db_version, p2p_version)
+}
+
+#[test]
+fn can_serialize_and_deserialize_version() -> Result<(), Error> {
+    let message = version("TEST", 1, 1);
+    let serialized = hex::encode(message.as_bytes()?);
+    let expected = "000000045445535400010001";
+    assert_eq!(expected, &serialized);
+
+    let deserialized = NetworkVersion::from_bytes(hex::decode(expected)?)?;
+    Ok(assert_eq!(message, deserialized))
+}
+
+#[test]
+fn can_negotiate_version() {
+    let supported = vec![version(CHAIN_NAME, 0, 1), version(CHAIN_NAME, 1, 1)];
+
+    // the highest common distributed_db_version wins
+    let negotiated = NetworkVersion::negotiate(&supported, &[version(CHAIN_NAME, 0, 1), version(CHAIN_NAME, 1, 1)]);
+    assert_eq!(Some(version(CHAIN_NAME, 1, 1)), negotiated);
+
+    // old peer supports only the old distributed_db_version and p2p_version
+    let negotiated = NetworkVersion::negotiate(&supported, &[version(CHAIN_NAME, 0, 0)]);
+    assert_eq!(Some(version(CHAIN_NAME, 0, 0)), negotiated);
+
+    // negotiation is symmetric
+    assert_eq!(negotiated, NetworkVersion::negotiate(&[version(CHAIN_NAME, 0, 0)], &supported));
+
+    assert_eq!(None, NetworkVersion::negotiate(&supported, &[version(CHAIN_NAME, 2, 1)]));
+    assert_eq!(None, NetworkVersion::negotiate(&supported, &[version("TEZOS_MAINNET", 0, 1)]));
+    assert_eq!(None, NetworkVersion::negotiate(&supported, &[]));
+}
+
+#[test]
+fn can_serialize_and_deserialize_peer_message_for_negotiated_version() -> Result<(), Error> {
+    let chain_id = hex::decode("8eceda2f")?;
+    let message: PeerMessageResponse = GetCurrentBranchMessage::new(chain_id.clone()).into();
+    let expected = "0000000600108eceda2f";
+
+    let new_version = version(CHAIN_NAME, 1, 1);
+    let serialized = hex::encode(message.as_bytes_for_version(&new_version)?);
+    assert_eq!(expected, &serialized);
+
+    let deserialized = PeerMessageResponse::from_bytes_for_version(hex::decode(&serialized)?, &new_version)?;
+    match deserialized.messages().as_slice() {
+        [PeerMessage::GetCurrentBranch(message)] => assert_eq!(chain_id, message.chain_id),
+        messages => panic!("Unexpected messages: {:?}", messages),
+    }
+
+    // received bytes are cached only for the version they were received with
+    assert_eq!(Some(hex::decode(expected)?), deserialized.cache_reader().get(Some(&new_version)));
+    assert_eq!(None, deserialized.cache_reader().get(Some(&version(CHAIN_NAME, 0, 0))));
+    assert_eq!(None, deserialized.cache_reader().get(None));
+    assert_eq!(expected, &hex::encode(deserialized.as_bytes()?));
+    Ok(())
+}